@@ -44,7 +44,9 @@ CREATE TABLE public.resources
     bucket        text   NOT NULL,
     create_time   bigint NOT NULL,
     hash          text   NOT NULL,
-    resource_size bigint NOT NULL
+    resource_size bigint NOT NULL,
+    content_type  text   NOT NULL DEFAULT 'application/octet-stream'::text,
+    filename      text
 );
 
 
@@ -79,6 +81,20 @@ COMMENT ON COLUMN public.resources.create_time IS 'resouce create time';
 COMMENT ON COLUMN public.resources.hash IS 'resource hash';
 
 
+--
+-- Name: COLUMN resources.content_type; Type: COMMENT; Schema: public; Owner: postgres
+--
+
+COMMENT ON COLUMN public.resources.content_type IS 'resource content type';
+
+
+--
+-- Name: COLUMN resources.filename; Type: COMMENT; Schema: public; Owner: postgres
+--
+
+COMMENT ON COLUMN public.resources.filename IS 'resource original filename';
+
+
 --
 -- Data for Name: id_generate; Type: TABLE DATA; Schema: public; Owner: postgres
 --
@@ -93,7 +109,7 @@ test_id	21
 -- Data for Name: resources; Type: TABLE DATA; Schema: public; Owner: postgres
 --
 
-COPY public.resources (id, bucket, create_time, hash, resource_size) FROM stdin;
+COPY public.resources (id, bucket, create_time, hash, resource_size, content_type, filename) FROM stdin;
 \.
 
 
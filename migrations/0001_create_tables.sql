@@ -0,0 +1,26 @@
+-- the tables may be created by db.sql already, so everything is created only when it is absent
+
+create table if not exists id_generate
+(
+    id_type  text   not null,
+    id_value bigint not null,
+    constraint id_generate_pk primary key (id_type)
+);
+
+create table if not exists resources
+(
+    id            text   not null,
+    bucket        text   not null,
+    create_time   bigint not null,
+    hash          text   not null,
+    resource_size bigint not null,
+    constraint resources_pk primary key (id)
+);
+
+comment on column resources.id is 'resource id';
+comment on column resources.bucket is 'resource bucket';
+comment on column resources.create_time is 'resouce create time';
+comment on column resources.hash is 'resource hash';
+
+-- used by get_resource_by_hash
+create index if not exists resources_hash_index on resources (hash);
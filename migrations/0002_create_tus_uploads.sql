@@ -0,0 +1,15 @@
+-- the unfinished tus uploads, the chunks are stored in the tus bucket until the upload is complete
+
+create table if not exists tus_uploads
+(
+    id            text   not null,
+    upload_length bigint not null,
+    upload_offset bigint not null default 0,
+    chunk_ids     text[] not null default '{}',
+    filename      text,
+    create_time   bigint not null,
+    constraint tus_uploads_pk primary key (id)
+);
+
+comment on column tus_uploads.upload_offset is 'received bytes';
+comment on column tus_uploads.chunk_ids is 'stored chunk ids in offset order';
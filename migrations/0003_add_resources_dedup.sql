@@ -0,0 +1,9 @@
+-- the resources deduped by hash are unique by hash, so the concurrent uploads of the same content
+-- can't insert twice, the resources with the client ids and the copies may share the hash
+
+alter table resources
+    add column if not exists dedup boolean not null default false;
+
+create unique index if not exists resources_dedup_hash_index on resources (hash) where dedup;
+
+comment on column resources.dedup is 'resource is deduped by hash';
@@ -0,0 +1,3 @@
+-- used by the listing which is ordered and filtered by the create time
+
+create index if not exists resources_create_time_index on resources (create_time);
@@ -0,0 +1,9 @@
+-- the dimensions are null for the non-raster or undetectable formats and the resources uploaded
+-- before they are recorded
+
+alter table resources
+    add column if not exists width integer,
+    add column if not exists height integer;
+
+comment on column resources.width is 'image width in pixels';
+comment on column resources.height is 'image height in pixels';
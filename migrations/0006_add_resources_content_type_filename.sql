@@ -0,0 +1,9 @@
+-- the baseline tables have no content type and filename, the tables created by db.sql may have
+-- them already
+
+alter table resources
+    add column if not exists content_type text not null default 'application/octet-stream'::text,
+    add column if not exists filename text;
+
+comment on column resources.content_type is 'resource content type';
+comment on column resources.filename is 'resource original filename';
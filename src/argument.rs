@@ -11,8 +11,18 @@ setting(ColorAuto),
 setting(ColoredHelp)
 )]
 pub struct Argument {
-    #[structopt(short, long, help = "config path, `-` means read config from stdin")]
-    pub config: PathBuf,
+    #[structopt(
+    short,
+    long,
+    help = "config path, `-` means read config from stdin, can be omitted when all required configs are set by `IMAGE_BED_*` envs"
+    )]
+    pub config: Option<PathBuf>,
+
+    #[structopt(
+    long,
+    help = "validate the config, check the db and store backend are reachable, then exit"
+    )]
+    pub check: bool,
 }
 
 impl Argument {
@@ -0,0 +1,198 @@
+use std::str::FromStr;
+
+use crate::config::Config;
+use crate::store::cos::{self, CosBackend, RetryConfig, ServerSideEncryption, TimeoutConfig};
+use crate::store::fallback::FallbackBackend;
+use crate::store::replicated::{ReplicaPolicy, ReplicatedBackend};
+use crate::store::DynBackend;
+
+/// The store backends which can be selected by `backend` in the config.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BackendKind {
+    Cos,
+    Replicated,
+}
+
+impl BackendKind {
+    pub const COS: &'static str = "cos";
+    pub const REPLICATED: &'static str = "replicated";
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            Self::COS => Ok(BackendKind::Cos),
+            Self::REPLICATED => Ok(BackendKind::Replicated),
+            s => Err(anyhow::anyhow!(
+                "backend {} is invalid, must be {} or {}",
+                s,
+                Self::COS,
+                Self::REPLICATED
+            )),
+        }
+    }
+}
+
+/// Build the backend selected by the config, the backends are boxed, so the handler is generic
+/// over a single backend type whichever backend is selected.
+pub fn from_config(config: &Config) -> anyhow::Result<DynBackend<cos::Error>> {
+    let primary = cos_backend(
+        config,
+        &config.region,
+        config.endpoint.as_deref(),
+        &config.app_id,
+    )?;
+
+    let backend: DynBackend<cos::Error> = match config.backend_kind()? {
+        BackendKind::Cos => Box::new(primary),
+
+        BackendKind::Replicated => {
+            let replica_region = config.replica_region.as_deref().ok_or_else(|| {
+                anyhow::anyhow!("replica_region is required by the replicated backend")
+            })?;
+
+            let replica = cos_backend(
+                config,
+                replica_region,
+                config.replica_endpoint.as_deref(),
+                config.replica_app_id.as_deref().unwrap_or(&config.app_id),
+            )?;
+
+            let policy = config
+                .replica_policy
+                .as_deref()
+                .map_or(Ok(ReplicaPolicy::Primary), ReplicaPolicy::from_str)?;
+
+            Box::new(ReplicatedBackend::new(primary, replica, policy))
+        }
+    };
+
+    match &config.fallback_region {
+        None => Ok(backend),
+
+        // the resources which are not found in the selected backend are read from the fallback
+        Some(fallback_region) => {
+            let fallback = cos_backend(
+                config,
+                fallback_region,
+                config.fallback_endpoint.as_deref(),
+                config.fallback_app_id.as_deref().unwrap_or(&config.app_id),
+            )?;
+
+            Ok(Box::new(FallbackBackend::new(backend, fallback)))
+        }
+    }
+}
+
+/// The cos of the region with the shared keys and object settings.
+fn cos_backend(
+    config: &Config,
+    region: &str,
+    endpoint: Option<&str>,
+    app_id: &str,
+) -> anyhow::Result<CosBackend> {
+    let mut backend = match endpoint {
+        None => CosBackend::new(&config.access_key, &config.secret_key, region, app_id),
+
+        Some(endpoint) => CosBackend::with_endpoint(
+            &config.access_key,
+            &config.secret_key,
+            region,
+            endpoint,
+            app_id,
+        ),
+    };
+
+    backend
+        .set_retry_config(RetryConfig::new(
+            config.cos_retry_max_attempts,
+            config.cos_retry_base_delay,
+        )?)
+        .set_timeout_config(TimeoutConfig::new(
+            config.cos_connect_timeout,
+            config.cos_request_timeout,
+        )?);
+
+    if let Some(storage_class) = &config.storage_class {
+        backend.set_storage_class(storage_class)?;
+    }
+
+    if let Some(key_prefix) = &config.key_prefix {
+        backend.set_key_prefix(key_prefix);
+    }
+
+    if let Some(mode) = &config.server_side_encryption {
+        backend.set_server_side_encryption(ServerSideEncryption::new(
+            mode,
+            config.kms_key_id.as_deref(),
+        )?);
+    }
+
+    Ok(backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            access_key: "access-key".to_string(),
+            secret_key: "secret-key".to_string(),
+            region: "ap-guangzhou".to_string(),
+            app_id: "1250000000".to_string(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_backend_kind_from_str() {
+        assert_eq!(BackendKind::from_str("cos").unwrap(), BackendKind::Cos);
+        assert_eq!(
+            BackendKind::from_str("replicated").unwrap(),
+            BackendKind::Replicated
+        );
+        assert_eq!(
+            BackendKind::from_str("local").unwrap_err().to_string(),
+            "backend local is invalid, must be cos or replicated"
+        );
+    }
+
+    #[test]
+    fn test_from_config() {
+        let mut config = config();
+
+        let backend = from_config(&config).unwrap();
+        assert!(format!("{:?}", backend).starts_with("CosBackend"));
+
+        // the replicated backend is selected by the replica region by default
+        config.replica_region = Some("ap-beijing".to_string());
+
+        let backend = from_config(&config).unwrap();
+        assert!(format!("{:?}", backend).starts_with("ReplicatedBackend"));
+
+        config.backend = Some("replicated".to_string());
+
+        let backend = from_config(&config).unwrap();
+        assert!(format!("{:?}", backend).starts_with("ReplicatedBackend"));
+
+        config.fallback_region = Some("ap-shanghai".to_string());
+
+        let backend = from_config(&config).unwrap();
+        assert!(format!("{:?}", backend).starts_with("FallbackBackend"));
+
+        let mut config = self::config();
+        config.backend = Some("replicated".to_string());
+
+        assert_eq!(
+            from_config(&config).unwrap_err().to_string(),
+            "replica_region is required by the replicated backend"
+        );
+
+        config.backend = Some("local".to_string());
+
+        assert!(from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::PgPool;
+
+use crate::log::LogContext;
+use crate::store::StoreBackend;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The result of every check, a failed check has the error message.
+#[derive(Debug, Default)]
+pub struct Report {
+    items: Vec<(&'static str, Result<(), String>)>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.items.iter().all(|(_, result)| result.is_ok())
+    }
+
+    fn add<E: Display>(&mut self, name: &'static str, result: Result<(), E>) {
+        self.items
+            .push((name, result.map_err(|err| err.to_string())));
+    }
+
+    fn failures(&self) -> Vec<String> {
+        self.items
+            .iter()
+            .filter_map(|(name, result)| {
+                result
+                    .as_ref()
+                    .err()
+                    .map(|err| format!("{}: {}", name, err))
+            })
+            .collect()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (name, result) in &self.items {
+            match result {
+                Ok(_) => writeln!(f, "[ok]   {}", name)?,
+                Err(err) => writeln!(f, "[fail] {}: {}", name, err)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check the db and the store backend are usable without serving, the config should be
+/// validated already.
+pub async fn check<S: StoreBackend + Sync>(connect_options: PgConnectOptions, backend: &S) -> Report {
+    let mut report = Report::default();
+    report.add("config", Ok::<_, String>(()));
+
+    let db_pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .connect_with(connect_options)
+        .await;
+
+    match db_pool {
+        Err(err) => {
+            report.add("db connection", Err(err));
+        }
+
+        Ok(db_pool) => {
+            report.add("db connection", Ok::<_, String>(()));
+
+            for (name, table) in &[
+                ("resources table", "resources"),
+                ("id_generate table", "id_generate"),
+            ] {
+                report.add(name, check_table(&db_pool, table).await);
+            }
+        }
+    }
+
+    let log_cx = LogContext::builder().request_id("check").build();
+
+    report.add("store backend", backend.ping(&log_cx).await);
+
+    report
+}
+
+/// Print the report, and return the failures as error so the process exits with non zero.
+pub async fn run<S: StoreBackend + Sync>(
+    connect_options: PgConnectOptions,
+    backend: &S,
+) -> anyhow::Result<()> {
+    let report = check(connect_options, backend).await;
+
+    print!("{}", report);
+
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("check failed: {}", report.failures().join("; ")))
+    }
+}
+
+async fn check_table(db_pool: &PgPool, table: &str) -> sqlx::Result<()> {
+    sqlx::query(&format!("select from {} limit 1", table))
+        .execute(db_pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::str::FromStr;
+
+    use crate::store::memory::{self, MemoryBackend};
+
+    use super::*;
+
+    fn connect_options() -> PgConnectOptions {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+
+        PgConnectOptions::from_str(&pg_uri).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_check_ok() {
+        let report = check(connect_options(), &MemoryBackend::new()).await;
+
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.items.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_check_store_backend_failed() {
+        let backend = MemoryBackend::new();
+        backend.inject_error(memory::Error::Unavailable);
+
+        let report = check(connect_options(), &backend).await;
+
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.failures(),
+            vec!["store backend: memory backend is unavailable".to_string()]
+        );
+    }
+}
@@ -1,18 +1,1555 @@
+use std::fmt::Display;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use hyper::Uri;
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+use crate::backend::BackendKind;
+use crate::hash::HashAlgorithm;
+use crate::http::cors;
+use crate::http::handle;
+use crate::http::ip_filter;
+use crate::http::listen::ConnectionOptions;
+use crate::id::IdEncoding;
+use crate::id::snowflake;
+use crate::log::{self, LogFormat};
+use crate::media::{self, WatermarkPosition};
+use crate::store::cos::{self, ServerSideEncryption};
+use crate::store::replicated::ReplicaPolicy;
+
+pub const ENV_PREFIX: &str = "IMAGE_BED_";
+/// The shorter secret is easy to brute force from a signed link.
+const MIN_URL_SIGNING_SECRET_LENGTH: usize = 16;
+
+/// All fields are optional when deserializing, so the config can be fully provided by the
+/// environment variables, the required fields are checked by `validate`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub domain: String,
     pub database_name: String,
     pub host: String,
     pub user: String,
     pub password: String,
+    pub password_file: Option<PathBuf>,
     pub port: Option<u16>,
     pub max_body_size: Option<u64>,
+    /// The max body size of the uploads, `max_body_size` is used if not set.
+    pub upload_max_body_size: Option<u64>,
+    /// The max body size of the delete batch requests, `max_body_size` is used if not set.
+    pub batch_max_body_size: Option<u64>,
     pub access_key: String,
+    pub access_key_file: Option<PathBuf>,
     pub secret_key: String,
+    pub secret_key_file: Option<PathBuf>,
     pub region: String,
     pub app_id: String,
     pub listen_addr: String,
     pub listen_port: u16,
+    /// Extra `ip:port` listen endpoints, served together with `listen_addr` and `listen_port`.
+    pub listen: Vec<String>,
+    pub strip_exif: Option<bool>,
+    pub cache_control_max_age: Option<u64>,
+    pub access_log: Option<bool>,
+    pub request_id_header: Option<String>,
+    pub admin_token: Option<String>,
+    pub db_max_connections: Option<u32>,
+    pub db_acquire_timeout: Option<u64>,
+    pub db_idle_timeout: Option<u64>,
+    /// Connect the db on startup up to the times, 7 by default.
+    pub db_connect_max_attempts: Option<u32>,
+    /// The first delay between the db connection attempts in milliseconds, doubled after every
+    /// attempt up to 30 seconds, 1 second by default.
+    pub db_connect_retry_delay: Option<u64>,
+    pub cos_retry_max_attempts: Option<u32>,
+    pub cos_retry_base_delay: Option<u64>,
+    /// Fail the cos connecting which is not done in the seconds, 10 by default.
+    pub cos_connect_timeout: Option<u64>,
+    /// Fail the cos request whose response head is not received in the seconds, the failed
+    /// requests are retried, 60 by default.
+    pub cos_request_timeout: Option<u64>,
+    pub circuit_breaker: Option<bool>,
+    pub circuit_breaker_failure_threshold: Option<u32>,
+    pub circuit_breaker_cooldown: Option<u64>,
+    pub default_scheme: Option<String>,
+    /// The CIDRs or addresses of the proxies whose `X-Forwarded-*` headers are trusted, the
+    /// client ip is the rightmost `X-Forwarded-For` hop which is not a trusted proxy.
+    pub trusted_proxies: Vec<String>,
+    /// The CIDRs or addresses which can reach the `ip_filter_paths`, all by default.
+    pub ip_filter_allow: Vec<String>,
+    /// The CIDRs or addresses which can't reach the `ip_filter_paths`, even if they are allowed.
+    pub ip_filter_deny: Vec<String>,
+    /// The paths filtered by the peer address, such as `/list`, `/usage` and `/bucket`.
+    pub ip_filter_paths: Vec<String>,
+    pub allowed_content_types: Vec<String>,
+    pub max_image_width: Option<u32>,
+    pub max_image_height: Option<u32>,
+    pub upload_path: Option<String>,
+    pub get_path: Option<String>,
+    /// The resource metadata cache is disabled when it is not set or 0.
+    pub resource_cache_capacity: Option<usize>,
+    pub resource_cache_ttl: Option<u64>,
+    pub compression: Option<bool>,
+    pub storage_class: Option<String>,
+    /// `AES256` or `aws:kms`, `kms_key_id` is required by `aws:kms`.
+    pub server_side_encryption: Option<String>,
+    pub kms_key_id: Option<String>,
+    /// Override the cos endpoint derived from the region, e.g. `https://cos.accelerate.myqcloud.com`.
+    pub endpoint: Option<String>,
+    /// Store the objects as `<key_prefix>/<id>`, so the apps can share a bucket.
+    pub key_prefix: Option<String>,
+    /// `cos` or `replicated`, `replicated` if `replica_region` is set, otherwise `cos` by default.
+    pub backend: Option<String>,
+    /// Read the resources which are not found from the cos of the region, for migrating the
+    /// resources to the cos above. The keys are shared, and the writes never go to it.
+    pub fallback_region: Option<String>,
+    /// The app id of the fallback cos, `app_id` by default.
+    pub fallback_app_id: Option<String>,
+    /// Override the fallback cos endpoint derived from `fallback_region`.
+    pub fallback_endpoint: Option<String>,
+    /// Write every resource to the cos of the region too, and read it when the cos above fails.
+    /// The keys are shared.
+    pub replica_region: Option<String>,
+    /// The app id of the replica cos, `app_id` by default.
+    pub replica_app_id: Option<String>,
+    /// Override the replica cos endpoint derived from `replica_region`.
+    pub replica_endpoint: Option<String>,
+    /// `primary` or `all`, whether the failed replica writes fail the requests, `primary` by
+    /// default.
+    pub replica_policy: Option<String>,
+    /// Run the db migrations on startup, enabled by default.
+    pub migrate: Option<bool>,
+    /// Transcode the JPEG and PNG images to WebP on download, disabled by default.
+    pub transcode: Option<bool>,
+    /// Check the full downloads with the stored SHA-256 hash and respond 500 on mismatch, it
+    /// buffers and hashes every full download, so it's disabled by default.
+    pub verify_on_read: Option<bool>,
+    /// Check the dedup hits against the uploaded bytes in case of a hash collision, `off`,
+    /// `size` or `full`, `off` by default.
+    pub verify_dedup_bytes: Option<String>,
+    /// `sha256` or `blake3`, the hash algorithm of the new uploads, `sha256` by default. The
+    /// blake3 hashes are stored as `blake3:<hex>`, and the uploads are not deduped against the
+    /// resources hashed by the other algorithm.
+    pub hash_algorithm: Option<String>,
+    /// Post the new uploads to the url as json.
+    pub upload_webhook_url: Option<String>,
+    /// Post the urls of the deleted resources to the url as json, so the CDN can purge them.
+    pub cdn_purge_url: Option<String>,
+    /// Reject the new uploads once the total bytes of the resources would exceed it.
+    pub max_total_bytes: Option<u64>,
+    /// Roll the uploads of a month over to `<month>-1`, `<month>-2` and so on once the bucket
+    /// holds the objects, unlimited by default.
+    pub max_objects_per_bucket: Option<u64>,
+    /// Create the upload bucket at startup, `/ready` fails until it is created, disabled by
+    /// default.
+    pub precreate_bucket: Option<bool>,
+    /// Upload all the resources to the bucket instead of the bucket of the upload month, for the
+    /// single bucket deployments.
+    pub fixed_bucket: Option<String>,
+    /// Export the request spans to the OTLP/HTTP collector, requires the `otlp` feature.
+    pub otlp_endpoint: Option<String>,
+    /// Abort the request when no body bytes are received in the seconds, 30 by default.
+    pub body_read_timeout: Option<u64>,
+    /// Respond 504 when the request is not handled in the seconds, including the body reading,
+    /// disabled by default.
+    pub request_timeout: Option<u64>,
+    /// The seconds after which the resources are swept by `POST /sweep` when the request has no
+    /// `before`, the sweep requires `before` when it's unset.
+    pub resource_ttl: Option<u64>,
+    /// Where the uploads larger than `memory_spill_threshold` are spilled, the system temp dir by
+    /// default.
+    pub temp_dir: Option<PathBuf>,
+    /// Spill the upload into a temp file once it's larger than the bytes, so the slow uploads
+    /// don't hold the memory, the uploads are kept in memory by default.
+    pub memory_spill_threshold: Option<u64>,
+    /// Respond 503 with `Retry-After` to the uploads beyond the number of the in-flight ones,
+    /// the downloads are not limited, unlimited by default.
+    pub max_concurrent_uploads: Option<usize>,
+    /// Enable CORS for the origins, such as `https://app.example.com`, `*` allows any origin,
+    /// disabled when it's empty.
+    pub cors_allowed_origins: Vec<String>,
+    /// Let the browsers cache the CORS preflight results for the seconds.
+    pub cors_max_age: Option<u64>,
+    /// Respond `Access-Control-Allow-Credentials: true`, it can't be used with the `*` origin,
+    /// false by default.
+    pub cors_allow_credentials: Option<bool>,
+    /// Reject the downloads whose `Referer` host is not in the list, disabled when it's empty.
+    pub allowed_referers: Vec<String>,
+    /// Allow the downloads without `Referer` when `allowed_referers` is set, true by default.
+    pub allow_empty_referer: Option<bool>,
+    /// The image served to the rejected hotlinks instead of 403.
+    pub hotlink_placeholder: Option<PathBuf>,
+    /// Overlay the image on the JPEG and PNG images downloaded with `?watermark=1`.
+    pub watermark_path: Option<PathBuf>,
+    /// `top-left`, `top-right`, `bottom-left`, `bottom-right` or `center`, `bottom-right` by
+    /// default.
+    pub watermark_position: Option<String>,
+    /// In `[0, 1]`, 0.5 by default.
+    pub watermark_opacity: Option<f32>,
+    /// Watermark every download of the JPEG and PNG images, disabled by default.
+    pub watermark_always: Option<bool>,
+    /// Sign the expiring download links issued by the admin `/sign/<id>` api, the links are
+    /// verified on download, at least 16 bytes.
+    pub url_signing_secret: Option<String>,
+    /// `counter` or `snowflake`, the snowflake ids don't need the `id_generate` table, `counter`
+    /// by default.
+    pub id_encoding: Option<String>,
+    /// The snowflake machine id, every instance sharing the db must have a different one.
+    pub machine_id: Option<u16>,
+    /// The first value of the `counter` ids, only applied when the counter is created, so the
+    /// ids of a previous deployment are not reused, 0 by default.
+    pub id_seed: Option<i64>,
+    /// The content type of the uploads which can't be detected, `application/octet-stream` by
+    /// default.
+    pub default_content_type: Option<String>,
+    /// Serve the resources of the default content type as the type of the extension in the get
+    /// url, like `/get/<id>.png`, enabled by default.
+    pub extension_content_type: Option<bool>,
+    /// `json` or `text`, `json` by default.
+    pub log_format: Option<String>,
+    /// `trace`, `debug`, `info`, `warning`, `error` or `critical`, `info` by default.
+    pub log_level: Option<String>,
+    /// Accept the HTTP/2 prior knowledge (h2c) connections besides HTTP/1, for the proxies
+    /// speaking h2c to the upstream, disabled by default.
+    pub http2: Option<bool>,
+    /// Only accept the h2c connections.
+    pub http2_only: Option<bool>,
+    /// In seconds, 0 disables the HTTP/1 keep-alive, otherwise the idle HTTP/1 connections are
+    /// closed after it, and it's the TCP keepalive time and the HTTP/2 ping interval. The system
+    /// defaults are used and the idle connections are kept when it's not set.
+    pub keep_alive_timeout: Option<u64>,
+    /// In seconds, the time to read the HTTP/1 request head, unlimited by default.
+    pub header_read_timeout: Option<u64>,
+    /// The excess connections wait until a connection is closed, unlimited by default.
+    pub max_connections: Option<usize>,
+    /// The accept queue length of the listeners, 1024 by default.
+    pub listen_backlog: Option<u32>,
+    /// Set `SO_REUSEADDR` on the listeners, enabled by default on unix.
+    pub reuse_address: Option<bool>,
+    /// Set `SO_REUSEPORT` on the listeners, so the worker processes can listen on the same port,
+    /// disabled by default.
+    pub reuse_port: Option<bool>,
+}
+
+impl Config {
+    /// Override the fields with the `IMAGE_BED_<FIELD>` environment variables, the variable name
+    /// is the upper case field name, e.g. `IMAGE_BED_LISTEN_PORT` overrides `listen_port`.
+    pub fn apply_env<F>(&mut self, lookup: F) -> anyhow::Result<()>
+        where
+            F: Fn(&str) -> Option<String>,
+    {
+        let env = Env { lookup };
+
+        env.set("DOMAIN", &mut self.domain)?;
+        env.set("DATABASE_NAME", &mut self.database_name)?;
+        env.set("HOST", &mut self.host)?;
+        env.set("USER", &mut self.user)?;
+        env.set("PASSWORD", &mut self.password)?;
+        env.set_option("PASSWORD_FILE", &mut self.password_file)?;
+        env.set_option("PORT", &mut self.port)?;
+        env.set_option("MAX_BODY_SIZE", &mut self.max_body_size)?;
+        env.set_option("UPLOAD_MAX_BODY_SIZE", &mut self.upload_max_body_size)?;
+        env.set_option("BATCH_MAX_BODY_SIZE", &mut self.batch_max_body_size)?;
+        env.set("ACCESS_KEY", &mut self.access_key)?;
+        env.set_option("ACCESS_KEY_FILE", &mut self.access_key_file)?;
+        env.set("SECRET_KEY", &mut self.secret_key)?;
+        env.set_option("SECRET_KEY_FILE", &mut self.secret_key_file)?;
+        env.set("REGION", &mut self.region)?;
+        env.set("APP_ID", &mut self.app_id)?;
+        env.set("LISTEN_ADDR", &mut self.listen_addr)?;
+        env.set("LISTEN_PORT", &mut self.listen_port)?;
+        env.set_list("LISTEN", &mut self.listen)?;
+        env.set_option("STRIP_EXIF", &mut self.strip_exif)?;
+        env.set_option("CACHE_CONTROL_MAX_AGE", &mut self.cache_control_max_age)?;
+        env.set_option("ACCESS_LOG", &mut self.access_log)?;
+        env.set_option("REQUEST_ID_HEADER", &mut self.request_id_header)?;
+        env.set_option("ADMIN_TOKEN", &mut self.admin_token)?;
+        env.set_option("DB_MAX_CONNECTIONS", &mut self.db_max_connections)?;
+        env.set_option("DB_ACQUIRE_TIMEOUT", &mut self.db_acquire_timeout)?;
+        env.set_option("DB_IDLE_TIMEOUT", &mut self.db_idle_timeout)?;
+        env.set_option("DB_CONNECT_MAX_ATTEMPTS", &mut self.db_connect_max_attempts)?;
+        env.set_option("DB_CONNECT_RETRY_DELAY", &mut self.db_connect_retry_delay)?;
+        env.set_option("COS_RETRY_MAX_ATTEMPTS", &mut self.cos_retry_max_attempts)?;
+        env.set_option("COS_RETRY_BASE_DELAY", &mut self.cos_retry_base_delay)?;
+        env.set_option("COS_CONNECT_TIMEOUT", &mut self.cos_connect_timeout)?;
+        env.set_option("COS_REQUEST_TIMEOUT", &mut self.cos_request_timeout)?;
+        env.set_option("CIRCUIT_BREAKER", &mut self.circuit_breaker)?;
+        env.set_option(
+            "CIRCUIT_BREAKER_FAILURE_THRESHOLD",
+            &mut self.circuit_breaker_failure_threshold,
+        )?;
+        env.set_option("CIRCUIT_BREAKER_COOLDOWN", &mut self.circuit_breaker_cooldown)?;
+        env.set_option("DEFAULT_SCHEME", &mut self.default_scheme)?;
+        env.set_list("TRUSTED_PROXIES", &mut self.trusted_proxies)?;
+        env.set_list("IP_FILTER_ALLOW", &mut self.ip_filter_allow)?;
+        env.set_list("IP_FILTER_DENY", &mut self.ip_filter_deny)?;
+        env.set_list("IP_FILTER_PATHS", &mut self.ip_filter_paths)?;
+        env.set_list("ALLOWED_CONTENT_TYPES", &mut self.allowed_content_types)?;
+        env.set_option("MAX_IMAGE_WIDTH", &mut self.max_image_width)?;
+        env.set_option("MAX_IMAGE_HEIGHT", &mut self.max_image_height)?;
+        env.set_option("UPLOAD_PATH", &mut self.upload_path)?;
+        env.set_option("GET_PATH", &mut self.get_path)?;
+        env.set_option("RESOURCE_CACHE_CAPACITY", &mut self.resource_cache_capacity)?;
+        env.set_option("RESOURCE_CACHE_TTL", &mut self.resource_cache_ttl)?;
+        env.set_option("COMPRESSION", &mut self.compression)?;
+        env.set_option("STORAGE_CLASS", &mut self.storage_class)?;
+        env.set_option("SERVER_SIDE_ENCRYPTION", &mut self.server_side_encryption)?;
+        env.set_option("KMS_KEY_ID", &mut self.kms_key_id)?;
+        env.set_option("ENDPOINT", &mut self.endpoint)?;
+        env.set_option("KEY_PREFIX", &mut self.key_prefix)?;
+        env.set_option("BACKEND", &mut self.backend)?;
+        env.set_option("FALLBACK_REGION", &mut self.fallback_region)?;
+        env.set_option("FALLBACK_APP_ID", &mut self.fallback_app_id)?;
+        env.set_option("FALLBACK_ENDPOINT", &mut self.fallback_endpoint)?;
+        env.set_option("REPLICA_REGION", &mut self.replica_region)?;
+        env.set_option("REPLICA_APP_ID", &mut self.replica_app_id)?;
+        env.set_option("REPLICA_ENDPOINT", &mut self.replica_endpoint)?;
+        env.set_option("REPLICA_POLICY", &mut self.replica_policy)?;
+        env.set_option("MIGRATE", &mut self.migrate)?;
+        env.set_option("TRANSCODE", &mut self.transcode)?;
+        env.set_option("VERIFY_ON_READ", &mut self.verify_on_read)?;
+        env.set_option("VERIFY_DEDUP_BYTES", &mut self.verify_dedup_bytes)?;
+        env.set_option("HASH_ALGORITHM", &mut self.hash_algorithm)?;
+        env.set_option("UPLOAD_WEBHOOK_URL", &mut self.upload_webhook_url)?;
+        env.set_option("CDN_PURGE_URL", &mut self.cdn_purge_url)?;
+        env.set_option("MAX_TOTAL_BYTES", &mut self.max_total_bytes)?;
+        env.set_option("MAX_OBJECTS_PER_BUCKET", &mut self.max_objects_per_bucket)?;
+        env.set_option("PRECREATE_BUCKET", &mut self.precreate_bucket)?;
+        env.set_option("FIXED_BUCKET", &mut self.fixed_bucket)?;
+        env.set_option("OTLP_ENDPOINT", &mut self.otlp_endpoint)?;
+        env.set_option("BODY_READ_TIMEOUT", &mut self.body_read_timeout)?;
+        env.set_option("REQUEST_TIMEOUT", &mut self.request_timeout)?;
+        env.set_option("RESOURCE_TTL", &mut self.resource_ttl)?;
+        env.set_option("TEMP_DIR", &mut self.temp_dir)?;
+        env.set_option("MEMORY_SPILL_THRESHOLD", &mut self.memory_spill_threshold)?;
+        env.set_option("MAX_CONCURRENT_UPLOADS", &mut self.max_concurrent_uploads)?;
+        env.set_list("CORS_ALLOWED_ORIGINS", &mut self.cors_allowed_origins)?;
+        env.set_option("CORS_MAX_AGE", &mut self.cors_max_age)?;
+        env.set_option("CORS_ALLOW_CREDENTIALS", &mut self.cors_allow_credentials)?;
+        env.set_list("ALLOWED_REFERERS", &mut self.allowed_referers)?;
+        env.set_option("ALLOW_EMPTY_REFERER", &mut self.allow_empty_referer)?;
+        env.set_option("HOTLINK_PLACEHOLDER", &mut self.hotlink_placeholder)?;
+        env.set_option("WATERMARK_PATH", &mut self.watermark_path)?;
+        env.set_option("WATERMARK_POSITION", &mut self.watermark_position)?;
+        env.set_option("WATERMARK_OPACITY", &mut self.watermark_opacity)?;
+        env.set_option("WATERMARK_ALWAYS", &mut self.watermark_always)?;
+        env.set_option("URL_SIGNING_SECRET", &mut self.url_signing_secret)?;
+        env.set_option("ID_ENCODING", &mut self.id_encoding)?;
+        env.set_option("MACHINE_ID", &mut self.machine_id)?;
+        env.set_option("ID_SEED", &mut self.id_seed)?;
+        env.set_option("DEFAULT_CONTENT_TYPE", &mut self.default_content_type)?;
+        env.set_option("EXTENSION_CONTENT_TYPE", &mut self.extension_content_type)?;
+        env.set_option("LOG_FORMAT", &mut self.log_format)?;
+        env.set_option("LOG_LEVEL", &mut self.log_level)?;
+        env.set_option("HTTP2", &mut self.http2)?;
+        env.set_option("HTTP2_ONLY", &mut self.http2_only)?;
+        env.set_option("KEEP_ALIVE_TIMEOUT", &mut self.keep_alive_timeout)?;
+        env.set_option("HEADER_READ_TIMEOUT", &mut self.header_read_timeout)?;
+        env.set_option("MAX_CONNECTIONS", &mut self.max_connections)?;
+        env.set_option("LISTEN_BACKLOG", &mut self.listen_backlog)?;
+        env.set_option("REUSE_ADDRESS", &mut self.reuse_address)?;
+        env.set_option("REUSE_PORT", &mut self.reuse_port)?;
+
+        Ok(())
+    }
+
+    /// Check the config and report every problem at once.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = vec![];
+
+        let required = [
+            ("domain", &self.domain),
+            ("database_name", &self.database_name),
+            ("host", &self.host),
+            ("user", &self.user),
+            ("password", &self.password),
+            ("access_key", &self.access_key),
+            ("secret_key", &self.secret_key),
+            ("region", &self.region),
+            ("app_id", &self.app_id),
+        ];
+
+        for (name, value) in required.iter() {
+            if value.trim().is_empty() {
+                problems.push(format!("{} is not set", name));
+            }
+        }
+
+        if self.listen_addr.is_empty() {
+            if self.listen.is_empty() {
+                problems.push("listen or listen_addr is not set".to_string());
+            }
+        } else {
+            if IpAddr::from_str(&self.listen_addr).is_err() {
+                problems.push(format!(
+                    "listen_addr {:?} is not a valid ip address",
+                    self.listen_addr
+                ));
+            }
+
+            if self.listen_port == 0 {
+                problems.push("listen_port must not be 0".to_string());
+            }
+        }
+
+        for listen in &self.listen {
+            if SocketAddr::from_str(listen).is_err() {
+                problems.push(format!("listen {:?} is not a valid ip:port address", listen));
+            }
+        }
+
+        if self.port == Some(0) {
+            problems.push("port must not be 0".to_string());
+        }
+
+        for (name, max_body_size) in &[
+            ("max_body_size", self.max_body_size),
+            ("upload_max_body_size", self.upload_max_body_size),
+            ("batch_max_body_size", self.batch_max_body_size),
+        ] {
+            if *max_body_size == Some(0) {
+                problems.push(format!("{} must be positive", name));
+            }
+        }
+
+        if self.max_image_width == Some(0) {
+            problems.push("max_image_width must be positive".to_string());
+        }
+
+        if self.max_image_height == Some(0) {
+            problems.push("max_image_height must be positive".to_string());
+        }
+
+        if self.cos_connect_timeout == Some(0) {
+            problems.push("cos_connect_timeout must be positive".to_string());
+        }
+
+        if self.cos_request_timeout == Some(0) {
+            problems.push("cos_request_timeout must be positive".to_string());
+        }
+
+        if self.body_read_timeout == Some(0) {
+            problems.push("body_read_timeout must be positive".to_string());
+        }
+
+        if self.request_timeout == Some(0) {
+            problems.push("request_timeout must be positive".to_string());
+        }
+
+        if self.resource_ttl == Some(0) {
+            problems.push("resource_ttl must be positive".to_string());
+        }
+
+        if let Some(temp_dir) = &self.temp_dir {
+            if !temp_dir.is_dir() {
+                problems.push(format!("temp_dir {:?} is not a directory", temp_dir));
+            }
+        }
+
+        if self.memory_spill_threshold == Some(0) {
+            problems.push("memory_spill_threshold must be positive".to_string());
+        }
+
+        if self.max_concurrent_uploads == Some(0) {
+            problems.push("max_concurrent_uploads must be positive".to_string());
+        }
+
+        if self.max_objects_per_bucket == Some(0) {
+            problems.push("max_objects_per_bucket must be positive".to_string());
+        }
+
+        if let Some(Err(problem)) = self.fixed_bucket.as_deref().map(handle::check_fixed_bucket) {
+            problems.push(problem);
+        }
+
+        if self.header_read_timeout == Some(0) {
+            problems.push("header_read_timeout must be positive".to_string());
+        }
+
+        if self.max_connections == Some(0) {
+            problems.push("max_connections must be positive".to_string());
+        }
+
+        if self.listen_backlog == Some(0) {
+            problems.push("listen_backlog must be positive".to_string());
+        }
+
+        if self.resource_cache_ttl == Some(0) {
+            problems.push("resource_cache_ttl must be positive".to_string());
+        }
+
+        if let Some(storage_class) = &self.storage_class {
+            if !cos::STORAGE_CLASSES.contains(&storage_class.as_str()) {
+                problems.push(format!(
+                    "storage_class {:?} must be one of {}",
+                    storage_class,
+                    cos::STORAGE_CLASSES.join(", ")
+                ));
+            }
+        }
+
+        if let Some(mode) = &self.server_side_encryption {
+            if let Err(err) = ServerSideEncryption::new(mode, self.kms_key_id.as_deref()) {
+                problems.push(err.to_string());
+            }
+        }
+
+        for (name, url) in &[
+            ("endpoint", &self.endpoint),
+            ("fallback_endpoint", &self.fallback_endpoint),
+            ("replica_endpoint", &self.replica_endpoint),
+            ("upload_webhook_url", &self.upload_webhook_url),
+            ("cdn_purge_url", &self.cdn_purge_url),
+            ("otlp_endpoint", &self.otlp_endpoint),
+        ] {
+            if let Some(url) = url {
+                let valid = Uri::from_str(url).is_ok_and(|uri| {
+                    matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some()
+                });
+
+                if !valid {
+                    problems.push(format!("{} {:?} must be a http or https url", name, url));
+                }
+            }
+        }
+
+        if self.fallback_region.is_none()
+            && (self.fallback_app_id.is_some() || self.fallback_endpoint.is_some())
+        {
+            problems.push("fallback_region is required by the fallback cos".to_string());
+        }
+
+        if self.replica_region.is_none()
+            && (self.replica_app_id.is_some()
+            || self.replica_endpoint.is_some()
+            || self.replica_policy.is_some())
+        {
+            problems.push("replica_region is required by the replica cos".to_string());
+        }
+
+        match self.backend_kind() {
+            Err(err) => problems.push(err.to_string()),
+
+            Ok(BackendKind::Cos) if self.replica_region.is_some() => {
+                problems.push("replica_region requires the replicated backend".to_string());
+            }
+
+            Ok(BackendKind::Replicated) if self.replica_region.is_none() => {
+                problems.push("replica_region is required by the replicated backend".to_string());
+            }
+
+            Ok(_) => {}
+        }
+
+        if let Some(Err(err)) = self
+            .replica_policy
+            .as_deref()
+            .map(ReplicaPolicy::from_str)
+        {
+            problems.push(err.to_string());
+        }
+
+        if cfg!(not(feature = "otlp")) && self.otlp_endpoint.is_some() {
+            problems.push("otlp_endpoint requires the otlp feature".to_string());
+        }
+
+        if let Some(scheme) = &self.default_scheme {
+            if scheme != "http" && scheme != "https" {
+                problems.push(format!("default_scheme {:?} must be http or https", scheme));
+            }
+        }
+
+        if let Some(key_prefix) = &self.key_prefix {
+            let is_valid = key_prefix.trim_matches('/').split('/').all(|segment| {
+                !segment.is_empty()
+                    && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+            });
+
+            if !is_valid {
+                problems.push(format!("key_prefix {:?} is invalid", key_prefix));
+            }
+        }
+
+        if let Some(content_type) = &self.default_content_type {
+            if !media::is_valid_content_type(content_type) {
+                problems.push(format!(
+                    "default_content_type {:?} must be a type/subtype MIME type",
+                    content_type
+                ));
+            }
+        }
+
+        if let Err(problem) = handle::check_path_prefixes(
+            self.upload_path.as_deref().unwrap_or(handle::DEFAULT_UPLOAD_PATH),
+            self.get_path.as_deref().unwrap_or(handle::DEFAULT_GET_PATH),
+        ) {
+            problems.push(problem);
+        }
+
+        for proxy in &self.trusted_proxies {
+            if let Err(problem) = ip_filter::parse_net(proxy) {
+                problems.push(format!("trusted proxy {}", problem));
+            }
+        }
+
+        for net in self.ip_filter_allow.iter().chain(&self.ip_filter_deny) {
+            if let Err(problem) = ip_filter::parse_net(net) {
+                problems.push(problem);
+            }
+        }
+
+        let has_ip_filter = !self.ip_filter_allow.is_empty() || !self.ip_filter_deny.is_empty();
+
+        if has_ip_filter && self.ip_filter_paths.is_empty() {
+            problems.push("ip_filter_allow and ip_filter_deny require ip_filter_paths".to_string());
+        }
+
+        for path in &self.ip_filter_paths {
+            if !path.starts_with('/') {
+                problems.push(format!("ip filter path {:?} must start with /", path));
+            }
+        }
+
+        for referer in &self.allowed_referers {
+            let is_host = !referer.is_empty()
+                && referer
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+
+            if !is_host {
+                problems.push(format!("allowed referer {:?} must be a host name", referer));
+            }
+        }
+
+        if self.hotlink_placeholder.is_some() && self.allowed_referers.is_empty() {
+            problems.push("hotlink_placeholder requires allowed_referers".to_string());
+        }
+
+        if self.watermark_path.is_none()
+            && (self.watermark_position.is_some()
+            || self.watermark_opacity.is_some()
+            || self.watermark_always.is_some())
+        {
+            problems.push("watermark_path is required by the watermark".to_string());
+        }
+
+        if let Some(Err(err)) = self
+            .watermark_position
+            .as_deref()
+            .map(WatermarkPosition::from_str)
+        {
+            problems.push(err.to_string());
+        }
+
+        if let Some(opacity) = self.watermark_opacity {
+            if !(0.0..=1.0).contains(&opacity) {
+                problems.push(format!("watermark_opacity {} must be in [0, 1]", opacity));
+            }
+        }
+
+        if let Some(secret) = &self.url_signing_secret {
+            if secret.len() < MIN_URL_SIGNING_SECRET_LENGTH {
+                problems.push(format!(
+                    "url_signing_secret must be at least {} bytes",
+                    MIN_URL_SIGNING_SECRET_LENGTH
+                ));
+            }
+        }
+
+        for origin in &self.cors_allowed_origins {
+            let is_origin = origin == cors::ANY_ORIGIN
+                || origin.parse::<Uri>().is_ok_and(|uri| {
+                uri.scheme().is_some()
+                    && uri.host().is_some()
+                    && uri.path() == "/"
+                    && uri.query().is_none()
+            });
+
+            if !is_origin {
+                problems.push(format!(
+                    "cors allowed origin {:?} must be scheme://host[:port] or *",
+                    origin
+                ));
+            }
+        }
+
+        if (self.cors_max_age.is_some() || self.cors_allow_credentials.is_some())
+            && self.cors_allowed_origins.is_empty()
+        {
+            problems.push(
+                "cors_max_age and cors_allow_credentials require cors_allowed_origins".to_string(),
+            );
+        }
+
+        if self.cors_allow_credentials == Some(true)
+            && self.cors_allowed_origins.iter().any(|origin| origin == cors::ANY_ORIGIN)
+        {
+            problems.push("cors_allow_credentials can't be used with the * origin".to_string());
+        }
+
+        if let Some(Err(err)) = self.id_encoding.as_deref().map(IdEncoding::from_str) {
+            problems.push(err.to_string());
+        }
+
+        if let Some(Err(err)) = self
+            .verify_dedup_bytes
+            .as_deref()
+            .map(handle::DedupVerify::from_str)
+        {
+            problems.push(err.to_string());
+        }
+
+        if let Some(Err(err)) = self.hash_algorithm.as_deref().map(HashAlgorithm::from_str) {
+            problems.push(err.to_string());
+        }
+
+        if let Some(Err(err)) = self.log_format.as_deref().map(LogFormat::from_str) {
+            problems.push(err.to_string());
+        }
+
+        if let Some(Err(err)) = self.log_level.as_deref().map(log::parse_level) {
+            problems.push(err.to_string());
+        }
+
+        if let Some(machine_id) = self.machine_id {
+            if machine_id > snowflake::MAX_MACHINE_ID {
+                problems.push(format!(
+                    "machine_id {} must not be greater than {}",
+                    machine_id,
+                    snowflake::MAX_MACHINE_ID
+                ));
+            }
+        }
+
+        if self.id_seed.is_some_and(|id_seed| id_seed < 0) {
+            problems.push("id_seed must not be negative".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("invalid config: {}", problems.join("; ")))
+        }
+    }
+
+    /// The selected store backend, it's inferred from `replica_region` if not set.
+    pub fn backend_kind(&self) -> anyhow::Result<BackendKind> {
+        match &self.backend {
+            Some(backend) => BackendKind::from_str(backend),
+            None if self.replica_region.is_some() => Ok(BackendKind::Replicated),
+            None => Ok(BackendKind::Cos),
+        }
+    }
+
+    /// All listen endpoints, the config should be validated already.
+    pub fn listen_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut addrs = vec![];
+
+        if !self.listen_addr.is_empty() {
+            addrs.push(SocketAddr::new(
+                IpAddr::from_str(&self.listen_addr)?,
+                self.listen_port,
+            ));
+        }
+
+        for listen in &self.listen {
+            addrs.push(SocketAddr::from_str(listen)?);
+        }
+
+        Ok(addrs)
+    }
+
+    pub fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            keep_alive_timeout: self.keep_alive_timeout.map(Duration::from_secs),
+            header_read_timeout: self.header_read_timeout.map(Duration::from_secs),
+            max_connections: self.max_connections,
+            backlog: self.listen_backlog,
+            reuse_address: self.reuse_address,
+            reuse_port: self.reuse_port,
+        }
+    }
+
+    /// Read the secrets from the `*_file` options, which take precedence over the inline values.
+    pub fn load_secret_files(&mut self) -> anyhow::Result<()> {
+        if let Some(path) = &self.password_file {
+            self.password = read_secret_file(path)?;
+        }
+
+        if let Some(path) = &self.access_key_file {
+            self.access_key = read_secret_file(path)?;
+        }
+
+        if let Some(path) = &self.secret_key_file {
+            self.secret_key = read_secret_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Env<F> {
+    lookup: F,
+}
+
+impl<F: Fn(&str) -> Option<String>> Env<F> {
+    fn get<T>(&self, name: &str) -> anyhow::Result<Option<T>>
+        where
+            T: FromStr,
+            T::Err: Display,
+    {
+        let name = format!("{}{}", ENV_PREFIX, name);
+
+        match (self.lookup)(&name) {
+            None => Ok(None),
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|err| anyhow::anyhow!("env {} is invalid: {}", name, err)),
+        }
+    }
+
+    fn set<T>(&self, name: &str, field: &mut T) -> anyhow::Result<()>
+        where
+            T: FromStr,
+            T::Err: Display,
+    {
+        if let Some(value) = self.get(name)? {
+            *field = value;
+        }
+
+        Ok(())
+    }
+
+    fn set_option<T>(&self, name: &str, field: &mut Option<T>) -> anyhow::Result<()>
+        where
+            T: FromStr,
+            T::Err: Display,
+    {
+        if let Some(value) = self.get(name)? {
+            field.replace(value);
+        }
+
+        Ok(())
+    }
+
+    /// Comma separated values.
+    fn set_list(&self, name: &str, field: &mut Vec<String>) -> anyhow::Result<()> {
+        if let Some(value) = self.get::<String>(name)? {
+            *field = value
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+
+        Ok(())
+    }
+}
+
+fn read_secret_file(path: &Path) -> anyhow::Result<String> {
+    let secret = fs::read_to_string(path)
+        .map_err(|err| anyhow::anyhow!("read secret file {} failed: {}", path.display(), err))?;
+
+    // files created by `echo` or k8s secrets usually end with a newline
+    let secret = secret.trim_end_matches(&['\r', '\n'][..]);
+
+    if secret.is_empty() {
+        return Err(anyhow::anyhow!("secret file {} is empty", path.display()));
+    }
+
+    Ok(secret.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+domain: test.com
+database_name: image_bed
+host: localhost
+user: postgres
+password: inline-password
+access_key: inline-access-key
+region: ap-shanghai
+app_id: "123"
+listen_addr: 127.0.0.1
+listen_port: 80
+"#;
+
+    fn write_temp_file(content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("image_bed-secret-{}", rand::random::<u64>()));
+
+        fs::write(&path, content).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn test_load_secret_files() {
+        let password_file = write_temp_file("file-password\n");
+        let secret_key_file = write_temp_file("file-secret-key\r\n");
+
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.password_file = Some(password_file.clone());
+        config.secret_key_file = Some(secret_key_file.clone());
+
+        config.load_secret_files().unwrap();
+
+        assert_eq!(config.password, "file-password");
+        assert_eq!(config.access_key, "inline-access-key");
+        assert_eq!(config.secret_key, "file-secret-key");
+
+        fs::remove_file(password_file).unwrap();
+        fs::remove_file(secret_key_file).unwrap();
+    }
+
+    #[test]
+    fn test_load_empty_secret_file() {
+        let secret_key_file = write_temp_file("\n");
+
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key_file = Some(secret_key_file.clone());
+
+        assert!(config.load_secret_files().is_err());
+
+        fs::remove_file(secret_key_file).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_secret_file() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key_file = Some(env::temp_dir().join("image_bed-secret-not-exist"));
+
+        assert!(config.load_secret_files().is_err());
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let envs = [
+            ("IMAGE_BED_DOMAIN", "env.com"),
+            ("IMAGE_BED_LISTEN_PORT", "8080"),
+            ("IMAGE_BED_APP_ID", "456"),
+            ("IMAGE_BED_STRIP_EXIF", "true"),
+            ("IMAGE_BED_SECRET_KEY_FILE", "/run/secrets/secret_key"),
+        ]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+
+        config.apply_env(|name| envs.get(name).cloned()).unwrap();
+
+        assert_eq!(config.domain, "env.com");
+        assert_eq!(config.listen_port, 8080);
+        assert_eq!(config.app_id, "456");
+        assert_eq!(config.strip_exif, Some(true));
+        assert_eq!(
+            config.secret_key_file,
+            Some(PathBuf::from("/run/secrets/secret_key"))
+        );
+        // not overridden
+        assert_eq!(config.host, "localhost");
+        assert_eq!(config.access_key, "inline-access-key");
+    }
+
+    #[test]
+    fn test_apply_env_without_file() {
+        let envs = [
+            ("DOMAIN", "test.com"),
+            ("DATABASE_NAME", "image_bed"),
+            ("HOST", "localhost"),
+            ("USER", "postgres"),
+            ("PASSWORD", "password"),
+            ("ACCESS_KEY", "access-key"),
+            ("SECRET_KEY", "secret-key"),
+            ("REGION", "ap-shanghai"),
+            ("APP_ID", "123"),
+            ("LISTEN_ADDR", "0.0.0.0"),
+            ("LISTEN_PORT", "80"),
+        ];
+
+        // use a unique prefix in the real environment, so the other tests are not affected
+        let prefix = format!("IMAGE_BED_TEST_{}_", rand::random::<u32>());
+
+        for (name, value) in envs.iter() {
+            env::set_var(format!("{}{}", prefix, name), value);
+        }
+
+        let mut config = Config::default();
+
+        assert!(config.validate().is_err());
+
+        config
+            .apply_env(|name| {
+                env::var(name.replacen(ENV_PREFIX, &prefix, 1)).ok()
+            })
+            .unwrap();
+
+        config.load_secret_files().unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.listen_addr, "0.0.0.0");
+        assert_eq!(config.listen_port, 80);
+        assert_eq!(config.secret_key, "secret-key");
+    }
+
+    #[test]
+    fn test_apply_invalid_env() {
+        let mut config = Config::default();
+
+        let err = config
+            .apply_env(|name| {
+                if name == "IMAGE_BED_LISTEN_PORT" {
+                    Some("http".to_string())
+                } else {
+                    None
+                }
+            })
+            .unwrap_err();
+
+        assert!(err.to_string().contains("IMAGE_BED_LISTEN_PORT"));
+    }
+
+    #[test]
+    fn test_validate_missing_secret() {
+        let config: Config = serde_yaml::from_str(CONFIG).unwrap();
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: secret_key is not set"
+        );
+    }
+
+    #[test]
+    fn test_validate_invalid_listen() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.listen_addr = "localhost".to_string();
+        config.listen_port = 0;
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: listen_addr \"localhost\" is not a valid ip address; listen_port must not be 0"
+        );
+    }
+
+    #[test]
+    fn test_validate_aggregate_problems() {
+        let config = Config {
+            port: Some(0),
+            max_body_size: Some(0),
+            upload_max_body_size: Some(0),
+            ..Config::default()
+        };
+
+        let err = config.validate().unwrap_err().to_string();
+
+        for problem in &[
+            "domain is not set",
+            "region is not set",
+            "listen or listen_addr is not set",
+            "port must not be 0",
+            "max_body_size must be positive",
+            "upload_max_body_size must be positive",
+        ] {
+            assert!(err.contains(problem), "{} not in {}", problem, err);
+        }
+    }
+
+    #[test]
+    fn test_validate_path_prefixes() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.get_path = Some("/upload-get".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: upload_path \"/upload\" overlaps get_path \"/upload-get\""
+        );
+
+        config.get_path = Some("/api/get".to_string());
+        config.upload_path = Some("/api/upload".to_string());
+
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_otlp_endpoint() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.otlp_endpoint = Some("http://127.0.0.1:4318".to_string());
+
+        if cfg!(feature = "otlp") {
+            config.validate().unwrap();
+        } else {
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                "invalid config: otlp_endpoint requires the otlp feature"
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_allowed_referers() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.hotlink_placeholder = Some(PathBuf::from("placeholder.png"));
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: hotlink_placeholder requires allowed_referers"
+        );
+
+        config.allowed_referers = vec!["blog.example.com".to_string()];
+        config.validate().unwrap();
+
+        config.allowed_referers = vec!["https://blog.example.com/".to_string()];
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: allowed referer \"https://blog.example.com/\" must be a host name"
+        );
+    }
+
+    #[test]
+    fn test_validate_ip_filter() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.ip_filter_allow = vec!["10.0.0.0/8".to_string(), "::1".to_string()];
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: ip_filter_allow and ip_filter_deny require ip_filter_paths"
+        );
+
+        config.ip_filter_paths = vec!["/list".to_string(), "/usage".to_string()];
+        config.validate().unwrap();
+
+        config.ip_filter_deny = vec!["10.0.0.0/33".to_string()];
+        config.ip_filter_paths.push("bucket".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: \"10.0.0.0/33\" is not a valid CIDR or ip address; \
+             ip filter path \"bucket\" must start with /"
+        );
+    }
+
+    #[test]
+    fn test_validate_cos_timeouts() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.cos_connect_timeout = Some(5);
+        config.cos_request_timeout = Some(30);
+
+        config.validate().unwrap();
+
+        config.cos_connect_timeout = Some(0);
+        config.cos_request_timeout = Some(0);
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: cos_connect_timeout must be positive; \
+             cos_request_timeout must be positive"
+        );
+    }
+
+    #[test]
+    fn test_validate_trusted_proxies() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.trusted_proxies = vec!["10.0.0.0/8".to_string(), "fd00::1".to_string()];
+
+        config.validate().unwrap();
+
+        config.trusted_proxies.push("proxy.internal".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: trusted proxy \"proxy.internal\" is not a valid CIDR or ip address"
+        );
+    }
+
+    #[test]
+    fn test_validate_id_encoding() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.id_encoding = Some("snowflake".to_string());
+        config.machine_id = Some(1023);
+
+        config.validate().unwrap();
+
+        config.id_encoding = Some("uuid".to_string());
+        config.machine_id = Some(1024);
+        config.id_seed = Some(-1);
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: id encoding uuid is invalid, must be counter or snowflake; \
+             machine_id 1024 must not be greater than 1023; id_seed must not be negative"
+        );
+    }
+
+    #[test]
+    fn test_validate_verify_dedup_bytes() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.verify_dedup_bytes = Some("full".to_string());
+
+        config.validate().unwrap();
+
+        config.verify_dedup_bytes = Some("hash".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: verify dedup bytes hash is invalid, must be off, size or full"
+        );
+    }
+
+    #[test]
+    fn test_validate_hash_algorithm() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.hash_algorithm = Some("blake3".to_string());
+
+        config.validate().unwrap();
+
+        config.hash_algorithm = Some("md5".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: hash algorithm md5 is invalid, must be sha256 or blake3"
+        );
+    }
+
+    #[test]
+    fn test_validate_default_content_type() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.default_content_type = Some("image/jpeg".to_string());
+
+        config.validate().unwrap();
+
+        config.default_content_type = Some("jpeg".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: default_content_type \"jpeg\" must be a type/subtype MIME type"
+        );
+    }
+
+    #[test]
+    fn test_validate_log_options() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.log_format = Some("text".to_string());
+        config.log_level = Some("debug".to_string());
+
+        config.validate().unwrap();
+
+        config.log_format = Some("yaml".to_string());
+        config.log_level = Some("loud".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: log format yaml is invalid, must be json or text; log level loud is \
+             invalid, must be trace, debug, info, warning, error or critical"
+        );
+    }
+
+    #[test]
+    fn test_connection_options() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+
+        assert_eq!(config.connection_options(), ConnectionOptions::default());
+
+        config.keep_alive_timeout = Some(75);
+        config.header_read_timeout = Some(10);
+        config.max_connections = Some(1024);
+        config.listen_backlog = Some(4096);
+        config.reuse_port = Some(true);
+        config.validate().unwrap();
+
+        assert_eq!(
+            config.connection_options(),
+            ConnectionOptions {
+                keep_alive_timeout: Some(Duration::from_secs(75)),
+                header_read_timeout: Some(Duration::from_secs(10)),
+                max_connections: Some(1024),
+                backlog: Some(4096),
+                reuse_address: None,
+                reuse_port: Some(true),
+            }
+        );
+
+        config.header_read_timeout = Some(0);
+        config.max_connections = Some(0);
+        config.listen_backlog = Some(0);
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: header_read_timeout must be positive; max_connections must be \
+             positive; listen_backlog must be positive"
+        );
+    }
+
+    #[test]
+    fn test_validate_key_prefix() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+
+        for key_prefix in &["app", "team/app/", "app-1.images"] {
+            config.key_prefix = Some(key_prefix.to_string());
+            config.validate().unwrap();
+        }
+
+        for key_prefix in &["", "/", "team//app", "app?"] {
+            config.key_prefix = Some(key_prefix.to_string());
+
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                format!("invalid config: key_prefix {:?} is invalid", key_prefix)
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_fallback() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.fallback_endpoint = Some("https://cos.ap-guangzhou.myqcloud.com".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: fallback_region is required by the fallback cos"
+        );
+
+        config.fallback_region = Some("ap-guangzhou".to_string());
+        config.validate().unwrap();
+
+        config.fallback_endpoint = Some("cos.ap-guangzhou.myqcloud.com".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: fallback_endpoint \"cos.ap-guangzhou.myqcloud.com\" must be a http \
+             or https url"
+        );
+    }
+
+    #[test]
+    fn test_validate_replica() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.replica_policy = Some("all".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: replica_region is required by the replica cos"
+        );
+
+        config.replica_region = Some("ap-beijing".to_string());
+        config.validate().unwrap();
+
+        config.replica_policy = Some("any".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: replica policy any is invalid, must be primary or all"
+        );
+    }
+
+    #[test]
+    fn test_validate_backend() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.backend = Some("replicated".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: replica_region is required by the replicated backend"
+        );
+
+        config.replica_region = Some("ap-beijing".to_string());
+        config.validate().unwrap();
+
+        config.backend = Some("cos".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: replica_region requires the replicated backend"
+        );
+
+        config.backend = None;
+        assert_eq!(config.backend_kind().unwrap(), BackendKind::Replicated);
+
+        config.backend = Some("local".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: backend local is invalid, must be cos or replicated"
+        );
+    }
+
+    #[test]
+    fn test_listen_addrs() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config
+            .apply_env(|name| {
+                if name == "IMAGE_BED_LISTEN" {
+                    Some("10.0.0.1:8080, [::1]:8081".to_string())
+                } else {
+                    None
+                }
+            })
+            .unwrap();
+
+        config.validate().unwrap();
+
+        assert_eq!(
+            config.listen_addrs().unwrap(),
+            vec![
+                "127.0.0.1:80".parse::<SocketAddr>().unwrap(),
+                "10.0.0.1:8080".parse().unwrap(),
+                "[::1]:8081".parse().unwrap(),
+            ]
+        );
+
+        config.listen_addr = String::new();
+        config.listen = vec!["localhost:80".to_string()];
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: listen \"localhost:80\" is not a valid ip:port address"
+        );
+    }
+
+    #[test]
+    fn test_validate_watermark() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.watermark_always = Some(true);
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: watermark_path is required by the watermark"
+        );
+
+        config.watermark_path = Some(PathBuf::from("watermark.png"));
+        config.watermark_position = Some("top-right".to_string());
+        config.watermark_opacity = Some(0.3);
+        config.validate().unwrap();
+
+        config.watermark_position = Some("middle".to_string());
+        config.watermark_opacity = Some(1.5);
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: watermark position middle is invalid, must be one of top-left, \
+             top-right, bottom-left, bottom-right or center; watermark_opacity 1.5 must be in [0, 1]"
+        );
+    }
+
+    #[test]
+    fn test_validate_url_signing_secret() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.url_signing_secret = Some("short".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: url_signing_secret must be at least 16 bytes"
+        );
+
+        config.url_signing_secret = Some("0123456789abcdef".to_string());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_fixed_bucket() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.fixed_bucket = Some("images".to_string());
+
+        config.validate().unwrap();
+
+        config.fixed_bucket = Some("images-".to_string());
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: fixed_bucket \"images-\" must be [a-z0-9-] characters and not start \
+             or end with -"
+        );
+    }
+
+    #[test]
+    fn test_validate_cors() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.cors_max_age = Some(600);
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: cors_max_age and cors_allow_credentials require cors_allowed_origins"
+        );
+
+        config.cors_allowed_origins = vec![
+            "https://app.example.com".to_string(),
+            "http://localhost:8080/".to_string(),
+        ];
+        config.cors_allow_credentials = Some(true);
+
+        config.validate().unwrap();
+
+        config.cors_allowed_origins = vec!["*".to_string(), "app.example.com".to_string()];
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid config: cors allowed origin \"app.example.com\" must be scheme://host[:port] or *; \
+             cors_allow_credentials can't be used with the * origin"
+        );
+    }
+
+    #[test]
+    fn test_validate_spill() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+        config.temp_dir = Some(env::temp_dir());
+        config.memory_spill_threshold = Some(1024 * 1024);
+
+        config.validate().unwrap();
+
+        config.temp_dir = Some(env::temp_dir().join("image_bed-temp-not-exist"));
+        config.memory_spill_threshold = Some(0);
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            format!(
+                "invalid config: temp_dir {:?} is not a directory; memory_spill_threshold must be positive",
+                env::temp_dir().join("image_bed-temp-not-exist")
+            )
+        );
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        let mut config: Config = serde_yaml::from_str(CONFIG).unwrap();
+        config.secret_key = "secret-key".to_string();
+
+        config.validate().unwrap();
+    }
 }
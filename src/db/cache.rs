@@ -0,0 +1,271 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use slog::debug;
+
+use crate::db::Resource;
+use crate::log::{self, LogContext};
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Entry {
+    resource: Resource,
+    expire_at: Instant,
+    tick: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    // the least recently used entry has the smallest tick
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// A LRU cache of the resources by id. The resources never mutate, so only the deleted ones need
+/// to be invalidated, the ttl bounds how long a resource deleted by other instances is served.
+#[derive(Debug)]
+pub struct ResourceCache {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ResourceCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            inner: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Return the cached resource, or load it and cache it if it exists.
+    pub async fn get_or_load<F, Fut>(
+        &self,
+        resource_id: &str,
+        load: F,
+        log_cx: &LogContext,
+    ) -> Result<Option<Resource>>
+        where
+            F: FnOnce() -> Fut,
+            Fut: Future<Output=Result<Option<Resource>>>,
+    {
+        if let Some(resource) = self.get(resource_id) {
+            let hits = self.hits.fetch_add(1, Ordering::Relaxed) + 1;
+
+            debug!(
+                log::get_logger(),
+                "resource cache hit";
+                log_cx,
+                "resource_id" => resource_id,
+                "hits" => hits,
+                "misses" => self.misses()
+            );
+
+            return Ok(Some(resource));
+        }
+
+        let misses = self.misses.fetch_add(1, Ordering::Relaxed) + 1;
+
+        debug!(
+            log::get_logger(),
+            "resource cache miss";
+            log_cx,
+            "resource_id" => resource_id,
+            "hits" => self.hits(),
+            "misses" => misses
+        );
+
+        let resource = load().await?;
+
+        if let Some(resource) = &resource {
+            self.insert(resource.clone());
+        }
+
+        Ok(resource)
+    }
+
+    pub fn invalidate(&self, resource_ids: &[String]) {
+        let mut inner = self.inner.lock().unwrap();
+
+        for resource_id in resource_ids {
+            if let Some(entry) = inner.entries.remove(resource_id) {
+                inner.lru.remove(&entry.tick);
+            }
+        }
+    }
+
+    fn get(&self, resource_id: &str) -> Option<Resource> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let entry = inner.entries.get_mut(resource_id)?;
+
+        if entry.expire_at <= Instant::now() {
+            inner.lru.remove(&entry.tick);
+            inner.entries.remove(resource_id);
+
+            return None;
+        }
+
+        inner.lru.remove(&entry.tick);
+        inner.lru.insert(tick, resource_id.to_owned());
+        entry.tick = tick;
+
+        Some(entry.resource.clone())
+    }
+
+    fn insert(&self, resource: Resource) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let resource_id = resource.id.clone();
+
+        if let Some(entry) = inner.entries.remove(&resource_id) {
+            inner.lru.remove(&entry.tick);
+        }
+
+        while inner.entries.len() >= self.capacity {
+            let oldest_tick = match inner.lru.keys().next() {
+                None => break,
+                Some(tick) => *tick,
+            };
+
+            if let Some(oldest_id) = inner.lru.remove(&oldest_tick) {
+                inner.entries.remove(&oldest_id);
+            }
+        }
+
+        inner.lru.insert(tick, resource_id.clone());
+        inner.entries.insert(
+            resource_id,
+            Entry {
+                resource,
+                expire_at: Instant::now() + self.ttl,
+                tick,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    fn resource(id: &str) -> Resource {
+        Resource {
+            id: id.to_owned(),
+            bucket: "bucket".to_owned(),
+            create_time: 0,
+            hash: "hash".to_owned(),
+            resource_size: 4,
+            content_type: "image/png".to_owned(),
+            filename: None,
+            width: None,
+            height: None,
+        }
+    }
+
+    async fn get(cache: &ResourceCache, resource_id: &str, loads: &AtomicU32) -> Option<Resource> {
+        let log_cx = LogContext::builder().request_id("").build();
+
+        cache
+            .get_or_load(
+                resource_id,
+                || async move {
+                    loads.fetch_add(1, Ordering::SeqCst);
+
+                    Ok(if resource_id == "not-exist" {
+                        None
+                    } else {
+                        Some(resource(resource_id))
+                    })
+                },
+                &log_cx,
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit() {
+        let cache = ResourceCache::new(10, DEFAULT_TTL);
+        let loads = AtomicU32::new(0);
+
+        assert_eq!(get(&cache, "id", &loads).await.unwrap().get_id(), "id");
+        assert_eq!(get(&cache, "id", &loads).await.unwrap().get_id(), "id");
+
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 1);
+
+        // not found resources are not cached
+        assert!(get(&cache, "not-exist", &loads).await.is_none());
+        assert!(get(&cache, "not-exist", &loads).await.is_none());
+
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cache_evict_lru() {
+        let cache = ResourceCache::new(2, DEFAULT_TTL);
+        let loads = AtomicU32::new(0);
+
+        get(&cache, "id1", &loads).await;
+        get(&cache, "id2", &loads).await;
+        // id1 is used recently, so id2 is evicted
+        get(&cache, "id1", &loads).await;
+        get(&cache, "id3", &loads).await;
+
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+
+        get(&cache, "id1", &loads).await;
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+
+        get(&cache, "id2", &loads).await;
+        assert_eq!(loads.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_cache_expire_and_invalidate() {
+        let cache = ResourceCache::new(10, Duration::from_millis(20));
+        let loads = AtomicU32::new(0);
+
+        get(&cache, "id", &loads).await;
+        tokio::time::delay_for(Duration::from_millis(30)).await;
+        get(&cache, "id", &loads).await;
+
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+
+        cache.invalidate(&["id".to_owned()]);
+        get(&cache, "id", &loads).await;
+
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,267 @@
+use std::time::SystemTime;
+
+use anyhow::Result;
+use slog::info;
+use sqlx::{Executor, PgPool};
+
+use crate::log;
+
+/// Serialize the concurrent migrations from multiple instances, the value is arbitrary but must
+/// be stable.
+const MIGRATE_LOCK_KEY: i64 = 0x696d_6167_655f_6264;
+
+/// The migrations are applied by the version order, an applied migration must not be changed,
+/// add a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_tables",
+        sql: include_str!("../../migrations/0001_create_tables.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_tus_uploads",
+        sql: include_str!("../../migrations/0002_create_tus_uploads.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "add_resources_dedup",
+        sql: include_str!("../../migrations/0003_add_resources_dedup.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "add_resources_create_time_index",
+        sql: include_str!("../../migrations/0004_add_resources_create_time_index.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "add_resources_dimensions",
+        sql: include_str!("../../migrations/0005_add_resources_dimensions.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "add_resources_content_type_filename",
+        sql: include_str!("../../migrations/0006_add_resources_content_type_filename.sql"),
+    },
+];
+
+#[derive(Debug)]
+struct Migration {
+    version: i64,
+    name: &'static str,
+    sql: &'static str,
+}
+
+/// Create or upgrade the tables, the applied versions are recorded in `schema_migrations`.
+pub async fn run(db_pool: &PgPool) -> Result<()> {
+    let mut tx = db_pool.begin().await?;
+
+    sqlx::query("select pg_advisory_xact_lock($1)")
+        .bind(MIGRATE_LOCK_KEY)
+        .execute(&mut tx)
+        .await?;
+
+    tx.execute(
+        "create table if not exists schema_migrations (version bigint primary key, name text not null, applied_at bigint not null)",
+    )
+        .await?;
+
+    let applied = sqlx::query_as::<_, (i64, )>("select version from schema_migrations")
+        .fetch_all(&mut tx)
+        .await?
+        .into_iter()
+        .map(|(version, )| version)
+        .collect::<Vec<_>>();
+
+    let applied_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+
+    for migration in MIGRATIONS {
+        if applied.contains(&migration.version) {
+            continue;
+        }
+
+        // execute the raw sql so the migration can contain multiple statements
+        tx.execute(migration.sql).await.map_err(|err| {
+            anyhow::anyhow!(
+                "apply migration {} {} failed: {}",
+                migration.version,
+                migration.name,
+                err
+            )
+        })?;
+
+        sqlx::query("insert into schema_migrations (version, name, applied_at) values ($1, $2, $3)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(applied_at as i64)
+            .execute(&mut tx)
+            .await?;
+
+        info!(
+            log::get_logger(),
+            "migration is applied";
+            "version" => migration.version,
+            "name" => migration.name
+        );
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use rand::Rng;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for window in MIGRATIONS.windows(2) {
+            assert!(window[0].version < window[1].version);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_on_fresh_schema() {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+
+        let schema = format!("migrate_test_{}", rand::thread_rng().gen::<u32>());
+
+        let admin_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        admin_pool
+            .execute(format!("create schema {}", schema).as_str())
+            .await
+            .unwrap();
+
+        let search_path = format!("set search_path to {}", schema);
+        let db_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .after_connect(move |conn| {
+                let search_path = search_path.clone();
+
+                Box::pin(async move {
+                    conn.execute(search_path.as_str()).await?;
+
+                    Ok(())
+                })
+            })
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        // the second run must be a no-op
+        run(&db_pool).await.unwrap();
+        run(&db_pool).await.unwrap();
+
+        for table in &["resources", "id_generate", "tus_uploads"] {
+            sqlx::query(&format!("select from {} limit 1", table))
+                .execute(&db_pool)
+                .await
+                .unwrap();
+        }
+
+        for index in &[
+            "resources_hash_index",
+            "resources_dedup_hash_index",
+            "resources_create_time_index",
+        ] {
+            let (count, ) = sqlx::query_as::<_, (i64, )>(
+                "select count(*) from pg_indexes where schemaname = $1 and indexname = $2",
+            )
+                .bind(&schema)
+                .bind(index)
+                .fetch_one(&admin_pool)
+                .await
+                .unwrap();
+            assert_eq!(count, 1, "{}", index);
+        }
+
+        let (count, ) = sqlx::query_as::<_, (i64, )>("select count(*) from schema_migrations")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(count, MIGRATIONS.len() as i64);
+
+        db_pool.close().await;
+
+        admin_pool
+            .execute(format!("drop schema {} cascade", schema).as_str())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_on_baseline_schema() {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+
+        let schema = format!("migrate_test_{}", rand::thread_rng().gen::<u32>());
+
+        let admin_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        admin_pool
+            .execute(format!("create schema {}", schema).as_str())
+            .await
+            .unwrap();
+
+        let search_path = format!("set search_path to {}", schema);
+        let db_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .after_connect(move |conn| {
+                let search_path = search_path.clone();
+
+                Box::pin(async move {
+                    conn.execute(search_path.as_str()).await?;
+
+                    Ok(())
+                })
+            })
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        // the tables of the deployments created by the baseline db.sql
+        db_pool
+            .execute(
+                "create table id_generate (id_type text not null, id_value bigint not null); create table resources (id text not null, bucket text not null, create_time bigint not null, hash text not null, resource_size bigint not null)",
+            )
+            .await
+            .unwrap();
+
+        run(&db_pool).await.unwrap();
+
+        for column in &["content_type", "filename", "dedup", "width", "height"] {
+            let (count, ) = sqlx::query_as::<_, (i64, )>(
+                "select count(*) from information_schema.columns where table_schema = $1 and table_name = 'resources' and column_name = $2",
+            )
+                .bind(&schema)
+                .bind(column)
+                .fetch_one(&admin_pool)
+                .await
+                .unwrap();
+            assert_eq!(count, 1, "{}", column);
+        }
+
+        db_pool.close().await;
+
+        admin_pool
+            .execute(format!("drop schema {} cascade", schema).as_str())
+            .await
+            .unwrap();
+    }
+}
@@ -1,19 +1,41 @@
 use std::fmt::Debug;
+use std::future::Future;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
 
 use anyhow::Result;
-use slog::error;
+use serde::Serialize;
+use slog::{error, info, warn};
 use sqlx::{Error, PgPool};
+use sqlx::error::DatabaseError;
+use sqlx::postgres::{PgConnectOptions, PgDatabaseError, PgPoolOptions};
 
 use crate::log::{self, LogContext};
+use crate::telemetry::{Span, SpanKind};
 
-#[derive(Debug, sqlx::FromRow, Clone)]
+pub use self::cache::ResourceCache;
+pub use self::quota::StorageQuota;
+pub use self::rollover::BucketRollover;
+pub use self::tus::TusUpload;
+
+pub mod cache;
+pub mod migrate;
+pub mod quota;
+pub mod rollover;
+pub mod tus;
+
+#[derive(Debug, sqlx::FromRow, Clone, Serialize)]
 pub struct Resource {
     id: String,
     bucket: String,
     create_time: i64,
+    hash: String,
     resource_size: i64,
+    content_type: String,
+    filename: Option<String>,
+    width: Option<i32>,
+    height: Option<i32>,
 }
 
 impl Resource {
@@ -29,14 +51,220 @@ impl Resource {
         SystemTime::UNIX_EPOCH + Duration::from_secs(self.create_time as _)
     }
 
+    pub fn get_hash(&self) -> &str {
+        &self.hash
+    }
+
     pub fn get_resource_size(&self) -> u64 {
         self.resource_size as _
     }
+
+    pub fn get_content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Serve the resource as the content type, the stored row is not changed.
+    pub fn set_content_type(&mut self, content_type: &str) {
+        self.content_type = content_type.to_owned();
+    }
+
+    pub fn get_filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The width and height of the image, `None` if they are not detected.
+    pub fn get_dimensions(&self) -> Option<(u32, u32)> {
+        Some((self.width? as _, self.height? as _))
+    }
+}
+
+const DEFAULT_MAX_CONNECTIONS: u32 = 20;
+/// Fail fast when the pool is saturated, the request gets 503 instead of piling up.
+const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// About a minute in total with the default delay, the db in the same deployment may still be
+/// starting.
+const DEFAULT_CONNECT_MAX_ATTEMPTS: u32 = 7;
+const DEFAULT_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// The unique index of the deduped resources hash.
+const DEDUP_HASH_INDEX: &str = "resources_dedup_hash_index";
+
+/// How much the dedup by hash saves, the resources sharing a hash are counted once.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, sqlx::FromRow, Serialize)]
+pub struct DedupStats {
+    pub total_resources: i64,
+    pub distinct_hashes: i64,
+    pub bytes_saved: i64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, sqlx::FromRow, Serialize)]
+pub struct BucketUsage {
+    pub bucket: String,
+    pub object_count: i64,
+    pub total_bytes: i64,
+}
+
+/// Settings of the postgres connection pool.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    pub acquire_timeout: Duration,
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            acquire_timeout: DEFAULT_ACQUIRE_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Build the pool config, the timeouts are in seconds and unset values use the defaults.
+    pub fn new(
+        max_connections: Option<u32>,
+        acquire_timeout: Option<u64>,
+        idle_timeout: Option<u64>,
+    ) -> Result<Self> {
+        if max_connections == Some(0) {
+            return Err(anyhow::anyhow!("db_max_connections must be positive"));
+        }
+
+        if acquire_timeout == Some(0) {
+            return Err(anyhow::anyhow!("db_acquire_timeout must be positive"));
+        }
+
+        if idle_timeout == Some(0) {
+            return Err(anyhow::anyhow!("db_idle_timeout must be positive"));
+        }
+
+        Ok(Self {
+            max_connections: max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS),
+            acquire_timeout: acquire_timeout
+                .map_or(DEFAULT_ACQUIRE_TIMEOUT, Duration::from_secs),
+            idle_timeout: idle_timeout.map_or(DEFAULT_IDLE_TIMEOUT, Duration::from_secs),
+        })
+    }
+
+    pub fn pool_options(&self) -> PgPoolOptions {
+        PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .connect_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout)
+    }
+
+    /// Connect the pool, retry when the db is not available yet.
+    pub async fn connect(
+        &self,
+        connect_options: &PgConnectOptions,
+        connect_retry: &ConnectRetry,
+    ) -> Result<PgPool> {
+        retry_connect(connect_retry, || self.connect_once(connect_options)).await
+    }
+
+    /// Connect the pool and make a connection, the pool without the min connections doesn't
+    /// connect until it's used, so the unreachable db would never be retried.
+    async fn connect_once(
+        &self,
+        connect_options: &PgConnectOptions,
+    ) -> std::result::Result<PgPool, Error> {
+        let db_pool = self
+            .pool_options()
+            .connect_with(connect_options.clone())
+            .await?;
+
+        db_pool.acquire().await?;
+
+        Ok(db_pool)
+    }
+}
+
+/// Retry policy of the startup db connection.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ConnectRetry {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_CONNECT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_CONNECT_RETRY_DELAY,
+        }
+    }
+}
+
+impl ConnectRetry {
+    /// Build the retry policy, the base delay is in milliseconds and unset values use the
+    /// defaults.
+    pub fn new(max_attempts: Option<u32>, base_delay: Option<u64>) -> Result<Self> {
+        if max_attempts == Some(0) {
+            return Err(anyhow::anyhow!("db_connect_max_attempts must be positive"));
+        }
+
+        Ok(Self {
+            max_attempts: max_attempts.unwrap_or(DEFAULT_CONNECT_MAX_ATTEMPTS),
+            base_delay: base_delay.map_or(DEFAULT_CONNECT_RETRY_DELAY, Duration::from_millis),
+        })
+    }
+
+    /// Exponential backoff, `attempt` starts from 1.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .checked_mul(1 << (attempt - 1).min(16))
+            .map_or(MAX_CONNECT_RETRY_DELAY, |delay| delay.min(MAX_CONNECT_RETRY_DELAY))
+    }
+}
+
+async fn retry_connect<T, F, Fut>(connect_retry: &ConnectRetry, mut connect: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output=std::result::Result<T, Error>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match connect().await {
+            Ok(connected) => return Ok(connected),
+
+            Err(err) if attempt < connect_retry.max_attempts => {
+                let delay = connect_retry.backoff(attempt);
+
+                warn!(
+                    log::get_logger(),
+                    "connect db failed, retry after {:?}: {}",
+                    delay, err;
+                    "attempt" => attempt,
+                    "max_attempts" => connect_retry.max_attempts
+                );
+
+                tokio::time::delay_for(delay).await;
+
+                attempt += 1;
+            }
+
+            Err(err) => {
+                return Err(anyhow::anyhow!(
+                    "connect db failed after {} attempts: {}",
+                    attempt,
+                    err
+                ));
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Database {
     db_pool: PgPool,
+    cache: Option<Arc<ResourceCache>>,
+    quota: Option<Arc<StorageQuota>>,
+    rollover: Option<Arc<BucketRollover>>,
 }
 
 impl Database {
@@ -47,28 +275,215 @@ impl Database {
 
         Ok(Self {
             db_pool: db_pool.clone(),
+            cache: None,
+            quota: None,
+            rollover: None,
         })
     }
 
+    /// Cache the resources got by id, the cache is shared by the clones.
+    pub fn set_cache(&mut self, cache: ResourceCache) -> &mut Self {
+        self.cache.replace(Arc::new(cache));
+
+        self
+    }
+
+    /// Limit the total bytes of the resources, the quota is shared by the clones.
+    pub fn set_quota(&mut self, quota: StorageQuota) -> &mut Self {
+        self.quota.replace(Arc::new(quota));
+
+        self
+    }
+
+    /// Limit the objects of a bucket, the rollover is shared by the clones.
+    pub fn set_bucket_rollover(&mut self, rollover: BucketRollover) -> &mut Self {
+        self.rollover.replace(Arc::new(rollover));
+
+        self
+    }
+
+    /// The bucket of a new resource uploaded in the month, `month` is the fixed bucket in the
+    /// fixed bucket mode. It's returned as is without the rollover, otherwise the suffixed buckets
+    /// are tried in order, so the instances agree on the bucket by the db counts.
+    pub async fn upload_bucket(&self, month: &str, log_cx: &LogContext) -> Result<String> {
+        let rollover = match &self.rollover {
+            None => return Ok(month.to_owned()),
+            Some(rollover) => rollover,
+        };
+
+        let mut current = rollover.current().lock().await;
+
+        let mut suffix = match &mut *current {
+            Some(current) if current.month == month => {
+                if current.objects < rollover.max_objects() {
+                    current.objects += 1;
+
+                    return Ok(rollover::bucket_name(month, current.suffix));
+                }
+
+                current.suffix + 1
+            }
+
+            _ => 0,
+        };
+
+        loop {
+            let bucket = rollover::bucket_name(month, suffix);
+            let objects = self.count_resources(Some(&bucket), None, None, log_cx).await?;
+
+            if objects < rollover.max_objects() {
+                info!(
+                    log::get_logger(),
+                    "upload bucket is selected";
+                    log_cx,
+                    "bucket" => &bucket,
+                    "objects" => objects
+                );
+
+                current.replace(rollover::CurrentBucket {
+                    month: month.to_owned(),
+                    suffix,
+                    objects: objects + 1,
+                });
+
+                return Ok(bucket);
+            }
+
+            suffix += 1;
+        }
+    }
+
+    /// Reserve the bytes of a new resource before inserting it, return false if the quota would
+    /// be exceeded. Always succeed when no quota is set.
+    pub fn reserve_quota(&self, size: u64) -> bool {
+        self.quota
+            .as_ref()
+            .is_none_or(|quota| quota.try_reserve(size))
+    }
+
+    /// Give back the reserved bytes when the resource is not inserted.
+    pub fn release_quota(&self, size: u64) {
+        if let Some(quota) = &self.quota {
+            quota.release(size);
+        }
+    }
+
+    pub async fn total_resource_bytes(&self, log_cx: &LogContext) -> Result<u64> {
+        let (total, ) = sqlx::query_as::<_, (i64, )>(
+            "select coalesce(sum(resource_size), 0)::bigint from resources",
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "get total resource bytes failed: {:?}", err; log_cx);
+
+                query_error(err)
+            })?;
+
+        Ok(total as _)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn insert_resource(
         &self,
         bucket: &str,
         resource_id: &str,
         resource_hash: &str,
         resource_size: u64,
-        _log_cx: &LogContext,
+        content_type: &str,
+        dimensions: Option<(u32, u32)>,
+        filename: Option<&str>,
+        log_cx: &LogContext,
     ) -> Result<Resource> {
-        let now = SystemTime::now();
-        let unix_timestamp = now.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        let mut span = Span::start("db.insert_resource", SpanKind::Client, log_cx);
+        span.set_attribute("resource_id", resource_id);
+
+        self
+            .insert(
+                bucket,
+                resource_id,
+                resource_hash,
+                resource_size,
+                content_type,
+                dimensions,
+                filename,
+                false,
+            )
+            .await
+            .map_err(query_error)
+    }
+
+    /// Insert the resource which is deduped by hash, return `None` if another deduped resource
+    /// has the hash, e.g. a concurrent upload of the same content wins the insert.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert_dedup_resource(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource_hash: &str,
+        resource_size: u64,
+        content_type: &str,
+        dimensions: Option<(u32, u32)>,
+        filename: Option<&str>,
+        log_cx: &LogContext,
+    ) -> Result<Option<Resource>> {
+        let mut span = Span::start("db.insert_dedup_resource", SpanKind::Client, log_cx);
+        span.set_attribute("resource_id", resource_id);
+
+        match self
+            .insert(
+                bucket,
+                resource_id,
+                resource_hash,
+                resource_size,
+                content_type,
+                dimensions,
+                filename,
+                true,
+            )
+            .await
+        {
+            Err(Error::Database(err)) if is_dedup_hash_violation(err.as_ref()) => Ok(None),
+
+            Err(err) => {
+                error!(log::get_logger(), "insert dedup resource {} failed: {:?}", resource_id, err; log_cx);
+
+                Err(query_error(err))
+            }
+
+            Ok(resource) => Ok(Some(resource)),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn insert(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource_hash: &str,
+        resource_size: u64,
+        content_type: &str,
+        dimensions: Option<(u32, u32)>,
+        filename: Option<&str>,
+        dedup: bool,
+    ) -> std::result::Result<Resource, Error> {
+        let unix_timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
 
         sqlx::query(
-            "insert into resources (id, bucket, create_time, hash, resource_size) values ($1, $2, $3, $4, $5)",
+            "insert into resources (id, bucket, create_time, hash, resource_size, content_type, filename, dedup, width, height) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
         )
             .bind(resource_id)
             .bind(bucket)
             .bind(unix_timestamp as i64)
             .bind(resource_hash)
             .bind(resource_size as i64)
+            .bind(content_type)
+            .bind(filename)
+            .bind(dedup)
+            .bind(dimensions.map(|(width, _)| width as i32))
+            .bind(dimensions.map(|(_, height)| height as i32))
             .execute(&self.db_pool)
             .await?;
 
@@ -76,7 +491,12 @@ impl Database {
             id: resource_id.to_owned(),
             bucket: bucket.to_owned(),
             create_time: unix_timestamp as _,
+            hash: resource_hash.to_owned(),
             resource_size: resource_size as _,
+            content_type: content_type.to_owned(),
+            filename: filename.map(ToOwned::to_owned),
+            width: dimensions.map(|(width, _)| width as _),
+            height: dimensions.map(|(_, height)| height as _),
         })
     }
 
@@ -85,7 +505,13 @@ impl Database {
         resource_hash: &str,
         log_cx: &LogContext,
     ) -> Result<Option<Resource>> {
-        match sqlx::query_as::<_, Resource>("select * from resources where hash=$1 limit 1")
+        let _span = Span::start("db.get_resource_by_hash", SpanKind::Client, log_cx);
+
+        // the deduped row of the hash is canonical, the colliding and the copied rows may share
+        // the hash, so the match must not change between the lookups
+        match sqlx::query_as::<_, Resource>(
+            "select * from resources where hash=$1 order by dedup desc, create_time, id limit 1",
+        )
             .bind(resource_hash)
             .fetch_one(&self.db_pool)
             .await
@@ -96,7 +522,7 @@ impl Database {
                 } else {
                     error!(log::get_logger(), "get resource by hash {} failed: {:?}", resource_hash, err; log_cx);
 
-                    Err(err.into())
+                    Err(query_error(err))
                 }
             }
 
@@ -104,10 +530,81 @@ impl Database {
         }
     }
 
+    pub async fn dedup_stats(&self, log_cx: &LogContext) -> Result<DedupStats> {
+        let _span = Span::start("db.dedup_stats", SpanKind::Client, log_cx);
+
+        // every resource beyond the first one of a hash group would be stored again without dedup
+        sqlx::query_as::<_, DedupStats>(
+            "select coalesce(sum(group_count), 0)::bigint as total_resources, count(*) as distinct_hashes, coalesce(sum((group_count - 1) * group_size), 0)::bigint as bytes_saved from (select count(*) as group_count, max(resource_size) as group_size from resources group by hash) as hash_groups",
+        )
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "get dedup stats failed: {:?}", err; log_cx);
+
+                query_error(err)
+            })
+    }
+
+    /// The object count and bytes of every bucket, the monthly buckets show the growth by month.
+    pub async fn bucket_usage(&self, log_cx: &LogContext) -> Result<Vec<BucketUsage>> {
+        let _span = Span::start("db.bucket_usage", SpanKind::Client, log_cx);
+
+        sqlx::query_as::<_, BucketUsage>(
+            "select bucket, count(*) as object_count, coalesce(sum(resource_size), 0)::bigint as total_bytes from resources group by bucket order by bucket",
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "get bucket usage failed: {:?}", err; log_cx);
+
+                query_error(err)
+            })
+    }
+
+    /// The object count and bytes of the bucket, the counts are zero if it has no resources.
+    pub async fn get_bucket_usage(&self, bucket: &str, log_cx: &LogContext) -> Result<BucketUsage> {
+        let _span = Span::start("db.get_bucket_usage", SpanKind::Client, log_cx);
+
+        sqlx::query_as::<_, BucketUsage>(
+            "select $1::text as bucket, count(*) as object_count, coalesce(sum(resource_size), 0)::bigint as total_bytes from resources where bucket = $1",
+        )
+            .bind(bucket)
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "get usage of bucket {} failed: {:?}", bucket, err; log_cx);
+
+                query_error(err)
+            })
+    }
+
     pub async fn get_resource_by_id(
         &self,
         resource_id: &str,
         log_cx: &LogContext,
+    ) -> Result<Option<Resource>> {
+        let mut span = Span::start("db.get_resource_by_id", SpanKind::Client, log_cx);
+        span.set_attribute("resource_id", resource_id);
+
+        match &self.cache {
+            None => self.query_resource_by_id(resource_id, log_cx).await,
+            Some(cache) => {
+                cache
+                    .get_or_load(
+                        resource_id,
+                        || self.query_resource_by_id(resource_id, log_cx),
+                        log_cx,
+                    )
+                    .await
+            }
+        }
+    }
+
+    async fn query_resource_by_id(
+        &self,
+        resource_id: &str,
+        log_cx: &LogContext,
     ) -> Result<Option<Resource>> {
         match sqlx::query_as::<_, Resource>("select * from resources where id=$1")
             .bind(resource_id)
@@ -120,7 +617,7 @@ impl Database {
                 } else {
                     error!(log::get_logger(), "get resource by id {} failed: {:?}", resource_id, err; log_cx);
 
-                    Err(err.into())
+                    Err(query_error(err))
                 }
             }
 
@@ -128,107 +625,742 @@ impl Database {
         }
     }
 
-    pub async fn update_resource_create_time(
+    pub async fn get_resources_by_ids(
         &self,
-        resource_id: &str,
+        resource_ids: &[String],
         log_cx: &LogContext,
-    ) -> Result<Option<()>> {
-        if let Err(err) = sqlx::query("update set resources (create_time) values ($1) where id=$2")
-            .bind(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?)
-            .bind(resource_id)
-            .execute(&self.db_pool)
+    ) -> Result<Vec<Resource>> {
+        let _span = Span::start("db.get_resources_by_ids", SpanKind::Client, log_cx);
+
+        sqlx::query_as::<_, Resource>("select * from resources where id = any($1)")
+            .bind(resource_ids)
+            .fetch_all(&self.db_pool)
             .await
-        {
-            if let Error::RowNotFound = err {
-                Ok(None)
-            } else {
+            .map_err(|err| {
+                error!(log::get_logger(), "get resources by ids {:?} failed: {:?}", resource_ids, err; log_cx);
+
+                query_error(err)
+            })
+    }
+
+    /// List at most `limit` resources in the id order, starting after the `after` id, all
+    /// buckets are listed if `bucket` is not set, so the whole table can be walked in batches.
+    pub async fn list_resources_after(
+        &self,
+        bucket: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+        log_cx: &LogContext,
+    ) -> Result<Vec<Resource>> {
+        let _span = Span::start("db.list_resources_after", SpanKind::Client, log_cx);
+
+        sqlx::query_as::<_, Resource>(
+            "select * from resources where ($1::text is null or bucket = $1) and ($2::text is null or id > $2) order by id limit $3",
+        )
+            .bind(bucket)
+            .bind(after)
+            .bind(limit as i64)
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "list resources after {:?} failed: {:?}", after, err; log_cx);
+
+                query_error(err)
+            })
+    }
+
+    /// List a page of the resources, the newest first.
+    pub async fn list_resources(
+        &self,
+        bucket: Option<&str>,
+        limit: usize,
+        offset: usize,
+        log_cx: &LogContext,
+    ) -> Result<Vec<Resource>> {
+        let _span = Span::start("db.list_resources", SpanKind::Client, log_cx);
+
+        // the id breaks the ties of the same second, so the pages don't overlap
+        sqlx::query_as::<_, Resource>(
+            "select * from resources where ($1::text is null or bucket = $1) order by create_time desc, id limit $2 offset $3",
+        )
+            .bind(bucket)
+            .bind(limit as i64)
+            .bind(offset as i64)
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "list resources of bucket {:?} failed: {:?}", bucket, err; log_cx);
+
+                query_error(err)
+            })
+    }
+
+    /// List a page of the resources created in the inclusive time range, the newest first. The
+    /// missing bound is open.
+    pub async fn list_resources_by_time(
+        &self,
+        bucket: Option<&str>,
+        from: Option<SystemTime>,
+        to: Option<SystemTime>,
+        limit: usize,
+        offset: usize,
+        log_cx: &LogContext,
+    ) -> Result<Vec<Resource>> {
+        let _span = Span::start("db.list_resources_by_time", SpanKind::Client, log_cx);
+
+        let (from_secs, to_secs) = time_range_secs(from, to);
+
+        sqlx::query_as::<_, Resource>(
+            "select * from resources where create_time between $2 and $3 and ($1::text is null or bucket = $1) order by create_time desc, id limit $4 offset $5",
+        )
+            .bind(bucket)
+            .bind(from_secs)
+            .bind(to_secs)
+            .bind(limit as i64)
+            .bind(offset as i64)
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(|err| {
                 error!(
                     log::get_logger(),
-                    "update resource {} create time failed: {:?}",
-                    resource_id, err;
+                    "list resources of bucket {:?} from {:?} to {:?} failed: {:?}",
+                    bucket, from, to, err;
                     log_cx
                 );
 
-                Err(err.into())
+                query_error(err)
+            })
+    }
+
+    /// Count the resources created in the inclusive time range, the missing bound is open.
+    pub async fn count_resources(
+        &self,
+        bucket: Option<&str>,
+        from: Option<SystemTime>,
+        to: Option<SystemTime>,
+        log_cx: &LogContext,
+    ) -> Result<u64> {
+        let _span = Span::start("db.count_resources", SpanKind::Client, log_cx);
+
+        let (from_secs, to_secs) = time_range_secs(from, to);
+
+        let (count, ) = sqlx::query_as::<_, (i64, )>(
+            "select count(*) from resources where create_time between $2 and $3 and ($1::text is null or bucket = $1)",
+        )
+            .bind(bucket)
+            .bind(from_secs)
+            .bind(to_secs)
+            .fetch_one(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(
+                    log::get_logger(),
+                    "count resources of bucket {:?} from {:?} to {:?} failed: {:?}",
+                    bucket, from, to, err;
+                    log_cx
+                );
+
+                query_error(err)
+            })?;
+
+        Ok(count as _)
+    }
+
+    pub async fn list_buckets(&self, log_cx: &LogContext) -> Result<Vec<String>> {
+        let _span = Span::start("db.list_buckets", SpanKind::Client, log_cx);
+
+        let buckets = sqlx::query_as::<_, (String, )>(
+            "select distinct bucket from resources order by bucket",
+        )
+            .fetch_all(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "list buckets failed: {:?}", err; log_cx);
+
+                query_error(err)
+            })?;
+
+        Ok(buckets.into_iter().map(|(bucket, )| bucket).collect())
+    }
+
+    pub async fn delete_resources(&self, resource_ids: &[String], log_cx: &LogContext) -> Result<()> {
+        let _span = Span::start("db.delete_resources", SpanKind::Client, log_cx);
+
+        match sqlx::query_as::<_, (i64, )>(
+            "delete from resources where id = any($1) returning resource_size",
+        )
+            .bind(resource_ids)
+            .fetch_all(&self.db_pool)
+            .await
+        {
+            Err(err) => {
+                error!(log::get_logger(), "delete resources {:?} failed: {:?}", resource_ids, err; log_cx);
+
+                Err(query_error(err))
+            }
+
+            Ok(sizes) => {
+                // after the delete, so a concurrent get can't cache the deleted rows again
+                if let Some(cache) = &self.cache {
+                    cache.invalidate(resource_ids);
+                }
+
+                self.release_quota(sizes.iter().map(|(size, )| *size as u64).sum());
+
+                Ok(())
             }
-        } else {
-            Ok(Some(()))
         }
     }
 
+    /// Delete all resources of the bucket, return the ids of the deleted ones.
+    pub async fn delete_resources_by_bucket(
+        &self,
+        bucket: &str,
+        log_cx: &LogContext,
+    ) -> Result<Vec<String>> {
+        let mut span = Span::start("db.delete_resources_by_bucket", SpanKind::Client, log_cx);
+        span.set_attribute("bucket", bucket);
+
+        match sqlx::query_as::<_, (String, i64)>(
+            "delete from resources where bucket=$1 returning id, resource_size",
+        )
+            .bind(bucket)
+            .fetch_all(&self.db_pool)
+            .await
+        {
+            Err(err) => {
+                error!(log::get_logger(), "delete bucket {} resources failed: {:?}", bucket, err; log_cx);
+
+                Err(query_error(err))
+            }
+
+            Ok(rows) => {
+                let resource_ids = rows.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+
+                if let Some(cache) = &self.cache {
+                    cache.invalidate(&resource_ids);
+                }
+
+                self.release_quota(rows.iter().map(|(_, size)| *size as u64).sum());
+
+                Ok(resource_ids)
+            }
+        }
+    }
+
+    /// Delete the resources created before the time, return the deleted ones, their objects are
+    /// not deleted.
     pub async fn delete_out_of_date_resources(
         &self,
         delete_before: &SystemTime,
         log_cx: &LogContext,
     ) -> Result<Vec<Resource>> {
+        let _span = Span::start("db.delete_out_of_date_resources", SpanKind::Client, log_cx);
+
         let unix_timestamp = delete_before
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_secs();
 
-        let mut offset = 0;
+        let delete_resources = match sqlx::query_as::<_, Resource>(
+            "delete from resources where create_time<=$1 returning *",
+        )
+            .bind(unix_timestamp as i64)
+            .fetch_all(&self.db_pool)
+            .await
+        {
+            Err(err) => {
+                error!(
+                    log::get_logger(),
+                    "delete resources before {:?} failed: {:?}",
+                    delete_before, err;
+                    log_cx
+                );
 
-        let mut delete_resources = vec![];
+                return Err(query_error(err));
+            }
 
-        loop {
-            match sqlx::query_as::<_, Resource>(
-                "select * from resources where create_time<=$1 offset $2 limit 1000",
+            Ok(resources) => resources,
+        };
+
+        if let Some(cache) = &self.cache {
+            cache.invalidate(
+                &delete_resources
+                    .iter()
+                    .map(|res| res.id.clone())
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        self.release_quota(
+            delete_resources
+                .iter()
+                .map(|res| res.get_resource_size())
+                .sum(),
+        );
+
+        Ok(delete_resources)
+    }
+
+    /// Backdate the resource, so the tests can have the old resources.
+    #[cfg(test)]
+    pub async fn set_resource_create_time(
+        &self,
+        resource_id: &str,
+        create_time: SystemTime,
+    ) -> Result<()> {
+        sqlx::query("update resources set create_time=$1 where id=$2")
+            .bind(create_time.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64)
+            .bind(resource_id)
+            .execute(&self.db_pool)
+            .await
+            .map_err(query_error)?;
+
+        Ok(())
+    }
+}
+
+/// Keep the sqlx error as the source of the boxed error, the boxed `anyhow::Error` hides its
+/// own error, so the handle couldn't tell the saturated pool from the failed query.
+pub(crate) fn query_error(err: Error) -> anyhow::Error {
+    anyhow::Error::new(err).context("db query failed")
+}
+
+fn is_dedup_hash_violation(err: &(dyn DatabaseError + 'static)) -> bool {
+    err.try_downcast_ref::<PgDatabaseError>()
+        .and_then(PgDatabaseError::constraint)
+        == Some(DEDUP_HASH_INDEX)
+}
+
+/// The unix seconds of the time range, the missing bounds are replaced by the extreme values
+/// instead of the null checks, so the create time index is still used.
+fn time_range_secs(from: Option<SystemTime>, to: Option<SystemTime>) -> (i64, i64) {
+    let secs = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs() as i64)
+    };
+
+    (from.map_or(0, secs), to.map_or(i64::MAX, secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_pool_config_from_config() {
+        let pool_config = PoolConfig::new(Some(5), Some(3), Some(60)).unwrap();
+
+        assert_eq!(
+            pool_config,
+            PoolConfig {
+                max_connections: 5,
+                acquire_timeout: Duration::from_secs(3),
+                idle_timeout: Duration::from_secs(60),
+            }
+        );
+    }
+
+    #[test]
+    fn test_pool_config_default() {
+        assert_eq!(PoolConfig::new(None, None, None).unwrap(), PoolConfig::default());
+    }
+
+    #[test]
+    fn test_pool_config_reject_zero() {
+        assert!(PoolConfig::new(Some(0), None, None).is_err());
+        assert!(PoolConfig::new(None, Some(0), None).is_err());
+        assert!(PoolConfig::new(None, None, Some(0)).is_err());
+    }
+
+    #[test]
+    fn test_connect_retry() {
+        assert_eq!(ConnectRetry::new(None, None).unwrap(), ConnectRetry::default());
+        assert!(ConnectRetry::new(Some(0), None).is_err());
+
+        let connect_retry = ConnectRetry::new(Some(5), Some(10_000)).unwrap();
+
+        assert_eq!(connect_retry.backoff(1), Duration::from_secs(10));
+        assert_eq!(connect_retry.backoff(2), Duration::from_secs(20));
+        assert_eq!(connect_retry.backoff(3), MAX_CONNECT_RETRY_DELAY);
+        assert_eq!(connect_retry.backoff(100), MAX_CONNECT_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_connect_unreachable_db() {
+        let pool_config = PoolConfig::new(None, Some(1), None).unwrap();
+        let connect_retry = ConnectRetry::new(Some(3), Some(10)).unwrap();
+        // nothing listens on the tcpmux port
+        let connect_options = PgConnectOptions::new().host("127.0.0.1").port(1);
+
+        let mut attempts = 0;
+
+        let err = retry_connect(&connect_retry, || {
+            attempts += 1;
+
+            pool_config.connect_once(&connect_options)
+        })
+            .await
+            .unwrap_err();
+
+        assert_eq!(attempts, 3);
+        assert!(
+            err.to_string().starts_with("connect db failed after 3 attempts"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dedup_stats() {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+
+        let db_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        let db = Database::new(&db_pool).await.unwrap();
+        let log_cx = LogContext::builder().request_id("test").build();
+
+        let before = db.dedup_stats(&log_cx).await.unwrap();
+
+        let prefix = format!("dedup-test-{}", rand::random::<u32>());
+        let duplicated_hash = format!("{}-duplicated", prefix);
+        let unique_hash = format!("{}-unique", prefix);
+
+        let mut resource_ids = vec![];
+        for (i, (hash, size)) in [
+            (&duplicated_hash, 100),
+            (&duplicated_hash, 100),
+            (&duplicated_hash, 100),
+            (&unique_hash, 50),
+        ]
+            .iter()
+            .enumerate()
+        {
+            let resource_id = format!("{}-{}", prefix, i);
+
+            db.insert_resource(
+                "test",
+                &resource_id,
+                hash,
+                *size,
+                "image/png",
+                None,
+                None,
+                &log_cx,
             )
-                .bind(unix_timestamp as i64)
-                .bind(offset)
-                .fetch_all(&self.db_pool)
                 .await
-            {
-                Err(err) => {
-                    if let Error::RowNotFound = &err {
-                        break;
-                    } else {
-                        error!(
-                            log::get_logger(),
-                            "query resources before {:?} failed: {:?}",
-                            delete_before, err;
-                            log_cx
-                        );
-
-                        return Err(err.into());
-                    }
-                }
+                .unwrap();
 
-                Ok(resources) => {
-                    if resources.is_empty() {
-                        break;
-                    }
+            resource_ids.push(resource_id);
+        }
 
-                    offset += resources.len() as i32;
+        let after = db.dedup_stats(&log_cx).await.unwrap();
 
-                    delete_resources.extend(resources);
-                }
+        db.delete_resources(&resource_ids, &log_cx).await.unwrap();
+
+        assert_eq!(after.total_resources - before.total_resources, 4);
+        assert_eq!(after.distinct_hashes - before.distinct_hashes, 2);
+        assert_eq!(after.bytes_saved - before.bytes_saved, 200);
+    }
+
+    #[tokio::test]
+    async fn test_bucket_usage() {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+
+        let db_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        let db = Database::new(&db_pool).await.unwrap();
+        let log_cx = LogContext::builder().request_id("test").build();
+
+        // the buckets of its own, so the usage is not changed by the other tests
+        let prefix = format!("usage-test-{}", rand::random::<u32>());
+        let buckets = [format!("{}-a", prefix), format!("{}-b", prefix)];
+
+        let mut resource_ids = vec![];
+        for (i, (bucket, size)) in [(&buckets[0], 100), (&buckets[0], 50), (&buckets[1], 7)]
+            .iter()
+            .enumerate()
+        {
+            let resource_id = format!("{}-{}", prefix, i);
+
+            db.insert_resource(
+                bucket,
+                &resource_id,
+                "hash",
+                *size,
+                "image/png",
+                None,
+                None,
+                &log_cx,
+            )
+                .await
+                .unwrap();
+
+            resource_ids.push(resource_id);
+        }
+
+        let usage = db.bucket_usage(&log_cx).await.unwrap();
+        let first_usage = db.get_bucket_usage(&buckets[0], &log_cx).await.unwrap();
+
+        db.delete_resources(&resource_ids, &log_cx).await.unwrap();
+
+        assert_eq!(
+            first_usage,
+            BucketUsage {
+                bucket: buckets[0].clone(),
+                object_count: 2,
+                total_bytes: 150,
             }
+        );
+        assert_eq!(
+            db.get_bucket_usage(&buckets[0], &log_cx).await.unwrap(),
+            BucketUsage {
+                bucket: buckets[0].clone(),
+                object_count: 0,
+                total_bytes: 0,
+            }
+        );
+
+        let usage = usage
+            .into_iter()
+            .filter(|usage| usage.bucket.starts_with(&prefix))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            usage,
+            vec![
+                BucketUsage {
+                    bucket: buckets[0].clone(),
+                    object_count: 2,
+                    total_bytes: 150,
+                },
+                BucketUsage {
+                    bucket: buckets[1].clone(),
+                    object_count: 1,
+                    total_bytes: 7,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_bucket_rollover() {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+
+        let db_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        let mut db = Database::new(&db_pool).await.unwrap();
+        db.set_bucket_rollover(BucketRollover::new(2));
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        // a month of its own, so the counts are not changed by the other tests
+        let month = format!("rollover-test-{}", rand::random::<u32>());
+
+        let mut buckets = vec![];
+        let mut resource_ids = vec![];
+        for i in 0..5 {
+            let bucket = db.upload_bucket(&month, &log_cx).await.unwrap();
+            let resource_id = format!("{}-{}", month, i);
+
+            db.insert_resource(&bucket, &resource_id, "hash", 10, "image/png", None, None, &log_cx)
+                .await
+                .unwrap();
+
+            buckets.push(bucket);
+            resource_ids.push(resource_id);
         }
 
-        if let Err(err) = sqlx::query("delete from resources where id in $1")
-            .bind(
-                delete_resources
-                    .iter()
-                    .map(|res| res.id.as_str())
-                    .collect::<Vec<_>>(),
+        // a restarted instance finds the same bucket by the db counts
+        let mut restarted_db = Database::new(&db_pool).await.unwrap();
+        restarted_db.set_bucket_rollover(BucketRollover::new(2));
+
+        let restarted_bucket = restarted_db.upload_bucket(&month, &log_cx).await.unwrap();
+
+        db.delete_resources(&resource_ids, &log_cx).await.unwrap();
+
+        let bucket = |suffix| rollover::bucket_name(&month, suffix);
+
+        assert_eq!(
+            buckets,
+            vec![bucket(0), bucket(0), bucket(1), bucket(1), bucket(2)]
+        );
+        assert_eq!(restarted_bucket, bucket(2));
+
+        // no rollover without the max objects
+        let db = Database::new(&db_pool).await.unwrap();
+        assert_eq!(db.upload_bucket(&month, &log_cx).await.unwrap(), month);
+    }
+
+    #[tokio::test]
+    async fn test_insert_dedup_resource() {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+
+        let db_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        migrate::run(&db_pool).await.unwrap();
+
+        let db = Database::new(&db_pool).await.unwrap();
+        let log_cx = LogContext::builder().request_id("test").build();
+
+        let prefix = format!("dedup-insert-test-{}", rand::random::<u32>());
+        let hash = format!("{}-hash", prefix);
+        let resource_ids = (0..3)
+            .map(|i| format!("{}-{}", prefix, i))
+            .collect::<Vec<_>>();
+
+        let inserted = db
+            .insert_dedup_resource(
+                "test",
+                &resource_ids[0],
+                &hash,
+                10,
+                "image/png",
+                None,
+                None,
+                &log_cx,
             )
-            .execute(&self.db_pool)
             .await
-        {
-            if let Error::RowNotFound = err {
-                Ok(delete_resources)
-            } else {
-                error!(
-                    log::get_logger(),
-                    "delete resource info {:?} before {:?} failed: {:?}",
-                    delete_resources, delete_before, err;
-                    log_cx
-                );
+            .unwrap();
+        assert_eq!(inserted.unwrap().get_id(), resource_ids[0]);
 
-                Err(err.into())
-            }
-        } else {
-            Ok(delete_resources)
+        // the second deduped resource with the same hash loses
+        let inserted = db
+            .insert_dedup_resource(
+                "test",
+                &resource_ids[1],
+                &hash,
+                10,
+                "image/png",
+                None,
+                None,
+                &log_cx,
+            )
+            .await
+            .unwrap();
+        assert!(inserted.is_none());
+
+        // the resources which are not deduped can share the hash
+        db.insert_resource("test", &resource_ids[2], &hash, 10, "image/png", None, None, &log_cx)
+            .await
+            .unwrap();
+
+        // the lookup by hash always finds the deduped one
+        for _ in 0..3 {
+            let resource = db.get_resource_by_hash(&hash, &log_cx).await.unwrap().unwrap();
+            assert_eq!(resource.get_id(), resource_ids[0]);
         }
+
+        let resources = db.get_resources_by_ids(&resource_ids, &log_cx).await.unwrap();
+
+        db.delete_resources(&resource_ids, &log_cx).await.unwrap();
+
+        let mut ids = resources
+            .iter()
+            .map(|resource| resource.get_id())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        assert_eq!(ids, vec![resource_ids[0].as_str(), resource_ids[2].as_str()]);
+    }
+
+    #[tokio::test]
+    async fn test_list_resources_by_time() {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+
+        let db_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        migrate::run(&db_pool).await.unwrap();
+
+        let db = Database::new(&db_pool).await.unwrap();
+        let log_cx = LogContext::builder().request_id("test").build();
+
+        // a bucket of its own, so the resources of the other tests are not listed
+        let bucket = format!("time-test-{}", rand::random::<u32>());
+        let mut resource_ids = vec![];
+
+        for create_time in &[1000, 2000, 3000] {
+            let resource_id = format!("{}-{}", bucket, create_time);
+
+            db.insert_resource(&bucket, &resource_id, "hash", 10, "image/png", None, None, &log_cx)
+                .await
+                .unwrap();
+
+            sqlx::query("update resources set create_time = $1 where id = $2")
+                .bind(*create_time as i64)
+                .bind(&resource_id)
+                .execute(&db_pool)
+                .await
+                .unwrap();
+
+            resource_ids.push(resource_id);
+        }
+
+        let time = |secs: u64| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+
+        let mut results = vec![];
+        for (from, to) in &[
+            (time(1000), time(3000)),
+            (time(2000), time(2000)),
+            (time(2001), time(2999)),
+            (None, time(2000)),
+            (time(2000), None),
+        ] {
+            let resources = db
+                .list_resources_by_time(Some(&bucket), *from, *to, 10, 0, &log_cx)
+                .await
+                .unwrap();
+            let count = db
+                .count_resources(Some(&bucket), *from, *to, &log_cx)
+                .await
+                .unwrap();
+
+            assert_eq!(resources.len() as u64, count);
+
+            results.push(
+                resources
+                    .iter()
+                    .map(|resource| resource.get_id().to_owned())
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        db.delete_resources(&resource_ids, &log_cx).await.unwrap();
+
+        // the bounds are inclusive and the newest is the first
+        assert_eq!(
+            results,
+            vec![
+                vec![
+                    resource_ids[2].clone(),
+                    resource_ids[1].clone(),
+                    resource_ids[0].clone(),
+                ],
+                vec![resource_ids[1].clone()],
+                vec![],
+                vec![resource_ids[1].clone(), resource_ids[0].clone()],
+                vec![resource_ids[2].clone(), resource_ids[1].clone()],
+            ]
+        );
     }
 }
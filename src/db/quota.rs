@@ -0,0 +1,99 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The total bytes cap of the resources. The used bytes are loaded once on startup and then
+/// updated by this instance, the uploads and deletes of other instances are not seen.
+#[derive(Debug)]
+pub struct StorageQuota {
+    max_total_bytes: u64,
+    used_bytes: AtomicU64,
+}
+
+impl StorageQuota {
+    pub fn new(max_total_bytes: u64, used_bytes: u64) -> Self {
+        Self {
+            max_total_bytes,
+            used_bytes: AtomicU64::new(used_bytes),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes.load(Ordering::Acquire)
+    }
+
+    /// Reserve the bytes for a new resource, return false if the quota would be exceeded.
+    pub fn try_reserve(&self, size: u64) -> bool {
+        let mut used = self.used_bytes.load(Ordering::Acquire);
+
+        loop {
+            let new_used = match used.checked_add(size) {
+                Some(new_used) if new_used <= self.max_total_bytes => new_used,
+                _ => return false,
+            };
+
+            match self.used_bytes.compare_exchange_weak(
+                used,
+                new_used,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return true,
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    pub fn release(&self, size: u64) {
+        let mut used = self.used_bytes.load(Ordering::Acquire);
+
+        loop {
+            match self.used_bytes.compare_exchange_weak(
+                used,
+                used.saturating_sub(size),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(current) => used = current,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_boundary() {
+        let quota = StorageQuota::new(100, 60);
+
+        assert!(!quota.try_reserve(41));
+        assert_eq!(quota.used_bytes(), 60);
+
+        assert!(quota.try_reserve(40));
+        assert_eq!(quota.used_bytes(), 100);
+
+        assert!(quota.try_reserve(0));
+        assert!(!quota.try_reserve(1));
+    }
+
+    #[test]
+    fn test_release() {
+        let quota = StorageQuota::new(100, 100);
+
+        quota.release(30);
+        assert_eq!(quota.used_bytes(), 70);
+        assert!(quota.try_reserve(30));
+
+        quota.release(1000);
+        assert_eq!(quota.used_bytes(), 0);
+    }
+
+    #[test]
+    fn test_reserve_overflow() {
+        let quota = StorageQuota::new(u64::MAX, 10);
+
+        assert!(!quota.try_reserve(u64::MAX));
+    }
+}
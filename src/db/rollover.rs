@@ -0,0 +1,56 @@
+use tokio::sync::Mutex;
+
+/// Roll the uploads of a month over to the suffixed buckets, like `2021-01`, `2021-01-1` and
+/// `2021-01-2`, once a bucket holds `max_objects` resources. The current bucket and its count
+/// are cached, the db counts are only read when the month changes or the cached bucket is full,
+/// so the buckets of other instances may get slightly more objects than the max.
+#[derive(Debug)]
+pub struct BucketRollover {
+    max_objects: u64,
+    current: Mutex<Option<CurrentBucket>>,
+}
+
+#[derive(Debug)]
+pub(super) struct CurrentBucket {
+    pub(super) month: String,
+    pub(super) suffix: u32,
+    pub(super) objects: u64,
+}
+
+impl BucketRollover {
+    pub fn new(max_objects: u64) -> Self {
+        Self {
+            max_objects,
+            current: Mutex::new(None),
+        }
+    }
+
+    pub fn max_objects(&self) -> u64 {
+        self.max_objects
+    }
+
+    pub(super) fn current(&self) -> &Mutex<Option<CurrentBucket>> {
+        &self.current
+    }
+}
+
+/// The first bucket of the month has no suffix.
+pub fn bucket_name(month: &str, suffix: u32) -> String {
+    if suffix == 0 {
+        month.to_owned()
+    } else {
+        format!("{}-{}", month, suffix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_name() {
+        assert_eq!(bucket_name("2021-01", 0), "2021-01");
+        assert_eq!(bucket_name("2021-01", 1), "2021-01-1");
+        assert_eq!(bucket_name("2021-01", 12), "2021-01-12");
+    }
+}
@@ -0,0 +1,165 @@
+use std::time::SystemTime;
+
+use anyhow::Result;
+use slog::error;
+use sqlx::Error;
+
+use crate::log::{self, LogContext};
+use crate::telemetry::{Span, SpanKind};
+
+use super::{query_error, Database};
+
+/// The state of an unfinished tus upload, it is kept in the database so the upload can be
+/// resumed after a restart.
+#[derive(Debug, sqlx::FromRow, Clone, Eq, PartialEq)]
+pub struct TusUpload {
+    id: String,
+    upload_length: i64,
+    upload_offset: i64,
+    chunk_ids: Vec<String>,
+    filename: Option<String>,
+}
+
+impl TusUpload {
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_upload_length(&self) -> u64 {
+        self.upload_length as _
+    }
+
+    pub fn get_upload_offset(&self) -> u64 {
+        self.upload_offset as _
+    }
+
+    /// The stored chunks in the offset order.
+    pub fn get_chunk_ids(&self) -> &[String] {
+        &self.chunk_ids
+    }
+
+    pub fn get_filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.upload_offset == self.upload_length
+    }
+}
+
+impl Database {
+    pub async fn insert_tus_upload(
+        &self,
+        upload_id: &str,
+        upload_length: u64,
+        filename: Option<&str>,
+        log_cx: &LogContext,
+    ) -> Result<TusUpload> {
+        let mut span = Span::start("db.insert_tus_upload", SpanKind::Client, log_cx);
+        span.set_attribute("upload_id", upload_id);
+
+        let unix_timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+
+        sqlx::query(
+            "insert into tus_uploads (id, upload_length, filename, create_time) values ($1, $2, $3, $4)",
+        )
+            .bind(upload_id)
+            .bind(upload_length as i64)
+            .bind(filename)
+            .bind(unix_timestamp as i64)
+            .execute(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "insert tus upload {} failed: {:?}", upload_id, err; log_cx);
+
+                query_error(err)
+            })?;
+
+        Ok(TusUpload {
+            id: upload_id.to_owned(),
+            upload_length: upload_length as _,
+            upload_offset: 0,
+            chunk_ids: vec![],
+            filename: filename.map(ToOwned::to_owned),
+        })
+    }
+
+    pub async fn get_tus_upload(
+        &self,
+        upload_id: &str,
+        log_cx: &LogContext,
+    ) -> Result<Option<TusUpload>> {
+        let mut span = Span::start("db.get_tus_upload", SpanKind::Client, log_cx);
+        span.set_attribute("upload_id", upload_id);
+
+        match sqlx::query_as::<_, TusUpload>("select * from tus_uploads where id=$1")
+            .bind(upload_id)
+            .fetch_one(&self.db_pool)
+            .await
+        {
+            Err(Error::RowNotFound) => Ok(None),
+
+            Err(err) => {
+                error!(log::get_logger(), "get tus upload {} failed: {:?}", upload_id, err; log_cx);
+
+                Err(query_error(err))
+            }
+
+            Ok(upload) => Ok(Some(upload)),
+        }
+    }
+
+    /// Record the stored chunk which starts at `offset`, return `None` if the upload is gone or
+    /// the offset is moved by another request.
+    pub async fn append_tus_chunk(
+        &self,
+        upload_id: &str,
+        offset: u64,
+        chunk_id: &str,
+        chunk_size: u64,
+        log_cx: &LogContext,
+    ) -> Result<Option<TusUpload>> {
+        let mut span = Span::start("db.append_tus_chunk", SpanKind::Client, log_cx);
+        span.set_attribute("upload_id", upload_id);
+
+        match sqlx::query_as::<_, TusUpload>(
+            "update tus_uploads set upload_offset = upload_offset + $3, chunk_ids = array_append(chunk_ids, $4) where id=$1 and upload_offset=$2 and upload_offset + $3 <= upload_length returning *",
+        )
+            .bind(upload_id)
+            .bind(offset as i64)
+            .bind(chunk_size as i64)
+            .bind(chunk_id)
+            .fetch_one(&self.db_pool)
+            .await
+        {
+            Err(Error::RowNotFound) => Ok(None),
+
+            Err(err) => {
+                error!(log::get_logger(), "append tus upload {} chunk failed: {:?}", upload_id, err; log_cx);
+
+                Err(query_error(err))
+            }
+
+            Ok(upload) => Ok(Some(upload)),
+        }
+    }
+
+    pub async fn delete_tus_upload(&self, upload_id: &str, log_cx: &LogContext) -> Result<()> {
+        let mut span = Span::start("db.delete_tus_upload", SpanKind::Client, log_cx);
+        span.set_attribute("upload_id", upload_id);
+
+        sqlx::query("delete from tus_uploads where id=$1")
+            .bind(upload_id)
+            .execute(&self.db_pool)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "delete tus upload {} failed: {:?}", upload_id, err; log_cx);
+
+                query_error(err)
+            })?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+use std::str::FromStr;
+
+use sha2::Digest;
+
+/// The hex encoded hash length of both algorithms.
+pub const HASH_HEX_LENGTH: usize = 64;
+const BLAKE3_PREFIX: &str = "blake3:";
+/// The smaller data is hashed faster on the current thread than by the rayon threads.
+const PARALLEL_HASH_THRESHOLD: usize = 1024 * 1024;
+
+/// The hash algorithm of the new uploads. The blake3 hashes are stored as `blake3:<hex>`, so the
+/// sha256 hashes of the existing resources are kept, and the resources hashed by different
+/// algorithms are never deduped against each other.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const SHA256: &'static str = "sha256";
+    pub const BLAKE3: &'static str = "blake3";
+
+    /// The algorithm of the stored hash.
+    pub fn of_hash(hash: &str) -> Self {
+        if hash.starts_with(BLAKE3_PREFIX) {
+            HashAlgorithm::Blake3
+        } else {
+            HashAlgorithm::Sha256
+        }
+    }
+
+    /// Hash the data to the stored form.
+    pub fn hash(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+
+        hasher.finalize()
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// The length of the stored hash.
+    #[cfg(test)]
+    pub fn hash_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => HASH_HEX_LENGTH,
+            HashAlgorithm::Blake3 => BLAKE3_PREFIX.len() + HASH_HEX_LENGTH,
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            Self::SHA256 => Ok(HashAlgorithm::Sha256),
+            Self::BLAKE3 => Ok(HashAlgorithm::Blake3),
+            s => Err(anyhow::anyhow!(
+                "hash algorithm {} is invalid, must be {} or {}",
+                s,
+                Self::SHA256,
+                Self::BLAKE3
+            )),
+        }
+    }
+}
+
+/// Hash the data chunk by chunk, such as the body chunks when they arrive, the hash is the same
+/// as hashing all the data at once.
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+
+            Hasher::Blake3(hasher) => {
+                if data.len() >= PARALLEL_HASH_THRESHOLD {
+                    hasher.update_rayon(data);
+                } else {
+                    hasher.update(data);
+                }
+            }
+        }
+    }
+
+    /// The hash in the stored form.
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Blake3(hasher) => format!("{}{}", BLAKE3_PREFIX, hasher.finalize().to_hex()),
+        }
+    }
+}
+
+/// Check the hash is in the stored form of either algorithm, the hex must be lowercase.
+pub fn is_valid_hash(hash: &str) -> bool {
+    let hex = hash.strip_prefix(BLAKE3_PREFIX).unwrap_or(hash);
+
+    hex.len() == HASH_HEX_LENGTH
+        && hex
+        .bytes()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Normalize the hash given by the clients to the stored form, the hex and the prefix are case
+/// insensitive. Return `None` if it's not a hash of either algorithm.
+pub fn parse_hash(hash: &str) -> Option<String> {
+    let hash = hash.to_ascii_lowercase();
+
+    if is_valid_hash(&hash) {
+        Some(hash)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_algorithm_from_str() {
+        assert_eq!(
+            HashAlgorithm::from_str("sha256").unwrap(),
+            HashAlgorithm::Sha256
+        );
+        assert_eq!(
+            HashAlgorithm::from_str("blake3").unwrap(),
+            HashAlgorithm::Blake3
+        );
+        assert_eq!(
+            HashAlgorithm::from_str("md5").unwrap_err().to_string(),
+            "hash algorithm md5 is invalid, must be sha256 or blake3"
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        let sha256_hash = HashAlgorithm::Sha256.hash(b"abc");
+
+        assert_eq!(
+            sha256_hash,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(sha256_hash.len(), HashAlgorithm::Sha256.hash_len());
+        assert_eq!(HashAlgorithm::of_hash(&sha256_hash), HashAlgorithm::Sha256);
+
+        let blake3_hash = HashAlgorithm::Blake3.hash(b"abc");
+
+        assert_eq!(
+            blake3_hash,
+            "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(blake3_hash.len(), HashAlgorithm::Blake3.hash_len());
+        assert_eq!(HashAlgorithm::of_hash(&blake3_hash), HashAlgorithm::Blake3);
+
+        // the parallel hashing gives the same hash
+        let data = vec![7u8; PARALLEL_HASH_THRESHOLD * 2];
+
+        assert_eq!(
+            HashAlgorithm::Blake3.hash(&data),
+            format!("{}{}", BLAKE3_PREFIX, blake3::hash(&data).to_hex())
+        );
+    }
+
+    #[test]
+    fn test_incremental_hash() {
+        let data = (0..PARALLEL_HASH_THRESHOLD * 3)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        for algorithm in &[HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            for chunk_size in &[1, 1000, 16 * 1024, PARALLEL_HASH_THRESHOLD + 1] {
+                let mut hasher = algorithm.hasher();
+
+                for chunk in data.chunks(*chunk_size) {
+                    hasher.update(chunk);
+                }
+
+                assert_eq!(
+                    hasher.finalize(),
+                    algorithm.hash(&data),
+                    "{:?} {}",
+                    algorithm,
+                    chunk_size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_valid_hash() {
+        let sha256_hash = HashAlgorithm::Sha256.hash(b"abc");
+        let blake3_hash = HashAlgorithm::Blake3.hash(b"abc");
+
+        assert!(is_valid_hash(&sha256_hash));
+        assert!(is_valid_hash(&blake3_hash));
+
+        for hash in &[
+            "not-hex".to_string(),
+            sha256_hash[1..].to_string(),
+            sha256_hash.to_ascii_uppercase(),
+            format!("md5:{}", sha256_hash),
+            format!("{}zz", &sha256_hash[2..]),
+        ] {
+            assert!(!is_valid_hash(hash), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn test_parse_hash() {
+        let sha256_hash = HashAlgorithm::Sha256.hash(b"abc");
+        let blake3_hash = HashAlgorithm::Blake3.hash(b"abc");
+
+        for (hash, expect) in &[
+            (sha256_hash.clone(), &sha256_hash),
+            (sha256_hash.to_ascii_uppercase(), &sha256_hash),
+            (blake3_hash.clone(), &blake3_hash),
+            (blake3_hash.to_ascii_uppercase(), &blake3_hash),
+        ] {
+            assert_eq!(parse_hash(hash).as_ref(), Some(*expect), "{}", hash);
+        }
+
+        assert_eq!(parse_hash(&format!("md5:{}", sha256_hash)), None);
+        assert_eq!(parse_hash("not-hex"), None);
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use hyper::{Body, Request, Response};
+use hyper::body::HttpBody;
+use hyper::service::Service;
+use slog::{info, warn, Logger};
+
+use crate::http::{log_context, ServiceResult};
+use crate::http::client_ip::TrustedProxies;
+use crate::log;
+
+#[derive(Debug)]
+pub struct AccessLogService<S> {
+    enabled: bool,
+    remote_addr: Option<SocketAddr>,
+    trusted_proxies: Arc<TrustedProxies>,
+    logger: Logger,
+    service: S,
+}
+
+impl<S> AccessLogService<S> {
+    /// The client ip is logged as the one forwarded by the trusted proxies.
+    pub fn new(
+        enabled: bool,
+        remote_addr: Option<SocketAddr>,
+        trusted_proxies: Arc<TrustedProxies>,
+        service: S,
+    ) -> Self {
+        Self {
+            enabled,
+            remote_addr,
+            trusted_proxies,
+            logger: log::get_logger().clone(),
+            service,
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for AccessLogService<S>
+    where
+        S: Service<Request<Body>, Response=Response<Body>>,
+        S::Future: Send + 'static,
+        S::Error: Debug + Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = ServiceResult<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if !self.enabled {
+            return Box::pin(self.service.call(req));
+        }
+
+        let start = Instant::now();
+
+        let log_cx = log_context(&req);
+        let method = req.method().to_string();
+        let path = req.uri().path().to_owned();
+        let client_ip = self
+            .trusted_proxies
+            .client_ip(self.remote_addr, req.headers())
+            .map(|ip| ip.to_string());
+        let logger = self.logger.clone();
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let result = fut.await;
+
+            let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+            match &result {
+                Ok(resp) => info!(
+                    logger,
+                    "access";
+                    log_cx,
+                    "method" => method,
+                    "path" => path,
+                    "status" => resp.status().as_u16(),
+                    "body_size" => resp.body().size_hint().exact(),
+                    "client_ip" => client_ip,
+                    "elapsed_ms" => elapsed_ms
+                ),
+
+                Err(err) => warn!(
+                    logger,
+                    "access";
+                    log_cx,
+                    "method" => method,
+                    "path" => path,
+                    "error" => format!("{:?}", err),
+                    "client_ip" => client_ip,
+                    "elapsed_ms" => elapsed_ms
+                ),
+            }
+
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future;
+    use std::future::Ready;
+
+    use hyper::StatusCode;
+
+    use crate::http::request_id::REQUEST_ID_HEADER;
+    use crate::log::testing::CaptureDrain;
+
+    use super::*;
+
+    struct MockService;
+
+    impl Service<Request<Body>> for MockService {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: Request<Body>) -> Self::Future {
+            let mut resp = Response::new(Body::from(&b"test"[..]));
+            *resp.status_mut() = StatusCode::CREATED;
+
+            future::ready(Ok(resp))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let drain = CaptureDrain::default();
+
+        let mut service = AccessLogService::new(
+            true,
+            Some("127.0.0.1:1234".parse().unwrap()),
+            Arc::new(TrustedProxies::default()),
+            MockService,
+        );
+        service.logger = Logger::root(drain.clone(), slog::o!());
+
+        // the header of the untrusted peer is ignored
+        let req = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header(REQUEST_ID_HEADER, "test-id")
+            .header("x-forwarded-for", "203.0.113.1")
+            .body(Body::empty())
+            .unwrap();
+
+        service.call(req).await.unwrap();
+
+        let records = drain.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].msg, "access");
+
+        let kv = &records[0].kv;
+        assert_eq!(kv["method"], "POST");
+        assert_eq!(kv["path"], "/upload");
+        assert_eq!(kv["status"], "201");
+        assert_eq!(kv["body_size"], "4");
+        assert_eq!(kv["client_ip"], "127.0.0.1");
+        assert_eq!(kv["requestId"], "test-id");
+        assert!(kv.contains_key("elapsed_ms"));
+    }
+
+    #[tokio::test]
+    async fn test_access_log_forwarded() {
+        let drain = CaptureDrain::default();
+
+        let mut service = AccessLogService::new(
+            true,
+            Some("127.0.0.1:1234".parse().unwrap()),
+            Arc::new(TrustedProxies::new(vec!["127.0.0.0/8".parse().unwrap()])),
+            MockService,
+        );
+        service.logger = Logger::root(drain.clone(), slog::o!());
+
+        let req = Request::builder()
+            .uri("/get/id")
+            .header("x-forwarded-for", "203.0.113.1, 127.0.0.2")
+            .body(Body::empty())
+            .unwrap();
+
+        service.call(req).await.unwrap();
+
+        let records = drain.records();
+        assert_eq!(records[0].kv["client_ip"], "203.0.113.1");
+    }
+
+    #[tokio::test]
+    async fn test_access_log_disabled() {
+        let drain = CaptureDrain::default();
+
+        let mut service =
+            AccessLogService::new(false, None, Arc::new(TrustedProxies::default()), MockService);
+        service.logger = Logger::root(drain.clone(), slog::o!());
+
+        service.call(Request::new(Body::empty())).await.unwrap();
+
+        assert!(drain.records().is_empty());
+    }
+}
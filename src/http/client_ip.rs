@@ -0,0 +1,147 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::HeaderMap;
+use ipnet::IpNet;
+
+/// The proxies whose `X-Forwarded-*` headers are trusted, the headers from the other peers may
+/// be spoofed by the clients.
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new(nets: Vec<IpNet>) -> Self {
+        Self { nets }
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Find the real client address. The `X-Forwarded-For` is walked from the right, the hops
+    /// appended by the trusted proxies are skipped, and the first hop which is not a trusted
+    /// proxy is the client. The header is ignored when the peer is not a trusted proxy, so the
+    /// peer is the client. `None` if the peer is unknown, or a trusted proxy forwarded an invalid
+    /// hop, the client can't be known then.
+    pub fn client_ip(
+        &self,
+        remote_addr: Option<SocketAddr>,
+        headers: &HeaderMap,
+    ) -> Option<IpAddr> {
+        let mut client_ip = remote_addr?.ip();
+
+        let forwarded_for = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|hop| !hop.is_empty())
+            .collect::<Vec<_>>();
+
+        for hop in forwarded_for.into_iter().rev() {
+            if !self.is_trusted(client_ip) {
+                break;
+            }
+
+            client_ip = parse_hop(hop)?;
+        }
+
+        Some(client_ip)
+    }
+}
+
+/// Some proxies append the hop with its port, like `203.0.113.1:1234` or `[2001:db8::1]:1234`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted_proxies() -> TrustedProxies {
+        TrustedProxies::new(vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "fd00::/8".parse().unwrap(),
+            "192.168.0.1/32".parse().unwrap(),
+        ])
+    }
+
+    fn headers(forwarded_for: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in forwarded_for {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+
+        headers
+    }
+
+    #[test]
+    fn test_is_trusted() {
+        let trusted_proxies = trusted_proxies();
+
+        for ip in &["10.1.2.3", "fd00::1", "192.168.0.1"] {
+            assert!(trusted_proxies.is_trusted(ip.parse().unwrap()), "{}", ip);
+        }
+
+        for ip in &["192.168.0.2", "203.0.113.1", "::1"] {
+            assert!(!trusted_proxies.is_trusted(ip.parse().unwrap()), "{}", ip);
+        }
+
+        assert!(!TrustedProxies::default().is_trusted("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted_proxies = trusted_proxies();
+
+        for (remote_addr, forwarded_for, client_ip) in vec![
+            // direct
+            ("203.0.113.1:1234", vec![], "203.0.113.1"),
+            // the header of the untrusted peer is ignored
+            ("203.0.113.1:1234", vec!["198.51.100.1"], "203.0.113.1"),
+            ("10.0.0.1:1234", vec!["198.51.100.1"], "198.51.100.1"),
+            // the client can prepend the spoofed hops, they are left of the real client
+            ("10.0.0.1:1234", vec!["10.0.0.9, 198.51.100.1"], "198.51.100.1"),
+            // the trusted proxy hops are skipped
+            ("10.0.0.1:1234", vec!["198.51.100.1, 192.168.0.1, 10.0.0.2"], "198.51.100.1"),
+            // the hops in the repeated headers
+            ("10.0.0.1:1234", vec!["198.51.100.1", "10.0.0.2"], "198.51.100.1"),
+            // all hops are trusted, the leftmost is the client
+            ("10.0.0.1:1234", vec!["10.0.0.3, 10.0.0.2"], "10.0.0.3"),
+            // the hops with the ports
+            ("[fd00::1]:1234", vec!["198.51.100.1:5678, [fd00::2]:80"], "198.51.100.1"),
+            ("[fd00::1]:1234", vec!["2001:db8::1"], "2001:db8::1"),
+            // the empty hops are skipped
+            ("10.0.0.1:1234", vec!["198.51.100.1, ,"], "198.51.100.1"),
+        ] {
+            let remote_addr = remote_addr.parse().unwrap();
+
+            assert_eq!(
+                trusted_proxies.client_ip(Some(remote_addr), &headers(&forwarded_for)),
+                Some(client_ip.parse().unwrap()),
+                "{} {:?}",
+                remote_addr,
+                forwarded_for
+            );
+        }
+
+        let remote_addr = Some("10.0.0.1:1234".parse().unwrap());
+
+        // the invalid hop forwarded by the trusted proxy
+        assert_eq!(
+            trusted_proxies.client_ip(remote_addr, &headers(&["unknown"])),
+            None
+        );
+        // the invalid hop left of the client is never reached
+        assert_eq!(
+            trusted_proxies.client_ip(remote_addr, &headers(&["unknown, 198.51.100.1"])),
+            Some("198.51.100.1".parse().unwrap())
+        );
+        assert_eq!(trusted_proxies.client_ip(None, &headers(&["198.51.100.1"])), None);
+    }
+}
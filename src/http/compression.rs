@@ -0,0 +1,326 @@
+use std::error::Error;
+use std::io::Write;
+use std::task::{Context, Poll};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::{body, Body, HeaderMap, Method, Request, Response, StatusCode};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use hyper::service::Service;
+
+use crate::http::ServiceResult;
+
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_SIZE: u32 = 22;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Compress the full responses of the compressible content types, the images like png and jpeg
+/// are compressed already, so they are served as is.
+#[derive(Debug)]
+pub struct CompressionService<S> {
+    enabled: bool,
+    service: S,
+}
+
+impl<S> CompressionService<S> {
+    pub fn new(enabled: bool, service: S) -> Self {
+        Self { enabled, service }
+    }
+}
+
+impl<S> Service<Request<Body>> for CompressionService<S>
+    where
+        S: Service<Request<Body>, Response=Response<Body>>,
+        S::Future: Send + 'static,
+        S::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Response = Response<Body>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = ServiceResult<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let encoding = if self.enabled && req.method() == Method::GET {
+            negotiate(req.headers())
+        } else {
+            None
+        };
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let resp = fut.await.map_err(Into::into)?;
+
+            let encoding = match encoding {
+                Some(encoding) if should_compress(&resp) => encoding,
+                _ => return Ok(resp),
+            };
+
+            let (mut parts, body) = resp.into_parts();
+            let data = body::to_bytes(body).await?;
+
+            let compressed = compress(encoding, &data)?;
+
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+            parts
+                .headers
+                .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+            Ok(Response::from_parts(parts, Body::from(compressed)))
+        })
+    }
+}
+
+impl<S: Clone> Clone for CompressionService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            enabled: self.enabled,
+            service: self.service.clone(),
+        }
+    }
+}
+
+/// Choose the encoding from `accept-encoding`, brotli is preferred when the q values are equal.
+fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for value in headers.get_all("accept-encoding") {
+        let value = match value.to_str() {
+            Err(_) => continue,
+            Ok(value) => value,
+        };
+
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or_default().trim();
+
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .next()
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+
+            let q = match q {
+                Some(q) if q > 0.0 => q,
+                _ => continue,
+            };
+
+            let encoding = if coding.eq_ignore_ascii_case("br") {
+                Encoding::Brotli
+            } else if coding.eq_ignore_ascii_case("gzip") {
+                Encoding::Gzip
+            } else {
+                continue;
+            };
+
+            let better = match best {
+                None => true,
+                Some((_, best_q)) => q > best_q || (q == best_q && encoding == Encoding::Brotli),
+            };
+
+            if better {
+                best = Some((encoding, q));
+            }
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+fn should_compress(resp: &Response<Body>) -> bool {
+    // range responses must keep the bytes of the original representation
+    if resp.status() != StatusCode::OK || resp.headers().contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+
+    resp.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_compressible)
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime == "image/svg+xml"
+        || mime == "application/json"
+        || mime == "application/javascript"
+        || mime == "application/xml"
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+}
+
+fn compress(encoding: Encoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), Compression::default());
+            encoder.write_all(data)?;
+
+            encoder.finish()
+        }
+
+        Encoding::Brotli => {
+            let mut compressed = Vec::with_capacity(data.len() / 2);
+
+            {
+                let mut encoder = brotli::CompressorWriter::new(
+                    &mut compressed,
+                    4096,
+                    BROTLI_QUALITY,
+                    BROTLI_WINDOW_SIZE,
+                );
+                encoder.write_all(data)?;
+            }
+
+            Ok(compressed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{self, Ready};
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    const SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="10" height="10"/></svg>"#;
+
+    #[derive(Clone)]
+    struct MockService {
+        status: StatusCode,
+        content_type: &'static str,
+    }
+
+    impl Service<Request<Body>> for MockService {
+        type Response = Response<Body>;
+        type Error = hyper::http::Error;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: Request<Body>) -> Self::Future {
+            future::ready(
+                Response::builder()
+                    .status(self.status)
+                    .header(CONTENT_TYPE, self.content_type)
+                    .header(CONTENT_LENGTH, SVG.len())
+                    .body(Body::from(SVG)),
+            )
+        }
+    }
+
+    fn request(accept_encoding: &str) -> Request<Body> {
+        Request::builder()
+            .header("accept-encoding", accept_encoding)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        for (accept_encoding, encoding) in &[
+            ("gzip", Some(Encoding::Gzip)),
+            ("gzip, deflate, br", Some(Encoding::Brotli)),
+            ("br;q=0.5, gzip", Some(Encoding::Gzip)),
+            ("br;q=0, gzip;q=0", None),
+            ("identity", None),
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert("accept-encoding", accept_encoding.parse().unwrap());
+
+            assert_eq!(negotiate(&headers), *encoding, "{}", accept_encoding);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compress_svg() {
+        let mut service = CompressionService::new(
+            true,
+            MockService {
+                status: StatusCode::OK,
+                content_type: "image/svg+xml",
+            },
+        );
+
+        let resp = service.call(request("gzip")).await.unwrap();
+
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[VARY], "accept-encoding");
+        assert!(!resp.headers().contains_key(CONTENT_LENGTH));
+
+        let data = body::to_bytes(resp).await.unwrap();
+        let mut decompressed = String::new();
+        GzDecoder::new(data.as_ref())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+
+        assert_eq!(decompressed, SVG);
+
+        let resp = service.call(request("br")).await.unwrap();
+
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "br");
+
+        let data = body::to_bytes(resp).await.unwrap();
+        let mut decompressed = String::new();
+        brotli::Decompressor::new(data.as_ref(), 4096)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+
+        assert_eq!(decompressed, SVG);
+    }
+
+    #[tokio::test]
+    async fn test_skip_compress() {
+        let cases = [
+            (StatusCode::OK, "image/png", "gzip", true),
+            (StatusCode::PARTIAL_CONTENT, "image/svg+xml", "gzip", true),
+            (StatusCode::OK, "image/svg+xml", "identity", true),
+            (StatusCode::OK, "image/svg+xml", "gzip", false),
+        ];
+
+        for (status, content_type, accept_encoding, enabled) in cases.iter() {
+            let mut service = CompressionService::new(
+                *enabled,
+                MockService {
+                    status: *status,
+                    content_type,
+                },
+            );
+
+            let resp = service.call(request(accept_encoding)).await.unwrap();
+
+            assert!(!resp.headers().contains_key(CONTENT_ENCODING), "{}", content_type);
+            assert_eq!(resp.headers()[CONTENT_LENGTH], SVG.len().to_string().as_str());
+        }
+    }
+}
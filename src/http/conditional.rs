@@ -0,0 +1,111 @@
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+
+/// Format the time as an RFC 7231 IMF-fixdate, such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Check the `If-Modified-Since` header against the last modified time, a malformed header is
+/// treated as absent.
+pub fn is_not_modified(if_modified_since: &str, last_modified: SystemTime) -> bool {
+    let since = match DateTime::parse_from_rfc2822(if_modified_since.trim()) {
+        Err(_) => return false,
+        Ok(since) => since,
+    };
+
+    // http date only has second precision
+    DateTime::<Utc>::from(last_modified).timestamp() <= since.timestamp()
+}
+
+/// The strong entity tag of the resource representation, the transcoded variants are tagged with
+/// their format, so the representations of the same url never share a tag.
+pub fn etag(hash: &str, variant: Option<&str>) -> String {
+    match variant {
+        None => format!("\"{}\"", hash),
+        Some(variant) => format!("\"{}-{}\"", hash, variant),
+    }
+}
+
+/// Check the `If-None-Match` header against the entity tag, it uses the weak comparison.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// The opaque tags of the `If-None-Match` header without the quotes and the weak prefix, `*` is
+/// skipped.
+pub fn entity_tags(if_none_match: &str) -> impl Iterator<Item=&str> {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .filter_map(|tag| tag.strip_prefix('"')?.strip_suffix('"'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn create_time() -> SystemTime {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        SystemTime::UNIX_EPOCH + Duration::from_secs(784111777)
+    }
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(http_date(create_time()), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    #[test]
+    fn test_not_modified() {
+        assert!(is_not_modified("Sun, 06 Nov 1994 08:49:37 GMT", create_time()));
+        assert!(is_not_modified("Mon, 07 Nov 1994 08:49:37 GMT", create_time()));
+    }
+
+    #[test]
+    fn test_modified() {
+        assert!(!is_not_modified("Sun, 06 Nov 1994 08:49:36 GMT", create_time()));
+    }
+
+    #[test]
+    fn test_etag() {
+        assert_eq!(etag("abc", None), "\"abc\"");
+        assert_eq!(etag("abc", Some("webp")), "\"abc-webp\"");
+        assert_ne!(etag("abc", Some("webp")), etag("abc", Some("avif")));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let etag = etag("abc", Some("webp"));
+
+        assert!(etag_matches("\"abc-webp\"", &etag));
+        assert!(etag_matches("\"xyz\", W/\"abc-webp\"", &etag));
+        assert!(etag_matches("*", &etag));
+
+        assert!(!etag_matches("\"abc\"", &etag));
+        assert!(!etag_matches("\"abc-avif\"", &etag));
+        assert!(!etag_matches("", &etag));
+    }
+
+    #[test]
+    fn test_entity_tags() {
+        assert_eq!(
+            entity_tags("\"abc\", W/\"xyz\", *, def").collect::<Vec<_>>(),
+            vec!["abc", "xyz"]
+        );
+        assert_eq!(entity_tags("").count(), 0);
+    }
+
+    #[test]
+    fn test_malformed_date() {
+        assert!(!is_not_modified("yesterday", create_time()));
+        assert!(!is_not_modified("", create_time()));
+    }
+}
@@ -0,0 +1,336 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use hyper::http::HeaderValue;
+use hyper::service::Service;
+
+use crate::http::ServiceResult;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Allows any origin, it can't be used with the credentials.
+pub const ANY_ORIGIN: &str = "*";
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// Let the browsers on the allowed origins call the service.
+#[derive(Debug)]
+pub struct Cors {
+    allowed_origins: Vec<String>,
+    max_age: Option<u64>,
+    allow_credentials: bool,
+}
+
+impl Cors {
+    pub fn new<I, T>(allowed_origins: I) -> Self
+        where
+            I: IntoIterator<Item=T>,
+            T: AsRef<str>,
+    {
+        Self {
+            allowed_origins: allowed_origins
+                .into_iter()
+                .map(|origin| origin.as_ref().trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+            max_age: None,
+            allow_credentials: false,
+        }
+    }
+
+    /// Let the browsers cache the preflight results for the seconds.
+    pub fn set_max_age(&mut self, max_age: u64) -> &mut Self {
+        self.max_age.replace(max_age);
+
+        self
+    }
+
+    /// Let the browsers send the cookies and the authorization, the allowed origin is always
+    /// responded as is instead of `*`.
+    pub fn set_allow_credentials(&mut self, allow_credentials: bool) -> &mut Self {
+        self.allow_credentials = allow_credentials;
+
+        self
+    }
+
+    /// The `Access-Control-Allow-Origin` of the origin, `None` if it's not allowed.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let origin_str = origin.to_str().ok()?.to_ascii_lowercase();
+
+        if self.allowed_origins.contains(&origin_str) {
+            return Some(origin.clone());
+        }
+
+        if self.allowed_origins.iter().any(|allowed| allowed == ANY_ORIGIN) {
+            if self.allow_credentials {
+                return Some(origin.clone());
+            }
+
+            return Some(HeaderValue::from_static(ANY_ORIGIN));
+        }
+
+        None
+    }
+
+    fn set_headers(&self, allow_origin: HeaderValue, headers: &mut HeaderMap) {
+        // the response depends on the origin unless any origin gets the same `*`
+        if allow_origin != ANY_ORIGIN {
+            headers.append("vary", HeaderValue::from_static("origin"));
+        }
+
+        headers.insert("access-control-allow-origin", allow_origin);
+
+        if self.allow_credentials {
+            headers.insert(
+                "access-control-allow-credentials",
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn preflight_response(
+        &self,
+        allow_origin: HeaderValue,
+        req_headers: &HeaderMap,
+    ) -> Result<Response<Body>, hyper::http::Error> {
+        let mut resp = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header("access-control-allow-methods", ALLOWED_METHODS)
+            .body(Body::empty())?;
+
+        let headers = resp.headers_mut();
+
+        if let Some(request_headers) = req_headers.get("access-control-request-headers") {
+            headers.insert("access-control-allow-headers", request_headers.clone());
+        }
+
+        if let Some(max_age) = self.max_age {
+            headers.insert("access-control-max-age", HeaderValue::from(max_age));
+        }
+
+        self.set_headers(allow_origin, headers);
+
+        Ok(resp)
+    }
+}
+
+/// Answer the preflights and add the CORS headers to the responses of the allowed origins. The
+/// requests of the disallowed origins are passed without the CORS headers, so the browsers
+/// block them.
+#[derive(Debug)]
+pub struct CorsService<S> {
+    cors: Option<Arc<Cors>>,
+    service: S,
+}
+
+impl<S> CorsService<S> {
+    pub fn new(cors: Option<Arc<Cors>>, service: S) -> Self {
+        Self { cors, service }
+    }
+}
+
+impl<S> Service<Request<Body>> for CorsService<S>
+    where
+        S: Service<Request<Body>, Response=Response<Body>>,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = ServiceResult<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let (cors, allow_origin) = match (&self.cors, req.headers().get("origin")) {
+            (Some(cors), Some(origin)) => match cors.allow_origin(origin) {
+                Some(allow_origin) => (cors.clone(), allow_origin),
+                None => {
+                    let fut = self.service.call(req);
+
+                    return Box::pin(async move { fut.await.map_err(Into::into) });
+                }
+            },
+
+            _ => {
+                let fut = self.service.call(req);
+
+                return Box::pin(async move { fut.await.map_err(Into::into) });
+            }
+        };
+
+        // the tus OPTIONS requests are not preflights, they have no requested method
+        if req.method() == Method::OPTIONS
+            && req.headers().contains_key("access-control-request-method")
+        {
+            let result = cors
+                .preflight_response(allow_origin, req.headers())
+                .map_err(|err| err.into());
+
+            return Box::pin(async move { result });
+        }
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut resp = fut.await.map_err(Into::into)?;
+
+            cors.set_headers(allow_origin, resp.headers_mut());
+
+            Ok(resp)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future;
+    use std::future::Ready;
+
+    use super::*;
+
+    struct MockService;
+
+    impl Service<Request<Body>> for MockService {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: Request<Body>) -> Self::Future {
+            future::ready(Ok(Response::new(Body::from("ok"))))
+        }
+    }
+
+    fn service(cors: Cors) -> CorsService<MockService> {
+        CorsService::new(Some(Arc::new(cors)), MockService)
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/upload")
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "authorization")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn request(origin: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/get/id")
+            .header("origin", origin)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let resp = service(Cors::new(["https://app.example.com"]))
+            .call(preflight("https://app.example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(resp.headers()["access-control-allow-methods"], ALLOWED_METHODS);
+        assert_eq!(resp.headers()["access-control-allow-headers"], "authorization");
+        assert_eq!(resp.headers()["vary"], "origin");
+        assert!(!resp.headers().contains_key("access-control-max-age"));
+        assert!(!resp.headers().contains_key("access-control-allow-credentials"));
+
+        let mut cors = Cors::new(["https://app.example.com"]);
+        cors.set_max_age(600).set_allow_credentials(true);
+
+        let resp = service(cors)
+            .call(preflight("https://app.example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.headers()["access-control-max-age"], "600");
+        assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let mut cors = Cors::new(["https://app.example.com"]);
+        cors.set_max_age(600);
+
+        let resp = service(cors)
+            .call(request("https://app.example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        // the max age is only for the preflights
+        assert!(!resp.headers().contains_key("access-control-max-age"));
+        assert!(!resp.headers().contains_key("access-control-allow-credentials"));
+
+        let mut cors = Cors::new(["https://app.example.com"]);
+        cors.set_allow_credentials(true);
+
+        let resp = service(cors)
+            .call(request("https://app.example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+    }
+
+    #[tokio::test]
+    async fn test_any_origin() {
+        let resp = service(Cors::new([ANY_ORIGIN]))
+            .call(request("https://other.example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.headers()["access-control-allow-origin"], "*");
+        assert!(!resp.headers().contains_key("vary"));
+
+        // the credentials are not allowed with `*`, the origin is responded instead
+        let mut cors = Cors::new([ANY_ORIGIN]);
+        cors.set_allow_credentials(true);
+
+        let resp = service(cors)
+            .call(preflight("https://other.example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "https://other.example.com"
+        );
+        assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+        assert_eq!(resp.headers()["vary"], "origin");
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_origin() {
+        let mut service = service(Cors::new(["https://app.example.com"]));
+
+        for req in [
+            request("https://evil.example.com"),
+            preflight("https://evil.example.com"),
+            Request::builder().uri("/get/id").body(Body::empty()).unwrap(),
+        ] {
+            let resp = service.call(req).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(!resp.headers().contains_key("access-control-allow-origin"));
+        }
+    }
+}
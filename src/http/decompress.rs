@@ -0,0 +1,132 @@
+use std::io::Write;
+use std::mem;
+
+use bytes::Bytes;
+use flate2::write::GzDecoder;
+
+use crate::http::size_limit::BodyError;
+
+/// The compressed chunk is decompressed piece by piece, deflate inflates a piece about 1032
+/// times at most, so the decompressed size can't go far beyond the limit before it's checked.
+const INPUT_PIECE_SIZE: usize = 1024;
+
+/// Decompress the gzip encoded body chunk by chunk. The decompressed size is limited, so a small
+/// compressed body can't be inflated to exhaust the memory.
+#[derive(Debug)]
+pub struct GzipDecoder {
+    decoder: GzDecoder<Vec<u8>>,
+    max_size: u64,
+    size: u64,
+}
+
+impl GzipDecoder {
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            decoder: GzDecoder::new(vec![]),
+            max_size,
+            size: 0,
+        }
+    }
+
+    /// Return the data decompressed from the chunk so far, it may be empty.
+    pub fn decode(&mut self, chunk: &[u8]) -> Result<Bytes, BodyError> {
+        for piece in chunk.chunks(INPUT_PIECE_SIZE) {
+            self.decoder
+                .write_all(piece)
+                .map_err(BodyError::InvalidGzip)?;
+
+            self.check_size()?;
+        }
+
+        Ok(self.take())
+    }
+
+    /// Return the rest of the decompressed data, the truncated body is invalid.
+    pub fn finish(mut self) -> Result<Bytes, BodyError> {
+        self.decoder.try_finish().map_err(BodyError::InvalidGzip)?;
+        self.check_size()?;
+
+        Ok(self.take())
+    }
+
+    fn check_size(&self) -> Result<(), BodyError> {
+        if self.size + self.decoder.get_ref().len() as u64 > self.max_size {
+            return Err(BodyError::DecompressedTooLarge(self.max_size));
+        }
+
+        Ok(())
+    }
+
+    fn take(&mut self) -> Bytes {
+        let data = mem::take(self.decoder.get_mut());
+        self.size += data.len() as u64;
+
+        Bytes::from(data)
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    pub fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+
+        encoder.finish().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let compressed = testing::gzip(&data);
+
+        for chunk_size in &[1, 100, 4096, compressed.len()] {
+            let mut decoder = GzipDecoder::new(data.len() as u64);
+            let mut decompressed = vec![];
+
+            for chunk in compressed.chunks(*chunk_size) {
+                decompressed.extend_from_slice(&decoder.decode(chunk).unwrap());
+            }
+
+            decompressed.extend_from_slice(&decoder.finish().unwrap());
+
+            assert_eq!(decompressed, data, "{}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn test_too_large() {
+        let compressed = testing::gzip(&vec![0; 10 * 1024 * 1024]);
+        let mut decoder = GzipDecoder::new(1024 * 1024);
+
+        let err = decoder.decode(&compressed).unwrap_err();
+
+        assert!(matches!(err, BodyError::DecompressedTooLarge(1048576)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut decoder = GzipDecoder::new(1024);
+        let err = decoder.decode(b"not a gzip encoded body").unwrap_err();
+
+        assert!(matches!(err, BodyError::InvalidGzip(_)));
+
+        // the truncated body
+        let compressed = testing::gzip(b"truncated gzip body");
+        let mut decoder = GzipDecoder::new(1024);
+        decoder.decode(&compressed[..compressed.len() - 4]).unwrap();
+
+        let err = decoder.finish().unwrap_err();
+
+        assert!(matches!(err, BodyError::InvalidGzip(_)));
+    }
+}
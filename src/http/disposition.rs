@@ -0,0 +1,175 @@
+use std::fmt::Write;
+
+/// Get the filename from the `content-disposition` header of the upload request, `filename*`
+/// takes precedence over `filename`. The directories are stripped.
+pub fn parse_filename(header: &str) -> Option<String> {
+    let mut filename = None;
+    let mut ext_filename = None;
+
+    for param in header.split(';').skip(1) {
+        let mut kv = param.splitn(2, '=');
+        let key = kv.next()?.trim();
+        let value = match kv.next() {
+            None => continue,
+            Some(value) => value.trim(),
+        };
+
+        if key.eq_ignore_ascii_case("filename*") {
+            ext_filename = parse_ext_value(value);
+        } else if key.eq_ignore_ascii_case("filename") {
+            filename = Some(unquote(value));
+        }
+    }
+
+    let filename = ext_filename.or(filename)?;
+
+    // only keep the base name, and drop the control characters
+    let filename = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>();
+
+    let filename = filename.trim();
+    if filename.is_empty() || filename == "." || filename == ".." {
+        None
+    } else {
+        Some(filename.to_owned())
+    }
+}
+
+/// Build the `content-disposition` header, the non-ascii filename is encoded as RFC 5987, with
+/// an ascii fallback for the old clients.
+pub fn content_disposition(filename: &str, attachment: bool) -> String {
+    let disposition_type = if attachment { "attachment" } else { "inline" };
+
+    let fallback = filename
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '"' | '\\' => format!("\\{}", c),
+            c if c.is_ascii() => c.to_string(),
+            _ => "_".to_owned(),
+        })
+        .collect::<String>();
+
+    if filename.is_ascii() {
+        return format!("{}; filename=\"{}\"", disposition_type, fallback);
+    }
+
+    let mut encoded = String::with_capacity(filename.len() * 3);
+    for byte in filename.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition_type, fallback, encoded
+    )
+}
+
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        None => value.to_owned(),
+        Some(value) => {
+            let mut unquoted = String::with_capacity(value.len());
+            let mut chars = value.chars();
+
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    unquoted.extend(chars.next());
+                } else {
+                    unquoted.push(c);
+                }
+            }
+
+            unquoted
+        }
+    }
+}
+
+/// Parse the RFC 5987 `charset'language'value`, only utf-8 is supported.
+fn parse_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let value = parts.next()?;
+
+    if !charset.eq_ignore_ascii_case("utf-8") {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut value = value.bytes();
+
+    while let Some(byte) = value.next() {
+        if byte == b'%' {
+            let hex = [value.next()?, value.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filename() {
+        for (header, filename) in &[
+            ("attachment; filename=\"cat.png\"", Some("cat.png")),
+            ("inline; filename=cat.png", Some("cat.png")),
+            ("attachment; filename=\"a\\\"b.png\"", Some("a\"b.png")),
+            ("attachment; filename=\"../../etc/passwd\"", Some("passwd")),
+            ("attachment; filename=\"C:\\\\img\\\\cat.png\"", Some("cat.png")),
+            (
+                "attachment; filename=\"fallback.png\"; filename*=UTF-8''%E7%8C%AB.png",
+                Some("猫.png"),
+            ),
+            ("attachment; filename*=iso-8859-1''cat.png", None),
+            ("attachment; filename=\"\"", None),
+            ("attachment", None),
+        ] {
+            assert_eq!(parse_filename(header).as_deref(), *filename, "{}", header);
+        }
+    }
+
+    #[test]
+    fn test_content_disposition_ascii() {
+        assert_eq!(
+            content_disposition("cat.png", false),
+            "inline; filename=\"cat.png\""
+        );
+        assert_eq!(
+            content_disposition("a\"b\\c\n.png", true),
+            "attachment; filename=\"a\\\"b\\\\c.png\""
+        );
+    }
+
+    #[test]
+    fn test_content_disposition_utf8() {
+        assert_eq!(
+            content_disposition("猫 cat.png", false),
+            "inline; filename=\"_ cat.png\"; filename*=UTF-8''%E7%8C%AB%20cat.png"
+        );
+        assert_eq!(
+            content_disposition("猫.png", true),
+            "attachment; filename=\"_.png\"; filename*=UTF-8''%E7%8C%AB.png"
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use hyper::http::HeaderValue;
+use hyper::{Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use slog::{error, warn};
+use thiserror::Error;
+
+use crate::http::size_limit::BodyError;
+use crate::log::{self, LogContext};
+use crate::store::circuit_breaker::CircuitOpenError;
+use crate::store::{ErrorKind, StoreError};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The seconds the client should wait before retrying when the db pool is saturated.
+const POOL_TIMEOUT_RETRY_AFTER: u64 = 1;
+/// The seconds the client should wait before retrying when the store backend is unavailable,
+/// such as the upload bucket is being created.
+const STORE_UNAVAILABLE_RETRY_AFTER: u64 = 1;
+
+/// How many requests failed to acquire a db connection since the process is started.
+static POOL_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+/// Keep the kind of the store error after it is boxed, so it can be mapped to the status code.
+#[derive(Debug, Error)]
+#[error("store error: {source}")]
+pub struct StoreFailure {
+    kind: ErrorKind,
+    source: BoxError,
+}
+
+impl StoreFailure {
+    pub fn new<E: StoreError + Send + Sync + 'static>(err: E) -> Self {
+        Self {
+            kind: err.kind(),
+            source: Box::new(err),
+        }
+    }
+}
+
+/// The JSON body of the error responses, `code` is stable for clients to match on.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+    pub request_id: String,
+}
+
+pub fn error_response(
+    status: StatusCode,
+    code: &str,
+    message: &str,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let error_response = ErrorResponse {
+        code: code.to_owned(),
+        message: message.to_owned(),
+        request_id: log_cx.request_id().to_owned(),
+    };
+
+    let body = serde_json::to_vec(&error_response).expect("serialize error response failed");
+
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+}
+
+/// Map the handle error to the status code. The underlying error is only logged, so the
+/// internals are not leaked to the client.
+pub fn handle_error_response(
+    err: BoxError,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, BoxError> {
+    // the client sent a bad body, it's not a server side failure
+    if let Some(body_err) = find_source::<BodyError>(err.as_ref()) {
+        let (status, code) = body_err.status_code();
+
+        warn!(log::get_logger(), "read request body failed: {}", body_err; log_cx);
+
+        return Ok(error_response(status, code, &body_err.to_string(), log_cx)?);
+    }
+
+    // the db pool is saturated, it's transient, so let the client retry later
+    if let Some(sqlx::Error::PoolTimedOut) = find_source::<sqlx::Error>(err.as_ref()) {
+        let pool_timeouts = POOL_TIMEOUTS.fetch_add(1, Ordering::Relaxed) + 1;
+
+        warn!(
+            log::get_logger(),
+            "db pool is saturated, acquire connection timed out";
+            log_cx,
+            "pool_timeouts" => pool_timeouts
+        );
+
+        let mut resp = error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "db_pool_exhausted",
+            "service is busy, please retry later",
+            log_cx,
+        )?;
+
+        resp.headers_mut()
+            .insert("retry-after", HeaderValue::from(POOL_TIMEOUT_RETRY_AFTER));
+
+        return Ok(resp);
+    }
+
+    let kind = error_kind(err.as_ref());
+
+    let (status, code, message) = match kind {
+        ErrorKind::NotFound => (
+            StatusCode::NOT_FOUND,
+            "resource_not_found",
+            "resource is not found",
+        ),
+        ErrorKind::Exist => (
+            StatusCode::CONFLICT,
+            "resource_exist",
+            "resource is already exist",
+        ),
+        ErrorKind::Unavailable => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "service_unavailable",
+            "service is unavailable, please retry later",
+        ),
+        ErrorKind::NotEmpty => (
+            StatusCode::CONFLICT,
+            "bucket_not_empty",
+            "bucket is not empty",
+        ),
+        ErrorKind::Other => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "internal server error",
+        ),
+    };
+
+    error!(
+        log::get_logger(),
+        "handle request failed: {}", err;
+        log_cx,
+        "status" => status.as_u16()
+    );
+
+    let mut resp = error_response(status, code, message, log_cx)?;
+
+    if kind == ErrorKind::Unavailable {
+        resp.headers_mut()
+            .insert("retry-after", HeaderValue::from(STORE_UNAVAILABLE_RETRY_AFTER));
+    }
+
+    Ok(resp)
+}
+
+fn find_source<'a, E: std::error::Error + 'static>(
+    err: &'a (dyn std::error::Error + 'static),
+) -> Option<&'a E> {
+    let mut source = Some(err);
+
+    while let Some(cause) = source {
+        if let Some(err) = cause.downcast_ref::<E>() {
+            return Some(err);
+        }
+
+        source = cause.source();
+    }
+
+    None
+}
+
+fn error_kind(err: &(dyn std::error::Error + 'static)) -> ErrorKind {
+    let mut source = Some(err);
+
+    while let Some(cause) = source {
+        if let Some(store_failure) = cause.downcast_ref::<StoreFailure>() {
+            return store_failure.kind;
+        }
+
+        if cause.is::<CircuitOpenError>() || cause.is::<io::Error>() {
+            return ErrorKind::Unavailable;
+        }
+
+        source = cause.source();
+    }
+
+    ErrorKind::Other
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::Duration;
+
+    use hyper::body;
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::db::Database;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let log_cx = LogContext::builder().request_id("request-id").build();
+
+        let resp = error_response(
+            StatusCode::NOT_FOUND,
+            "resource_not_found",
+            "resource test is not found",
+            &log_cx,
+        )
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+
+        let error_response: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(resp).await.unwrap()).unwrap();
+
+        assert_eq!(error_response.code, "resource_not_found");
+        assert_eq!(error_response.message, "resource test is not found");
+        assert_eq!(error_response.request_id, "request-id");
+    }
+
+    #[tokio::test]
+    async fn test_pool_timeout_response() {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+
+        let db_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect_timeout(Duration::from_millis(200))
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        let db = Database::new(&db_pool).await.unwrap();
+        let log_cx = LogContext::builder().request_id("request-id").build();
+
+        // hold the only connection, so the query can't acquire one
+        let _conn = db_pool.acquire().await.unwrap();
+
+        let err = db
+            .get_resource_by_id("pool-timeout", &log_cx)
+            .await
+            .unwrap_err();
+
+        let resp = handle_error_response(err.into(), &log_cx).unwrap();
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()["retry-after"], "1");
+
+        let error_response: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(resp).await.unwrap()).unwrap();
+
+        assert_eq!(error_response.code, "db_pool_exhausted");
+        assert!(POOL_TIMEOUTS.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_store_unavailable_response() {
+        use crate::store::memory::Error as MemoryError;
+
+        let log_cx = LogContext::builder().request_id("request-id").build();
+
+        let resp = handle_error_response(
+            Box::new(StoreFailure::new(MemoryError::Unavailable)),
+            &log_cx,
+        )
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()["retry-after"], "1");
+
+        let resp = handle_error_response("unexpected".into(), &log_cx).unwrap();
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!resp.headers().contains_key("retry-after"));
+    }
+
+    #[test]
+    fn test_error_kind() {
+        use crate::store::memory::Error as MemoryError;
+
+        let cases: Vec<(BoxError, _)> = vec![
+            (
+                Box::new(StoreFailure::new(MemoryError::ResourceNotFound("id".to_owned()))),
+                ErrorKind::NotFound,
+            ),
+            (
+                Box::new(StoreFailure::new(MemoryError::ResourceExist("id".to_owned()))),
+                ErrorKind::Exist,
+            ),
+            (
+                Box::new(StoreFailure::new(MemoryError::Unavailable)),
+                ErrorKind::Unavailable,
+            ),
+            (
+                Box::new(StoreFailure::new(MemoryError::BucketNotEmpty("bucket".to_owned()))),
+                ErrorKind::NotEmpty,
+            ),
+            (
+                Box::new(io::Error::new(io::ErrorKind::TimedOut, "timeout")),
+                ErrorKind::Unavailable,
+            ),
+            (Box::new(CircuitOpenError), ErrorKind::Unavailable),
+            ("unexpected".into(), ErrorKind::Other),
+        ];
+
+        for (err, kind) in cases {
+            assert_eq!(error_kind(err.as_ref()), kind, "{}", err);
+        }
+    }
+}
@@ -1,31 +1,151 @@
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::error::Error;
 use std::future;
 use std::future::Ready;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
 
-use chrono::Local;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Local, NaiveDate};
+use hyper::body::HttpBody;
 use hyper::{body, Method};
-use hyper::{Body, Request, Response, StatusCode, Uri};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode, Uri};
+use hyper::http::header::{HeaderName, HeaderValue};
+use hyper::http::response;
 use hyper::service::Service;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use slog::{info, warn};
-use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
-
-use crate::db::Database;
-use crate::http::request_id::RequestIdService;
-use crate::http::ServiceResult;
-use crate::http::size_limit::SizeLimitService;
+use slog::{error, info, warn};
+use sqlx::postgres::PgConnectOptions;
+use tokio::sync::Semaphore;
+use tokio::task;
+
+use crate::db::{
+    cache, migrate, BucketRollover, ConnectRetry, Database, PoolConfig, Resource, ResourceCache,
+    StorageQuota, TusUpload,
+};
+use crate::hash::{self, HashAlgorithm};
+use crate::http::{log_context, RemoteAddr, ServiceResult};
+use crate::http::access_log::AccessLogService;
+use crate::http::client_ip::TrustedProxies;
+use crate::http::compression::CompressionService;
+use crate::http::conditional;
+use crate::http::cors::{Cors, CorsService};
+use crate::http::decompress::GzipDecoder;
+use crate::http::disposition;
+use crate::http::error::{self, StoreFailure};
+use crate::http::hotlink::HotlinkProtection;
+use crate::http::ip_filter::{self, IpFilter, IpFilterService};
+use crate::http::range::{self, ByteRange, MultipartRanges, RangeRequest};
+use crate::http::request_id::{REQUEST_ID_HEADER, RequestIdService};
+use crate::http::signed_url::{self, SignatureError, UrlSigner};
+use crate::http::size_limit::{SizeLimitService, SizeLimits};
+use crate::http::spill::{SpillBuffer, UploadBody};
+use crate::http::trace::{TRACE_PARENT_HEADER, TraceService};
+use crate::http::tus;
+use crate::http::webhook::{UploadEvent, Webhook};
 use crate::id::generate::Generator;
+use crate::id::snowflake::SnowflakeGenerator;
+use crate::id::{IdEncoding, IdGenerator};
 use crate::log::{self, LogContext};
-use crate::store::StoreBackend;
+use crate::media::{self, TranscodeFormat, Watermark, WatermarkPosition};
+use crate::reconcile;
+use crate::store::{ErrorKind, PostConditions, PresignedPost, StoreBackend, StoreError};
+use crate::telemetry::{Span, SpanKind};
 
 type BoxError = Box<dyn Error + Send + Sync>;
 
-const UPLOAD_PATH: &str = "/upload";
-const GET_PATH: &str = "/get";
+pub const DEFAULT_UPLOAD_PATH: &str = "/upload";
+pub const DEFAULT_GET_PATH: &str = "/get";
+const META_PATH: &str = "/meta/";
+const DELETE_BATCH_PATH: &str = "/delete-batch";
+const DEDUP_STATS_PATH: &str = "/dedup-stats";
+const USAGE_PATH: &str = "/usage";
+const ID_STATS_PATH: &str = "/id-stats";
+const BY_HASH_PATH: &str = "/by-hash/";
+const HASH_PATH: &str = "/hash/";
+const COPY_PATH: &str = "/copy";
+const RECONCILE_PATH: &str = "/reconcile";
+const BUCKET_PATH: &str = "/bucket/";
+const LIST_PATH: &str = "/list";
+const READY_PATH: &str = "/ready";
+const SWEEP_PATH: &str = "/sweep";
+const SIGN_PATH: &str = "/sign/";
+const DEFAULT_LIST_LIMIT: usize = 100;
+/// The larger limit is lowered to it, so a page can't load the whole table.
+const MAX_LIST_LIMIT: usize = 1000;
+/// Matched before the upload path, so the default upload path can be a prefix of it.
+const UPLOAD_POLICY_PATH: &str = "/upload-policy";
+const UPLOAD_POLICY_COMPLETE_PATH: &str = "/upload-policy/complete";
+const UPLOAD_POLICY_EXPIRES_IN: Duration = Duration::from_secs(15 * 60);
+/// The seconds the signed links are valid for, when the sign api is called without `ttl`.
+const DEFAULT_SIGNED_URL_TTL: u64 = 60 * 60;
+const MAX_SIGNED_URL_TTL: u64 = 7 * 24 * 60 * 60;
+const MAX_RESOURCE_ID_LENGTH: usize = 64;
+/// The versioned paths are the same as the unversioned ones after stripping this prefix.
+pub(crate) const API_VERSION_PREFIX: &str = "/v1";
 const DEFAULT_MAX_BODY_SIZE: u64 = 20 * 1024 * 1024;
+const DEFAULT_BODY_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_CACHE_CONTROL_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+const DEFAULT_SCHEME: &str = "https";
+/// `miss` when the upload is stored as a new object, `hit` when the same content is stored
+/// already.
+const DEDUP_HEADER: &str = "x-image-bed-dedup";
+/// The hash of the content to check by `HEAD` the upload path, in the stored form.
+const CONTENT_HASH_HEADER: &str = "x-content-hash";
+/// The url of the stored resource which has the checked hash.
+const RESOURCE_URL_HEADER: &str = "x-image-bed-url";
+/// The dimensions of the image resource, they are absent if they are not detected.
+const IMAGE_WIDTH_HEADER: &str = "x-image-width";
+const IMAGE_HEIGHT_HEADER: &str = "x-image-height";
+const UPLOADS_SATURATED_RETRY_AFTER: u64 = 1;
+const DEFAULT_WATERMARK_OPACITY: f32 = 0.5;
+/// Every hash of the upload `If-None-Match` costs a db query, so only the first ones are checked.
+const MAX_UPLOAD_IF_NONE_MATCH_HASHES: usize = 8;
+
+/// How the dedup hits are checked against the uploaded bytes, so a hash collision doesn't serve
+/// the bytes of another upload.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DedupVerify {
+    /// Trust the hash.
+    Off,
+    /// Compare the sizes.
+    Size,
+    /// Compare the sizes and the stored bytes, the object of every dedup hit is read.
+    Full,
+}
+
+impl DedupVerify {
+    pub const OFF: &'static str = "off";
+    pub const SIZE: &'static str = "size";
+    pub const FULL: &'static str = "full";
+}
+
+impl FromStr for DedupVerify {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            Self::OFF => Ok(DedupVerify::Off),
+            Self::SIZE => Ok(DedupVerify::Size),
+            Self::FULL => Ok(DedupVerify::Full),
+            s => Err(anyhow::anyhow!(
+                "verify dedup bytes {} is invalid, must be {}, {} or {}",
+                s,
+                Self::OFF,
+                Self::SIZE,
+                Self::FULL
+            )),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct HandlerBuilder<'a, S: StoreBackend> {
@@ -37,6 +157,64 @@ pub struct HandlerBuilder<'a, S: StoreBackend> {
     port: Option<u16>,
     store_backend: Option<S>,
     max_body_size: Option<u64>,
+    upload_max_body_size: Option<u64>,
+    batch_max_body_size: Option<u64>,
+    strip_exif: Option<bool>,
+    cache_control_max_age: Option<u64>,
+    access_log: Option<bool>,
+    request_id_header: Option<&'a str>,
+    admin_token: Option<&'a str>,
+    db_max_connections: Option<u32>,
+    db_acquire_timeout: Option<u64>,
+    db_idle_timeout: Option<u64>,
+    db_connect_max_attempts: Option<u32>,
+    db_connect_retry_delay: Option<u64>,
+    default_scheme: Option<&'a str>,
+    trusted_proxies: Option<&'a [String]>,
+    ip_filter_allow: Option<&'a [String]>,
+    ip_filter_deny: Option<&'a [String]>,
+    ip_filter_paths: Option<&'a [String]>,
+    allowed_content_types: Option<&'a [String]>,
+    max_image_width: Option<u32>,
+    max_image_height: Option<u32>,
+    upload_path: Option<&'a str>,
+    get_path: Option<&'a str>,
+    resource_cache_capacity: Option<usize>,
+    resource_cache_ttl: Option<u64>,
+    compression: Option<bool>,
+    migrate: Option<bool>,
+    transcode: Option<bool>,
+    verify_on_read: Option<bool>,
+    verify_dedup_bytes: Option<&'a str>,
+    hash_algorithm: Option<&'a str>,
+    default_content_type: Option<&'a str>,
+    extension_content_type: Option<bool>,
+    upload_webhook_url: Option<&'a str>,
+    cdn_purge_url: Option<&'a str>,
+    max_total_bytes: Option<u64>,
+    max_objects_per_bucket: Option<u64>,
+    precreate_bucket: Option<bool>,
+    body_read_timeout: Option<u64>,
+    request_timeout: Option<u64>,
+    resource_ttl: Option<u64>,
+    temp_dir: Option<&'a Path>,
+    memory_spill_threshold: Option<u64>,
+    max_concurrent_uploads: Option<usize>,
+    cors_allowed_origins: Option<&'a [String]>,
+    cors_max_age: Option<u64>,
+    cors_allow_credentials: Option<bool>,
+    allowed_referers: Option<&'a [String]>,
+    allow_empty_referer: Option<bool>,
+    hotlink_placeholder: Option<&'a Path>,
+    watermark: Option<&'a Path>,
+    watermark_position: Option<&'a str>,
+    watermark_opacity: Option<f32>,
+    watermark_always: Option<bool>,
+    url_signing_secret: Option<&'a str>,
+    id_encoding: Option<&'a str>,
+    machine_id: Option<u16>,
+    id_seed: Option<i64>,
+    fixed_bucket: Option<&'a str>,
 }
 
 impl<'a, S: StoreBackend> Default for HandlerBuilder<'a, S> {
@@ -56,6 +234,64 @@ impl<'a, S: StoreBackend> HandlerBuilder<'a, S> {
             port: None,
             store_backend: None,
             max_body_size: None,
+            upload_max_body_size: None,
+            batch_max_body_size: None,
+            strip_exif: None,
+            cache_control_max_age: None,
+            access_log: None,
+            request_id_header: None,
+            admin_token: None,
+            db_max_connections: None,
+            db_acquire_timeout: None,
+            db_idle_timeout: None,
+            db_connect_max_attempts: None,
+            db_connect_retry_delay: None,
+            default_scheme: None,
+            trusted_proxies: None,
+            ip_filter_allow: None,
+            ip_filter_deny: None,
+            ip_filter_paths: None,
+            allowed_content_types: None,
+            max_image_width: None,
+            max_image_height: None,
+            upload_path: None,
+            get_path: None,
+            resource_cache_capacity: None,
+            resource_cache_ttl: None,
+            compression: None,
+            migrate: None,
+            transcode: None,
+            verify_on_read: None,
+            verify_dedup_bytes: None,
+            hash_algorithm: None,
+            default_content_type: None,
+            extension_content_type: None,
+            upload_webhook_url: None,
+            cdn_purge_url: None,
+            max_total_bytes: None,
+            max_objects_per_bucket: None,
+            precreate_bucket: None,
+            body_read_timeout: None,
+            request_timeout: None,
+            resource_ttl: None,
+            temp_dir: None,
+            memory_spill_threshold: None,
+            max_concurrent_uploads: None,
+            cors_allowed_origins: None,
+            cors_max_age: None,
+            cors_allow_credentials: None,
+            allowed_referers: None,
+            allow_empty_referer: None,
+            hotlink_placeholder: None,
+            watermark: None,
+            watermark_position: None,
+            watermark_opacity: None,
+            watermark_always: None,
+            url_signing_secret: None,
+            id_encoding: None,
+            machine_id: None,
+            id_seed: None,
+            fixed_bucket: None,
         }
     }
 
@@ -107,537 +343,9113 @@ impl<'a, S: StoreBackend> HandlerBuilder<'a, S> {
         self
     }
 
-    pub async fn build(mut self) -> anyhow::Result<Handler<S>> {
-        let domain = match self.domain.take() {
-            None => return Err(anyhow::anyhow!("domain is not set")),
-            Some(domain) => domain,
-        };
-
-        let database_name = match self.database_name.take() {
-            None => return Err(anyhow::anyhow!("database_name is not set")),
-            Some(database_name) => database_name,
-        };
+    /// The max body size of the uploads, `max_body_size` is used if not set.
+    pub fn set_upload_max_body_size(&mut self, max_body_size: u64) -> &mut Self {
+        self.upload_max_body_size.replace(max_body_size);
 
-        let host = match self.host.take() {
-            None => return Err(anyhow::anyhow!("host is not set")),
-            Some(host) => host,
-        };
+        self
+    }
 
-        let user = match self.user.take() {
-            None => return Err(anyhow::anyhow!("user is not set")),
-            Some(user) => user,
-        };
+    /// The max body size of the delete batch requests, `max_body_size` is used if not set.
+    pub fn set_batch_max_body_size(&mut self, max_body_size: u64) -> &mut Self {
+        self.batch_max_body_size.replace(max_body_size);
 
-        let password = match self.password.take() {
-            None => return Err(anyhow::anyhow!("password is not set")),
-            Some(password) => password,
-        };
+        self
+    }
 
-        let store_backend = match self.store_backend.take() {
-            None => return Err(anyhow::anyhow!("store_backend is not set")),
-            Some(store_backend) => store_backend,
-        };
+    pub fn set_strip_exif(&mut self, strip_exif: bool) -> &mut Self {
+        self.strip_exif.replace(strip_exif);
 
-        const ID_TYPE: &str = "image_bed";
+        self
+    }
 
-        let connect_options = PgConnectOptions::new()
-            .database(database_name)
-            .host(host)
-            .username(user)
-            .password(password)
-            .port(self.port.unwrap_or(5432));
+    pub fn set_cache_control_max_age(&mut self, max_age: u64) -> &mut Self {
+        self.cache_control_max_age.replace(max_age);
 
-        let db_pool = PgPoolOptions::new()
-            .max_connections(20)
-            .connect_with(connect_options)
-            .await?;
+        self
+    }
 
-        info!(log::get_logger(), "db pool is connected");
+    pub fn set_access_log(&mut self, access_log: bool) -> &mut Self {
+        self.access_log.replace(access_log);
 
-        let id_generator = Generator::new(&db_pool, ID_TYPE).await?;
+        self
+    }
 
-        info!(log::get_logger(), "id generator is init");
+    pub fn set_request_id_header(&mut self, request_id_header: &'a str) -> &mut Self {
+        self.request_id_header.replace(request_id_header);
 
-        let db = Database::new(&db_pool).await?;
+        self
+    }
 
-        info!(log::get_logger(), "db is init");
+    pub fn set_admin_token(&mut self, admin_token: &'a str) -> &mut Self {
+        self.admin_token.replace(admin_token);
 
-        Ok(Handler {
-            store_backend: Arc::new(store_backend),
-            id_generator,
-            db,
-            domain: Arc::new(domain.to_owned()),
-            max_body_size: self.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
-        })
+        self
     }
-}
-
-#[derive(Debug)]
-pub struct Handler<S: StoreBackend> {
-    store_backend: Arc<S>,
-    id_generator: Generator,
-    db: Database,
-    domain: Arc<String>,
-    max_body_size: u64,
-}
 
-impl<T, S> Service<T> for Handler<S>
-    where
-        S: StoreBackend + Send + Sync,
-{
-    type Response = RequestIdService<SizeLimitService<Handle<S>>>;
-    type Error = Infallible;
-    type Future = Ready<Result<Self::Response, Self::Error>>;
+    pub fn set_db_max_connections(&mut self, max_connections: u32) -> &mut Self {
+        self.db_max_connections.replace(max_connections);
 
-    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+        self
     }
 
-    fn call(&mut self, _req: T) -> Self::Future {
-        let max_body_size = self.max_body_size;
-        let handle = Handle::from(self);
+    /// Set the db connection acquire timeout in seconds.
+    pub fn set_db_acquire_timeout(&mut self, acquire_timeout: u64) -> &mut Self {
+        self.db_acquire_timeout.replace(acquire_timeout);
 
-        future::ready(Ok(SizeLimitService::new(max_body_size, handle).into()))
+        self
     }
-}
 
-#[derive(Debug)]
-pub struct Handle<S: StoreBackend> {
-    store_backend: Arc<S>,
-    id_generator: Generator,
-    db: Database,
-    domain: Arc<String>,
-}
+    /// Set the db connection idle timeout in seconds.
+    pub fn set_db_idle_timeout(&mut self, idle_timeout: u64) -> &mut Self {
+        self.db_idle_timeout.replace(idle_timeout);
 
-impl<S: StoreBackend> Clone for Handle<S> {
-    fn clone(&self) -> Self {
-        Self {
-            store_backend: self.store_backend.clone(),
-            id_generator: self.id_generator.clone(),
-            db: self.db.clone(),
-            domain: self.domain.clone(),
-        }
+        self
     }
-}
 
-impl<'a, S: StoreBackend> From<&'a mut Handler<S>> for Handle<S> {
-    fn from(h: &'a mut Handler<S>) -> Self {
-        Handle {
-            store_backend: h.store_backend.clone(),
-            id_generator: h.id_generator.clone(),
-            db: h.db.clone(),
-            domain: h.domain.clone(),
-        }
+    /// Set how many times to connect the db on startup before giving up.
+    pub fn set_db_connect_max_attempts(&mut self, max_attempts: u32) -> &mut Self {
+        self.db_connect_max_attempts.replace(max_attempts);
+
+        self
     }
-}
 
-impl<S> Service<Request<Body>> for Handle<S>
-    where
-        S: StoreBackend + 'static + Send + Sync,
-        S::Error: Send + Sync,
-{
-    type Response = Response<Body>;
-    type Error = BoxError;
-    type Future = ServiceResult<Self::Response, Self::Error>;
+    /// Set the first delay between the db connection attempts in milliseconds, it's doubled
+    /// after every attempt.
+    pub fn set_db_connect_retry_delay(&mut self, retry_delay: u64) -> &mut Self {
+        self.db_connect_retry_delay.replace(retry_delay);
 
-    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+        self
     }
 
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let path = req.uri().path();
+    /// Set the scheme of the returned url when the request isn't from a trusted proxy.
+    pub fn set_default_scheme(&mut self, default_scheme: &'a str) -> &mut Self {
+        self.default_scheme.replace(default_scheme);
+
+        self
+    }
 
-        if path.starts_with(UPLOAD_PATH) && req.method() == Method::POST {
-            let handle = self.clone();
+    /// Set the CIDRs or addresses of the proxies whose `X-Forwarded-For`, `X-Forwarded-Proto`
+    /// and `X-Forwarded-Host` headers are trusted.
+    pub fn set_trusted_proxies(&mut self, trusted_proxies: &'a [String]) -> &mut Self {
+        self.trusted_proxies.replace(trusted_proxies);
 
-            Box::pin(async move { handle.handle_upload(req).await })
-        } else if path.starts_with(GET_PATH) && req.method() == Method::GET {
-            let handle = self.clone();
+        self
+    }
 
-            Box::pin(async move { handle.handle_get(req).await })
-        } else if path.starts_with(GET_PATH) && req.method() == Method::HEAD {
-            let handle = self.clone();
+    /// Only let the peers in the CIDRs reach the `ip_filter_paths`, all peers by default.
+    pub fn set_ip_filter_allow(&mut self, allow: &'a [String]) -> &mut Self {
+        self.ip_filter_allow.replace(allow);
 
-            Box::pin(async move { handle.handle_head(req).await })
-        } else {
-            warn!(log::get_logger(), "illegal request {:?}", req);
+        self
+    }
 
-            let mut resp = Response::new(Body::empty());
-            *resp.status_mut() = StatusCode::BAD_REQUEST;
+    /// Reject the peers in the CIDRs on the `ip_filter_paths`, even if they are allowed.
+    pub fn set_ip_filter_deny(&mut self, deny: &'a [String]) -> &mut Self {
+        self.ip_filter_deny.replace(deny);
 
-            Box::pin(async move { Ok(resp) })
-        }
+        self
     }
-}
 
-impl<S> Handle<S>
-    where
-        S: StoreBackend + Send + Sync + 'static,
-        S::Error: Send + Sync,
-{
-    async fn handle_upload(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
-        let host = if let Some(host) = req.headers().get("host").cloned() {
-            host.to_str()?.to_owned()
-        } else {
-            self.domain.as_str().to_owned()
-        };
+    /// The paths and their sub paths which are filtered by the peer address, the trusted
+    /// proxies' `X-Forwarded-For` is used as the peer.
+    pub fn set_ip_filter_paths(&mut self, paths: &'a [String]) -> &mut Self {
+        self.ip_filter_paths.replace(paths);
 
-        let log_cx = LogContext::builder()
-            .request_id(get_request_id(&req))
-            .build();
+        self
+    }
 
-        let data = body::to_bytes(req.into_body()).await?;
+    /// Only accept the uploads with these content types, accept anything when it is empty.
+    pub fn set_allowed_content_types(&mut self, allowed_content_types: &'a [String]) -> &mut Self {
+        self.allowed_content_types.replace(allowed_content_types);
 
-        let mut hasher = Sha256::new();
-        hasher.update(&data);
+        self
+    }
 
-        let hash_result = hex::encode(hasher.finalize());
+    pub fn set_max_image_width(&mut self, max_image_width: u32) -> &mut Self {
+        self.max_image_width.replace(max_image_width);
 
-        let resource =
-            if let Some(resource) = self.db.get_resource_by_hash(&hash_result, &log_cx).await? {
-                resource
-            } else {
-                let resource_id = self.id_generator.get_id(&log_cx).await?;
+        self
+    }
 
-                let bucket = Local::today().format("%Y-%m").to_string();
+    pub fn set_max_image_height(&mut self, max_image_height: u32) -> &mut Self {
+        self.max_image_height.replace(max_image_height);
 
-                let resource = self
-                    .db
-                    .insert_resource(
-                        &bucket,
-                        &resource_id,
-                        &hash_result,
-                        data.len() as _,
-                        &log_cx,
-                    )
-                    .await?;
+        self
+    }
 
-                self.store_backend
-                    .put(&bucket, &resource_id, data.as_ref(), &log_cx)
-                    .await?;
+    pub fn set_upload_path(&mut self, upload_path: &'a str) -> &mut Self {
+        self.upload_path.replace(upload_path);
 
-                resource
-            };
+        self
+    }
 
-        let resource_uri = Uri::builder()
-            .scheme("https")
-            .authority(host.as_str())
-            .path_and_query(format!("{}/{}", GET_PATH, resource.get_id()))
-            .build()?
-            .to_string();
+    pub fn set_get_path(&mut self, get_path: &'a str) -> &mut Self {
+        self.get_path.replace(get_path);
 
-        let mut resp = Response::new(Body::from(resource_uri));
-        let headers = resp.headers_mut();
-        headers.append("content-type", "text/plain".parse()?);
-        headers.append("content-type", "charset=utf-8".parse()?);
+        self
+    }
 
-        info!(
-            log::get_logger(),
-            "upload success";
-            log_cx,
-            "resource" => format!("{:?}", resource)
-        );
+    /// Cache the resources got by id in memory, the cache is disabled when the capacity is 0.
+    pub fn set_resource_cache_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.resource_cache_capacity.replace(capacity);
 
-        Ok(resp)
+        self
     }
 
-    async fn handle_get(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
-        let log_cx = LogContext::builder()
-            .request_id(get_request_id(&req))
-            .build();
+    /// Set the resource cache ttl in seconds.
+    pub fn set_resource_cache_ttl(&mut self, ttl: u64) -> &mut Self {
+        self.resource_cache_ttl.replace(ttl);
 
-        let path = req.uri().path().replace(GET_PATH, "");
-        let resource_id = path.strip_prefix('/').unwrap_or(&path);
+        self
+    }
 
-        let resource = match self.db.get_resource_by_id(resource_id, &log_cx).await? {
-            None => {
-                return Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Body::empty())?);
-            }
+    /// Compress the compressible responses, such as svg, with gzip or brotli.
+    pub fn set_compression(&mut self, compression: bool) -> &mut Self {
+        self.compression.replace(compression);
 
-            Some(resource) => resource,
-        };
+        self
+    }
 
-        let (start, end) = match req.headers().get("range") {
-            None => (None, None),
-            Some(range) => range.to_str().map_or((None, None), |range| {
-                if !range.starts_with("bytes=") {
-                    (None, None)
-                } else {
-                    let bytes = range.replace("bytes=", "");
-                    let start_end = bytes.split('-').collect::<Vec<_>>();
+    /// Create or upgrade the tables before using them, disable it when the schema is managed
+    /// externally.
+    pub fn set_migrate(&mut self, migrate: bool) -> &mut Self {
+        self.migrate.replace(migrate);
 
-                    if start_end.len() != 2 {
-                        (None, None)
-                    } else {
-                        let start = start_end[0].parse::<u64>().ok();
-                        let end = start_end[1].parse::<u64>().ok();
+        self
+    }
 
-                        (start, end)
-                    }
-                }
-            }),
-        };
+    /// Transcode the JPEG and PNG images to WebP on download when the client accepts
+    /// them, the transcoded variants are saved to the store backend.
+    pub fn set_transcode(&mut self, transcode: bool) -> &mut Self {
+        self.transcode.replace(transcode);
 
-        let status_code = if start.is_some() || end.is_some() {
-            StatusCode::PARTIAL_CONTENT
-        } else {
-            StatusCode::OK
-        };
+        self
+    }
 
-        let data = self
-            .store_backend
-            .get(
-                resource.get_bucket(),
-                resource.get_id(),
-                start,
-                end,
-                &log_cx,
-            )
-            .await?;
+    /// Hash the full downloads and compare with the stored hash, the corrupted resources get 500
+    /// instead of being served. The download is buffered and hashed before the first byte is
+    /// sent, so it costs memory, CPU and latency, disabled by default.
+    pub fn set_verify_on_read(&mut self, verify_on_read: bool) -> &mut Self {
+        self.verify_on_read.replace(verify_on_read);
 
-        let mut resp_builder = Response::builder();
-        resp_builder = resp_builder.header("content-type", "text/plain");
-        resp_builder = resp_builder.header("content-type", "charset=utf-8");
-        resp_builder = resp_builder.status(status_code);
+        self
+    }
 
-        if status_code == StatusCode::PARTIAL_CONTENT {
-            let start = start.unwrap_or(0);
-            // content-range is [start, end], not [start, end)
-            let end = end.unwrap_or_else(|| (data.len() as u64) - start - 1);
+    /// Check the dedup hits against the uploaded bytes, `off`, `size` or `full`, `off` by default.
+    /// The upload which doesn't match the hit is stored as a new resource.
+    pub fn set_verify_dedup_bytes(&mut self, verify_dedup_bytes: &'a str) -> &mut Self {
+        self.verify_dedup_bytes.replace(verify_dedup_bytes);
 
-            resp_builder = resp_builder.header(
-                "content-range",
-                format!("bytes: {}-{}/{}", start, end, resource.get_resource_size()),
-            );
-        }
+        self
+    }
 
-        info!(
-            log::get_logger(),
-            "get success";
-            log_cx,
-            "resource" => format!("{:?}", resource),
-            "start" => start,
-            "end" => end
-        );
+    /// The hash algorithm of the new uploads, `sha256` or `blake3`, `sha256` by default. The
+    /// uploads are not deduped against the resources hashed by the other algorithm.
+    pub fn set_hash_algorithm(&mut self, hash_algorithm: &'a str) -> &mut Self {
+        self.hash_algorithm.replace(hash_algorithm);
 
-        Ok(resp_builder.body(Body::from(data))?)
+        self
     }
 
-    async fn handle_head(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
-        let log_cx = LogContext::builder()
-            .request_id(get_request_id(&req))
-            .build();
+    /// The content type of the uploads which can't be detected, `application/octet-stream` by
+    /// default.
+    pub fn set_default_content_type(&mut self, default_content_type: &'a str) -> &mut Self {
+        self.default_content_type.replace(default_content_type);
 
-        let path = req.uri().path().replace(GET_PATH, "");
-        let resource_id = path.strip_prefix('/').unwrap_or(&path);
+        self
+    }
 
-        let resource = match self.db.get_resource_by_id(resource_id, &log_cx).await? {
-            None => {
-                return Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Body::empty())?);
-            }
+    /// Serve the resources of the default content type as the type of the extension in the get
+    /// url, like `/get/<id>.png`, enabled by default.
+    pub fn set_extension_content_type(&mut self, extension_content_type: bool) -> &mut Self {
+        self.extension_content_type.replace(extension_content_type);
 
-            Some(resource) => resource,
-        };
+        self
+    }
 
-        let (start, end) = match req.headers().get("range") {
-            None => (None, None),
-            Some(range) => range.to_str().map_or((None, None), |range| {
-                if !range.starts_with("bytes=") {
-                    (None, None)
-                } else {
-                    let bytes = range.replace("bytes=", "");
-                    let start_end = bytes.split('-').collect::<Vec<_>>();
+    /// Post the new uploads to the url, the dedup hits are not posted.
+    pub fn set_upload_webhook_url(&mut self, upload_webhook_url: &'a str) -> &mut Self {
+        self.upload_webhook_url.replace(upload_webhook_url);
 
-                    if start_end.len() != 2 {
-                        (None, None)
-                    } else {
-                        let start = start_end[0].parse::<u64>().ok();
-                        let end = start_end[1].parse::<u64>().ok();
+        self
+    }
 
-                        (start, end)
-                    }
-                }
-            }),
-        };
+    /// Post the urls of the deleted resources to the url, so the CDN can purge them.
+    pub fn set_cdn_purge_url(&mut self, cdn_purge_url: &'a str) -> &mut Self {
+        self.cdn_purge_url.replace(cdn_purge_url);
 
-        let status_code = if start.is_some() || end.is_some() {
-            StatusCode::PARTIAL_CONTENT
-        } else {
-            StatusCode::OK
-        };
+        self
+    }
 
-        let resource_size = resource.get_resource_size();
+    /// Reject the new uploads with 507 once the total bytes of the resources would exceed it.
+    pub fn set_max_total_bytes(&mut self, max_total_bytes: u64) -> &mut Self {
+        self.max_total_bytes.replace(max_total_bytes);
 
-        let (start, end, total) = match (start, end) {
-            (Some(start), Some(end)) => {
-                if start <= resource_size && end <= resource_size {
-                    (Some(start), Some(end), end - start + 1)
-                } else if start > resource_size {
-                    (Some(resource_size), Some(resource_size), 0)
-                } else {
-                    (Some(start), Some(resource_size), resource_size - start + 1)
-                }
-            }
+        self
+    }
 
-            (Some(start), None) => {
-                if start <= resource_size {
-                    (Some(start), None, resource_size - start + 1)
-                } else {
-                    (Some(resource_size), None, 0)
-                }
-            }
+    /// Roll the new uploads over to the suffixed bucket once the bucket of the month holds the
+    /// objects.
+    pub fn set_max_objects_per_bucket(&mut self, max_objects: u64) -> &mut Self {
+        self.max_objects_per_bucket.replace(max_objects);
 
-            (None, Some(end)) => {
-                if end <= resource_size {
-                    (Some(0), Some(end), end + 1)
-                } else {
-                    (Some(0), Some(resource_size), resource_size)
-                }
-            }
+        self
+    }
 
-            (None, None) => (None, None, resource_size),
-        };
+    /// Create the upload bucket of the month at startup, the `/ready` route fails until it is
+    /// created, disabled by default.
+    pub fn set_precreate_bucket(&mut self, precreate_bucket: bool) -> &mut Self {
+        self.precreate_bucket.replace(precreate_bucket);
 
-        let mut resp_builder = Response::builder();
-        resp_builder = resp_builder.header("content-type", "text/plain");
-        resp_builder = resp_builder.header("content-type", "charset=utf-8");
-        resp_builder = resp_builder.status(status_code);
+        self
+    }
 
-        if status_code == StatusCode::PARTIAL_CONTENT {
-            let start = start.unwrap_or(0);
-            // content-range is [start, end], not [start, end)
-            let end = end.unwrap_or(total);
+    /// Abort the request with 408 when no body bytes are received in the seconds, 30 by default.
+    pub fn set_body_read_timeout(&mut self, body_read_timeout: u64) -> &mut Self {
+        self.body_read_timeout.replace(body_read_timeout);
 
-            resp_builder = resp_builder.header(
-                "content-range",
-                format!("bytes: {}-{}/{}", start, end, total),
-            );
-        }
+        self
+    }
 
-        resp_builder = resp_builder.header("content-length", format!("{}", total));
+    /// Respond 504 when the request is not handled in the seconds, disabled by default. The
+    /// requests which change the db or the store keep running in the background after the
+    /// timeout, so the resource and its object are not left half done.
+    pub fn set_request_timeout(&mut self, request_timeout: u64) -> &mut Self {
+        self.request_timeout.replace(request_timeout);
 
-        info!(
+        self
+    }
+
+    /// The resources older than the seconds are swept by `POST /sweep` without `before`.
+    pub fn set_resource_ttl(&mut self, resource_ttl: u64) -> &mut Self {
+        self.resource_ttl.replace(resource_ttl);
+
+        self
+    }
+
+    /// Where the uploads larger than the memory spill threshold are spilled, the system temp dir
+    /// by default.
+    pub fn set_temp_dir(&mut self, temp_dir: &'a Path) -> &mut Self {
+        self.temp_dir.replace(temp_dir);
+
+        self
+    }
+
+    /// Spill the upload body into a temp file once it's larger than the bytes, the body is kept
+    /// in memory by default.
+    pub fn set_memory_spill_threshold(&mut self, memory_spill_threshold: u64) -> &mut Self {
+        self.memory_spill_threshold.replace(memory_spill_threshold);
+
+        self
+    }
+
+    /// Respond 503 to the uploads beyond the number of the in-flight ones, so they don't
+    /// overwhelm the store backend and the db pool, unlimited by default.
+    pub fn set_max_concurrent_uploads(&mut self, max_concurrent_uploads: usize) -> &mut Self {
+        self.max_concurrent_uploads.replace(max_concurrent_uploads);
+
+        self
+    }
+
+    /// Enable CORS for the origins, such as `https://app.example.com`, `*` allows any origin.
+    pub fn set_cors_allowed_origins(&mut self, cors_allowed_origins: &'a [String]) -> &mut Self {
+        self.cors_allowed_origins.replace(cors_allowed_origins);
+
+        self
+    }
+
+    /// Let the browsers cache the CORS preflight results for the seconds.
+    pub fn set_cors_max_age(&mut self, cors_max_age: u64) -> &mut Self {
+        self.cors_max_age.replace(cors_max_age);
+
+        self
+    }
+
+    /// Let the browsers send the credentials with the CORS requests, false by default.
+    pub fn set_cors_allow_credentials(&mut self, cors_allow_credentials: bool) -> &mut Self {
+        self.cors_allow_credentials.replace(cors_allow_credentials);
+
+        self
+    }
+
+    /// Enable the hotlink protection, the downloads are rejected unless the `Referer` host is one
+    /// of the allowed hosts.
+    pub fn set_allowed_referers(&mut self, allowed_referers: &'a [String]) -> &mut Self {
+        self.allowed_referers.replace(allowed_referers);
+
+        self
+    }
+
+    /// Allow the downloads without `Referer` when the hotlink protection is enabled, true by
+    /// default.
+    pub fn set_allow_empty_referer(&mut self, allow_empty_referer: bool) -> &mut Self {
+        self.allow_empty_referer.replace(allow_empty_referer);
+
+        self
+    }
+
+    /// Serve the image file to the rejected hotlinks instead of 403.
+    pub fn set_hotlink_placeholder(&mut self, hotlink_placeholder: &'a Path) -> &mut Self {
+        self.hotlink_placeholder.replace(hotlink_placeholder);
+
+        self
+    }
+
+    /// Overlay the image file on the JPEG and PNG images downloaded with `?watermark=1`, the
+    /// watermarked variants are saved to the store backend.
+    pub fn set_watermark(&mut self, watermark: &'a Path) -> &mut Self {
+        self.watermark.replace(watermark);
+
+        self
+    }
+
+    /// `top-left`, `top-right`, `bottom-left`, `bottom-right` or `center`, `bottom-right` by
+    /// default.
+    pub fn set_watermark_position(&mut self, watermark_position: &'a str) -> &mut Self {
+        self.watermark_position.replace(watermark_position);
+
+        self
+    }
+
+    /// Scale the alpha of the watermark, in `[0, 1]`, 0.5 by default.
+    pub fn set_watermark_opacity(&mut self, watermark_opacity: f32) -> &mut Self {
+        self.watermark_opacity.replace(watermark_opacity);
+
+        self
+    }
+
+    /// Watermark every download instead of the ones with `?watermark=1`, so the originals are
+    /// never served.
+    pub fn set_watermark_always(&mut self, watermark_always: bool) -> &mut Self {
+        self.watermark_always.replace(watermark_always);
+
+        self
+    }
+
+    /// Sign the links issued by the admin sign api, the signed links are verified on download.
+    pub fn set_url_signing_secret(&mut self, url_signing_secret: &'a str) -> &mut Self {
+        self.url_signing_secret.replace(url_signing_secret);
+
+        self
+    }
+
+    /// `counter` by default, `snowflake` generates the ids without the `id_generate` table.
+    pub fn set_id_encoding(&mut self, id_encoding: &'a str) -> &mut Self {
+        self.id_encoding.replace(id_encoding);
+
+        self
+    }
+
+    /// The snowflake machine id, 0 by default, only used by the `snowflake` encoding.
+    pub fn set_machine_id(&mut self, machine_id: u16) -> &mut Self {
+        self.machine_id.replace(machine_id);
+
+        self
+    }
+
+    /// The first `counter` id is generated from the seed plus 1, only applied when the counter
+    /// is created, 0 by default.
+    pub fn set_id_seed(&mut self, id_seed: i64) -> &mut Self {
+        self.id_seed.replace(id_seed);
+
+        self
+    }
+
+    /// Upload all the resources to the bucket instead of the bucket of the upload month, for
+    /// the single bucket deployments. It's still rolled over by the `max_objects_per_bucket`.
+    pub fn set_fixed_bucket(&mut self, fixed_bucket: &'a str) -> &mut Self {
+        self.fixed_bucket.replace(fixed_bucket);
+
+        self
+    }
+
+    pub async fn build(mut self) -> anyhow::Result<Handler<S>>
+        where
+            S: Sync,
+    {
+        let domain = match self.domain.take() {
+            None => return Err(anyhow::anyhow!("domain is not set")),
+            Some(domain) => domain,
+        };
+
+        let database_name = match self.database_name.take() {
+            None => return Err(anyhow::anyhow!("database_name is not set")),
+            Some(database_name) => database_name,
+        };
+
+        let host = match self.host.take() {
+            None => return Err(anyhow::anyhow!("host is not set")),
+            Some(host) => host,
+        };
+
+        let user = match self.user.take() {
+            None => return Err(anyhow::anyhow!("user is not set")),
+            Some(user) => user,
+        };
+
+        let password = match self.password.take() {
+            None => return Err(anyhow::anyhow!("password is not set")),
+            Some(password) => password,
+        };
+
+        let store_backend = match self.store_backend.take() {
+            None => return Err(anyhow::anyhow!("store_backend is not set")),
+            Some(store_backend) => store_backend,
+        };
+
+        let request_id_header = self.request_id_header.unwrap_or(REQUEST_ID_HEADER);
+        let request_id_header = HeaderName::from_bytes(request_id_header.as_bytes())
+            .map_err(|err| anyhow::anyhow!("request_id_header {} is invalid: {}", request_id_header, err))?;
+
+        let default_scheme = self.default_scheme.unwrap_or(DEFAULT_SCHEME);
+        if default_scheme != "http" && default_scheme != "https" {
+            return Err(anyhow::anyhow!("default_scheme {} is invalid", default_scheme));
+        }
+
+        let default_content_type = self
+            .default_content_type
+            .unwrap_or(media::DEFAULT_CONTENT_TYPE);
+        if !media::is_valid_content_type(default_content_type) {
+            return Err(anyhow::anyhow!(
+                "default_content_type {} is invalid",
+                default_content_type
+            ));
+        }
+
+        let upload_path = self.upload_path.unwrap_or(DEFAULT_UPLOAD_PATH);
+        let get_path = self.get_path.unwrap_or(DEFAULT_GET_PATH);
+        check_path_prefixes(upload_path, get_path).map_err(|err| anyhow::anyhow!(err))?;
+
+        let size_limits = size_limits(
+            self.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            upload_path,
+            self.upload_max_body_size,
+            self.batch_max_body_size,
+        );
+
+        let trusted_proxies = self
+            .trusted_proxies
+            .unwrap_or_default()
+            .iter()
+            .map(|proxy| {
+                ip_filter::parse_net(proxy)
+                    .map_err(|err| anyhow::anyhow!("trusted proxy is invalid: {}", err))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let upload_webhook = self.upload_webhook_url.map(Webhook::new).transpose()?;
+        let cdn_purge = self.cdn_purge_url.map(Webhook::new).transpose()?;
+
+        let ip_filter_allow = self.ip_filter_allow.unwrap_or_default();
+        let ip_filter_deny = self.ip_filter_deny.unwrap_or_default();
+        let ip_filter_paths = self.ip_filter_paths.unwrap_or_default();
+
+        let ip_filter = if ip_filter_allow.is_empty() && ip_filter_deny.is_empty() {
+            None
+        } else {
+            if ip_filter_paths.is_empty() {
+                return Err(anyhow::anyhow!("ip_filter_paths is not set"));
+            }
+
+            let parse_nets = |nets: &[String]| {
+                nets.iter()
+                    .map(|net| ip_filter::parse_net(net).map_err(|err| anyhow::anyhow!(err)))
+                    .collect::<anyhow::Result<Vec<_>>>()
+            };
+
+            Some(Arc::new(IpFilter::new(
+                parse_nets(ip_filter_allow)?,
+                parse_nets(ip_filter_deny)?,
+                ip_filter_paths.to_vec(),
+            )))
+        };
+
+        let hotlink_protection = match self.allowed_referers {
+            Some(allowed_referers) if !allowed_referers.is_empty() => {
+                let mut hotlink_protection = HotlinkProtection::new(
+                    allowed_referers,
+                    self.allow_empty_referer.unwrap_or(true),
+                );
+
+                if let Some(path) = self.hotlink_placeholder {
+                    let placeholder = fs::read(path).map_err(|err| {
+                        anyhow::anyhow!("read hotlink placeholder {:?} failed: {}", path, err)
+                    })?;
+
+                    hotlink_protection.set_placeholder(placeholder.into());
+                }
+
+                Some(Arc::new(hotlink_protection))
+            }
+
+            _ => None,
+        };
+
+        let watermark = match self.watermark {
+            None => None,
+
+            Some(path) => {
+                let data = fs::read(path)
+                    .map_err(|err| anyhow::anyhow!("read watermark {:?} failed: {}", path, err))?;
+
+                let position = self
+                    .watermark_position
+                    .map_or(Ok(WatermarkPosition::BottomRight), WatermarkPosition::from_str)?;
+
+                Some(Arc::new(Watermark::new(
+                    &data,
+                    position,
+                    self.watermark_opacity.unwrap_or(DEFAULT_WATERMARK_OPACITY),
+                )?))
+            }
+        };
+
+        let cors = match self.cors_allowed_origins {
+            Some(cors_allowed_origins) if !cors_allowed_origins.is_empty() => {
+                let mut cors = Cors::new(cors_allowed_origins);
+                cors.set_allow_credentials(self.cors_allow_credentials.unwrap_or(false));
+
+                if let Some(max_age) = self.cors_max_age {
+                    cors.set_max_age(max_age);
+                }
+
+                Some(Arc::new(cors))
+            }
+
+            _ => None,
+        };
+
+        const ID_TYPE: &str = "image_bed";
+
+        let id_encoding = self
+            .id_encoding
+            .map_or(Ok(IdEncoding::Counter), IdEncoding::from_str)?;
+
+        let verify_dedup_bytes = self
+            .verify_dedup_bytes
+            .map_or(Ok(DedupVerify::Off), DedupVerify::from_str)?;
+
+        let hash_algorithm = self
+            .hash_algorithm
+            .map_or(Ok(HashAlgorithm::Sha256), HashAlgorithm::from_str)?;
+
+        let connect_options = PgConnectOptions::new()
+            .database(database_name)
+            .host(host)
+            .username(user)
+            .password(password)
+            .port(self.port.unwrap_or(5432));
+
+        let pool_config = PoolConfig::new(
+            self.db_max_connections,
+            self.db_acquire_timeout,
+            self.db_idle_timeout,
+        )?;
+
+        let connect_retry =
+            ConnectRetry::new(self.db_connect_max_attempts, self.db_connect_retry_delay)?;
+
+        let db_pool = pool_config.connect(&connect_options, &connect_retry).await?;
+
+        info!(
             log::get_logger(),
-            "head success";
-            log_cx,
-            "resource" => format!("{:?}", resource),
-            "start" => start,
-            "end" => end
+            "db pool is connected";
+            "max_connections" => pool_config.max_connections,
+            "acquire_timeout" => format!("{:?}", pool_config.acquire_timeout),
+            "idle_timeout" => format!("{:?}", pool_config.idle_timeout)
+        );
+
+        if self.migrate.unwrap_or(true) {
+            migrate::run(&db_pool).await?;
+
+            info!(log::get_logger(), "db migrations are applied");
+        }
+
+        let id_generator: Arc<dyn IdGenerator> = match id_encoding {
+            IdEncoding::Counter => Arc::new(
+                Generator::new(&db_pool, ID_TYPE, self.id_seed.unwrap_or(0)).await?,
+            ),
+            IdEncoding::Snowflake => {
+                Arc::new(SnowflakeGenerator::new(self.machine_id.unwrap_or(0))?)
+            }
+        };
+
+        info!(log::get_logger(), "id generator is init"; "encoding" => format!("{:?}", id_encoding));
+
+        let mut db = Database::new(&db_pool).await?;
+
+        info!(log::get_logger(), "db is init");
+
+        if let Some(max_total_bytes) = self.max_total_bytes {
+            let log_cx = LogContext::builder().request_id("startup").build();
+            let used_bytes = db.total_resource_bytes(&log_cx).await?;
+
+            db.set_quota(StorageQuota::new(max_total_bytes, used_bytes));
+
+            info!(
+                log::get_logger(),
+                "storage quota is enabled";
+                "max_total_bytes" => max_total_bytes,
+                "used_bytes" => used_bytes
+            );
+        }
+
+        let bucket_strategy = match self.fixed_bucket {
+            None => BucketStrategy::Monthly,
+            Some(fixed_bucket) => {
+                check_fixed_bucket(fixed_bucket).map_err(|err| anyhow::anyhow!(err))?;
+
+                info!(log::get_logger(), "fixed bucket is enabled"; "bucket" => fixed_bucket);
+
+                BucketStrategy::Fixed(fixed_bucket.to_owned())
+            }
+        };
+
+        if let Some(max_objects) = self.max_objects_per_bucket {
+            if max_objects == 0 {
+                return Err(anyhow::anyhow!("max_objects_per_bucket must be positive"));
+            }
+
+            db.set_bucket_rollover(BucketRollover::new(max_objects));
+
+            info!(
+                log::get_logger(),
+                "bucket rollover is enabled";
+                "max_objects_per_bucket" => max_objects
+            );
+        }
+
+        if let Some(capacity) = self.resource_cache_capacity.filter(|capacity| *capacity > 0) {
+            let ttl = self
+                .resource_cache_ttl
+                .map_or(cache::DEFAULT_TTL, Duration::from_secs);
+
+            db.set_cache(ResourceCache::new(capacity, ttl));
+
+            info!(
+                log::get_logger(),
+                "resource cache is enabled";
+                "capacity" => capacity,
+                "ttl" => format!("{:?}", ttl)
+            );
+        }
+
+        let precreate = self.precreate_bucket.unwrap_or(false);
+        let bucket_ready = Arc::new(AtomicBool::new(!precreate));
+
+        if precreate {
+            let log_cx = LogContext::builder().request_id("startup").build();
+
+            // the instance still starts, the creation is retried by the ready checks
+            precreate_bucket(&store_backend, &db, &bucket_strategy, &bucket_ready, &log_cx).await;
+        }
+
+        Ok(Handler {
+            store_backend: Arc::new(store_backend),
+            id_generator,
+            db,
+            domain: Arc::new(domain.to_owned()),
+            size_limits,
+            body_read_timeout: self
+                .body_read_timeout
+                .map_or(DEFAULT_BODY_READ_TIMEOUT, Duration::from_secs),
+            request_timeout: self.request_timeout.map(Duration::from_secs),
+            strip_exif: self.strip_exif.unwrap_or(false),
+            cache_control: Arc::new(cache_control(
+                self.cache_control_max_age
+                    .unwrap_or(DEFAULT_CACHE_CONTROL_MAX_AGE),
+            )),
+            access_log: self.access_log.unwrap_or(true),
+            compression: self.compression.unwrap_or(true),
+            request_id_header,
+            admin_token: self.admin_token.map(|token| Arc::new(token.to_owned())),
+            default_scheme: Arc::new(default_scheme.to_owned()),
+            trusted_proxies: Arc::new(TrustedProxies::new(trusted_proxies)),
+            ip_filter,
+            allowed_content_types: Arc::new(
+                self.allowed_content_types.unwrap_or_default().to_vec(),
+            ),
+            max_image_width: self.max_image_width,
+            max_image_height: self.max_image_height,
+            upload_path: Arc::new(upload_path.to_owned()),
+            get_path: Arc::new(get_path.to_owned()),
+            transcode: self.transcode.unwrap_or(false),
+            verify_on_read: self.verify_on_read.unwrap_or(false),
+            verify_dedup_bytes,
+            hash_algorithm,
+            default_content_type: Arc::new(default_content_type.to_owned()),
+            extension_content_type: self.extension_content_type.unwrap_or(true),
+            upload_webhook,
+            cdn_purge,
+            hotlink_protection,
+            watermark,
+            watermark_always: self.watermark_always.unwrap_or(false),
+            url_signer: self
+                .url_signing_secret
+                .map(|secret| Arc::new(UrlSigner::new(secret.as_bytes()))),
+            bucket_ready,
+            bucket_strategy: Arc::new(bucket_strategy),
+            resource_ttl: self.resource_ttl.map(Duration::from_secs),
+            temp_dir: Arc::new(
+                self.temp_dir
+                    .map_or_else(std::env::temp_dir, Path::to_path_buf),
+            ),
+            memory_spill_threshold: self.memory_spill_threshold,
+            upload_permits: self
+                .max_concurrent_uploads
+                .map(|max| Arc::new(Semaphore::new(max))),
+            cors,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Handler<S: StoreBackend> {
+    store_backend: Arc<S>,
+    id_generator: Arc<dyn IdGenerator>,
+    db: Database,
+    domain: Arc<String>,
+    size_limits: SizeLimits,
+    body_read_timeout: Duration,
+    request_timeout: Option<Duration>,
+    strip_exif: bool,
+    cache_control: Arc<String>,
+    access_log: bool,
+    compression: bool,
+    request_id_header: HeaderName,
+    admin_token: Option<Arc<String>>,
+    default_scheme: Arc<String>,
+    trusted_proxies: Arc<TrustedProxies>,
+    ip_filter: Option<Arc<IpFilter>>,
+    allowed_content_types: Arc<Vec<String>>,
+    max_image_width: Option<u32>,
+    max_image_height: Option<u32>,
+    upload_path: Arc<String>,
+    get_path: Arc<String>,
+    transcode: bool,
+    verify_on_read: bool,
+    verify_dedup_bytes: DedupVerify,
+    hash_algorithm: HashAlgorithm,
+    default_content_type: Arc<String>,
+    extension_content_type: bool,
+    upload_webhook: Option<Webhook>,
+    cdn_purge: Option<Webhook>,
+    hotlink_protection: Option<Arc<HotlinkProtection>>,
+    watermark: Option<Arc<Watermark>>,
+    watermark_always: bool,
+    url_signer: Option<Arc<UrlSigner>>,
+    bucket_ready: Arc<AtomicBool>,
+    bucket_strategy: Arc<BucketStrategy>,
+    resource_ttl: Option<Duration>,
+    temp_dir: Arc<PathBuf>,
+    memory_spill_threshold: Option<u64>,
+    upload_permits: Option<Arc<Semaphore>>,
+    cors: Option<Arc<Cors>>,
+}
+
+impl<S: StoreBackend> Clone for Handler<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store_backend: self.store_backend.clone(),
+            id_generator: self.id_generator.clone(),
+            db: self.db.clone(),
+            domain: self.domain.clone(),
+            size_limits: self.size_limits.clone(),
+            body_read_timeout: self.body_read_timeout,
+            request_timeout: self.request_timeout,
+            strip_exif: self.strip_exif,
+            cache_control: self.cache_control.clone(),
+            access_log: self.access_log,
+            compression: self.compression,
+            request_id_header: self.request_id_header.clone(),
+            admin_token: self.admin_token.clone(),
+            default_scheme: self.default_scheme.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+            ip_filter: self.ip_filter.clone(),
+            allowed_content_types: self.allowed_content_types.clone(),
+            max_image_width: self.max_image_width,
+            max_image_height: self.max_image_height,
+            upload_path: self.upload_path.clone(),
+            get_path: self.get_path.clone(),
+            transcode: self.transcode,
+            verify_on_read: self.verify_on_read,
+            verify_dedup_bytes: self.verify_dedup_bytes,
+            hash_algorithm: self.hash_algorithm,
+            default_content_type: self.default_content_type.clone(),
+            extension_content_type: self.extension_content_type,
+            upload_webhook: self.upload_webhook.clone(),
+            cdn_purge: self.cdn_purge.clone(),
+            hotlink_protection: self.hotlink_protection.clone(),
+            watermark: self.watermark.clone(),
+            watermark_always: self.watermark_always,
+            url_signer: self.url_signer.clone(),
+            bucket_ready: self.bucket_ready.clone(),
+            bucket_strategy: self.bucket_strategy.clone(),
+            resource_ttl: self.resource_ttl,
+            temp_dir: self.temp_dir.clone(),
+            memory_spill_threshold: self.memory_spill_threshold,
+            upload_permits: self.upload_permits.clone(),
+            cors: self.cors.clone(),
+        }
+    }
+}
+
+impl<T, S> Service<T> for Handler<S>
+    where
+        T: RemoteAddr,
+        S: StoreBackend + Send + Sync,
+{
+    type Response = RequestIdService<
+        TraceService<
+            AccessLogService<
+                CorsService<IpFilterService<SizeLimitService<CompressionService<Handle<S>>>>>,
+            >,
+        >,
+    >;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, conn: T) -> Self::Future {
+        let size_limits = self.size_limits.clone();
+        let body_read_timeout = self.body_read_timeout;
+        let access_log = self.access_log;
+        let compression = self.compression;
+        let remote_addr = conn.remote_addr();
+
+        let mut handle = Handle::from(&mut *self);
+        handle.remote_addr = remote_addr;
+
+        let service = CompressionService::new(compression, handle);
+        let service = SizeLimitService::new(size_limits, body_read_timeout, service);
+        let service = IpFilterService::new(
+            self.ip_filter.clone(),
+            remote_addr,
+            self.trusted_proxies.clone(),
+            service,
         );
+        // the rejected responses get the CORS headers too, so the browsers can read them
+        let service = CorsService::new(self.cors.clone(), service);
+        let service = AccessLogService::new(
+            access_log,
+            remote_addr,
+            self.trusted_proxies.clone(),
+            service,
+        );
+        let service = TraceService::new(service);
+        let service = RequestIdService::new(self.request_id_header.clone(), service);
+
+        future::ready(Ok(service))
+    }
+}
+
+#[derive(Debug)]
+pub struct Handle<S: StoreBackend> {
+    store_backend: Arc<S>,
+    id_generator: Arc<dyn IdGenerator>,
+    db: Database,
+    domain: Arc<String>,
+    request_timeout: Option<Duration>,
+    strip_exif: bool,
+    cache_control: Arc<String>,
+    admin_token: Option<Arc<String>>,
+    default_scheme: Arc<String>,
+    trusted_proxies: Arc<TrustedProxies>,
+    allowed_content_types: Arc<Vec<String>>,
+    max_image_width: Option<u32>,
+    max_image_height: Option<u32>,
+    upload_path: Arc<String>,
+    get_path: Arc<String>,
+    transcode: bool,
+    verify_on_read: bool,
+    verify_dedup_bytes: DedupVerify,
+    hash_algorithm: HashAlgorithm,
+    default_content_type: Arc<String>,
+    extension_content_type: bool,
+    upload_webhook: Option<Webhook>,
+    cdn_purge: Option<Webhook>,
+    hotlink_protection: Option<Arc<HotlinkProtection>>,
+    watermark: Option<Arc<Watermark>>,
+    watermark_always: bool,
+    url_signer: Option<Arc<UrlSigner>>,
+    bucket_ready: Arc<AtomicBool>,
+    bucket_strategy: Arc<BucketStrategy>,
+    resource_ttl: Option<Duration>,
+    temp_dir: Arc<PathBuf>,
+    memory_spill_threshold: Option<u64>,
+    /// Shared by all the handles, a permit is held by every in-flight upload.
+    upload_permits: Option<Arc<Semaphore>>,
+    max_upload_size: u64,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl<S: StoreBackend> Clone for Handle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store_backend: self.store_backend.clone(),
+            id_generator: self.id_generator.clone(),
+            db: self.db.clone(),
+            domain: self.domain.clone(),
+            request_timeout: self.request_timeout,
+            strip_exif: self.strip_exif,
+            cache_control: self.cache_control.clone(),
+            admin_token: self.admin_token.clone(),
+            default_scheme: self.default_scheme.clone(),
+            trusted_proxies: self.trusted_proxies.clone(),
+            allowed_content_types: self.allowed_content_types.clone(),
+            max_image_width: self.max_image_width,
+            max_image_height: self.max_image_height,
+            upload_path: self.upload_path.clone(),
+            get_path: self.get_path.clone(),
+            transcode: self.transcode,
+            verify_on_read: self.verify_on_read,
+            verify_dedup_bytes: self.verify_dedup_bytes,
+            hash_algorithm: self.hash_algorithm,
+            default_content_type: self.default_content_type.clone(),
+            extension_content_type: self.extension_content_type,
+            upload_webhook: self.upload_webhook.clone(),
+            cdn_purge: self.cdn_purge.clone(),
+            hotlink_protection: self.hotlink_protection.clone(),
+            watermark: self.watermark.clone(),
+            watermark_always: self.watermark_always,
+            url_signer: self.url_signer.clone(),
+            bucket_ready: self.bucket_ready.clone(),
+            bucket_strategy: self.bucket_strategy.clone(),
+            resource_ttl: self.resource_ttl,
+            temp_dir: self.temp_dir.clone(),
+            memory_spill_threshold: self.memory_spill_threshold,
+            upload_permits: self.upload_permits.clone(),
+            max_upload_size: self.max_upload_size,
+            remote_addr: self.remote_addr,
+        }
+    }
+}
+
+impl<'a, S: StoreBackend> From<&'a mut Handler<S>> for Handle<S> {
+    fn from(h: &'a mut Handler<S>) -> Self {
+        Handle {
+            store_backend: h.store_backend.clone(),
+            id_generator: h.id_generator.clone(),
+            db: h.db.clone(),
+            domain: h.domain.clone(),
+            request_timeout: h.request_timeout,
+            strip_exif: h.strip_exif,
+            cache_control: h.cache_control.clone(),
+            admin_token: h.admin_token.clone(),
+            default_scheme: h.default_scheme.clone(),
+            trusted_proxies: h.trusted_proxies.clone(),
+            allowed_content_types: h.allowed_content_types.clone(),
+            max_image_width: h.max_image_width,
+            max_image_height: h.max_image_height,
+            upload_path: h.upload_path.clone(),
+            get_path: h.get_path.clone(),
+            transcode: h.transcode,
+            verify_on_read: h.verify_on_read,
+            verify_dedup_bytes: h.verify_dedup_bytes,
+            hash_algorithm: h.hash_algorithm,
+            default_content_type: h.default_content_type.clone(),
+            extension_content_type: h.extension_content_type,
+            upload_webhook: h.upload_webhook.clone(),
+            cdn_purge: h.cdn_purge.clone(),
+            hotlink_protection: h.hotlink_protection.clone(),
+            watermark: h.watermark.clone(),
+            watermark_always: h.watermark_always,
+            url_signer: h.url_signer.clone(),
+            bucket_ready: h.bucket_ready.clone(),
+            bucket_strategy: h.bucket_strategy.clone(),
+            resource_ttl: h.resource_ttl,
+            temp_dir: h.temp_dir.clone(),
+            memory_spill_threshold: h.memory_spill_threshold,
+            upload_permits: h.upload_permits.clone(),
+            max_upload_size: h.size_limits.max_size(&Method::POST, &h.upload_path),
+            remote_addr: None,
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for Handle<S>
+    where
+        S: StoreBackend + 'static + Send + Sync,
+        S::Error: Send + Sync,
+{
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = ServiceResult<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let log_cx = log_context(&req);
+
+        if let Some(uri) = strip_api_version(req.uri()) {
+            *req.uri_mut() = uri;
+        }
+
+        let route = match self.route(req.method(), req.uri().path()) {
+            Err(RouteError::NotFound) => {
+                warn!(log::get_logger(), "unknown path {}", req.uri().path(); &log_cx);
+
+                let result = error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "not_found",
+                    &format!("path {} is not found", req.uri().path()),
+                    &log_cx,
+                )
+                    .map_err(|err| err.into());
+
+                return Box::pin(async move { result });
+            }
+
+            Err(RouteError::MethodNotAllowed(allow)) => {
+                warn!(
+                    log::get_logger(),
+                    "method {} is not allowed for path {}",
+                    req.method(), req.uri().path();
+                    &log_cx
+                );
+
+                let result = method_not_allowed(req.method(), &allow, &log_cx)
+                    .map_err(|err| err.into());
+
+                return Box::pin(async move { result });
+            }
+
+            Ok(route) => route,
+        };
+
+        // the permit is held until the upload is handled, even if it's timed out
+        let upload_permit = match &self.upload_permits {
+            Some(upload_permits) if route.is_upload() => {
+                match upload_permits.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+
+                    Err(_) => {
+                        let result = uploads_saturated_response(&log_cx).map_err(|err| err.into());
+
+                        return Box::pin(async move { result });
+                    }
+                }
+            }
+
+            _ => None,
+        };
+
+        // the db and store spans of the handlers are the children of the route span
+        let mut span = Span::start(route.name(), SpanKind::Internal, &log_cx);
+        if let Some(trace_parent) = span
+            .trace_parent()
+            .and_then(|trace_parent| HeaderValue::from_str(&trace_parent).ok())
+        {
+            req.headers_mut().insert(TRACE_PARENT_HEADER, trace_parent);
+        }
+
+        let log_cx = span.log_context(&log_cx);
+        let handle = self.clone();
+        let request_timeout = self.request_timeout;
+        let path = req.uri().path().to_owned();
+
+        let handle_fut = async move {
+            let _upload_permit = upload_permit;
+
+            match route {
+                Route::Upload => handle.handle_upload(req, false).await,
+                Route::UploadWithId => handle.handle_upload(req, true).await,
+                Route::UploadCheck => handle.handle_upload_check(req).await,
+                Route::Get => handle.handle_get(req).await,
+                Route::GetByHash => handle.handle_get_by_hash(req).await,
+                Route::Head => handle.handle_head(req).await,
+                Route::Meta => handle.handle_meta(req).await,
+                Route::DeleteBatch => handle.handle_delete_batch(req).await,
+                Route::DedupStats => handle.handle_dedup_stats(req).await,
+                Route::Usage => handle.handle_usage(req).await,
+                Route::IdStats => handle.handle_id_stats(req).await,
+                Route::DeleteByHash => handle.handle_delete_by_hash(req).await,
+                Route::Copy => handle.handle_copy(req).await,
+                Route::TusOptions => handle.handle_tus_options(req).await,
+                Route::TusCreate => handle.handle_tus_create(req).await,
+                Route::TusHead => handle.handle_tus_head(req).await,
+                Route::TusPatch => handle.handle_tus_patch(req).await,
+                Route::UploadPolicy => handle.handle_upload_policy(req).await,
+                Route::UploadPolicyComplete => handle.handle_upload_policy_complete(req).await,
+                Route::Reconcile => handle.handle_reconcile(req).await,
+                Route::DeleteBucket => handle.handle_delete_bucket(req).await,
+                Route::List => handle.handle_list(req).await,
+                Route::Ready => handle.handle_ready(req).await,
+                Route::Sweep => handle.handle_sweep(req).await,
+                Route::Sign => handle.handle_sign(req).await,
+            }
+        };
+
+        Box::pin(async move {
+            let result = match request_timeout {
+                None => handle_fut.await,
+
+                Some(request_timeout) => {
+                    handle_with_timeout(route, request_timeout, handle_fut, &path, &log_cx).await
+                }
+            };
+
+            if result.is_err() {
+                span.set_error();
+            }
+            span.end();
+
+            let mut result = result.or_else(|err| error::handle_error_response(err, &log_cx));
+
+            if route.is_tus() {
+                if let Ok(resp) = &mut result {
+                    tus::set_resumable(resp);
+                }
+            }
+
+            result
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Route {
+    Upload,
+    UploadWithId,
+    UploadCheck,
+    Get,
+    GetByHash,
+    Head,
+    Meta,
+    DeleteBatch,
+    DedupStats,
+    Usage,
+    IdStats,
+    DeleteByHash,
+    Copy,
+    TusOptions,
+    TusCreate,
+    TusHead,
+    TusPatch,
+    UploadPolicy,
+    UploadPolicyComplete,
+    Reconcile,
+    DeleteBucket,
+    List,
+    Ready,
+    Sweep,
+    Sign,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum RouteError {
+    NotFound,
+    /// The path is known, but the method is not, contains the allowed methods.
+    MethodNotAllowed(String),
+}
+
+impl Route {
+    /// The name of the route span.
+    fn name(&self) -> &'static str {
+        match self {
+            Route::Upload => "upload",
+            Route::UploadWithId => "upload_with_id",
+            Route::UploadCheck => "upload_check",
+            Route::Get => "get",
+            Route::GetByHash => "get_by_hash",
+            Route::Head => "head",
+            Route::Meta => "meta",
+            Route::DeleteBatch => "delete_batch",
+            Route::DedupStats => "dedup_stats",
+            Route::Usage => "usage",
+            Route::IdStats => "id_stats",
+            Route::DeleteByHash => "delete_by_hash",
+            Route::Copy => "copy",
+            Route::TusOptions => "tus_options",
+            Route::TusCreate => "tus_create",
+            Route::TusHead => "tus_head",
+            Route::TusPatch => "tus_patch",
+            Route::UploadPolicy => "upload_policy",
+            Route::UploadPolicyComplete => "upload_policy_complete",
+            Route::Reconcile => "reconcile",
+            Route::DeleteBucket => "delete_bucket",
+            Route::List => "list",
+            Route::Ready => "ready",
+            Route::Sweep => "sweep",
+            Route::Sign => "sign",
+        }
+    }
+
+    fn is_tus(&self) -> bool {
+        matches!(
+            self,
+            Route::TusOptions | Route::TusCreate | Route::TusHead | Route::TusPatch
+        )
+    }
+
+    /// The route receives the uploaded data, it's limited by the max concurrent uploads.
+    fn is_upload(&self) -> bool {
+        matches!(self, Route::Upload | Route::UploadWithId | Route::TusPatch)
+    }
+
+    /// The route changes the db or the store, its handling must not be cancelled halfway.
+    fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Route::Upload
+                | Route::UploadWithId
+                | Route::DeleteBatch
+                | Route::DeleteByHash
+                | Route::Copy
+                | Route::TusCreate
+                | Route::TusPatch
+                | Route::UploadPolicyComplete
+                | Route::Reconcile
+                | Route::DeleteBucket
+                | Route::Sweep
+        )
+    }
+}
+
+/// Respond 504 when the handling is not finished in the timeout. The read only handlings are
+/// cancelled, but the mutating ones are spawned and keep running after the timeout, so the db
+/// row and the stored object are not left half done, such as a resource without its object.
+async fn handle_with_timeout<F>(
+    route: Route,
+    request_timeout: Duration,
+    handle_fut: F,
+    path: &str,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, BoxError>
+    where
+        F: std::future::Future<Output=Result<Response<Body>, BoxError>> + Send + 'static,
+{
+    let result = if route.is_mutating() {
+        let mut handle_task = task::spawn(handle_fut);
+
+        tokio::time::timeout(request_timeout, &mut handle_task)
+            .await
+            .map(|result| result.unwrap_or_else(|err| Err(err.into())))
+    } else {
+        tokio::time::timeout(request_timeout, handle_fut).await
+    };
+
+    match result {
+        Ok(result) => result,
+
+        Err(_) => {
+            warn!(
+                log::get_logger(),
+                "request is timed out";
+                log_cx,
+                "path" => path,
+                "route" => route.name(),
+                "timeout" => format!("{:?}", request_timeout)
+            );
+
+            Ok(error::error_response(
+                StatusCode::GATEWAY_TIMEOUT,
+                "timeout",
+                &format!("request is not handled in {:?}", request_timeout),
+                log_cx,
+            )?)
+        }
+    }
+}
+
+impl<S> Handle<S>
+    where
+        S: StoreBackend + Send + Sync + 'static,
+        S::Error: Send + Sync,
+{
+    /// Match the unversioned request path, the api version should be stripped already. The path
+    /// is matched first, so a known path with a wrong method can be told from an unknown path.
+    fn route(&self, method: &Method, path: &str) -> Result<Route, RouteError> {
+        let upload_id_path = path.strip_prefix(self.upload_path.as_str());
+        let tus_id_path = path.strip_prefix(tus::TUS_PATH);
+
+        let routes: &[(&str, Route)] = if path == UPLOAD_POLICY_PATH {
+            &[("GET", Route::UploadPolicy)]
+        } else if path == UPLOAD_POLICY_COMPLETE_PATH {
+            &[("POST", Route::UploadPolicyComplete)]
+        } else if let Some(id_path) = upload_id_path {
+            if id_path.starts_with('/') {
+                &[("POST", Route::Upload), ("PUT", Route::UploadWithId)]
+            } else {
+                &[("POST", Route::Upload), ("HEAD", Route::UploadCheck)]
+            }
+        } else if path.starts_with(self.get_path.as_str()) {
+            &[("GET", Route::Get), ("HEAD", Route::Head)]
+        } else if path.starts_with(META_PATH) {
+            &[("GET", Route::Meta)]
+        } else if path == DELETE_BATCH_PATH {
+            &[("POST", Route::DeleteBatch)]
+        } else if path == DEDUP_STATS_PATH {
+            &[("GET", Route::DedupStats)]
+        } else if path == USAGE_PATH {
+            &[("GET", Route::Usage)]
+        } else if path == ID_STATS_PATH {
+            &[("GET", Route::IdStats)]
+        } else if path.starts_with(BY_HASH_PATH) {
+            &[("DELETE", Route::DeleteByHash)]
+        } else if path.starts_with(HASH_PATH) {
+            &[("GET", Route::GetByHash)]
+        } else if path == COPY_PATH {
+            &[("POST", Route::Copy)]
+        } else if path == RECONCILE_PATH {
+            &[("POST", Route::Reconcile)]
+        } else if path.starts_with(BUCKET_PATH) {
+            &[("DELETE", Route::DeleteBucket)]
+        } else if path == LIST_PATH {
+            &[("GET", Route::List)]
+        } else if path == READY_PATH {
+            &[("GET", Route::Ready)]
+        } else if path == SWEEP_PATH {
+            &[("POST", Route::Sweep)]
+        } else if path.starts_with(SIGN_PATH) {
+            &[("GET", Route::Sign)]
+        } else if tus_id_path == Some("") {
+            &[("OPTIONS", Route::TusOptions), ("POST", Route::TusCreate)]
+        } else if tus_id_path.is_some_and(|id_path| id_path.starts_with('/')) {
+            &[("HEAD", Route::TusHead), ("PATCH", Route::TusPatch)]
+        } else {
+            return Err(RouteError::NotFound);
+        };
+
+        routes
+            .iter()
+            .find(|(route_method, _)| method.as_str() == *route_method)
+            .map(|(_, route)| *route)
+            .ok_or_else(|| {
+                RouteError::MethodNotAllowed(
+                    routes
+                        .iter()
+                        .map(|(route_method, _)| *route_method)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+            })
+    }
+
+    /// Upload with a generated id, or with the id in the path when `with_id` is true.
+    async fn handle_upload(
+        &self,
+        req: Request<Body>,
+        with_id: bool,
+    ) -> Result<Response<Body>, BoxError> {
+        let (scheme, host) = self.origin(req.headers())?;
+
+        let log_cx = log_context(&req);
+
+        let client_id = if with_id {
+            let path = req.uri().path();
+            let resource_id = path[self.upload_path.len()..].trim_start_matches('/');
+
+            if !is_valid_client_id(resource_id, self.id_generator.encoding()) {
+                warn!(log::get_logger(), "resource id {:?} is invalid", resource_id; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_resource_id",
+                    &format!(
+                        "resource id must be 1 to {} characters of [A-Za-z0-9_-] and not like a generated id",
+                        MAX_RESOURCE_ID_LENGTH
+                    ),
+                    &log_cx,
+                )?);
+            }
+
+            Some(resource_id.to_owned())
+        } else {
+            None
+        };
+
+        // the client id upload is idempotent by itself, the stored content of the hash may have
+        // another id
+        if client_id.is_none() {
+            if let Some(resp) = self
+                .upload_not_modified(&req, &scheme, &host, &log_cx)
+                .await?
+            {
+                return Ok(resp);
+            }
+        }
+
+        let filename = req
+            .headers()
+            .get("content-disposition")
+            .and_then(|value| value.to_str().ok())
+            .and_then(disposition::parse_filename);
+
+        // the compressed upload is stored decompressed, so the object is the real image
+        let decoder = match req
+            .headers()
+            .get("content-encoding")
+            .map(|value| value.to_str().unwrap_or_default().trim())
+        {
+            None => None,
+            Some(encoding) if encoding.eq_ignore_ascii_case("identity") => None,
+            Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => {
+                Some(GzipDecoder::new(self.max_upload_size))
+            }
+
+            Some(encoding) => {
+                warn!(log::get_logger(), "content encoding {:?} is not supported", encoding; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "unsupported_content_encoding",
+                    &format!("content encoding {:?} is not supported, only gzip is", encoding),
+                    &log_cx,
+                )?);
+            }
+        };
+
+        let capacity = req.body().size_hint().lower().min(self.max_upload_size);
+        let buffer = SpillBuffer::new(&self.temp_dir, self.memory_spill_threshold, capacity);
+
+        let (body, hash) =
+            read_body_hashed(req.into_body(), self.hash_algorithm, buffer, decoder).await?;
+
+        let (resource, created) = match self
+            .store_upload(
+                body,
+                Some(hash),
+                client_id.as_deref(),
+                filename.as_deref(),
+                &log_cx,
+            )
+            .await?
+        {
+            StoredUpload::Created(resource) => (resource, true),
+            StoredUpload::Exist(resource) => (resource, false),
+            StoredUpload::Rejected(resp) => return Ok(resp),
+        };
+
+        let resource_uri = self.resource_uri(&scheme, &host, resource.get_id())?;
+
+        // the dedup hits are not new resources, the webhook is told already
+        if created {
+            if let Some(webhook) = &self.upload_webhook {
+                webhook.notify(UploadEvent::new(&resource, &resource_uri), log_cx.clone());
+            }
+        }
+
+        let mut resp = Response::new(Body::from(resource_uri));
+        if client_id.is_some() && created {
+            *resp.status_mut() = StatusCode::CREATED;
+        }
+
+        let headers = resp.headers_mut();
+        headers.append("content-type", "text/plain".parse()?);
+        headers.append("content-type", "charset=utf-8".parse()?);
+        headers.insert(DEDUP_HEADER, HeaderValue::from_static(dedup_status(created)));
+
+        info!(
+            log::get_logger(),
+            "upload success";
+            log_cx,
+            "resource" => format!("{:?}", resource)
+        );
+
+        Ok(resp)
+    }
+
+    /// Return 304 with the url if the content of a hash in `If-None-Match` is stored already, the
+    /// body is never read, so the client doesn't send it again.
+    async fn upload_not_modified(
+        &self,
+        req: &Request<Body>,
+        scheme: &str,
+        host: &str,
+        log_cx: &LogContext,
+    ) -> Result<Option<Response<Body>>, BoxError> {
+        let if_none_match = match req
+            .headers()
+            .get("if-none-match")
+            .and_then(|value| value.to_str().ok())
+        {
+            None => return Ok(None),
+            Some(if_none_match) => if_none_match,
+        };
+
+        let hashes = conditional::entity_tags(if_none_match)
+            .filter_map(hash::parse_hash)
+            .take(MAX_UPLOAD_IF_NONE_MATCH_HASHES);
+
+        for hash in hashes {
+            let resource = match self.db.get_resource_by_hash(&hash, log_cx).await? {
+                None => continue,
+                Some(resource) => resource,
+            };
+
+            let resource_uri = self.resource_uri(scheme, host, resource.get_id())?;
+
+            info!(
+                log::get_logger(),
+                "upload not modified";
+                log_cx,
+                "resource" => format!("{:?}", resource)
+            );
+
+            return Ok(Some(
+                Response::builder()
+                    .status(StatusCode::NOT_MODIFIED)
+                    .header("etag", conditional::etag(resource.get_hash(), None))
+                    .header(RESOURCE_URL_HEADER, resource_uri)
+                    .body(Body::empty())?,
+            ));
+        }
+
+        Ok(None)
+    }
+
+    /// Check and store the uploaded body, the same content is deduped unless the client gives
+    /// the id. The hash of the body is computed if it's not given.
+    async fn store_upload(
+        &self,
+        mut body: UploadBody,
+        mut hash: Option<String>,
+        client_id: Option<&str>,
+        filename: Option<&str>,
+        log_cx: &LogContext,
+    ) -> Result<StoredUpload, BoxError> {
+        // sniff the bytes instead of trusting the content-type header from client
+        let content_type =
+            media::detect_content_type(body.head()).unwrap_or(self.default_content_type.as_str());
+        // only the header is decoded, the galleries can lay out the images without them
+        let dimensions = body.image_dimensions().await?;
+
+        if let Some(resp) = self.check_content(content_type, dimensions, log_cx)? {
+            return Ok(StoredUpload::Rejected(resp));
+        }
+
+        if self.strip_exif {
+            // the image is rewritten in the memory, the spilled one is read back for it
+            let data = body.into_bytes().await?;
+
+            body = match media::strip_exif(&data) {
+                Err(err) => {
+                    warn!(log::get_logger(), "strip exif failed, keep original data: {:?}", err; log_cx);
+
+                    data.into()
+                }
+
+                Ok(Some(stripped)) => {
+                    hash = None;
+
+                    stripped.into()
+                }
+
+                Ok(None) => data.into(),
+            };
+        }
+
+        // hash after stripping exif, so the same image with different metadata can be deduped
+        let hash_result = match hash {
+            Some(hash) => hash,
+            None => body.hash(self.hash_algorithm).await?,
+        };
+
+        let size = body.size();
+
+        let dedup_lookup = match client_id {
+            None => self.lookup_dedup(&hash_result, &body, log_cx).await?,
+            Some(_) => DedupLookup::Miss,
+        };
+
+        let (resource, created) = if let Some(resource_id) = client_id {
+            match self
+                .create_resource_with_id(
+                    resource_id,
+                    &hash_result,
+                    &body,
+                    content_type,
+                    dimensions,
+                    filename,
+                    log_cx,
+                )
+                .await?
+            {
+                ClientIdUpload::Created(resource) => (resource, true),
+                ClientIdUpload::Exist(resource) => (resource, false),
+
+                ClientIdUpload::Conflict => {
+                    warn!(
+                        log::get_logger(),
+                        "resource {} exists with different content", resource_id;
+                        log_cx
+                    );
+
+                    return Ok(StoredUpload::Rejected(error::error_response(
+                        StatusCode::CONFLICT,
+                        "resource_conflict",
+                        &format!("resource {} exists with different content", resource_id),
+                        log_cx,
+                    )?));
+                }
+
+                ClientIdUpload::QuotaExceeded => {
+                    return Ok(StoredUpload::Rejected(quota_exceeded_response(size, log_cx)?));
+                }
+            }
+        } else if let DedupLookup::Hit(resource) = dedup_lookup {
+            (resource, false)
+        } else {
+            // the colliding content is not deduped, the hit keeps the dedup hash
+            let collision = matches!(dedup_lookup, DedupLookup::Collision);
+            let bucket = self.upload_bucket(log_cx).await?;
+
+            // only the new resources take the quota, the dedup hits add no bytes
+            if !self.db.reserve_quota(size) {
+                return Ok(StoredUpload::Rejected(quota_exceeded_response(size, log_cx)?));
+            }
+
+            let inserted = async {
+                let resource_id = self.id_generator.get_id(log_cx).await?;
+
+                if collision {
+                    return self
+                        .db
+                        .insert_resource(
+                            &bucket,
+                            &resource_id,
+                            &hash_result,
+                            size,
+                            content_type,
+                            dimensions,
+                            filename,
+                            log_cx,
+                        )
+                        .await
+                        .map(Some);
+                }
+
+                self.db
+                    .insert_dedup_resource(
+                        &bucket,
+                        &resource_id,
+                        &hash_result,
+                        size,
+                        content_type,
+                        dimensions,
+                        filename,
+                        log_cx,
+                    )
+                    .await
+            }
+                .await;
+
+            match inserted {
+                Err(err) => {
+                    self.db.release_quota(size);
+
+                    return Err(err.into());
+                }
+
+                // a concurrent upload of the same content wins the insert, it puts the object
+                Ok(None) => {
+                    self.db.release_quota(size);
+
+                    match self.db.get_resource_by_hash(&hash_result, log_cx).await? {
+                        None => {
+                            return Err(anyhow::anyhow!(
+                                "resource of hash {} is deleted after the insert conflict",
+                                hash_result
+                            )
+                                .into());
+                        }
+
+                        Some(resource) => (resource, false),
+                    }
+                }
+
+                Ok(Some(resource)) => {
+                    let resource_id = resource.get_id();
+
+                    if let Err(err) = self
+                        .store_backend
+                        .put(&bucket, resource_id, body.reader().await?, log_cx)
+                        .await
+                    {
+                        // remove the row, otherwise the same content would be deduped to the
+                        // resource without the stored content
+                        if let Err(err) = self
+                            .db
+                            .delete_resources(&[resource_id.to_owned()], log_cx)
+                            .await
+                        {
+                            error!(log::get_logger(), "delete resource {} failed: {:?}", resource_id, err; log_cx);
+                        }
+
+                        return Err(StoreFailure::new(err).into());
+                    }
+
+                    (resource, true)
+                }
+            }
+        };
+
+
+        if created {
+            Ok(StoredUpload::Created(resource))
+        } else {
+            Ok(StoredUpload::Exist(resource))
+        }
+    }
+
+    /// Find the resource of the same hash, and check it against the data by the
+    /// `verify_dedup_bytes`.
+    async fn lookup_dedup(
+        &self,
+        hash: &str,
+        body: &UploadBody,
+        log_cx: &LogContext,
+    ) -> Result<DedupLookup, BoxError> {
+        let resource = match self.db.get_resource_by_hash(hash, log_cx).await? {
+            None => return Ok(DedupLookup::Miss),
+            Some(resource) => resource,
+        };
+
+        let same_size = resource.get_resource_size() == body.size();
+
+        let same = match self.verify_dedup_bytes {
+            DedupVerify::Off => true,
+            DedupVerify::Size => same_size,
+            DedupVerify::Full => {
+                same_size
+                    && body
+                    .eq_bytes(
+                        &self
+                            .store_backend
+                            .get(resource.get_bucket(), resource.get_id(), None, None, log_cx)
+                            .await
+                            .map_err(StoreFailure::new)?,
+                    )
+                    .await?
+            }
+        };
+
+        if !same {
+            warn!(
+                log::get_logger(),
+                "hash collision, upload is stored as a new resource";
+                log_cx,
+                "hash" => hash,
+                "resource" => resource.get_id(),
+                "resource_size" => resource.get_resource_size(),
+                "upload_size" => body.size()
+            );
+
+            return Ok(DedupLookup::Collision);
+        }
+
+        Ok(DedupLookup::Hit(resource))
+    }
+
+    /// Return the rejected response if the content is not accepted.
+    fn check_content(
+        &self,
+        content_type: &str,
+        dimensions: Option<(u32, u32)>,
+        log_cx: &LogContext,
+    ) -> Result<Option<Response<Body>>, hyper::http::Error> {
+        if !self.allowed_content_types.is_empty()
+            && !self
+            .allowed_content_types
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(content_type))
+        {
+            warn!(log::get_logger(), "content type {} is not allowed", content_type; log_cx);
+
+            return Ok(Some(error::error_response(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                &format!("content type {} is not allowed", content_type),
+                log_cx,
+            )?));
+        }
+
+        if self.max_image_width.is_some() || self.max_image_height.is_some() {
+            if let Some((width, height)) = dimensions {
+                if self.max_image_width.is_some_and(|max| width > max)
+                    || self.max_image_height.is_some_and(|max| height > max)
+                {
+                    warn!(
+                        log::get_logger(),
+                        "image {}x{} is too large", width, height;
+                        log_cx
+                    );
+
+                    return Ok(Some(error::error_response(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "image_too_large",
+                        &format!("image {}x{} is too large", width, height),
+                        log_cx,
+                    )?));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Store the upload under the client id, the upload is idempotent when the id holds the same
+    /// content already.
+    #[allow(clippy::too_many_arguments)]
+    async fn create_resource_with_id(
+        &self,
+        resource_id: &str,
+        hash: &str,
+        body: &UploadBody,
+        content_type: &str,
+        dimensions: Option<(u32, u32)>,
+        filename: Option<&str>,
+        log_cx: &LogContext,
+    ) -> Result<ClientIdUpload, BoxError> {
+        let existing = |resource: Resource| {
+            if resource.get_hash() == hash {
+                ClientIdUpload::Exist(resource)
+            } else {
+                ClientIdUpload::Conflict
+            }
+        };
+
+        if let Some(resource) = self.db.get_resource_by_id(resource_id, log_cx).await? {
+            return Ok(existing(resource));
+        }
+
+        let bucket = self.upload_bucket(log_cx).await?;
+
+        let size = body.size();
+
+        if !self.db.reserve_quota(size) {
+            return Ok(ClientIdUpload::QuotaExceeded);
+        }
+
+        let resource = match self
+            .db
+            .insert_resource(
+                &bucket,
+                resource_id,
+                hash,
+                size,
+                content_type,
+                dimensions,
+                filename,
+                log_cx,
+            )
+            .await
+        {
+            Err(err) => {
+                self.db.release_quota(size);
+
+                // a concurrent upload of the same id may win the insert
+                return match self.db.get_resource_by_id(resource_id, log_cx).await? {
+                    None => Err(err.into()),
+                    Some(resource) => Ok(existing(resource)),
+                };
+            }
+
+            Ok(resource) => resource,
+        };
+
+        if let Err(err) = self
+            .store_backend
+            .put(&bucket, resource_id, body.reader().await?, log_cx)
+            .await
+        {
+            // remove the row, otherwise the retry would be accepted without the stored content
+            if let Err(err) = self
+                .db
+                .delete_resources(&[resource_id.to_owned()], log_cx)
+                .await
+            {
+                error!(log::get_logger(), "delete resource {} failed: {:?}", resource_id, err; log_cx);
+            }
+
+            return Err(StoreFailure::new(err).into());
+        }
+
+        Ok(ClientIdUpload::Created(resource))
+    }
+
+    async fn handle_tus_options(&self, _req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(tus::TUS_VERSION_HEADER, tus::TUS_VERSION)
+            .header(tus::TUS_MAX_SIZE_HEADER, self.max_upload_size)
+            .body(Body::empty())?)
+    }
+
+    /// Create a tus upload, the client sends the chunks to the returned location by `PATCH`.
+    async fn handle_tus_create(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if !tus::is_supported_version(req.headers()) {
+            return Ok(tus_version_rejected(&log_cx)?);
+        }
+
+        let upload_length =
+            match tus::parse_size_header(req.headers(), tus::UPLOAD_LENGTH_HEADER) {
+                None => {
+                    warn!(log::get_logger(), "tus upload length is invalid"; &log_cx);
+
+                    return Ok(error::error_response(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_upload_length",
+                        "upload-length must be a non-negative integer",
+                        &log_cx,
+                    )?);
+                }
+
+                Some(upload_length) => upload_length,
+            };
+
+        if upload_length > self.max_upload_size {
+            warn!(
+                log::get_logger(),
+                "tus upload length {} is too large", upload_length;
+                &log_cx
+            );
+
+            return Ok(error::error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                &format!("upload length is larger than {} bytes", self.max_upload_size),
+                &log_cx,
+            )?);
+        }
+
+        let (scheme, host) = self.origin(req.headers())?;
+        let filename = tus::metadata_filename(req.headers());
+        let upload_id = tus::new_upload_id();
+
+        self.db
+            .insert_tus_upload(&upload_id, upload_length, filename.as_deref(), &log_cx)
+            .await?;
+
+        let location = Uri::builder()
+            .scheme(scheme.as_str())
+            .authority(host.as_str())
+            .path_and_query(format!("{}/{}", tus::TUS_PATH, upload_id))
+            .build()?
+            .to_string();
+
+        info!(
+            log::get_logger(),
+            "tus upload is created";
+            log_cx,
+            "upload_id" => upload_id,
+            "upload_length" => upload_length
+        );
+
+        Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .header("location", location)
+            .body(Body::empty())?)
+    }
+
+    async fn handle_tus_head(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if !tus::is_supported_version(req.headers()) {
+            return Ok(tus_version_rejected(&log_cx)?);
+        }
+
+        let upload_id = tus_upload_id(req.uri().path());
+
+        let upload = match self.get_tus_upload(upload_id, &log_cx).await? {
+            None => return Ok(tus_upload_not_found(upload_id, &log_cx)?),
+            Some(upload) => upload,
+        };
+
+        Ok(Response::builder()
+            .header(tus::UPLOAD_OFFSET_HEADER, upload.get_upload_offset())
+            .header(tus::UPLOAD_LENGTH_HEADER, upload.get_upload_length())
+            .header("cache-control", "no-store")
+            .body(Body::empty())?)
+    }
+
+    /// Append a chunk to the tus upload, the upload is stored like a normal upload when all bytes
+    /// are received.
+    async fn handle_tus_patch(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if !tus::is_supported_version(req.headers()) {
+            return Ok(tus_version_rejected(&log_cx)?);
+        }
+
+        if req
+            .headers()
+            .get("content-type")
+            .is_none_or(|value| value != tus::OFFSET_CONTENT_TYPE)
+        {
+            warn!(log::get_logger(), "tus patch content type is invalid"; &log_cx);
+
+            return Ok(error::error_response(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                &format!("content-type must be {}", tus::OFFSET_CONTENT_TYPE),
+                &log_cx,
+            )?);
+        }
+
+        let offset = match tus::parse_size_header(req.headers(), tus::UPLOAD_OFFSET_HEADER) {
+            None => {
+                warn!(log::get_logger(), "tus upload offset is invalid"; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_upload_offset",
+                    "upload-offset must be a non-negative integer",
+                    &log_cx,
+                )?);
+            }
+
+            Some(offset) => offset,
+        };
+
+        let upload_id = tus_upload_id(req.uri().path()).to_owned();
+
+        let upload = match self.get_tus_upload(&upload_id, &log_cx).await? {
+            None => return Ok(tus_upload_not_found(&upload_id, &log_cx)?),
+            Some(upload) => upload,
+        };
+
+        if offset != upload.get_upload_offset() {
+            return Ok(tus_offset_mismatch(upload.get_upload_offset(), &log_cx)?);
+        }
+
+        let (scheme, host) = self.origin(req.headers())?;
+
+        let data = body::to_bytes(req.into_body()).await?;
+
+        if offset + data.len() as u64 > upload.get_upload_length() {
+            warn!(
+                log::get_logger(),
+                "tus upload {} chunk exceeds the upload length", upload_id;
+                &log_cx
+            );
+
+            return Ok(error::error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "upload_length_exceeded",
+                &format!("upload length is {} bytes", upload.get_upload_length()),
+                &log_cx,
+            )?);
+        }
+
+        // an empty chunk retries the finishing of the complete upload
+        let upload = if data.is_empty() {
+            upload
+        } else {
+            let chunk_id = tus::new_chunk_id(&upload_id, offset);
+
+            self.store_backend
+                .put(tus::TUS_BUCKET, &chunk_id, data.as_ref(), &log_cx)
+                .await
+                .map_err(StoreFailure::new)?;
+
+            match self
+                .db
+                .append_tus_chunk(&upload_id, offset, &chunk_id, data.len() as _, &log_cx)
+                .await?
+            {
+                Some(upload) => upload,
+
+                // another request appends the chunk first
+                None => {
+                    self.delete_tus_chunks(&[chunk_id], &log_cx).await;
+
+                    return Ok(tus_offset_mismatch(offset, &log_cx)?);
+                }
+            }
+        };
+
+        let mut builder = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(tus::UPLOAD_OFFSET_HEADER, upload.get_upload_offset());
+
+        if upload.is_complete() {
+            let (resource, created) = match self.finish_tus_upload(&upload, &log_cx).await? {
+                StoredUpload::Created(resource) => (resource, true),
+                StoredUpload::Exist(resource) => (resource, false),
+                StoredUpload::Rejected(resp) => return Ok(resp),
+            };
+
+            let resource_uri = self.resource_uri(&scheme, &host, resource.get_id())?;
+
+            if created {
+                if let Some(webhook) = &self.upload_webhook {
+                    webhook.notify(UploadEvent::new(&resource, &resource_uri), log_cx.clone());
+                }
+            }
+
+            info!(
+                log::get_logger(),
+                "tus upload success";
+                &log_cx,
+                "upload_id" => &upload_id,
+                "resource" => format!("{:?}", resource)
+            );
+
+            // tell the client where the upload is, the tus clients ignore it
+            builder = builder
+                .header("content-location", resource_uri)
+                .header(DEDUP_HEADER, dedup_status(created));
+        }
+
+        Ok(builder.body(Body::empty())?)
+    }
+
+    async fn get_tus_upload(
+        &self,
+        upload_id: &str,
+        log_cx: &LogContext,
+    ) -> Result<Option<TusUpload>, BoxError> {
+        if !tus::is_valid_upload_id(upload_id) {
+            return Ok(None);
+        }
+
+        Ok(self.db.get_tus_upload(upload_id, log_cx).await?)
+    }
+
+    /// Store the complete tus upload like a normal upload, then remove the chunks. The rejected
+    /// upload is removed too, retrying it can't be accepted either.
+    async fn finish_tus_upload(
+        &self,
+        upload: &TusUpload,
+        log_cx: &LogContext,
+    ) -> Result<StoredUpload, BoxError> {
+        let mut data = BytesMut::with_capacity(upload.get_upload_length() as _);
+
+        for chunk_id in upload.get_chunk_ids() {
+            let chunk = self
+                .store_backend
+                .get(tus::TUS_BUCKET, chunk_id, None::<u64>, None::<u64>, log_cx)
+                .await
+                .map_err(StoreFailure::new)?;
+
+            data.extend_from_slice(&chunk);
+        }
+
+        // a failed finishing keeps the upload, so the client can retry it with an empty chunk,
+        // the retry is deduped if the upload is stored already
+        let stored = self
+            .store_upload(data.freeze().into(), None, None, upload.get_filename(), log_cx)
+            .await?;
+
+        self.db.delete_tus_upload(upload.get_id(), log_cx).await?;
+        self.delete_tus_chunks(upload.get_chunk_ids(), log_cx).await;
+
+        Ok(stored)
+    }
+
+    /// The leftover chunks only waste the space, so the failure is logged only.
+    async fn delete_tus_chunks(&self, chunk_ids: &[String], log_cx: &LogContext) {
+        match self
+            .store_backend
+            .delete_many(tus::TUS_BUCKET, chunk_ids, log_cx)
+            .await
+        {
+            Err(err) => {
+                error!(log::get_logger(), "delete tus chunks failed: {:?}", err; log_cx);
+            }
+
+            Ok(failed_ids) if !failed_ids.is_empty() => {
+                error!(log::get_logger(), "delete tus chunks {:?} failed", failed_ids; log_cx);
+            }
+
+            Ok(_) => {}
+        }
+    }
+
+    /// Presign a POST form, so the browser uploads to the store backend directly, the upload is
+    /// recorded by the completion callback.
+    async fn handle_upload_policy(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "upload policy is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let (scheme, host) = self.origin(req.headers())?;
+
+        let resource_id = self.id_generator.get_id(&log_cx).await?;
+        let bucket = self.upload_bucket(&log_cx).await?;
+
+        let conditions = PostConditions {
+            content_type_prefix: common_prefix(&self.allowed_content_types),
+            max_size: self.max_upload_size,
+            expires_in: UPLOAD_POLICY_EXPIRES_IN,
+        };
+
+        let form = match self
+            .store_backend
+            .presign_post(&bucket, &resource_id, &conditions, &log_cx)
+            .await
+            .map_err(StoreFailure::new)?
+        {
+            None => {
+                warn!(log::get_logger(), "store backend doesn't support presigned post"; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::NOT_IMPLEMENTED,
+                    "not_implemented",
+                    "the store backend doesn't support the presigned upload",
+                    &log_cx,
+                )?);
+            }
+
+            Some(form) => form,
+        };
+
+        let expires_at = Local::now() + chrono::Duration::from_std(UPLOAD_POLICY_EXPIRES_IN)?;
+
+        let upload_policy = UploadPolicy {
+            resource_url: self.resource_uri(&scheme, &host, &resource_id)?,
+            id: resource_id,
+            bucket,
+            expires_at: expires_at.timestamp(),
+            form,
+        };
+
+        info!(
+            log::get_logger(),
+            "upload policy is signed";
+            &log_cx,
+            "resource" => &upload_policy.id,
+            "bucket" => &upload_policy.bucket
+        );
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&upload_policy)?))?)
+    }
+
+    /// Record the resource which is posted to the store backend with the upload policy, it is
+    /// checked like a normal upload, and removed if it's rejected.
+    async fn handle_upload_policy_complete(
+        &self,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "upload policy complete is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let (scheme, host) = self.origin(req.headers())?;
+
+        let data = body::to_bytes(req.into_body()).await?;
+
+        let complete = match serde_json::from_slice::<UploadPolicyComplete>(&data) {
+            Ok(complete)
+                if self.bucket_strategy.is_upload_bucket(&complete.bucket)
+                    && self.id_generator.encoding().is_generated(&complete.id) =>
+            {
+                complete
+            }
+
+            result => {
+                let message = match result {
+                    Err(err) => format!("upload policy complete body is invalid: {}", err),
+                    Ok(_) => "bucket or resource id is invalid".to_string(),
+                };
+
+                warn!(log::get_logger(), "{}", message; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &message,
+                    &log_cx,
+                )?);
+            }
+        };
+
+        let (bucket, resource_id) = (complete.bucket.as_str(), complete.id.as_str());
+
+        if self.db.get_resource_by_id(resource_id, &log_cx).await?.is_some() {
+            return Ok(error::error_response(
+                StatusCode::CONFLICT,
+                "resource_conflict",
+                &format!("resource {} exists", resource_id),
+                &log_cx,
+            )?);
+        }
+
+        let data = match self
+            .store_backend
+            .get(bucket, resource_id, None, None, &log_cx)
+            .await
+        {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                warn!(log::get_logger(), "resource {} is not uploaded", resource_id; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource {} is not uploaded", resource_id),
+                    &log_cx,
+                )?);
+            }
+
+            Err(err) => return Err(StoreFailure::new(err).into()),
+            Ok(data) => data,
+        };
+
+        let content_type =
+            media::detect_content_type(&data).unwrap_or(self.default_content_type.as_str());
+        let dimensions = media::image_dimensions(&data);
+
+        // the policy limits the size, but the content is only known now
+        let rejected = if data.len() as u64 > self.max_upload_size {
+            warn!(log::get_logger(), "resource {} is too large", resource_id; &log_cx);
+
+            Some(error::error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                &format!("resource is larger than {} bytes", self.max_upload_size),
+                &log_cx,
+            )?)
+        } else if let Some(resp) = self.check_content(content_type, dimensions, &log_cx)? {
+            Some(resp)
+        } else if !self.db.reserve_quota(data.len() as _) {
+            Some(quota_exceeded_response(data.len() as _, &log_cx)?)
+        } else {
+            None
+        };
+
+        if let Some(resp) = rejected {
+            if let Err(err) = self.store_backend.delete(bucket, resource_id, &log_cx).await {
+                error!(
+                    log::get_logger(),
+                    "delete rejected resource {} failed: {:?}", resource_id, err;
+                    &log_cx
+                );
+            }
+
+            return Ok(resp);
+        }
+
+        let hash_result = self.hash_algorithm.hash(&data);
+
+        let resource = match self
+            .db
+            .insert_resource(
+                bucket,
+                resource_id,
+                &hash_result,
+                data.len() as _,
+                content_type,
+                dimensions,
+                None,
+                &log_cx,
+            )
+            .await
+        {
+            Err(err) => {
+                self.db.release_quota(data.len() as _);
+
+                return Err(err.into());
+            }
+
+            Ok(resource) => resource,
+        };
+
+        let resource_uri = self.resource_uri(&scheme, &host, resource_id)?;
+
+        if let Some(webhook) = &self.upload_webhook {
+            webhook.notify(UploadEvent::new(&resource, &resource_uri), log_cx.clone());
+        }
+
+        info!(
+            log::get_logger(),
+            "presigned upload is recorded";
+            log_cx,
+            "resource" => format!("{:?}", resource)
+        );
+
+        Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .header("content-type", "text/plain; charset=utf-8")
+            .body(Body::from(resource_uri))?)
+    }
+
+    async fn handle_get(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(hotlink_protection) = &self.hotlink_protection {
+            if !hotlink_protection.is_allowed(req.headers().get("referer")) {
+                return Ok(hotlink_rejected_response(hotlink_protection, &req, &log_cx)?);
+            }
+        }
+
+        let path = req.uri().path().replace(self.get_path.as_str(), "");
+        let resource_id = path.strip_prefix('/').unwrap_or(&path);
+
+        if let Some(resp) = self.check_signed_url(req.uri(), resource_id, &log_cx)? {
+            return Ok(resp);
+        }
+
+        let resource = match self.get_resource_by_path_id(resource_id, &log_cx).await? {
+            None => {
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource {} is not found", resource_id),
+                    &log_cx,
+                )?);
+            }
+
+            Some(resource) => resource,
+        };
+
+        self.get_resource_response(&req, &resource, &log_cx).await
+    }
+
+    /// Verify the link issued by the sign api, the links without the signature params are not
+    /// checked. Return the rejected response when the signature is invalid or expired.
+    fn check_signed_url(
+        &self,
+        uri: &Uri,
+        path_id: &str,
+        log_cx: &LogContext,
+    ) -> Result<Option<Response<Body>>, BoxError> {
+        let expires = query_param(uri, signed_url::EXPIRES_PARAM);
+        let signature = query_param(uri, signed_url::SIGNATURE_PARAM);
+
+        if expires.is_none() && signature.is_none() {
+            return Ok(None);
+        }
+
+        // the link is signed for the id, the extension appended by the clients is not signed
+        let resource_id = path_id
+            .rfind('.')
+            .map_or(path_id, |index| &path_id[..index]);
+
+        let expires = expires.and_then(|expires| expires.parse::<u64>().ok());
+
+        let result = match (&self.url_signer, expires, signature) {
+            (Some(url_signer), Some(expires), Some(signature)) => {
+                url_signer.verify(resource_id, expires, signature, SystemTime::now())
+            }
+
+            // the link can't be verified without the secret
+            _ => Err(SignatureError::Invalid),
+        };
+
+        let (code, message) = match result {
+            Ok(()) => return Ok(None),
+            Err(SignatureError::Invalid) => ("invalid_signature", "the link signature is invalid"),
+            Err(SignatureError::Expired) => ("link_expired", "the link is expired"),
+        };
+
+        warn!(
+            log::get_logger(),
+            "signed link of resource {} is rejected", resource_id;
+            log_cx,
+            "code" => code
+        );
+
+        Ok(Some(error::error_response(
+            StatusCode::FORBIDDEN,
+            code,
+            message,
+            log_cx,
+        )?))
+    }
+
+    /// Find the resource by the id in the get path. The naive clients may append an extension to
+    /// the id, like `<id>.png`, it must agree with the stored content type, except the default
+    /// content type, which is overridden by it when `extension_content_type` is enabled.
+    async fn get_resource_by_path_id(
+        &self,
+        path_id: &str,
+        log_cx: &LogContext,
+    ) -> Result<Option<Resource>, BoxError> {
+        // the ids never contain `.`
+        let (resource_id, extension_type) = match path_id.rfind('.') {
+            None => (path_id, None),
+
+            Some(index) => match media::extension_content_type(&path_id[index + 1..]) {
+                None => return Ok(None),
+                Some(extension_type) => (&path_id[..index], Some(extension_type)),
+            },
+        };
+
+        let mut resource = match self.db.get_resource_by_id(resource_id, log_cx).await? {
+            None => return Ok(None),
+            Some(resource) => resource,
+        };
+
+        if let Some(extension_type) = extension_type {
+            let content_type = resource.get_content_type();
+
+            if content_type != extension_type {
+                if content_type != self.default_content_type.as_str() {
+                    warn!(
+                        log::get_logger(),
+                        "extension of {} doesn't match content type {}",
+                        path_id, content_type;
+                        log_cx
+                    );
+
+                    return Ok(None);
+                }
+
+                if self.extension_content_type {
+                    resource.set_content_type(extension_type);
+                }
+            }
+        }
+
+        Ok(Some(resource))
+    }
+
+    /// Serve the resource by its content hash, so the caching layers can key on the content.
+    async fn handle_get_by_hash(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(hotlink_protection) = &self.hotlink_protection {
+            if !hotlink_protection.is_allowed(req.headers().get("referer")) {
+                return Ok(hotlink_rejected_response(hotlink_protection, &req, &log_cx)?);
+            }
+        }
+
+        let path_hash = req.uri().path().trim_start_matches(HASH_PATH);
+
+        let hash = match hash::parse_hash(path_hash) {
+            None => {
+                warn!(log::get_logger(), "hash {} is invalid", path_hash; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!(
+                        "hash must be {} hex characters, which may be prefixed with blake3:",
+                        hash::HASH_HEX_LENGTH
+                    ),
+                    &log_cx,
+                )?);
+            }
+
+            Some(hash) => hash,
+        };
+
+        let resource = match self.db.get_resource_by_hash(&hash, &log_cx).await? {
+            None => {
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource with hash {} is not found", hash),
+                    &log_cx,
+                )?);
+            }
+
+            Some(resource) => resource,
+        };
+
+        self.get_resource_response(&req, &resource, &log_cx).await
+    }
+
+    /// Check the content is stored already by its hash, so the clients can skip uploading it. The
+    /// url of the stored resource is responded in the header without any body.
+    async fn handle_upload_check(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        let hash = req
+            .headers()
+            .get(CONTENT_HASH_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(hash::parse_hash);
+
+        let hash = match hash {
+            None => {
+                warn!(log::get_logger(), "content hash is missing or invalid"; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!(
+                        "{} must be {} hex characters, which may be prefixed with blake3:",
+                        CONTENT_HASH_HEADER,
+                        hash::HASH_HEX_LENGTH
+                    ),
+                    &log_cx,
+                )?);
+            }
+
+            Some(hash) => hash,
+        };
+
+        let resource = match self.db.get_resource_by_hash(&hash, &log_cx).await? {
+            None => {
+                info!(log::get_logger(), "upload check miss"; &log_cx, "hash" => &hash);
+
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource with hash {} is not found", hash),
+                    &log_cx,
+                )?);
+            }
+
+            Some(resource) => resource,
+        };
+
+        let (scheme, host) = self.origin(req.headers())?;
+        let resource_uri = self.resource_uri(&scheme, &host, resource.get_id())?;
+
+        info!(
+            log::get_logger(),
+            "upload check hit";
+            &log_cx,
+            "resource" => format!("{:?}", resource)
+        );
+
+        Ok(Response::builder()
+            .header(RESOURCE_URL_HEADER, resource_uri)
+            .body(Body::empty())?)
+    }
+
+    /// Serve the found resource, the transcoding, conditional and range requests are handled the
+    /// same whether it's found by id or by hash.
+    async fn get_resource_response(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+        log_cx: &LogContext,
+    ) -> Result<Response<Body>, BoxError> {
+        let (watermark, transcode_format) = self.download_variant(req, resource);
+
+        if let Some(resp) = self.not_modified_response(
+            req,
+            resource,
+            variant_tag(watermark, transcode_format),
+        )? {
+            return Ok(resp);
+        }
+
+        let range_request = match range::parse_range(
+            req.headers()
+                .get("range")
+                .and_then(|value| value.to_str().ok()),
+            resource.get_resource_size(),
+        ) {
+            RangeRequest::Unsatisfiable => {
+                return Ok(range_not_satisfiable(resource, log_cx)?);
+            }
+
+            range_request => range_request,
+        };
+
+        if let Some((resp_builder, data)) = self
+            .variant_response(
+                req,
+                resource,
+                watermark,
+                transcode_format,
+                &range_request,
+                log_cx,
+            )
+            .await?
+        {
+            info!(
+                log::get_logger(),
+                "get variant success";
+                log_cx,
+                "resource" => format!("{:?}", resource),
+                "variant" => variant_tag(watermark, transcode_format)
+            );
+
+            return Ok(resp_builder.body(Body::from(data))?);
+        }
+
+        if self.verify_on_read && range_request == RangeRequest::Full {
+            return self.verified_get_response(req, resource, log_cx).await;
+        }
+
+        let range = match range_request {
+            RangeRequest::Full | RangeRequest::Unsatisfiable => None,
+            RangeRequest::Partial(range) => Some(range),
+            RangeRequest::Multiple(ranges) => {
+                return self
+                    .multipart_get_response(req, resource, ranges, log_cx)
+                    .await;
+            }
+        };
+
+        let stream = self
+            .store_backend
+            .get_stream(
+                resource.get_bucket(),
+                resource.get_id(),
+                range.map(|range| range.start),
+                range.map(|range| range.end),
+                log_cx,
+            )
+            .await
+            .map_err(StoreFailure::new)?;
+
+        let resp_builder = self.resource_response_builder(req, resource, range);
+
+        info!(
+            log::get_logger(),
+            "get success";
+            log_cx,
+            "resource" => format!("{:?}", resource),
+            "start" => range.map(|range| range.start),
+            "end" => range.map(|range| range.end)
+        );
+
+        Ok(resp_builder.body(Body::wrap_stream(stream))?)
+    }
+
+    /// Serve the multiple ranges as the parts of a `multipart/byteranges` body. The ranges are
+    /// fetched one by one and buffered, they are never larger than the resource in total.
+    async fn multipart_get_response(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+        ranges: Vec<ByteRange>,
+        log_cx: &LogContext,
+    ) -> Result<Response<Body>, BoxError> {
+        let multipart = MultipartRanges::new(
+            resource.get_content_type(),
+            resource.get_resource_size(),
+            ranges,
+        );
+
+        let mut parts = Vec::with_capacity(multipart.ranges().len());
+        for range in multipart.ranges() {
+            let data = self
+                .store_backend
+                .get(
+                    resource.get_bucket(),
+                    resource.get_id(),
+                    range.start,
+                    range.end,
+                    log_cx,
+                )
+                .await
+                .map_err(StoreFailure::new)?;
+
+            parts.push(data);
+        }
+
+        let resp_builder = self.multipart_response_builder(req, resource, &multipart);
+
+        info!(
+            log::get_logger(),
+            "get multiple ranges success";
+            log_cx,
+            "resource" => format!("{:?}", resource),
+            "ranges" => format!("{:?}", multipart.ranges())
+        );
+
+        Ok(resp_builder.body(Body::from(multipart.body(&parts)))?)
+    }
+
+    /// Buffer the full resource and check it with the stored hash before sending it.
+    async fn verified_get_response(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+        log_cx: &LogContext,
+    ) -> Result<Response<Body>, BoxError> {
+        let data = self
+            .store_backend
+            .get(resource.get_bucket(), resource.get_id(), None, None, log_cx)
+            .await
+            .map_err(StoreFailure::new)?;
+
+        let hash_result = HashAlgorithm::of_hash(resource.get_hash()).hash(&data);
+
+        if hash_result != resource.get_hash() {
+            error!(
+                log::get_logger(),
+                "resource is corrupted, the hash mismatches";
+                log_cx,
+                "resource" => format!("{:?}", resource),
+                "actual_hash" => &hash_result,
+                "actual_size" => data.len()
+            );
+
+            return Ok(error::error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "integrity_check_failed",
+                &format!("resource {} is corrupted", resource.get_id()),
+                log_cx,
+            )?);
+        }
+
+        info!(
+            log::get_logger(),
+            "get verified success";
+            log_cx,
+            "resource" => format!("{:?}", resource)
+        );
+
+        Ok(self
+            .resource_response_builder(req, resource, None)
+            .body(Body::from(data))?)
+    }
+
+    /// Select the variant of the download, shared by GET and HEAD so they agree on the etag. The
+    /// conditional request is evaluated before the range, so the format is negotiated for the
+    /// range requests too, but only the full downloads are transcoded, the watermarked downloads
+    /// are never transcoded.
+    fn download_variant(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+    ) -> (Option<&Arc<Watermark>>, Option<TranscodeFormat>) {
+        let watermark = self.download_watermark(req, resource);
+
+        let transcode_format = if watermark.is_none() && self.vary_accept(resource) {
+            req.headers()
+                .get("accept")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|accept| media::negotiate_transcode(accept, resource.get_content_type()))
+        } else {
+            None
+        };
+
+        (watermark, transcode_format)
+    }
+
+    /// The watermark overlaid on the download, `None` if the original is served.
+    fn download_watermark(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+    ) -> Option<&Arc<Watermark>> {
+        let watermark = self.watermark.as_ref()?;
+
+        let requested = self.watermark_always || has_query_flag(req.uri(), "watermark");
+
+        if requested && media::is_watermarkable(resource.get_content_type()) {
+            Some(watermark)
+        } else {
+            None
+        }
+    }
+
+    /// The response depends on the accept header once the resource can be transcoded.
+    fn vary_accept(&self, resource: &Resource) -> bool {
+        self.transcode && media::is_transcodable(resource.get_content_type())
+    }
+
+    /// Build the response of the original resource without the body, shared by GET and HEAD so
+    /// they always have the same headers.
+    fn resource_response_builder(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+        range: Option<ByteRange>,
+    ) -> response::Builder {
+        let resp_builder = self
+            .resource_headers_builder(req, resource)
+            .header("content-type", resource.get_content_type());
+
+        match range {
+            None => resp_builder
+                .status(StatusCode::OK)
+                .header("content-length", resource.get_resource_size()),
+
+            Some(range) => resp_builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header("content-length", range.len())
+                .header(
+                    "content-range",
+                    range.content_range(resource.get_resource_size()),
+                ),
+        }
+    }
+
+    /// Build the response of the multiple ranges without the body, shared by GET and HEAD too.
+    fn multipart_response_builder(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+        multipart: &MultipartRanges,
+    ) -> response::Builder {
+        self.resource_headers_builder(req, resource)
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header("content-type", multipart.content_type())
+            .header("content-length", multipart.content_length())
+    }
+
+    /// The headers of the original resource, whether it's served in full, in a range or in the
+    /// multiple ranges.
+    fn resource_headers_builder(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+    ) -> response::Builder {
+        let mut resp_builder = Response::builder()
+            .header("cache-control", self.cache_control.as_str())
+            .header(
+                "last-modified",
+                conditional::http_date(resource.get_create_time()),
+            )
+            .header("etag", conditional::etag(resource.get_hash(), None));
+
+        if let Some(disposition) = resource_disposition(req.uri(), resource) {
+            resp_builder = resp_builder.header("content-disposition", disposition);
+        }
+
+        if self.vary_accept(resource) {
+            resp_builder = resp_builder.header("vary", "accept");
+        }
+
+        resp_builder
+    }
+
+    /// Get the watermarked or transcoded variant and build its response without the body, return
+    /// `None` when the original should be served.
+    async fn variant_response(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+        watermark: Option<&Arc<Watermark>>,
+        transcode_format: Option<TranscodeFormat>,
+        range_request: &RangeRequest,
+        log_cx: &LogContext,
+    ) -> Result<Option<(response::Builder, Bytes)>, BoxError> {
+        // the range of the original is never served, the whole watermarked image is
+        if let Some(watermark) = watermark {
+            if let Some(data) = self.watermarked_variant(resource, watermark, log_cx).await? {
+                let resp_builder = self.variant_response_builder(
+                    req,
+                    resource,
+                    resource.get_content_type(),
+                    watermark.variant(),
+                    Some(data.len() as u64),
+                );
+
+                return Ok(Some((resp_builder, data)));
+            }
+        }
+
+        if let Some(format) = transcode_format.filter(|_| *range_request == RangeRequest::Full) {
+            if let Some(data) = self.transcoded_variant(resource, format, log_cx).await? {
+                let resp_builder = self
+                    .variant_response_builder(
+                        req,
+                        resource,
+                        format.content_type(),
+                        format.extension(),
+                        Some(data.len() as u64),
+                    )
+                    .header("vary", "accept");
+
+                return Ok(Some((resp_builder, data)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Build the HEAD response of the watermarked or transcoded variant from the metadata only,
+    /// HEAD never makes the variant, so the length is unknown until a GET caches it. Return `None`
+    /// when the original is served.
+    async fn variant_head_response(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+        watermark: Option<&Arc<Watermark>>,
+        transcode_format: Option<TranscodeFormat>,
+        range_request: &RangeRequest,
+        log_cx: &LogContext,
+    ) -> Option<response::Builder> {
+        let (variant_id, content_type, variant) = match (watermark, transcode_format) {
+            (Some(watermark), _) => (
+                watermarked_variant_id(resource.get_id(), watermark),
+                resource.get_content_type(),
+                watermark.variant(),
+            ),
+
+            (None, Some(format)) if *range_request == RangeRequest::Full => (
+                transcoded_variant_id(resource.get_id(), format),
+                format.content_type(),
+                format.extension(),
+            ),
+
+            _ => return None,
+        };
+
+        let size = match self
+            .store_backend
+            .size(resource.get_bucket(), &variant_id, log_cx)
+            .await
+        {
+            Ok(size) => size,
+
+            Err(err) => {
+                warn!(log::get_logger(), "get variant {} size failed: {:?}", variant_id, err; log_cx);
+
+                None
+            }
+        };
+
+        // the empty variant marks the original is served instead
+        if size == Some(0) {
+            return None;
+        }
+
+        let mut resp_builder =
+            self.variant_response_builder(req, resource, content_type, variant, size);
+
+        if watermark.is_none() {
+            resp_builder = resp_builder.header("vary", "accept");
+        }
+
+        Some(resp_builder)
+    }
+
+    /// Build the response of the transcoded or watermarked variant without the body, the variant
+    /// is tagged in the etag, the length is left out if it's unknown.
+    fn variant_response_builder(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+        content_type: &str,
+        variant: &str,
+        content_length: Option<u64>,
+    ) -> response::Builder {
+        let mut resp_builder = Response::builder().header("content-type", content_type);
+
+        if let Some(content_length) = content_length {
+            resp_builder = resp_builder.header("content-length", content_length);
+        }
+
+        resp_builder = resp_builder
+            .header("cache-control", self.cache_control.as_str())
+            .header(
+                "last-modified",
+                conditional::http_date(resource.get_create_time()),
+            )
+            .header("etag", conditional::etag(resource.get_hash(), Some(variant)));
+
+        if let Some(disposition) = resource_disposition(req.uri(), resource) {
+            resp_builder = resp_builder.header("content-disposition", disposition);
+        }
+
+        resp_builder
+    }
+
+    /// Get the cached watermarked variant, or overlay the watermark on the original and cache it.
+    /// Return `None` when the original should be served, such as the original can't be decoded.
+    async fn watermarked_variant(
+        &self,
+        resource: &Resource,
+        watermark: &Arc<Watermark>,
+        log_cx: &LogContext,
+    ) -> Result<Option<Bytes>, BoxError> {
+        let bucket = resource.get_bucket();
+        let variant_id = watermarked_variant_id(resource.get_id(), watermark);
+
+        match self
+            .store_backend
+            .get(bucket, &variant_id, None, None, log_cx)
+            .await
+        {
+            Ok(data) => return Ok(Some(data)),
+
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+
+            Err(err) => {
+                warn!(log::get_logger(), "get watermarked variant {} failed: {:?}", variant_id, err; log_cx);
+            }
+        }
+
+        let original = self
+            .store_backend
+            .get(bucket, resource.get_id(), None, None, log_cx)
+            .await
+            .map_err(StoreFailure::new)?;
+
+        let watermark = watermark.clone();
+
+        let data = match task::spawn_blocking(move || watermark.apply(&original)).await? {
+            Err(err) => {
+                warn!(
+                    log::get_logger(),
+                    "watermark resource {} failed, serve the original: {:?}",
+                    resource.get_id(), err;
+                    log_cx
+                );
+
+                return Ok(None);
+            }
+
+            Ok(data) => data,
+        };
+
+        if let Err(err) = self
+            .store_backend
+            .put(bucket, &variant_id, data.as_ref(), log_cx)
+            .await
+        {
+            warn!(log::get_logger(), "save watermarked variant {} failed: {:?}", variant_id, err; log_cx);
+        }
+
+        Ok(Some(data))
+    }
+
+    /// Get the cached transcoded variant, or transcode the original and cache it. Return `None`
+    /// when the original should be served, such as transcoding failed or the variant is larger,
+    /// then an empty variant is cached as the marker, so the original is transcoded only once.
+    async fn transcoded_variant(
+        &self,
+        resource: &Resource,
+        format: TranscodeFormat,
+        log_cx: &LogContext,
+    ) -> Result<Option<Bytes>, BoxError> {
+        let bucket = resource.get_bucket();
+        let variant_id = transcoded_variant_id(resource.get_id(), format);
+
+        match self
+            .store_backend
+            .get(bucket, &variant_id, None, None, log_cx)
+            .await
+        {
+            Ok(data) if data.is_empty() => return Ok(None),
+
+            Ok(data) => return Ok(Some(data)),
+
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+
+            Err(err) => {
+                warn!(log::get_logger(), "get transcoded variant {} failed: {:?}", variant_id, err; log_cx);
+            }
+        }
+
+        let original = self
+            .store_backend
+            .get(bucket, resource.get_id(), None, None, log_cx)
+            .await
+            .map_err(StoreFailure::new)?;
+
+        let original_size = original.len();
+
+        let data = match task::spawn_blocking(move || media::transcode(&original, format)).await? {
+            Err(err) => {
+                warn!(
+                    log::get_logger(),
+                    "transcode resource {} to {} failed, serve the original: {:?}",
+                    resource.get_id(), format.extension(), err;
+                    log_cx
+                );
+
+                None
+            }
+
+            Ok(data) if data.len() >= original_size => None,
+
+            Ok(data) => Some(data),
+        };
+
+        let cached = data.as_deref().unwrap_or_default();
+
+        if let Err(err) = self
+            .store_backend
+            .put(bucket, &variant_id, cached, log_cx)
+            .await
+        {
+            warn!(log::get_logger(), "save transcoded variant {} failed: {:?}", variant_id, err; log_cx);
+        }
+
+        Ok(data)
+    }
+
+    async fn handle_head(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(hotlink_protection) = &self.hotlink_protection {
+            if !hotlink_protection.is_allowed(req.headers().get("referer")) {
+                return Ok(hotlink_rejected_response(hotlink_protection, &req, &log_cx)?);
+            }
+        }
+
+        let path = req.uri().path().replace(self.get_path.as_str(), "");
+        let resource_id = path.strip_prefix('/').unwrap_or(&path);
+
+        if let Some(resp) = self.check_signed_url(req.uri(), resource_id, &log_cx)? {
+            return Ok(resp);
+        }
+
+        let resource = match self.get_resource_by_path_id(resource_id, &log_cx).await? {
+            None => {
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource {} is not found", resource_id),
+                    &log_cx,
+                )?);
+            }
+
+            Some(resource) => resource,
+        };
+
+        let (watermark, transcode_format) = self.download_variant(&req, &resource);
+
+        if let Some(resp) = self.not_modified_response(
+            &req,
+            &resource,
+            variant_tag(watermark, transcode_format),
+        )? {
+            return Ok(resp);
+        }
+
+        let range_request = range::parse_range(
+            req.headers()
+                .get("range")
+                .and_then(|value| value.to_str().ok()),
+            resource.get_resource_size(),
+        );
+
+        if range_request == RangeRequest::Unsatisfiable {
+            return Ok(range_not_satisfiable(&resource, &log_cx)?);
+        }
+
+        let variant_response = self
+            .variant_head_response(
+                &req,
+                &resource,
+                watermark,
+                transcode_format,
+                &range_request,
+                &log_cx,
+            )
+            .await;
+
+        // the size of the original is known from the db, the variant's from the store metadata
+        let mut resp_builder = match (variant_response, &range_request) {
+            (Some(resp_builder), _) => resp_builder,
+
+            (None, RangeRequest::Full) | (None, RangeRequest::Unsatisfiable) => {
+                self.resource_response_builder(&req, &resource, None)
+            }
+
+            (None, RangeRequest::Partial(range)) => {
+                self.resource_response_builder(&req, &resource, Some(*range))
+            }
+
+            (None, RangeRequest::Multiple(ranges)) => {
+                let multipart = MultipartRanges::new(
+                    resource.get_content_type(),
+                    resource.get_resource_size(),
+                    ranges.clone(),
+                );
+
+                self.multipart_response_builder(&req, &resource, &multipart)
+            }
+        };
+
+        if let Some((width, height)) = resource.get_dimensions() {
+            resp_builder = resp_builder
+                .header(IMAGE_WIDTH_HEADER, width)
+                .header(IMAGE_HEIGHT_HEADER, height);
+        }
+
+        info!(
+            log::get_logger(),
+            "head success";
+            log_cx,
+            "resource" => format!("{:?}", resource),
+            "range" => format!("{:?}", range_request)
+        );
+
+        // hyper reports the empty body as `content-length: 0`, the body of the unknown length
+        // leaves the length of the uncached variant out
+        let body = if resp_builder
+            .headers_ref()
+            .is_some_and(|headers| headers.contains_key("content-length"))
+        {
+            Body::empty()
+        } else {
+            Body::wrap_stream(futures_util::stream::empty::<Result<Bytes, Infallible>>())
+        };
+
+        Ok(resp_builder.body(body)?)
+    }
+
+    async fn handle_meta(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        let resource_id = req.uri().path().trim_start_matches(META_PATH);
+
+        let resource = match self.db.get_resource_by_id(resource_id, &log_cx).await? {
+            None => {
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource {} is not found", resource_id),
+                    &log_cx,
+                )?);
+            }
+
+            Some(resource) => resource,
+        };
+
+        info!(
+            log::get_logger(),
+            "get meta success";
+            log_cx,
+            "resource" => format!("{:?}", resource)
+        );
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .header("cache-control", self.cache_control.as_str())
+            .body(Body::from(serde_json::to_vec(&resource)?))?)
+    }
+
+    /// Issue the link of the resource signed by the url signing secret, it expires after `ttl`
+    /// seconds, one hour by default.
+    async fn handle_sign(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "sign is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let url_signer = match &self.url_signer {
+            None => {
+                warn!(log::get_logger(), "url signing secret is not configured"; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::FORBIDDEN,
+                    "url_signing_disabled",
+                    "url signing secret is not configured",
+                    &log_cx,
+                )?);
+            }
+
+            Some(url_signer) => url_signer,
+        };
+
+        let ttl = match parse_query_param(req.uri(), "ttl", DEFAULT_SIGNED_URL_TTL) {
+            Ok(ttl) if ttl > 0 && ttl <= MAX_SIGNED_URL_TTL => ttl,
+            _ => {
+                warn!(log::get_logger(), "ttl of sign is invalid"; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!("ttl must be 1 to {} seconds", MAX_SIGNED_URL_TTL),
+                    &log_cx,
+                )?);
+            }
+        };
+
+        let resource_id = req.uri().path().trim_start_matches(SIGN_PATH);
+
+        let resource = match self.db.get_resource_by_id(resource_id, &log_cx).await? {
+            None => {
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource {} is not found", resource_id),
+                    &log_cx,
+                )?);
+            }
+
+            Some(resource) => resource,
+        };
+
+        let (scheme, host) = self.origin(req.headers())?;
+        let resource_uri = self.resource_uri(&scheme, &host, resource.get_id())?;
+
+        let expires_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs()
+            + ttl;
+
+        let signed_url = SignedUrl {
+            url: format!(
+                "{}?{}={}&{}={}",
+                resource_uri,
+                signed_url::EXPIRES_PARAM,
+                expires_at,
+                signed_url::SIGNATURE_PARAM,
+                url_signer.sign(resource.get_id(), expires_at)
+            ),
+            expires_at,
+        };
+
+        info!(
+            log::get_logger(),
+            "sign success";
+            log_cx,
+            "resource" => format!("{:?}", resource),
+            "expires_at" => expires_at
+        );
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&signed_url)?))?)
+    }
+
+    /// Find the resources without the stored object and the stored objects without the resource,
+    /// and delete them if it's asked. It scans all resources, so it may take a long time, the
+    /// progress is logged.
+    async fn handle_reconcile(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "reconcile is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let data = body::to_bytes(req.into_body()).await?;
+
+        // the empty body uses the default options
+        let reconcile_request = if data.is_empty() {
+            Ok(ReconcileRequest::default())
+        } else {
+            serde_json::from_slice::<ReconcileRequest>(&data)
+        };
+
+        let reconcile_request = match reconcile_request {
+            Err(err) => {
+                warn!(log::get_logger(), "reconcile body is invalid: {}", err; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!("reconcile body is invalid: {}", err),
+                    &log_cx,
+                )?);
+            }
+
+            Ok(reconcile_request) => reconcile_request,
+        };
+
+        let options = reconcile::Options {
+            bucket: reconcile_request.bucket,
+            delete: reconcile_request.delete,
+            batch_size: reconcile_request
+                .batch_size
+                .unwrap_or(reconcile::DEFAULT_BATCH_SIZE),
+            min_age: reconcile_request
+                .min_age
+                .map_or(reconcile::DEFAULT_MIN_AGE, Duration::from_secs),
+        };
+
+        let report =
+            reconcile::reconcile(&self.db, self.store_backend.as_ref(), &options, &log_cx).await?;
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&report)?))?)
+    }
+
+    /// Copy a resource to a new id in the current bucket, the object is copied by the store
+    /// backend and a new row points to it.
+    async fn handle_copy(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "copy is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let (scheme, host) = self.origin(req.headers())?;
+
+        let data = body::to_bytes(req.into_body()).await?;
+
+        let copy_request = match serde_json::from_slice::<CopyRequest>(&data) {
+            Err(err) => {
+                warn!(log::get_logger(), "copy body is invalid: {}", err; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!("copy body is invalid: {}", err),
+                    &log_cx,
+                )?);
+            }
+
+            Ok(copy_request) => copy_request,
+        };
+
+        if let Some(destination) = &copy_request.destination {
+            if !is_valid_client_id(destination, self.id_generator.encoding()) {
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_resource_id",
+                    &format!("destination id {:?} is invalid", destination),
+                    &log_cx,
+                )?);
+            }
+
+            if self.db.get_resource_by_id(destination, &log_cx).await?.is_some() {
+                return Ok(error::error_response(
+                    StatusCode::CONFLICT,
+                    "resource_conflict",
+                    &format!("resource {} exists", destination),
+                    &log_cx,
+                )?);
+            }
+        }
+
+        let source = match self
+            .db
+            .get_resource_by_id(&copy_request.source, &log_cx)
+            .await?
+        {
+            None => {
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource {} is not found", copy_request.source),
+                    &log_cx,
+                )?);
+            }
+
+            Some(source) => source,
+        };
+
+        let size = source.get_resource_size();
+        let bucket = self.upload_bucket(&log_cx).await?;
+
+        if !self.db.reserve_quota(size) {
+            return Ok(quota_exceeded_response(size as _, &log_cx)?);
+        }
+
+        let inserted = async {
+            let resource_id = match &copy_request.destination {
+                None => self.id_generator.get_id(&log_cx).await?,
+                Some(destination) => destination.clone(),
+            };
+
+            self.db
+                .insert_resource(
+                    &bucket,
+                    &resource_id,
+                    source.get_hash(),
+                    size,
+                    source.get_content_type(),
+                    source.get_dimensions(),
+                    source.get_filename(),
+                    &log_cx,
+                )
+                .await
+        }
+            .await;
+
+        let resource = match inserted {
+            Err(err) => {
+                self.db.release_quota(size);
+
+                return Err(err.into());
+            }
+
+            Ok(resource) => resource,
+        };
+
+        if let Err(err) = self
+            .store_backend
+            .copy(
+                source.get_bucket(),
+                source.get_id(),
+                &bucket,
+                resource.get_id(),
+                &log_cx,
+            )
+            .await
+        {
+            if let Err(err) = self
+                .db
+                .delete_resources(&[resource.get_id().to_owned()], &log_cx)
+                .await
+            {
+                error!(log::get_logger(), "delete resource {} failed: {:?}", resource.get_id(), err; &log_cx);
+            }
+
+            if err.kind() == ErrorKind::NotFound {
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource {} is not found", source.get_id()),
+                    &log_cx,
+                )?);
+            }
+
+            return Err(StoreFailure::new(err).into());
+        }
+
+        let resource_uri = self.resource_uri(&scheme, &host, resource.get_id())?;
+
+        let mut resp = Response::new(Body::from(resource_uri));
+        *resp.status_mut() = StatusCode::CREATED;
+        resp.headers_mut()
+            .insert("content-type", "text/plain; charset=utf-8".parse()?);
+
+        info!(
+            log::get_logger(),
+            "copy success";
+            log_cx,
+            "source" => format!("{:?}", source),
+            "resource" => format!("{:?}", resource)
+        );
+
+        Ok(resp)
+    }
+
+    async fn handle_delete_batch(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "delete batch is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let (parts, body) = req.into_parts();
+        let data = body::to_bytes(body).await?;
+
+        let resource_ids = match serde_json::from_slice::<Vec<String>>(&data) {
+            Err(err) => {
+                warn!(log::get_logger(), "delete batch body is invalid: {}", err; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!("delete batch body is invalid: {}", err),
+                    &log_cx,
+                )?);
+            }
+
+            Ok(resource_ids) => resource_ids,
+        };
+
+        let mut results = resource_ids
+            .iter()
+            .map(|resource_id| (resource_id.clone(), DeleteResult::NotFound))
+            .collect::<BTreeMap<_, _>>();
+
+        let resources = self.db.get_resources_by_ids(&resource_ids, &log_cx).await?;
+
+        let mut bucket_resources: HashMap<_, Vec<_>> = HashMap::new();
+        for resource in resources {
+            bucket_resources
+                .entry(resource.get_bucket().to_owned())
+                .or_default()
+                .push(resource.get_id().to_owned());
+        }
+
+        let mut deleted_ids = vec![];
+
+        for (bucket, resource_ids) in bucket_resources {
+            let failed_ids = match self
+                .store_backend
+                .delete_many(&bucket, &resource_ids, &log_cx)
+                .await
+            {
+                Err(err) => {
+                    error!(log::get_logger(), "delete bucket {} resources failed: {:?}", bucket, err; &log_cx);
+
+                    resource_ids.clone()
+                }
+
+                Ok(failed_ids) => failed_ids,
+            };
+
+            for resource_id in resource_ids {
+                if failed_ids.contains(&resource_id) {
+                    results.insert(resource_id, DeleteResult::Error);
+                } else {
+                    results.insert(resource_id.clone(), DeleteResult::Deleted);
+                    deleted_ids.push(resource_id);
+                }
+            }
+        }
+
+        if !deleted_ids.is_empty() {
+            if self.has_variants() {
+                self.delete_variants(&deleted_ids, &log_cx).await?;
+            }
+
+            self.db.delete_resources(&deleted_ids, &log_cx).await?;
+
+            self.purge_deleted(&parts.headers, &deleted_ids, &log_cx);
+        }
+
+        info!(
+            log::get_logger(),
+            "delete batch success";
+            log_cx,
+            "deleted" => deleted_ids.len(),
+            "requested" => resource_ids.len()
+        );
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&results)?))?)
+    }
+
+    async fn handle_delete_by_hash(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "delete by hash is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let path_hash = req.uri().path().trim_start_matches(BY_HASH_PATH);
+
+        let hash = match hash::parse_hash(path_hash) {
+            None => {
+                warn!(log::get_logger(), "hash {} is invalid", path_hash; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!(
+                        "hash must be {} hex characters, which may be prefixed with blake3:",
+                        hash::HASH_HEX_LENGTH
+                    ),
+                    &log_cx,
+                )?);
+            }
+
+            Some(hash) => hash,
+        };
+
+        let resource = match self.db.get_resource_by_hash(&hash, &log_cx).await? {
+            None => {
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "resource_not_found",
+                    &format!("resource with hash {} is not found", hash),
+                    &log_cx,
+                )?);
+            }
+
+            Some(resource) => resource,
+        };
+
+        match self
+            .store_backend
+            .delete(resource.get_bucket(), resource.get_id(), &log_cx)
+            .await
+        {
+            // the object is gone already, still remove the dangling row
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+
+            result => result.map_err(StoreFailure::new)?,
+        }
+
+        let resource_ids = [resource.get_id().to_owned()];
+
+        if self.has_variants() {
+            self.delete_variants(&resource_ids, &log_cx).await?;
+        }
+
+        self.db.delete_resources(&resource_ids, &log_cx).await?;
+
+        self.purge_deleted(req.headers(), &resource_ids, &log_cx);
+
+        info!(
+            log::get_logger(),
+            "delete by hash success";
+            log_cx,
+            "resource" => format!("{:?}", resource)
+        );
+
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())?)
+    }
+
+    /// Delete the bucket with its objects and resources, `?empty=false` deletes the objects
+    /// first, otherwise the bucket must be empty. `?dry_run=true` only returns the resources
+    /// which would be deleted, nothing is deleted.
+    async fn handle_delete_bucket(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "delete bucket is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let bucket = req.uri().path().trim_start_matches(BUCKET_PATH);
+
+        let flags = parse_bool_param(req.uri(), "empty", true).and_then(|need_empty| {
+            parse_bool_param(req.uri(), "dry_run", false).map(|dry_run| (need_empty, dry_run))
+        });
+
+        let (need_empty, dry_run) = match flags {
+            Ok(flags) => flags,
+            Err(name) => {
+                warn!(log::get_logger(), "{} of delete bucket is invalid", name; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!("{} must be true or false", name),
+                    &log_cx,
+                )?);
+            }
+        };
+
+        if !is_valid_bucket_name(bucket) {
+            warn!(log::get_logger(), "bucket {:?} is invalid", bucket; &log_cx);
+
+            return Ok(error::error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                "bucket must be [a-z0-9-] characters",
+                &log_cx,
+            )?);
+        }
+
+        if dry_run {
+            let plan = self.delete_bucket_plan(bucket, need_empty, &log_cx).await?;
+
+            info!(
+                log::get_logger(),
+                "delete bucket dry run success";
+                &log_cx,
+                "bucket" => bucket,
+                "need_empty" => need_empty,
+                "would_reject" => plan.would_reject,
+                "total_resources" => plan.total_resources,
+                "total_bytes" => plan.total_bytes
+            );
+
+            return Ok(Response::builder()
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(&plan)?))?);
+        }
+
+        match self
+            .store_backend
+            .delete_bucket(bucket, need_empty, &log_cx)
+            .await
+        {
+            Err(err) if err.kind() == ErrorKind::NotEmpty => {
+                warn!(log::get_logger(), "bucket {} is not empty", bucket; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::CONFLICT,
+                    "bucket_not_empty",
+                    &format!("bucket {} is not empty", bucket),
+                    &log_cx,
+                )?);
+            }
+
+            result => result.map_err(StoreFailure::new)?,
+        }
+
+        let deleted_ids = self.db.delete_resources_by_bucket(bucket, &log_cx).await?;
+        let deleted_resources = deleted_ids.len() as u64;
+
+        self.purge_deleted(req.headers(), &deleted_ids, &log_cx);
+
+        info!(
+            log::get_logger(),
+            "delete bucket success";
+            &log_cx,
+            "bucket" => bucket,
+            "need_empty" => need_empty,
+            "deleted_resources" => deleted_resources
+        );
+
+        let result = DeleteBucketResult {
+            bucket: bucket.to_owned(),
+            deleted_resources,
+        };
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&result)?))?)
+    }
+
+    /// Count the resources of the bucket and list the first ones of them, the bucket may hold too
+    /// many resources to list them all in one response.
+    async fn delete_bucket_plan(
+        &self,
+        bucket: &str,
+        need_empty: bool,
+        log_cx: &LogContext,
+    ) -> Result<DeleteBucketPlan, BoxError> {
+        let usage = self.db.get_bucket_usage(bucket, log_cx).await?;
+        let resources = self
+            .db
+            .list_resources_after(Some(bucket), None, MAX_LIST_LIMIT, log_cx)
+            .await?;
+
+        let total_resources = usage.object_count as u64;
+
+        Ok(DeleteBucketPlan {
+            bucket: bucket.to_owned(),
+            dry_run: true,
+            need_empty,
+            would_reject: need_empty && total_resources > 0,
+            total_resources,
+            total_bytes: usage.total_bytes as u64,
+            resources,
+        })
+    }
+
+    /// Delete the resources created before `?before=`, which is the resource ttl ago by default,
+    /// and their objects now.
+    async fn handle_sweep(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "sweep is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let before = match (parse_time_param(req.uri(), "before"), self.resource_ttl) {
+            (Ok(Some(before)), _) => before,
+
+            (Ok(None), Some(resource_ttl)) => SystemTime::now()
+                .checked_sub(resource_ttl)
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+
+            (Ok(None), None) => {
+                warn!(log::get_logger(), "sweep before is not set"; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    "before is required when the resource ttl is not set",
+                    &log_cx,
+                )?);
+            }
+
+            (Err(name), _) => {
+                warn!(log::get_logger(), "{} of sweep is invalid", name; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!("{} must be unix seconds or rfc3339 time", name),
+                    &log_cx,
+                )?);
+            }
+        };
+
+        let resources = self.db.delete_out_of_date_resources(&before, &log_cx).await?;
+
+        let mut bucket_resources: HashMap<_, Vec<_>> = HashMap::new();
+        for resource in &resources {
+            bucket_resources
+                .entry(resource.get_bucket().to_owned())
+                .or_default()
+                .push(resource.get_id().to_owned());
+        }
+
+        let mut failed_objects = vec![];
+
+        for (bucket, resource_ids) in bucket_resources {
+            let failed_ids = match self
+                .store_backend
+                .delete_many(&bucket, &resource_ids, &log_cx)
+                .await
+            {
+                Err(err) => {
+                    error!(log::get_logger(), "delete bucket {} resources failed: {:?}", bucket, err; &log_cx);
+
+                    resource_ids
+                }
+
+                Ok(failed_ids) => failed_ids,
+            };
+
+            failed_objects.extend(failed_ids.into_iter().map(|id| reconcile::Orphan {
+                bucket: bucket.clone(),
+                id,
+            }));
+        }
+
+        if self.has_variants() {
+            self.delete_resource_variants(&resources, &log_cx).await;
+        }
+
+        let deleted_ids = resources
+            .iter()
+            .map(|resource| resource.get_id().to_owned())
+            .collect::<Vec<_>>();
+
+        self.purge_deleted(req.headers(), &deleted_ids, &log_cx);
+
+        let result = SweepResult {
+            before: before
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs() as i64),
+            deleted_resources: resources.len() as u64,
+            deleted_bytes: resources
+                .iter()
+                .map(|resource| resource.get_resource_size())
+                .sum(),
+            failed_objects,
+        };
+
+        info!(
+            log::get_logger(),
+            "sweep success";
+            &log_cx,
+            "before" => result.before,
+            "deleted_resources" => result.deleted_resources,
+            "deleted_bytes" => result.deleted_bytes,
+            "failed_objects" => result.failed_objects.len()
+        );
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&result)?))?)
+    }
+
+    /// List a page of the resources, the newest first, `?bucket=` only lists the bucket and
+    /// `?from=` and `?to=` only list the resources created in the inclusive time range.
+    async fn handle_list(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "list is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let page = parse_query_param(req.uri(), "limit", DEFAULT_LIST_LIMIT).and_then(|limit| {
+            parse_query_param(req.uri(), "offset", 0).map(|offset| (limit, offset))
+        });
+
+        let (limit, offset) = match page {
+            Ok((limit, offset)) => (limit.min(MAX_LIST_LIMIT), offset),
+            Err(name) => {
+                warn!(log::get_logger(), "{} of list is invalid", name; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!("{} must be a non-negative integer", name),
+                    &log_cx,
+                )?);
+            }
+        };
+
+        let bucket = query_param(req.uri(), "bucket");
+        if let Some(bucket) = bucket {
+            if !is_valid_bucket_name(bucket) {
+                warn!(log::get_logger(), "bucket {:?} is invalid", bucket; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    "bucket must be [a-z0-9-] characters",
+                    &log_cx,
+                )?);
+            }
+        }
+
+        let time_range = parse_time_param(req.uri(), "from")
+            .and_then(|from| parse_time_param(req.uri(), "to").map(|to| (from, to)));
+
+        let (from, to) = match time_range {
+            Ok(time_range) => time_range,
+            Err(name) => {
+                warn!(log::get_logger(), "{} of list is invalid", name; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    &format!("{} must be unix seconds or rfc3339 time", name),
+                    &log_cx,
+                )?);
+            }
+        };
+
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                warn!(log::get_logger(), "from {:?} is later than to {:?}", from, to; &log_cx);
+
+                return Ok(error::error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request",
+                    "from must not be later than to",
+                    &log_cx,
+                )?);
+            }
+        }
+
+        let resources = if from.is_none() && to.is_none() {
+            self.db.list_resources(bucket, limit, offset, &log_cx).await?
+        } else {
+            self.db
+                .list_resources_by_time(bucket, from, to, limit, offset, &log_cx)
+                .await?
+        };
+        let total = self.db.count_resources(bucket, from, to, &log_cx).await?;
+
+        info!(
+            log::get_logger(),
+            "list resources success";
+            &log_cx,
+            "bucket" => bucket,
+            "from" => format!("{:?}", from),
+            "to" => format!("{:?}", to),
+            "limit" => limit,
+            "offset" => offset,
+            "count" => resources.len(),
+            "total" => total
+        );
+
+        let result = ListResult {
+            limit,
+            offset,
+            total,
+            resources,
+        };
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&result)?))?)
+    }
+
+    async fn handle_dedup_stats(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "dedup stats is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let stats = self.db.dedup_stats(&log_cx).await?;
+
+        info!(
+            log::get_logger(),
+            "get dedup stats success";
+            &log_cx,
+            "stats" => format!("{:?}", stats)
+        );
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&stats)?))?)
+    }
+
+    async fn handle_usage(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "usage is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let usage = self.db.bucket_usage(&log_cx).await?;
+
+        info!(
+            log::get_logger(),
+            "get bucket usage success";
+            &log_cx,
+            "buckets" => usage.len()
+        );
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&usage)?))?)
+    }
+
+    /// The consumption of the id counter, only available for the `counter` ids.
+    async fn handle_id_stats(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if let Some(status_code) = self.check_admin(&req) {
+            warn!(log::get_logger(), "id stats is not authorized"; &log_cx);
+
+            return Ok(admin_rejected_response(status_code, &log_cx)?);
+        }
+
+        let stats = match self.id_generator.stats(&log_cx).await? {
+            None => {
+                return Ok(error::error_response(
+                    StatusCode::NOT_FOUND,
+                    "id_stats_not_available",
+                    "id stats are only available for the counter ids",
+                    &log_cx,
+                )?);
+            }
+
+            Some(stats) => stats,
+        };
+
+        info!(
+            log::get_logger(),
+            "get id stats success";
+            &log_cx,
+            "id_value" => stats.id_value,
+            "consumed" => stats.consumed
+        );
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&stats)?))?)
+    }
+
+    /// Ready once the upload bucket is created, the creation failed at startup is retried here, so
+    /// the instance becomes ready after the backend is back.
+    async fn handle_ready(&self, req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let log_cx = log_context(&req);
+
+        if !self.bucket_ready.load(Ordering::Acquire)
+            && !precreate_bucket(
+                &*self.store_backend,
+                &self.db,
+                &self.bucket_strategy,
+                &self.bucket_ready,
+                &log_cx,
+            )
+            .await
+        {
+            return Ok(error::error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "not_ready",
+                "upload bucket is not created",
+                &log_cx,
+            )?);
+        }
+
+        Ok(Response::new(Body::from("ready")))
+    }
+
+    /// Post the urls of the deleted resources to the CDN purge url in the background.
+    fn purge_deleted(&self, headers: &HeaderMap, resource_ids: &[String], log_cx: &LogContext) {
+        let cdn_purge = match &self.cdn_purge {
+            None => return,
+            Some(cdn_purge) => cdn_purge,
+        };
+
+        // the urls are the same as the ones returned by the uploads through the same origin
+        let (scheme, host) = match self.origin(headers) {
+            Err(err) => {
+                warn!(log::get_logger(), "get origin of purge urls failed: {}", err; log_cx);
+
+                return;
+            }
+
+            Ok(origin) => origin,
+        };
+
+        let urls = resource_ids
+            .iter()
+            .filter_map(|resource_id| self.resource_uri(&scheme, &host, resource_id).ok())
+            .collect();
+
+        cdn_purge.purge(urls, log_cx.clone());
+    }
+
+    /// The bucket of the new resources, named by the bucket strategy and rolled over by the
+    /// `max_objects_per_bucket`.
+    async fn upload_bucket(&self, log_cx: &LogContext) -> anyhow::Result<String> {
+        self.db
+            .upload_bucket(&self.bucket_strategy.base_bucket(), log_cx)
+            .await
+    }
+
+    /// The transcoded or watermarked variants are saved besides the resources.
+    fn has_variants(&self) -> bool {
+        self.transcode || self.watermark.is_some()
+    }
+
+    /// Delete the transcoded and watermarked variants of the resources, the missing variants are
+    /// ignored.
+    async fn delete_variants(
+        &self,
+        resource_ids: &[String],
+        log_cx: &LogContext,
+    ) -> Result<(), BoxError> {
+        let resources = self.db.get_resources_by_ids(resource_ids, log_cx).await?;
+
+        self.delete_resource_variants(&resources, log_cx).await;
+
+        Ok(())
+    }
+
+    /// Like `delete_variants`, but the resources may be deleted from the db already.
+    async fn delete_resource_variants(&self, resources: &[Resource], log_cx: &LogContext) {
+        for resource in resources {
+            let transcoded_ids = [TranscodeFormat::Webp]
+                .iter()
+                .filter(|_| self.transcode)
+                .map(|format| transcoded_variant_id(resource.get_id(), *format));
+
+            // only the variant of the current watermark is known
+            let watermarked_id = self
+                .watermark
+                .as_ref()
+                .map(|watermark| watermarked_variant_id(resource.get_id(), watermark));
+
+            for variant_id in transcoded_ids.chain(watermarked_id) {
+                match self
+                    .store_backend
+                    .delete(resource.get_bucket(), &variant_id, log_cx)
+                    .await
+                {
+                    Err(err) if err.kind() != ErrorKind::NotFound => {
+                        warn!(log::get_logger(), "delete variant {} failed: {:?}", variant_id, err; log_cx);
+                    }
+
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// The scheme and host of the resource urls.
+    fn origin(&self, headers: &HeaderMap) -> Result<(String, String), BoxError> {
+        let from_trusted_proxy = self
+            .remote_addr
+            .is_some_and(|addr| self.trusted_proxies.is_trusted(addr.ip()));
+
+        resource_origin(
+            headers,
+            from_trusted_proxy,
+            &self.default_scheme,
+            &self.domain,
+        )
+    }
+
+    fn resource_uri(&self, scheme: &str, host: &str, resource_id: &str) -> Result<String, BoxError> {
+        Ok(Uri::builder()
+            .scheme(scheme)
+            .authority(host)
+            .path_and_query(format!("{}/{}", self.get_path, resource_id))
+            .build()?
+            .to_string())
+    }
+
+    /// Return the rejected status code if the request is not from admin.
+    fn check_admin(&self, req: &Request<Body>) -> Option<StatusCode> {
+        let admin_token = match &self.admin_token {
+            // admin api is disabled when no token is configured
+            None => return Some(StatusCode::FORBIDDEN),
+            Some(admin_token) => admin_token,
+        };
+
+        let token = req
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        if token.is_some_and(|token| token_eq(token, admin_token)) {
+            None
+        } else {
+            Some(StatusCode::UNAUTHORIZED)
+        }
+    }
+
+    /// `If-None-Match` takes precedence over `If-Modified-Since`. The original tag always
+    /// matches, because the original is served when the variant can't be made.
+    fn not_modified_response(
+        &self,
+        req: &Request<Body>,
+        resource: &Resource,
+        variant: Option<&str>,
+    ) -> Result<Option<Response<Body>>, BoxError> {
+        let last_modified = resource.get_create_time();
+        let original_etag = conditional::etag(resource.get_hash(), None);
+        let variant_etag = variant.map(|variant| conditional::etag(resource.get_hash(), Some(variant)));
+
+        let etag = match req
+            .headers()
+            .get("if-none-match")
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(if_none_match) => {
+                match variant_etag
+                    .into_iter()
+                    .chain(Some(original_etag))
+                    .find(|etag| conditional::etag_matches(if_none_match, etag))
+                {
+                    None => return Ok(None),
+                    Some(etag) => etag,
+                }
+            }
+
+            None => {
+                let not_modified = req
+                    .headers()
+                    .get("if-modified-since")
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|since| conditional::is_not_modified(since, last_modified));
+
+                if !not_modified {
+                    return Ok(None);
+                }
+
+                variant_etag.unwrap_or(original_etag)
+            }
+        };
+
+        let mut resp_builder = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header("cache-control", self.cache_control.as_str())
+            .header("last-modified", conditional::http_date(last_modified))
+            .header("etag", etag);
+
+        if self.vary_accept(resource) {
+            resp_builder = resp_builder.header("vary", "accept");
+        }
+
+        Ok(Some(resp_builder.body(Body::empty())?))
+    }
+}
+
+/// The presigned upload, the browser posts the form fields with the file to `form.url`, then
+/// the upload is completed with the id and bucket.
+#[derive(Debug, Serialize)]
+struct UploadPolicy {
+    id: String,
+    bucket: String,
+    resource_url: String,
+    /// Unix timestamp in seconds.
+    expires_at: i64,
+    form: PresignedPost,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadPolicyComplete {
+    id: String,
+    bucket: String,
+}
+
+/// The body of the reconcile request, the missing fields use the default options.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ReconcileRequest {
+    bucket: Option<String>,
+    delete: bool,
+    batch_size: Option<usize>,
+    /// In seconds.
+    min_age: Option<u64>,
+}
+
+/// The link issued by the sign api.
+#[derive(Debug, Serialize)]
+struct SignedUrl {
+    url: String,
+    /// Unix timestamp in seconds.
+    expires_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct DeleteBucketResult {
+    bucket: String,
+    deleted_resources: u64,
+}
+
+/// The resources which would be deleted with the bucket, `need_empty` is the real deleting
+/// flag, the bucket with resources is rejected when it's true, which `would_reject` tells. At
+/// most `MAX_LIST_LIMIT` resources are listed, the totals count all of them.
+#[derive(Debug, Serialize)]
+struct DeleteBucketPlan {
+    bucket: String,
+    dry_run: bool,
+    need_empty: bool,
+    would_reject: bool,
+    total_resources: u64,
+    total_bytes: u64,
+    resources: Vec<Resource>,
+}
+
+/// The resources deleted by the sweep, the objects which are failed to delete are left as the
+/// orphans of the reconcile.
+#[derive(Debug, Serialize)]
+struct SweepResult {
+    before: i64,
+    deleted_resources: u64,
+    deleted_bytes: u64,
+    failed_objects: Vec<reconcile::Orphan>,
+}
+
+/// A page of the resources, the total counts all the resources matching the bucket filter.
+#[derive(Debug, Serialize)]
+struct ListResult {
+    limit: usize,
+    offset: usize,
+    total: u64,
+    resources: Vec<Resource>,
+}
+
+/// The body of the copy request, the destination id is generated when it's not set.
+#[derive(Debug, Deserialize)]
+struct CopyRequest {
+    source: String,
+    destination: Option<String>,
+}
+
+#[derive(Debug)]
+enum StoredUpload {
+    Created(Resource),
+    /// The same content is stored already.
+    Exist(Resource),
+    /// The upload is not accepted, contains the error response.
+    Rejected(Response<Body>),
+}
+
+#[derive(Debug)]
+enum DedupLookup {
+    Hit(Resource),
+    /// The resource of the same hash holds different content.
+    Collision,
+    Miss,
+}
+
+#[derive(Debug)]
+enum ClientIdUpload {
+    Created(Resource),
+    /// The id holds the same content.
+    Exist(Resource),
+    /// The id holds different content.
+    Conflict,
+    QuotaExceeded,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum DeleteResult {
+    Deleted,
+    NotFound,
+    Error,
+}
+
+/// Return the scheme and host of the resource url, the `X-Forwarded-*` headers are only used when
+/// the request is from a trusted proxy.
+fn resource_origin(
+    headers: &HeaderMap,
+    from_trusted_proxy: bool,
+    default_scheme: &str,
+    domain: &str,
+) -> Result<(String, String), BoxError> {
+    // the proxies may append their values, the first one is from the client side proxy
+    let forwarded = |name: &str| -> Result<Option<String>, BoxError> {
+        if !from_trusted_proxy {
+            return Ok(None);
+        }
+
+        match headers.get(name) {
+            None => Ok(None),
+            Some(value) => Ok(value
+                .to_str()?
+                .split(',')
+                .map(str::trim)
+                .find(|value| !value.is_empty())
+                .map(str::to_owned)),
+        }
+    };
+
+    let scheme = match forwarded("x-forwarded-proto")? {
+        Some(scheme) if scheme.eq_ignore_ascii_case("http") => "http".to_owned(),
+        Some(scheme) if scheme.eq_ignore_ascii_case("https") => "https".to_owned(),
+        _ => default_scheme.to_owned(),
+    };
+
+    let host = match forwarded("x-forwarded-host")? {
+        Some(host) => host,
+        None => match headers.get("host") {
+            Some(host) => host.to_str()?.to_owned(),
+            None => domain.to_owned(),
+        },
+    };
+
+    Ok((scheme, host))
+}
+
+/// Return the uri without the api version prefix, or `None` if the uri isn't versioned.
+fn strip_api_version(uri: &Uri) -> Option<Uri> {
+    let path = uri.path().strip_prefix(API_VERSION_PREFIX)?;
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let path_and_query = match uri.query() {
+        None => path.to_owned(),
+        Some(query) => format!("{}?{}", path, query),
+    };
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+
+    Uri::from_parts(parts).ok()
+}
+
+/// The value of the first query parameter with the name, the value is not percent decoded.
+fn query_param<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?.split('&').find_map(|param| {
+        let mut pair = param.splitn(2, '=');
+
+        if pair.next()? == name {
+            Some(pair.next().unwrap_or(""))
+        } else {
+            None
+        }
+    })
+}
+
+/// Parse the unix seconds or the rfc3339 time, the times before the unix epoch are invalid. The
+/// value is not percent decoded, so the `+` of the offset is kept as is.
+fn parse_unix_time(value: &str) -> Option<SystemTime> {
+    let secs = if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+        value.parse::<u64>().ok()?
+    } else {
+        let timestamp = DateTime::parse_from_rfc3339(value).ok()?.timestamp();
+        if timestamp < 0 {
+            return None;
+        }
+
+        timestamp as u64
+    };
+
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// Parse the time query parameter, the error is the name of the invalid parameter.
+fn parse_time_param<'a>(uri: &Uri, name: &'a str) -> Result<Option<SystemTime>, &'a str> {
+    match query_param(uri, name) {
+        None => Ok(None),
+        Some(value) => parse_unix_time(value).map(Some).ok_or(name),
+    }
+}
+
+/// Parse the query parameter, or use the default when it's missing. The error is the name of the
+/// invalid parameter.
+fn parse_query_param<'a, T: FromStr>(uri: &Uri, name: &'a str, default: T) -> Result<T, &'a str> {
+    match query_param(uri, name) {
+        None => Ok(default),
+        Some(value) => value.parse().map_err(|_| name),
+    }
+}
+
+/// Parse the `true`, `1`, `false` or `0` query parameter, or use the default when it's missing.
+/// The error is the name of the invalid parameter.
+fn parse_bool_param<'a>(uri: &Uri, name: &'a str, default: bool) -> Result<bool, &'a str> {
+    match query_param(uri, name) {
+        None => Ok(default),
+        Some("true") | Some("1") => Ok(true),
+        Some("false") | Some("0") => Ok(false),
+        Some(_) => Err(name),
+    }
+}
+
+/// Check the query has the flag like `name=1` or `name=true`, the invalid value is not set.
+fn has_query_flag(uri: &Uri, name: &str) -> bool {
+    parse_bool_param(uri, name, false).unwrap_or(false)
+}
+
+/// The `content-disposition` of the resource, `?download=1` asks the browser to save it.
+fn resource_disposition(uri: &Uri, resource: &Resource) -> Option<String> {
+    let download = has_query_flag(uri, "download");
+
+    match resource.get_filename() {
+        Some(filename) => Some(disposition::content_disposition(filename, download)),
+        None if download => Some(disposition::content_disposition(resource.get_id(), true)),
+        None => None,
+    }
+}
+
+/// The body size limits of the routes, the versioned paths share the limits.
+fn size_limits(
+    max_body_size: u64,
+    upload_path: &str,
+    upload_max_body_size: Option<u64>,
+    batch_max_body_size: Option<u64>,
+) -> SizeLimits {
+    let mut size_limits = SizeLimits::new(max_body_size);
+
+    for prefix in &["", API_VERSION_PREFIX] {
+        let upload_path = format!("{}{}", prefix, upload_path);
+        let batch_path = format!("{}{}", prefix, DELETE_BATCH_PATH);
+        let tus_path = format!("{}{}", prefix, tus::TUS_PATH);
+
+        // the tus chunks are parts of the uploads
+        if let Some(max_size) = upload_max_body_size {
+            size_limits
+                .add_route(Method::POST, &upload_path, max_size)
+                .add_route(Method::PUT, &upload_path, max_size)
+                .add_route(Method::PATCH, &tus_path, max_size);
+        }
+
+        if let Some(max_size) = batch_max_body_size {
+            size_limits.add_route(Method::POST, &batch_path, max_size);
+        }
+    }
+
+    size_limits
+}
+
+/// Create the current upload bucket and mark the handler ready, return false if it fails.
+async fn precreate_bucket<S: StoreBackend + Sync>(
+    store_backend: &S,
+    db: &Database,
+    bucket_strategy: &BucketStrategy,
+    bucket_ready: &AtomicBool,
+    log_cx: &LogContext,
+) -> bool {
+    let bucket = match db.upload_bucket(&bucket_strategy.base_bucket(), log_cx).await {
+        Err(err) => {
+            warn!(log::get_logger(), "select upload bucket failed: {}", err; log_cx);
+
+            return false;
+        }
+
+        Ok(bucket) => bucket,
+    };
+
+    if let Err(err) = store_backend.create_bucket(&bucket, log_cx).await {
+        warn!(log::get_logger(), "precreate bucket {} failed: {}", bucket, err; log_cx);
+
+        return false;
+    }
+
+    bucket_ready.store(true, Ordering::Release);
+
+    info!(log::get_logger(), "upload bucket is created"; log_cx, "bucket" => &bucket);
+
+    true
+}
+
+/// Check the upload and get path prefixes, they are matched by prefix, so they must not overlap
+/// with each other or the fixed paths.
+pub fn check_path_prefixes(upload_path: &str, get_path: &str) -> Result<(), String> {
+    for (name, path) in &[("upload_path", upload_path), ("get_path", get_path)] {
+        if !path.starts_with('/') || path.ends_with('/') {
+            return Err(format!("{} {:?} must start with / and not end with /", name, path));
+        }
+    }
+
+    let overlap = |a: &str, b: &str| a.starts_with(b) || b.starts_with(a);
+
+    if overlap(upload_path, get_path) {
+        return Err(format!(
+            "upload_path {:?} overlaps get_path {:?}",
+            upload_path, get_path
+        ));
+    }
+
+    for (name, path) in &[("upload_path", upload_path), ("get_path", get_path)] {
+        for fixed_path in &[
+            META_PATH,
+            DELETE_BATCH_PATH,
+            DEDUP_STATS_PATH,
+            USAGE_PATH,
+            ID_STATS_PATH,
+            BY_HASH_PATH,
+            HASH_PATH,
+            COPY_PATH,
+            RECONCILE_PATH,
+            BUCKET_PATH,
+            LIST_PATH,
+            READY_PATH,
+            SWEEP_PATH,
+            SIGN_PATH,
+            tus::TUS_PATH,
+            API_VERSION_PREFIX,
+        ] {
+            if overlap(path, fixed_path) {
+                return Err(format!("{} {:?} overlaps {:?}", name, path, fixed_path));
+            }
+        }
+
+        // the upload policy paths are matched exactly before the prefixes, so only the paths
+        // under them are shadowed
+        if path
+            .strip_prefix(UPLOAD_POLICY_PATH)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        {
+            return Err(format!("{} {:?} overlaps {:?}", name, path, UPLOAD_POLICY_PATH));
+        }
+    }
+
+    Ok(())
+}
+
+fn range_not_satisfiable(
+    resource: &Resource,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let mut resp = error::error_response(
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        "range_not_satisfiable",
+        &format!("range is out of the resource size {}", resource.get_resource_size()),
+        log_cx,
+    )?;
+
+    resp.headers_mut().insert(
+        "content-range",
+        HeaderValue::from_str(&format!("bytes */{}", resource.get_resource_size()))
+            .expect("content range must be valid"),
+    );
+
+    Ok(resp)
+}
+
+/// The client ids can't contain `.`, which is used by the transcoded variants, or look like the
+/// ids of the encoding, which would break the later uploads.
+fn is_valid_client_id(resource_id: &str, encoding: IdEncoding) -> bool {
+    !resource_id.is_empty()
+        && resource_id.len() <= MAX_RESOURCE_ID_LENGTH
+        && resource_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && !encoding.is_generated(resource_id)
+}
+
+/// The names accepted by both cos and the memory backend.
+fn is_valid_bucket_name(bucket: &str) -> bool {
+    !bucket.is_empty()
+        && bucket
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+/// The buckets are named by the upload month, like `2021-01`, the rolled over ones have a
+/// suffix, like `2021-01-1`.
+fn is_valid_bucket(bucket: &str) -> bool {
+    let month = match bucket.get(..7) {
+        None => return false,
+        Some(month) => month,
+    };
+
+    is_valid_rollover_suffix(&bucket[7..])
+        && NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d").is_ok()
+}
+
+/// The suffix after the base bucket, empty or like `-1`.
+fn is_valid_rollover_suffix(suffix: &str) -> bool {
+    match suffix.strip_prefix('-') {
+        None => suffix.is_empty(),
+        Some(suffix) => {
+            !suffix.is_empty()
+                && !suffix.starts_with('0')
+                && suffix.bytes().all(|b| b.is_ascii_digit())
+        }
+    }
+}
+
+/// How the upload buckets are named, the buckets of both are rolled over by the
+/// `max_objects_per_bucket`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum BucketStrategy {
+    /// Named by the upload month, like `2021-01`.
+    Monthly,
+    /// All the uploads go to the bucket.
+    Fixed(String),
+}
+
+impl BucketStrategy {
+    /// The bucket of the new uploads before it's rolled over.
+    fn base_bucket(&self) -> String {
+        match self {
+            BucketStrategy::Monthly => Local::now().format("%Y-%m").to_string(),
+            BucketStrategy::Fixed(bucket) => bucket.clone(),
+        }
+    }
+
+    /// Check the bucket may be an upload bucket, including the rolled over ones.
+    fn is_upload_bucket(&self, bucket: &str) -> bool {
+        match self {
+            BucketStrategy::Monthly => is_valid_bucket(bucket),
+            BucketStrategy::Fixed(fixed_bucket) => bucket
+                .strip_prefix(fixed_bucket.as_str())
+                .is_some_and(is_valid_rollover_suffix),
+        }
+    }
+}
+
+/// Check the fixed bucket is a valid bucket name, it must not end with `-`, so it can't be
+/// confused with the rolled over ones.
+pub fn check_fixed_bucket(bucket: &str) -> Result<(), String> {
+    if !is_valid_bucket_name(bucket) || bucket.starts_with('-') || bucket.ends_with('-') {
+        return Err(format!(
+            "fixed_bucket {:?} must be [a-z0-9-] characters and not start or end with -",
+            bucket
+        ));
+    }
+
+    Ok(())
+}
+
+/// The longest common prefix of the allowed content types, the presigned policy can only limit
+/// the content type by prefix, the exact type is checked when the upload is completed.
+fn common_prefix(content_types: &[String]) -> Option<String> {
+    let (first, rest) = content_types.split_first()?;
+
+    let prefix = rest.iter().fold(first.as_str(), |prefix, content_type| {
+        let len = prefix
+            .char_indices()
+            .zip(content_type.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map_or(0, |((i, c), _)| i + c.len_utf8());
+
+        &prefix[..len]
+    });
+
+    Some(prefix.to_owned())
+}
+
+fn method_not_allowed(
+    method: &Method,
+    allow: &str,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let mut resp = error::error_response(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        &format!("method {} is not allowed, allowed methods: {}", method, allow),
+        log_cx,
+    )?;
+
+    resp.headers_mut().insert(
+        "allow",
+        HeaderValue::from_str(allow).expect("allowed methods must be valid header value"),
+    );
+
+    Ok(resp)
+}
+
+/// Serve the placeholder image if it's configured, otherwise 403, the response is not cached
+/// since it depends on the referer.
+fn hotlink_rejected_response(
+    hotlink_protection: &HotlinkProtection,
+    req: &Request<Body>,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, hyper::http::Error> {
+    warn!(
+        log::get_logger(),
+        "hotlink is rejected";
+        log_cx,
+        "referer" => format!("{:?}", req.headers().get("referer"))
+    );
+
+    match hotlink_protection.placeholder() {
+        None => error::error_response(
+            StatusCode::FORBIDDEN,
+            "hotlink_forbidden",
+            "the referer is not allowed",
+            log_cx,
+        ),
+
+        Some(placeholder) => Response::builder()
+            .header("content-type", placeholder.content_type)
+            .header("cache-control", "no-store")
+            .body(Body::from(placeholder.data.clone())),
+    }
+}
+
+/// Read the body into the buffer and hash the chunks when they arrive, so the data isn't passed
+/// again for the hash after reading. The compressed body is decompressed first.
+async fn read_body_hashed(
+    mut body: Body,
+    hash_algorithm: HashAlgorithm,
+    mut buffer: SpillBuffer<'_>,
+    mut decoder: Option<GzipDecoder>,
+) -> Result<(UploadBody, String), BoxError> {
+    let mut hasher = hash_algorithm.hasher();
+
+    while let Some(chunk) = body.data().await {
+        let mut chunk = chunk?;
+
+        if let Some(decoder) = &mut decoder {
+            chunk = decoder.decode(&chunk)?;
+        }
+
+        hasher.update(&chunk);
+        buffer.write(&chunk).await?;
+    }
+
+    if let Some(decoder) = decoder {
+        let rest = decoder.finish()?;
+
+        hasher.update(&rest);
+        buffer.write(&rest).await?;
+    }
+
+    Ok((buffer.finish().await?, hasher.finalize()))
+}
+
+fn uploads_saturated_response(
+    log_cx: &LogContext,
+) -> Result<Response<Body>, hyper::http::Error> {
+    warn!(log::get_logger(), "concurrent uploads are saturated"; log_cx);
+
+    let mut resp = error::error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "uploads_saturated",
+        "too many uploads in progress, please retry later",
+        log_cx,
+    )?;
+
+    resp.headers_mut().insert(
+        "retry-after",
+        HeaderValue::from(UPLOADS_SATURATED_RETRY_AFTER),
+    );
+
+    Ok(resp)
+}
+
+fn dedup_status(created: bool) -> &'static str {
+    if created {
+        "miss"
+    } else {
+        "hit"
+    }
+}
+
+fn quota_exceeded_response(
+    size: u64,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, hyper::http::Error> {
+    warn!(log::get_logger(), "storage quota is exceeded"; log_cx, "size" => size);
+
+    error::error_response(
+        StatusCode::INSUFFICIENT_STORAGE,
+        "insufficient_storage",
+        "storage quota is exceeded",
+        log_cx,
+    )
+}
+
+fn tus_version_rejected(log_cx: &LogContext) -> Result<Response<Body>, hyper::http::Error> {
+    warn!(log::get_logger(), "tus version is not supported"; log_cx);
+
+    let mut resp = error::error_response(
+        StatusCode::PRECONDITION_FAILED,
+        "unsupported_tus_version",
+        &format!("only tus {} is supported", tus::TUS_VERSION),
+        log_cx,
+    )?;
+
+    resp.headers_mut().insert(
+        tus::TUS_VERSION_HEADER,
+        HeaderValue::from_static(tus::TUS_VERSION),
+    );
+
+    Ok(resp)
+}
+
+fn tus_upload_not_found(
+    upload_id: &str,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, hyper::http::Error> {
+    warn!(log::get_logger(), "tus upload {} is not found", upload_id; log_cx);
+
+    error::error_response(
+        StatusCode::NOT_FOUND,
+        "upload_not_found",
+        &format!("upload {} is not found", upload_id),
+        log_cx,
+    )
+}
+
+/// The client should `HEAD` the upload to get the right offset.
+fn tus_offset_mismatch(
+    offset: u64,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, hyper::http::Error> {
+    warn!(log::get_logger(), "tus upload offset is mismatched"; log_cx, "offset" => offset);
+
+    error::error_response(
+        StatusCode::CONFLICT,
+        "upload_offset_mismatch",
+        &format!("upload offset is {}", offset),
+        log_cx,
+    )
+}
+
+/// The upload id in the `/files/<id>` path.
+fn tus_upload_id(path: &str) -> &str {
+    path[tus::TUS_PATH.len()..].trim_start_matches('/')
+}
+
+fn transcoded_variant_id(resource_id: &str, format: TranscodeFormat) -> String {
+    format!("{}.{}", resource_id, format.extension())
+}
+
+/// The variant tagged in the etag of the download, `None` if the original is served.
+fn variant_tag(
+    watermark: Option<&Arc<Watermark>>,
+    transcode_format: Option<TranscodeFormat>,
+) -> Option<&str> {
+    match watermark {
+        Some(watermark) => Some(watermark.variant()),
+        None => transcode_format.map(TranscodeFormat::extension),
+    }
+}
+
+fn watermarked_variant_id(resource_id: &str, watermark: &Watermark) -> String {
+    format!("{}.{}", resource_id, watermark.variant())
+}
+
+/// Compare the tokens in constant time, so the admin token can't be guessed byte by byte from
+/// the response time. The digests are compared, so the length is not leaked either.
+fn token_eq(token: &str, expected: &str) -> bool {
+    let token = Sha256::digest(token.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+
+    token
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+fn admin_rejected_response(
+    status_code: StatusCode,
+    log_cx: &LogContext,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let (code, message) = if status_code == StatusCode::FORBIDDEN {
+        ("admin_disabled", "admin token is not configured")
+    } else {
+        ("unauthorized", "admin token is invalid")
+    };
+
+    error::error_response(status_code, code, message, log_cx)
+}
+
+fn cache_control(max_age: u64) -> String {
+    // resources are content-addressed and never mutate, so caches can keep them forever
+    format!("public, max-age={}, immutable", max_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::env;
+
+    use sha2::{Digest, Sha256};
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::http::decompress;
+    use crate::http::error::ErrorResponse;
+    use crate::http::webhook;
+    use crate::store::cos::CosBackend;
+    use crate::store::memory::MemoryBackend;
+    use crate::store::traced::TracedBackend;
+    use crate::telemetry;
+
+    use super::*;
+
+    async fn new_test_handler() -> Handler<CosBackend> {
+        let access_key = env::var("COS_ACCESS_KEY").expect("need set COS_ACCESS_KEY env");
+        let secret_key = env::var("COS_SECRET_KEY").expect("need set COS_SECRET_KEY env");
+        let region = env::var("COS_REGION").expect("need set COS_REGION env");
+        let app_id = env::var("COS_APP_ID").expect("need set COS_APP_ID env");
+
+        let store_backend = CosBackend::new(&access_key, &secret_key, &region, &app_id);
+
+        new_test_handler_with(store_backend).await
+    }
+
+    /// Only need postgres, the resources are kept in memory.
+    async fn new_memory_test_handler() -> Handler<MemoryBackend> {
+        new_test_handler_with(MemoryBackend::new()).await
+    }
+
+    async fn new_test_handler_with<S: StoreBackend>(store_backend: S) -> Handler<S> {
+        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
+        let id_type = env::var("ID_TYPE").expect("must set environment ID_TYPE");
+
+        let pg_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&pg_uri)
+            .await
+            .unwrap();
+
+        // the tables of the newer migrations may be absent in the test database
+        migrate::run(&pg_pool).await.unwrap();
+
+        let id_generator = Arc::new(Generator::new(&pg_pool, &id_type, 0).await.unwrap());
+        let db = Database::new(&pg_pool).await.unwrap();
+
+        Handler {
+            store_backend: Arc::new(store_backend),
+            id_generator,
+            db,
+            domain: Arc::new("test.com".to_string()),
+            size_limits: SizeLimits::new(10 * 1024 * 1024),
+            body_read_timeout: DEFAULT_BODY_READ_TIMEOUT,
+            request_timeout: None,
+            strip_exif: false,
+            cache_control: Arc::new(cache_control(DEFAULT_CACHE_CONTROL_MAX_AGE)),
+            access_log: false,
+            compression: true,
+            request_id_header: HeaderName::from_static("x-image-bed-request-id"),
+            admin_token: Some(Arc::new("test-token".to_string())),
+            default_scheme: Arc::new("https".to_string()),
+            trusted_proxies: Arc::new(TrustedProxies::default()),
+            ip_filter: None,
+            allowed_content_types: Arc::new(vec![]),
+            max_image_width: None,
+            max_image_height: None,
+            upload_path: Arc::new(DEFAULT_UPLOAD_PATH.to_string()),
+            get_path: Arc::new(DEFAULT_GET_PATH.to_string()),
+            transcode: false,
+            verify_on_read: false,
+            verify_dedup_bytes: DedupVerify::Off,
+            hash_algorithm: HashAlgorithm::Sha256,
+            default_content_type: Arc::new(media::DEFAULT_CONTENT_TYPE.to_string()),
+            extension_content_type: true,
+            upload_webhook: None,
+            cdn_purge: None,
+            hotlink_protection: None,
+            watermark: None,
+            watermark_always: false,
+            url_signer: None,
+            bucket_ready: Arc::new(AtomicBool::new(true)),
+            bucket_strategy: Arc::new(BucketStrategy::Monthly),
+            resource_ttl: None,
+            temp_dir: Arc::new(env::temp_dir()),
+            memory_spill_threshold: None,
+            upload_permits: None,
+            cors: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn read_body_hashed_chunks() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        for hash_algorithm in &[HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            let chunks = data
+                .chunks(4096)
+                .map(|chunk| Ok::<_, Infallible>(Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>();
+            let body = Body::wrap_stream(futures_util::stream::iter(chunks));
+
+            let temp_dir = env::temp_dir();
+            let buffer = SpillBuffer::new(&temp_dir, None, 0);
+            let (read_data, hash) = read_body_hashed(body, *hash_algorithm, buffer, None)
+                .await
+                .unwrap();
+
+            assert_eq!(read_data.into_bytes().await.unwrap(), data);
+            assert_eq!(hash, hash_algorithm.hash(&data));
+        }
+    }
+
+    #[test]
+    fn resource_origin_direct() {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "image.test.com".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        headers.insert("x-forwarded-host", "evil.com".parse().unwrap());
+
+        assert_eq!(
+            resource_origin(&headers, false, "https", "test.com").unwrap(),
+            ("https".to_string(), "image.test.com".to_string())
+        );
+
+        assert_eq!(
+            resource_origin(&HeaderMap::new(), false, "http", "test.com").unwrap(),
+            ("http".to_string(), "test.com".to_string())
+        );
+    }
+
+    #[test]
+    fn resource_origin_proxied() {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "127.0.0.1:8080".parse().unwrap());
+        headers.insert("x-forwarded-proto", "HTTP, https".parse().unwrap());
+        headers.insert("x-forwarded-host", "image.test.com".parse().unwrap());
+
+        assert_eq!(
+            resource_origin(&headers, true, "https", "test.com").unwrap(),
+            ("http".to_string(), "image.test.com".to_string())
+        );
+
+        headers.remove("x-forwarded-host");
+        headers.insert("x-forwarded-proto", "ftp".parse().unwrap());
+
+        assert_eq!(
+            resource_origin(&headers, true, "https", "test.com").unwrap(),
+            ("https".to_string(), "127.0.0.1:8080".to_string())
+        );
+    }
+
+    #[test]
+    fn cache_control_max_age() {
+        assert_eq!(cache_control(3600), "public, max-age=3600, immutable");
+    }
+
+    #[tokio::test]
+    async fn get_resource_cache_control() {
+        let mut handler = new_test_handler().await;
+        handler.cache_control = Arc::new(cache_control(60));
+
+        let mut post_req = Request::new(Body::from(&b"test"[..]));
+        *post_req.method_mut() = Method::POST;
+        *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+        let mut handle = handler.call(()).await.unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data);
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(
+            get_resp.headers()["cache-control"],
+            "public, max-age=60, immutable"
+        );
+    }
+
+    #[tokio::test]
+    async fn put_resource() {
+        let mut handler = new_test_handler().await;
+
+        let data = b"test";
+
+        let mut req = Request::new(Body::from(&data[..]));
+        *req.method_mut() = Method::POST;
+        *req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+        let mut handle = handler.call(()).await.unwrap();
+
+        let mut resp = handle.call(req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        eprintln!(
+            "result {}",
+            String::from_utf8_lossy(&body::to_bytes(resp.body_mut()).await.unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn get_resource() {
+        let mut handler = new_test_handler().await;
+
+        let data = b"test";
+
+        let mut post_req = Request::new(Body::from(&data[..]));
+        *post_req.method_mut() = Method::POST;
+        *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+        let mut handle = handler.call(()).await.unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::OK);
+
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data);
+        eprintln!("get uri {}", get_uri);
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        eprintln!("get resp {:?}", get_resp);
+
+        assert_eq!(body::to_bytes(get_resp).await.unwrap().as_ref(), b"test");
+    }
+
+    #[tokio::test]
+    async fn strip_exif_on_upload() {
+        const EXIF: &[u8] = b"Exif\0\0II*\0\x08\0\0\0\0\0";
+
+        let mut handler = new_test_handler().await;
+        handler.strip_exif = true;
+
+        let random = rand::random::<u64>().to_be_bytes();
+
+        let mut data = vec![0xff, 0xd8, 0xff, 0xe1];
+        data.extend_from_slice(&((EXIF.len() + 2) as u16).to_be_bytes());
+        data.extend_from_slice(EXIF);
+        // make sure the image is never deduped with an earlier run
+        data.extend_from_slice(&[0xff, 0xfe, 0x00, 0x0a]);
+        data.extend_from_slice(&random);
+        data.extend_from_slice(&[0xff, 0xd9]);
+
+        let mut post_req = Request::new(Body::from(data));
+        *post_req.method_mut() = Method::POST;
+        *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+        let mut handle = handler.call(()).await.unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::OK);
+
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data);
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+        let stored = body::to_bytes(get_resp).await.unwrap();
+
+        assert!(!stored.windows(4).any(|window| window == b"Exif"));
+        assert!(stored.windows(random.len()).any(|window| window == random));
+    }
+
+    #[tokio::test]
+    async fn delete_batch() {
+        let mut handler = new_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = rand::random::<u64>().to_be_bytes();
+
+        let mut post_req = Request::new(Body::from(data.to_vec()));
+        *post_req.method_mut() = Method::POST;
+        *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+
+        let delete_body = serde_json::to_vec(&[resource_id.as_str(), "not-exist"]).unwrap();
+
+        let unauthorized_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/delete-batch")
+            .body(Body::from(delete_body.clone()))
+            .unwrap();
+
+        let unauthorized_resp = handle.call(unauthorized_req).await.unwrap();
+
+        assert_eq!(unauthorized_resp.status(), StatusCode::UNAUTHORIZED);
+
+        let delete_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/delete-batch")
+            .header("authorization", "Bearer test-token")
+            .body(Body::from(delete_body))
+            .unwrap();
+
+        let delete_resp = handle.call(delete_req).await.unwrap();
+
+        assert_eq!(delete_resp.status(), StatusCode::OK);
+
+        let results: HashMap<String, String> =
+            serde_json::from_slice(&body::to_bytes(delete_resp).await.unwrap()).unwrap();
+
+        assert_eq!(results[&resource_id], "deleted");
+        assert_eq!(results["not-exist"], "not_found");
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn get_resource_meta() {
+        let mut handler = new_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let mut data = b"GIF89a".to_vec();
+        data.extend_from_slice(&rand::random::<u64>().to_be_bytes());
+
+        let mut post_req = Request::new(Body::from(data.clone()));
+        *post_req.method_mut() = Method::POST;
+        *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data);
+        let resource_id = get_uri.rsplit('/').next().unwrap();
+
+        let meta_req = Request::builder()
+            .uri(format!("https://test.com/meta/{}", resource_id))
+            .body(Body::empty())
+            .unwrap();
+
+        let meta_resp = handle.call(meta_req).await.unwrap();
+
+        assert_eq!(meta_resp.status(), StatusCode::OK);
+
+        let meta: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(meta_resp).await.unwrap()).unwrap();
+
+        assert_eq!(meta["id"], resource_id);
+        assert_eq!(meta["hash"], hex::encode(Sha256::digest(&data)));
+        assert_eq!(meta["resource_size"], data.len() as u64);
+        assert_eq!(meta["content_type"], "image/gif");
+        assert!(meta["bucket"].is_string());
+        assert!(meta["create_time"].is_i64());
+
+        let not_found_req = Request::builder()
+            .uri("https://test.com/meta/not-exist")
+            .body(Body::empty())
+            .unwrap();
+
+        let not_found_resp = handle.call(not_found_req).await.unwrap();
+
+        assert_eq!(not_found_resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn memory_precreate_bucket() {
+        let store_backend = Arc::new(MemoryBackend::new());
+        let handler = new_test_handler_with(store_backend.clone()).await;
+        let bucket_ready = AtomicBool::new(false);
+        let log_cx = LogContext::builder().request_id("test").build();
+        let month = Local::now().format("%Y-%m").to_string();
+
+        assert!(
+            precreate_bucket(
+                &store_backend,
+                &handler.db,
+                &BucketStrategy::Monthly,
+                &bucket_ready,
+                &log_cx
+            )
+            .await
+        );
+
+        assert!(store_backend.contains_bucket(&month));
+        assert!(bucket_ready.load(Ordering::Acquire));
+
+        let bucket_ready = AtomicBool::new(false);
+
+        assert!(
+            precreate_bucket(
+                &store_backend,
+                &handler.db,
+                &BucketStrategy::Fixed("images".to_string()),
+                &bucket_ready,
+                &log_cx
+            )
+            .await
+        );
+
+        assert!(store_backend.contains_bucket("images"));
+        assert!(bucket_ready.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn memory_ready() {
+        use crate::store::memory::Error as MemoryError;
+
+        let store_backend = Arc::new(MemoryBackend::new());
+        let mut handler = new_test_handler_with(store_backend.clone()).await;
+        handler.bucket_ready = Arc::new(AtomicBool::new(false));
+
+        let mut handle = handler.call(()).await.unwrap();
+
+        let ready_req = || {
+            Request::builder()
+                .uri("https://test.com/ready")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        store_backend.inject_error(MemoryError::Unavailable);
+
+        let not_ready_resp = handle.call(ready_req()).await.unwrap();
+
+        assert_eq!(not_ready_resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // the failed creation is retried by the next check
+        let ready_resp = handle.call(ready_req()).await.unwrap();
+
+        assert_eq!(ready_resp.status(), StatusCode::OK);
+        assert!(store_backend.contains_bucket(&Local::now().format("%Y-%m").to_string()));
+    }
+
+    #[tokio::test]
+    async fn memory_max_concurrent_uploads() {
+        let mut handler = new_memory_test_handler().await;
+        let upload_permits = Arc::new(Semaphore::new(1));
+        handler.upload_permits = Some(upload_permits.clone());
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("concurrent-{}", rand::random::<u64>());
+
+        let post_req = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data.clone()))
+                .unwrap()
+        };
+
+        // an in-flight upload holds the only permit
+        let permit = upload_permits.clone().try_acquire_owned().unwrap();
+
+        let post_resp = handle.call(post_req()).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(post_resp.headers()["retry-after"], "1");
+
+        let error_resp: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(post_resp).await.unwrap()).unwrap();
+
+        assert_eq!(error_resp.code, "uploads_saturated");
+
+        // the reads are not limited
+        let get_req = Request::builder()
+            .uri(format!("https://test.com/get/concurrent-{}", rand::random::<u64>()))
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::NOT_FOUND);
+
+        drop(permit);
+
+        let post_resp = handle.call(post_req()).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::OK);
+        assert_eq!(upload_permits.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_request_timeout() {
+        let mut handler = new_memory_test_handler().await;
+        handler.request_timeout = Some(Duration::from_millis(200));
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("timeout-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let get_uri =
+            String::from_utf8_lossy(&body::to_bytes(post_resp.body_mut()).await.unwrap())
+                .to_string();
+
+        store_backend.set_delay(Duration::from_secs(1));
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let error_resp: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(get_resp).await.unwrap()).unwrap();
+
+        assert_eq!(error_resp.code, "timeout");
+
+        // the timed out upload still finishes in the background
+        let slow_data = format!("slow-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(slow_data.clone()))
+            .unwrap();
+
+        let post_resp = handle.call(post_req).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        tokio::time::delay_for(Duration::from_millis(1500)).await;
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        let resource = handler
+            .db
+            .get_resource_by_hash(&hex::encode(Sha256::digest(slow_data.as_bytes())), &log_cx)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(store_backend.contains(resource.get_bucket(), resource.get_id()));
+    }
+
+    #[tokio::test]
+    async fn memory_dedup_stats() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let unauthorized_req = Request::builder()
+            .uri("https://test.com/dedup-stats")
+            .body(Body::empty())
+            .unwrap();
+
+        let unauthorized_resp = handle.call(unauthorized_req).await.unwrap();
+
+        assert_eq!(unauthorized_resp.status(), StatusCode::UNAUTHORIZED);
+
+        let stats_req = Request::builder()
+            .uri("https://test.com/dedup-stats")
+            .header("authorization", "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+
+        let stats_resp = handle.call(stats_req).await.unwrap();
+
+        assert_eq!(stats_resp.status(), StatusCode::OK);
+
+        let stats: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(stats_resp).await.unwrap()).unwrap();
+
+        assert!(stats["total_resources"].is_i64());
+        assert!(stats["distinct_hashes"].is_i64());
+        assert!(stats["bytes_saved"].is_i64());
+    }
+
+    #[tokio::test]
+    async fn memory_usage() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let unauthorized_req = Request::builder()
+            .uri("https://test.com/usage")
+            .body(Body::empty())
+            .unwrap();
+
+        let unauthorized_resp = handle.call(unauthorized_req).await.unwrap();
+
+        assert_eq!(unauthorized_resp.status(), StatusCode::UNAUTHORIZED);
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        let bucket = format!("usage-{}", rand::random::<u32>());
+        let resource_id = format!("usage-{}", rand::random::<u64>());
+
+        handler
+            .db
+            .insert_resource(&bucket, &resource_id, "hash", 42, "text/plain", None, None, &log_cx)
+            .await
+            .unwrap();
+
+        let usage_req = Request::builder()
+            .uri("https://test.com/usage")
+            .header("authorization", "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+
+        let usage_resp = handle.call(usage_req).await.unwrap();
+
+        handler
+            .db
+            .delete_resources(&[resource_id], &log_cx)
+            .await
+            .unwrap();
+
+        assert_eq!(usage_resp.status(), StatusCode::OK);
+
+        let usage: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(usage_resp).await.unwrap()).unwrap();
+
+        let bucket_usage = usage
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|usage| usage["bucket"] == bucket.as_str())
+            .unwrap();
+
+        assert_eq!(bucket_usage["object_count"], 1);
+        assert_eq!(bucket_usage["total_bytes"], 42);
+    }
+
+    #[tokio::test]
+    async fn memory_id_stats() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let id_stats_req = |token: Option<&str>| {
+            let mut builder = Request::builder().uri("https://test.com/id-stats");
+
+            if let Some(token) = token {
+                builder = builder.header("authorization", format!("Bearer {}", token));
+            }
+
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let resp = handle.call(id_stats_req(None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = handle.call(id_stats_req(Some("test-token"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let stats: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(resp).await.unwrap()).unwrap();
+
+        assert_eq!(stats["id_type"], env::var("ID_TYPE").unwrap().as_str());
+        assert_eq!(stats["step"], 10);
+        assert!(stats["id_value"].as_i64().unwrap() >= stats["consumed"].as_i64().unwrap());
+
+        // the snowflake ids have no counter
+        let mut handler = new_memory_test_handler().await;
+        handler.id_generator = Arc::new(SnowflakeGenerator::new(1).unwrap());
+        let mut handle = handler.call(()).await.unwrap();
+
+        let resp = handle.call(id_stats_req(Some("test-token"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn memory_upload_get_delete() {
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("memory-{}", rand::random::<u64>());
+
+        let mut post_req = Request::new(Body::from(data.clone()));
+        *post_req.method_mut() = Method::POST;
+        *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::OK);
+
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+        let bucket = Local::now().format("%Y-%m").to_string();
+
+        assert!(store_backend.contains(&bucket, &resource_id));
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), data.as_bytes());
+
+        let range_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .header("range", "bytes=0-5")
+            .body(Body::empty())
+            .unwrap();
+
+        let range_resp = handle.call(range_req).await.unwrap();
+
+        assert_eq!(range_resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body::to_bytes(range_resp).await.unwrap(), &b"memory"[..]);
+
+        let delete_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/delete-batch")
+            .header("authorization", "Bearer test-token")
+            .body(Body::from(serde_json::to_vec(&[&resource_id]).unwrap()))
+            .unwrap();
+
+        let delete_resp = handle.call(delete_req).await.unwrap();
+
+        assert_eq!(delete_resp.status(), StatusCode::OK);
+        assert!(!store_backend.contains(&bucket, &resource_id));
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn memory_get_multiple_ranges() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("0123456789-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = uri.rsplit('/').next().unwrap().to_string();
+
+        let range_req = |method: Method| {
+            Request::builder()
+                .method(method)
+                .uri(uri.as_str())
+                .header("range", "bytes=0-2,5-7")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let mut resp = handle.call(range_req(Method::GET)).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(resp.headers().get("content-range").is_none());
+
+        let content_type = resp.headers()["content-type"].to_str().unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let content_length = resp.headers()["content-length"].clone();
+
+        let body = body::to_bytes(resp.body_mut()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert_eq!(content_length, body.len().to_string().as_str());
+
+        let delimiter = format!("--{}", boundary);
+        let parts = body.split(delimiter.as_str()).collect::<Vec<_>>();
+
+        // the leading empty preamble, the two parts and the closing `--`
+        assert_eq!(parts.len(), 4, "{}", body);
+        assert_eq!(parts[0], "");
+        assert_eq!(parts[3], "--\r\n");
+
+        for (part, (content_range, segment)) in parts[1..3]
+            .iter()
+            .zip(&[("bytes 0-2", "012"), ("bytes 5-7", "567")])
+        {
+            let content_range = format!("{}/{}", content_range, data.len());
+            let (head, segment_data) = part.split_once("\r\n\r\n").unwrap();
+
+            assert!(
+                head.contains(&format!("content-range: {}", content_range)),
+                "{}",
+                head
+            );
+            assert!(head.contains("content-type: "), "{}", head);
+            assert_eq!(segment_data, format!("{}\r\n", segment));
+        }
+
+        // HEAD has the same headers without the body
+        let resp = handle.call(range_req(Method::HEAD)).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(resp.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("multipart/byteranges; boundary="));
+        assert_eq!(resp.headers()["content-length"], content_length);
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        handler
+            .db
+            .delete_resources(&[resource_id], &log_cx)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_not_found_error_body() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let get_req = Request::builder()
+            .uri("https://test.com/get/not-exist")
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(get_resp.headers()["content-type"], "application/json");
+
+        let request_id = get_resp.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        let error_response: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(get_resp).await.unwrap()).unwrap();
+
+        assert_eq!(error_response.code, "resource_not_found");
+        assert_eq!(error_response.request_id, request_id);
+    }
+
+    #[tokio::test]
+    async fn memory_store_error_status() {
+        use crate::store::memory::Error as MemoryError;
+
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let upload = |data: String| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data))
+                .unwrap()
+        };
+
+        let cases = vec![
+            (
+                MemoryError::ResourceExist("id".to_owned()),
+                StatusCode::CONFLICT,
+                "resource_exist",
+            ),
+            (
+                MemoryError::Unavailable,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "service_unavailable",
+            ),
+            (
+                MemoryError::BucketNotEmpty("secret-bucket".to_owned()),
+                StatusCode::CONFLICT,
+                "bucket_not_empty",
+            ),
+        ];
+
+        for (err, status, code) in cases {
+            store_backend.inject_error(err);
+
+            let post_resp = handle
+                .call(upload(format!("error-{}", rand::random::<u64>())))
+                .await
+                .unwrap();
+
+            assert_eq!(post_resp.status(), status);
+
+            let error_response: ErrorResponse =
+                serde_json::from_slice(&body::to_bytes(post_resp).await.unwrap()).unwrap();
+
+            assert_eq!(error_response.code, code);
+            assert!(!error_response.message.contains("secret"));
+        }
+
+        let mut post_resp = handle
+            .call(upload(format!("error-{}", rand::random::<u64>())))
+            .await
+            .unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        for (err, status) in [
+            (
+                MemoryError::BucketNotFound("bucket".to_owned()),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                MemoryError::ResourceNotFound("id".to_owned()),
+                StatusCode::NOT_FOUND,
+            ),
+            (MemoryError::Unavailable, StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            store_backend.inject_error(err);
+
+            let get_req = Request::builder()
+                .uri(Uri::from_str(&get_uri).unwrap())
+                .body(Body::empty())
+                .unwrap();
+
+            let get_resp = handle.call(get_req).await.unwrap();
+
+            assert_eq!(get_resp.status(), status);
+        }
+    }
+
+    #[test]
+    fn strip_api_version_prefix() {
+        for (uri, stripped) in &[
+            ("https://test.com/v1/upload", Some("https://test.com/upload")),
+            ("/v1/get/id?download=1", Some("/get/id?download=1")),
+            ("/get/id", None),
+            ("/v1", None),
+            ("/v10/get/id", None),
+        ] {
+            assert_eq!(
+                strip_api_version(&Uri::from_static(uri)),
+                stripped.map(Uri::from_static),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn valid_bucket() {
+        for bucket in &["2021-01", "2021-12-1", "2021-01-25"] {
+            assert!(is_valid_bucket(bucket), "{}", bucket);
+        }
+
+        for bucket in &["2021-13", "2021-01-", "2021-01-0", "2021-01-01", "2021-01-x", "../x"] {
+            assert!(!is_valid_bucket(bucket), "{}", bucket);
+        }
+    }
+
+    #[test]
+    fn fixed_upload_bucket() {
+        let bucket_strategy = BucketStrategy::Fixed("images".to_string());
+
+        assert_eq!(bucket_strategy.base_bucket(), "images");
+
+        for bucket in &["images", "images-1", "images-12"] {
+            assert!(bucket_strategy.is_upload_bucket(bucket), "{}", bucket);
+        }
+
+        for bucket in &["2021-01", "images-", "images-0", "images2", "images-x", "other"] {
+            assert!(!bucket_strategy.is_upload_bucket(bucket), "{}", bucket);
+        }
+
+        assert!(check_fixed_bucket("images").is_ok());
+
+        for bucket in &["", "Images", "images-", "-images", "images/x"] {
+            assert!(check_fixed_bucket(bucket).is_err(), "{}", bucket);
+        }
+    }
+
+    #[test]
+    fn parse_time() {
+        let time = |secs: u64| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+
+        assert_eq!(parse_unix_time("0"), time(0));
+        assert_eq!(parse_unix_time("1609459200"), time(1_609_459_200));
+        assert_eq!(parse_unix_time("2021-01-01T00:00:00Z"), time(1_609_459_200));
+        assert_eq!(parse_unix_time("2021-01-01T08:00:00+08:00"), time(1_609_459_200));
+
+        for value in &["", "-1", "1.5", "2021-01-01", "1969-12-31T23:59:59Z"] {
+            assert_eq!(parse_unix_time(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn path_prefixes() {
+        check_path_prefixes("/api/upload", "/api/get").unwrap();
+
+        for (upload_path, get_path) in &[
+            ("upload", "/get"),
+            ("/upload/", "/get"),
+            ("/", "/get"),
+            ("/img", "/img/get"),
+            ("/upload", "/upload"),
+            ("/upload", "/meta"),
+            ("/delete", "/get"),
+            ("/v1/upload", "/get"),
+            ("/dedup", "/get"),
+            ("/upload-policy", "/get"),
+            ("/upload", "/upload-policy/get"),
+            ("/bucket", "/get"),
+            ("/upload", "/list"),
+            ("/usage", "/get"),
+            ("/upload", "/id-stats"),
+            ("/upload", "/ready"),
+            ("/sweep", "/get"),
+            ("/upload", "/hash"),
+        ] {
+            assert!(
+                check_path_prefixes(upload_path, get_path).is_err(),
+                "{} {}",
+                upload_path,
+                get_path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_versioned_paths() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("versioned-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/v1/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::OK);
+
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+
+        for uri in &[
+            format!("https://test.com/v1/get/{}", resource_id),
+            format!("https://test.com/get/{}", resource_id),
+        ] {
+            let get_req = Request::builder()
+                .uri(Uri::from_str(uri).unwrap())
+                .body(Body::empty())
+                .unwrap();
+
+            let get_resp = handle.call(get_req).await.unwrap();
+
+            assert_eq!(get_resp.status(), StatusCode::OK, "{}", uri);
+            assert_eq!(body::to_bytes(get_resp).await.unwrap(), data.as_bytes());
+        }
+
+        let get_req = Request::builder()
+            .uri("https://test.com/v2/get/id")
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn memory_content_disposition() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .header(
+                "content-disposition",
+                "attachment; filename=\"cat.png\"; filename*=UTF-8''%E7%8C%AB.png",
+            )
+            .body(Body::from(format!("disposition-{}", rand::random::<u64>())))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        for (query, disposition) in &[
+            ("", "inline; filename=\"_.png\"; filename*=UTF-8''%E7%8C%AB.png"),
+            (
+                "?download=1",
+                "attachment; filename=\"_.png\"; filename*=UTF-8''%E7%8C%AB.png",
+            ),
+        ] {
+            let get_req = Request::builder()
+                .uri(Uri::from_str(&format!("{}{}", get_uri, query)).unwrap())
+                .body(Body::empty())
+                .unwrap();
+
+            let get_resp = handle.call(get_req).await.unwrap();
+
+            assert_eq!(get_resp.status(), StatusCode::OK);
+            assert_eq!(get_resp.headers()["content-disposition"], *disposition);
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_transcode() {
+        let mut handler = new_memory_test_handler().await;
+        handler.transcode = true;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(media::testing::png(32, 32)))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+
+        for (accept, content_type) in &[
+            ("image/webp,*/*", "image/webp"),
+            ("image/avif,*/*", "image/png"),
+            ("image/png,*/*", "image/png"),
+        ] {
+            let get_req = Request::builder()
+                .uri(Uri::from_str(&get_uri).unwrap())
+                .header("accept", *accept)
+                .body(Body::empty())
+                .unwrap();
+
+            let get_resp = handle.call(get_req).await.unwrap();
+
+            assert_eq!(get_resp.status(), StatusCode::OK);
+            assert_eq!(get_resp.headers()["content-type"], *content_type, "{}", accept);
+            assert_eq!(get_resp.headers()["vary"], "accept");
+
+            let data = body::to_bytes(get_resp).await.unwrap();
+            assert_eq!(media::detect_content_type(&data), Some(*content_type));
+        }
+
+        let bucket = Local::now().format("%Y-%m").to_string();
+        assert!(store_backend.contains(&bucket, &format!("{}.webp", resource_id)));
+
+        // the range request is always served from the original
+        let range_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .header("accept", "image/webp")
+            .header("range", "bytes=0-7")
+            .body(Body::empty())
+            .unwrap();
+
+        let range_resp = handle.call(range_req).await.unwrap();
+
+        assert_eq!(range_resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(range_resp.headers()["content-type"], "image/png");
+    }
+
+    #[tokio::test]
+    async fn memory_watermark() {
+        let mut handler = new_memory_test_handler().await;
+        let watermark = Arc::new(
+            Watermark::new(&media::testing::png(8, 8), WatermarkPosition::BottomRight, 0.5)
+                .unwrap(),
+        );
+        handler.watermark = Some(watermark.clone());
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let original = media::testing::png(40, 30);
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(original.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+
+        let get = |uri: String| {
+            Request::builder()
+                .uri(Uri::from_str(&uri).unwrap())
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // not asked
+        let get_resp = handle.call(get(get_uri.clone())).await.unwrap();
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), original);
+
+        // HEAD doesn't make the variant, its length is unknown before a GET
+        let head_req = Request::builder()
+            .method(Method::HEAD)
+            .uri(Uri::from_str(&format!("{}?watermark=1", get_uri)).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let head_resp = handle.call(head_req).await.unwrap();
+
+        assert_eq!(head_resp.status(), StatusCode::OK);
+        assert!(!head_resp.headers().contains_key("content-length"));
+
+        let bucket = Local::now().format("%Y-%m").to_string();
+        assert!(!store_backend.contains(
+            &bucket,
+            &watermarked_variant_id(&resource_id, &watermark)
+        ));
+
+        let mut etags = vec![];
+
+        for _ in 0..2 {
+            let get_resp = handle
+                .call(get(format!("{}?watermark=1", get_uri)))
+                .await
+                .unwrap();
+
+            assert_eq!(get_resp.status(), StatusCode::OK);
+            assert_eq!(get_resp.headers()["content-type"], "image/png");
+            etags.push(get_resp.headers()["etag"].clone());
+
+            let data = body::to_bytes(get_resp).await.unwrap();
+
+            assert_ne!(data, original);
+            assert_eq!(media::image_dimensions(&data), Some((40, 30)));
+        }
+
+        // the second one is served from the cached variant
+        assert!(store_backend.contains(
+            &bucket,
+            &watermarked_variant_id(&resource_id, &watermark)
+        ));
+        assert_eq!(etags[0], etags[1]);
+        assert_ne!(etags[0], conditional::etag(&hex::encode(Sha256::digest(&original)), None));
+
+        // HEAD agrees with GET, the whole variant is reported even when a range is asked
+        let get_resp = handle
+            .call(get(format!("{}?watermark=1", get_uri)))
+            .await
+            .unwrap();
+        let variant_len = body::to_bytes(get_resp).await.unwrap().len();
+
+        let head_req = Request::builder()
+            .method(Method::HEAD)
+            .uri(Uri::from_str(&format!("{}?watermark=1", get_uri)).unwrap())
+            .header("range", "bytes=0-4")
+            .body(Body::empty())
+            .unwrap();
+
+        let head_resp = handle.call(head_req).await.unwrap();
+
+        assert_eq!(head_resp.status(), StatusCode::OK);
+        assert_eq!(head_resp.headers()["etag"], etags[0]);
+        assert_eq!(
+            head_resp.headers()["content-length"],
+            variant_len.to_string().as_str()
+        );
+
+        let head_req = Request::builder()
+            .method(Method::HEAD)
+            .uri(Uri::from_str(&format!("{}?watermark=1", get_uri)).unwrap())
+            .header("if-none-match", etags[0].clone())
+            .body(Body::empty())
+            .unwrap();
+
+        let head_resp = handle.call(head_req).await.unwrap();
+
+        assert_eq!(head_resp.status(), StatusCode::NOT_MODIFIED);
+
+        // the non-raster resources are served as is
+        let data = format!("watermark-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let text_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        let get_resp = handle
+            .call(get(format!("{}?watermark=1", text_uri)))
+            .await
+            .unwrap();
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), data.as_bytes());
+
+        // every download is watermarked
+        handler.watermark_always = true;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let get_resp = handle.call(get(get_uri)).await.unwrap();
+        assert_ne!(body::to_bytes(get_resp).await.unwrap(), original);
+    }
+
+    #[tokio::test]
+    async fn memory_signed_url() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("signed-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+
+        let sign = |path: String, token: &str| {
+            Request::builder()
+                .uri(format!("https://test.com{}", path))
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let get = |uri: &str| {
+            Request::builder()
+                .uri(Uri::from_str(uri).unwrap())
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let error_code = |resp: Response<Body>| async move {
+            let data = body::to_bytes(resp).await.unwrap();
+
+            serde_json::from_slice::<ErrorResponse>(&data).unwrap().code
+        };
+
+        // no secret
+        let sign_resp = handle
+            .call(sign(format!("/sign/{}", resource_id), "test-token"))
+            .await
+            .unwrap();
+        assert_eq!(sign_resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(sign_resp).await, "url_signing_disabled");
+
+        let url_signer = Arc::new(UrlSigner::new(b"signing-secret"));
+        handler.url_signer = Some(url_signer.clone());
+        let mut handle = handler.call(()).await.unwrap();
+
+        let sign_resp = handle
+            .call(sign(format!("/sign/{}", resource_id), "wrong-token"))
+            .await
+            .unwrap();
+        assert_eq!(sign_resp.status(), StatusCode::UNAUTHORIZED);
+
+        for (path, status) in &[
+            (format!("/sign/{}?ttl=0", resource_id), StatusCode::BAD_REQUEST),
+            (format!("/sign/{}?ttl=x", resource_id), StatusCode::BAD_REQUEST),
+            ("/sign/unknown".to_string(), StatusCode::NOT_FOUND),
+        ] {
+            let sign_resp = handle.call(sign(path.clone(), "test-token")).await.unwrap();
+            assert_eq!(sign_resp.status(), *status, "{}", path);
+        }
+
+        let sign_resp = handle
+            .call(sign(format!("/sign/{}?ttl=60", resource_id), "test-token"))
+            .await
+            .unwrap();
+        assert_eq!(sign_resp.status(), StatusCode::OK);
+
+        let signed: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(sign_resp).await.unwrap()).unwrap();
+        let signed_uri = signed["url"].as_str().unwrap().to_string();
+        let expires_at = signed["expires_at"].as_u64().unwrap();
+
+        assert!(signed_uri.starts_with(&format!("{}?expires={}&sig=", get_uri, expires_at)));
+
+        // valid
+        let get_resp = handle.call(get(&signed_uri)).await.unwrap();
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), data.as_bytes());
+
+        // tampered
+        let signature = signed_uri.rsplit('=').next().unwrap();
+        let tampered_signature = format!(
+            "{}{}",
+            &signature[..signature.len() - 1],
+            if signature.ends_with('0') { '1' } else { '0' }
+        );
+
+        for uri in &[
+            format!("{}?expires={}&sig={}", get_uri, expires_at, tampered_signature),
+            format!("{}?expires={}&sig={}", get_uri, expires_at + 60, signature),
+            format!("{}?expires={}", get_uri, expires_at),
+            format!("{}?sig={}", get_uri, signature),
+        ] {
+            let get_resp = handle.call(get(uri)).await.unwrap();
+            assert_eq!(get_resp.status(), StatusCode::FORBIDDEN, "{}", uri);
+            assert_eq!(error_code(get_resp).await, "invalid_signature", "{}", uri);
+        }
+
+        // expired
+        let expired_uri = format!(
+            "{}?expires=1000&sig={}",
+            get_uri,
+            url_signer.sign(&resource_id, 1000)
+        );
+
+        let get_resp = handle.call(get(&expired_uri)).await.unwrap();
+        assert_eq!(get_resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(get_resp).await, "link_expired");
+
+        // HEAD checks the link too
+        for (uri, status) in &[
+            (&expired_uri, StatusCode::FORBIDDEN),
+            (&signed_uri, StatusCode::OK),
+        ] {
+            let head_req = Request::builder()
+                .method(Method::HEAD)
+                .uri(Uri::from_str(uri).unwrap())
+                .body(Body::empty())
+                .unwrap();
+
+            let head_resp = handle.call(head_req).await.unwrap();
+            assert_eq!(head_resp.status(), *status, "{}", uri);
+        }
+
+        // the unsigned links are not checked
+        let get_resp = handle.call(get(&get_uri)).await.unwrap();
+        assert_eq!(get_resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn memory_transcode_etag() {
+        let mut handler = new_memory_test_handler().await;
+        handler.transcode = true;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(media::testing::png(32, 32)))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        let mut etags = HashMap::new();
+
+        for accept in &["image/webp,*/*", "image/png,*/*"] {
+            let get_req = Request::builder()
+                .uri(Uri::from_str(&get_uri).unwrap())
+                .header("accept", *accept)
+                .body(Body::empty())
+                .unwrap();
+
+            let get_resp = handle.call(get_req).await.unwrap();
+
+            assert_eq!(get_resp.status(), StatusCode::OK);
+            assert_eq!(get_resp.headers()["vary"], "accept");
+
+            // HEAD negotiates the same variant
+            let head_req = Request::builder()
+                .method(Method::HEAD)
+                .uri(Uri::from_str(&get_uri).unwrap())
+                .header("accept", *accept)
+                .body(Body::empty())
+                .unwrap();
+
+            let head_resp = handle.call(head_req).await.unwrap();
+
+            assert_eq!(head_resp.status(), StatusCode::OK);
+            for name in &["vary", "etag", "content-type", "content-length"] {
+                assert_eq!(head_resp.headers()[*name], get_resp.headers()[*name], "{}", name);
+            }
+
+            etags.insert(
+                get_resp.headers()["etag"].to_str().unwrap().to_string(),
+                *accept,
+            );
+        }
+
+        assert_eq!(etags.len(), 2, "{:?}", etags);
+
+        for (etag, accept) in &etags {
+            let get_req = Request::builder()
+                .uri(Uri::from_str(&get_uri).unwrap())
+                .header("accept", *accept)
+                .header("if-none-match", etag.as_str())
+                .body(Body::empty())
+                .unwrap();
+
+            let get_resp = handle.call(get_req).await.unwrap();
+
+            assert_eq!(get_resp.status(), StatusCode::NOT_MODIFIED, "{}", accept);
+            assert_eq!(get_resp.headers()["etag"], etag.as_str());
+            assert_eq!(get_resp.headers()["vary"], "accept");
+
+            let head_req = Request::builder()
+                .method(Method::HEAD)
+                .uri(Uri::from_str(&get_uri).unwrap())
+                .header("accept", *accept)
+                .header("if-none-match", etag.as_str())
+                .body(Body::empty())
+                .unwrap();
+
+            let head_resp = handle.call(head_req).await.unwrap();
+
+            assert_eq!(head_resp.status(), StatusCode::NOT_MODIFIED, "{}", accept);
+        }
+
+        // the webp variant doesn't match the request of the original
+        let webp_etag = etags
+            .iter()
+            .find(|(_, accept)| accept.starts_with("image/webp"))
+            .map(|(etag, _)| etag.clone())
+            .unwrap();
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .header("accept", "image/png,*/*")
+            .header("if-none-match", webp_etag.as_str())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(get_resp.headers()["content-type"], "image/png");
+    }
+
+    #[tokio::test]
+    async fn memory_transcode_disabled() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(media::testing::png(32, 32)))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .header("accept", "image/webp,*/*")
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.headers()["content-type"], "image/png");
+        assert!(!get_resp.headers().contains_key("vary"));
+    }
+
+    #[tokio::test]
+    async fn memory_transcode_marker() {
+        let mut handler = new_memory_test_handler().await;
+        handler.transcode = true;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        // looks like a png, but can't be decoded
+        let mut original = b"\x89PNG\r\n\x1a\n".to_vec();
+        original.extend_from_slice(format!("broken-{}", rand::random::<u64>()).as_bytes());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(original.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+
+        let req = |method: Method| {
+            Request::builder()
+                .method(method)
+                .uri(Uri::from_str(&get_uri).unwrap())
+                .header("accept", "image/webp,*/*")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let get_resp = handle.call(req(Method::GET)).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(get_resp.headers()["content-type"], "image/png");
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), original);
+
+        // the failed transcode is remembered by the empty variant
+        let log_cx = LogContext::builder().request_id("test").build();
+        let bucket = Local::now().format("%Y-%m").to_string();
+        let marker = store_backend
+            .get(
+                &bucket,
+                &transcoded_variant_id(&resource_id, TranscodeFormat::Webp),
+                None,
+                None,
+                &log_cx,
+            )
+            .await
+            .unwrap();
+        assert!(marker.is_empty());
+
+        // HEAD reports the original, which is served instead
+        let head_resp = handle.call(req(Method::HEAD)).await.unwrap();
+
+        assert_eq!(head_resp.status(), StatusCode::OK);
+        assert_eq!(head_resp.headers()["content-type"], "image/png");
+        assert_eq!(
+            head_resp.headers()["content-length"],
+            original.len().to_string().as_str()
+        );
+
+        let get_resp = handle.call(req(Method::GET)).await.unwrap();
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), original);
+    }
+
+    #[tokio::test]
+    async fn memory_dedup_header() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("dedup-header-{}", rand::random::<u64>());
+        let mut get_uris = vec![];
+
+        for dedup in &["miss", "hit"] {
+            let post_req = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data.clone()))
+                .unwrap();
+
+            let mut post_resp = handle.call(post_req).await.unwrap();
+
+            assert_eq!(post_resp.status(), StatusCode::OK);
+            assert_eq!(post_resp.headers()[DEDUP_HEADER], *dedup);
+
+            let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+            get_uris.push(String::from_utf8_lossy(&resp_data).to_string());
+        }
+
+        assert_eq!(get_uris[0], get_uris[1]);
+    }
+
+    #[tokio::test]
+    async fn memory_upload_check() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("upload-check-{}", rand::random::<u64>());
+        let hash = hex::encode(Sha256::digest(data.as_bytes()));
+
+        let check_req = |hash: &str| {
+            Request::builder()
+                .method(Method::HEAD)
+                .uri("https://test.com/upload")
+                .header(CONTENT_HASH_HEADER, hash)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for invalid_hash in &["not-hex", &hash[1..], format!("md5:{}", hash).as_str()] {
+            let resp = handle.call(check_req(invalid_hash)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", invalid_hash);
+        }
+
+        let missing_req = Request::builder()
+            .method(Method::HEAD)
+            .uri("https://test.com/upload")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = handle.call(missing_req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // the miss before the upload
+        let resp = handle.call(check_req(&hash)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(!resp.headers().contains_key(RESOURCE_URL_HEADER));
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        assert_eq!(post_resp.status(), StatusCode::OK);
+
+        let get_uri = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&get_uri).to_string();
+
+        // the hit after the upload, the hex is case insensitive
+        let mut resp = handle.call(check_req(&hash)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[RESOURCE_URL_HEADER], get_uri);
+        assert!(body::to_bytes(resp.body_mut()).await.unwrap().is_empty());
+
+        let resp = handle
+            .call(check_req(&hash.to_ascii_uppercase()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn memory_upload_if_none_match() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+        let log_cx = LogContext::builder().request_id("").build();
+
+        let data = format!("if-none-match-{}", rand::random::<u64>());
+        let hash = hex::encode(Sha256::digest(data.as_bytes()));
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        assert_eq!(post_resp.status(), StatusCode::OK);
+
+        let get_uri = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&get_uri).to_string();
+
+        // the body is not read, so another content is not stored
+        let other_data = format!("if-none-match-other-{}", rand::random::<u64>());
+        let other_hash = hex::encode(Sha256::digest(other_data.as_bytes()));
+
+        for if_none_match in &[
+            format!("\"{}\"", hash),
+            format!("\"not-a-hash\", W/\"{}\"", hash),
+        ] {
+            let conditional_req = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .header("if-none-match", if_none_match.as_str())
+                .body(Body::from(other_data.clone()))
+                .unwrap();
+
+            let mut resp = handle.call(conditional_req).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{}", if_none_match);
+            assert_eq!(resp.headers()[RESOURCE_URL_HEADER], get_uri);
+            assert_eq!(resp.headers()["etag"], format!("\"{}\"", hash));
+            assert!(body::to_bytes(resp.body_mut()).await.unwrap().is_empty());
+        }
+
+        assert!(handler
+            .db
+            .get_resource_by_hash(&other_hash, &log_cx)
+            .await
+            .unwrap()
+            .is_none());
+
+        // the unknown hash uploads as usual
+        let conditional_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .header("if-none-match", format!("\"{}\"", other_hash))
+            .body(Body::from(other_data.clone()))
+            .unwrap();
+
+        let resp = handle.call(conditional_req).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(handler
+            .db
+            .get_resource_by_hash(&other_hash, &log_cx)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn memory_fixed_bucket() {
+        let mut handler = new_memory_test_handler().await;
+        // a bucket of its own, so the resources of the other tests are not counted
+        let fixed_bucket = format!("fixed-bucket-{}", rand::random::<u32>());
+        handler.bucket_strategy = Arc::new(BucketStrategy::Fixed(fixed_bucket.clone()));
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let month = Local::now().format("%Y-%m").to_string();
+        let mut resource_ids = vec![];
+
+        for i in 0..2 {
+            let post_req = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(format!("fixed-bucket-{}-{}", i, rand::random::<u64>())))
+                .unwrap();
+
+            let mut post_resp = handle.call(post_req).await.unwrap();
+            assert_eq!(post_resp.status(), StatusCode::OK);
+
+            let get_uri = body::to_bytes(post_resp.body_mut()).await.unwrap();
+            let get_uri = String::from_utf8_lossy(&get_uri).to_string();
+            let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+
+            assert!(store_backend.contains(&fixed_bucket, &resource_id));
+            assert!(!store_backend.contains(&month, &resource_id));
+
+            resource_ids.push(resource_id);
+        }
+
+        let list_req = Request::builder()
+            .uri(format!("https://test.com/list?bucket={}", fixed_bucket))
+            .header("authorization", "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+
+        let mut list_resp = handle.call(list_req).await.unwrap();
+        assert_eq!(list_resp.status(), StatusCode::OK);
+
+        let list: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(list_resp.body_mut()).await.unwrap()).unwrap();
+        let mut listed_ids = list["resources"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|resource| resource["id"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        listed_ids.sort();
+        resource_ids.sort();
+
+        assert_eq!(listed_ids, resource_ids);
+
+        let delete_req = Request::builder()
+            .method(Method::DELETE)
+            .uri(format!("https://test.com/bucket/{}?empty=false", fixed_bucket))
+            .header("authorization", "Bearer test-token")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = handle.call(delete_req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        for resource_id in &resource_ids {
+            assert!(!store_backend.contains(&fixed_bucket, resource_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_dedup_collision() {
+        let mut handler = new_memory_test_handler().await;
+        handler.verify_dedup_bytes = DedupVerify::Size;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        let data = format!("collision-{}", rand::random::<u64>());
+        let hash = hex::encode(Sha256::digest(data.as_bytes()));
+
+        // another content of the same hash, which has a different size
+        let collided_id = format!("collision-{}", rand::random::<u64>());
+        handler
+            .db
+            .insert_dedup_resource(
+                "collision",
+                &collided_id,
+                &hash,
+                data.len() as u64 + 1,
+                "text/plain",
+                None,
+                None,
+                &log_cx,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::OK);
+        // the colliding content is stored as a new object
+        assert_eq!(post_resp.headers()[DEDUP_HEADER], "miss");
+
+        let get_uri =
+            String::from_utf8_lossy(&body::to_bytes(post_resp.body_mut()).await.unwrap())
+                .to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_owned();
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        handler
+            .db
+            .delete_resources(&[collided_id.clone(), resource_id.clone()], &log_cx)
+            .await
+            .unwrap();
+
+        assert_ne!(resource_id, collided_id);
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), data.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn memory_hash_algorithm() {
+        let store_backend = Arc::new(MemoryBackend::new());
+        let mut blake3_handler = new_test_handler_with(store_backend.clone()).await;
+        blake3_handler.hash_algorithm = HashAlgorithm::Blake3;
+        let mut blake3_handle = blake3_handler.call(()).await.unwrap();
+        let mut sha256_handle = new_test_handler_with(store_backend)
+            .await
+            .call(())
+            .await
+            .unwrap();
+
+        async fn upload<H>(handle: &mut H, data: &str) -> String
+            where
+                H: Service<Request<Body>, Response=Response<Body>>,
+                H::Error: std::fmt::Debug,
+        {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data.to_owned()))
+                .unwrap();
+
+            let mut resp = handle.call(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            String::from_utf8_lossy(&body::to_bytes(resp.body_mut()).await.unwrap()).to_string()
+        }
+
+        let data = format!("blake3-{}", rand::random::<u64>());
+        let get_uri = upload(&mut blake3_handle, &data).await;
+
+        // deduped within the algorithm
+        assert_eq!(upload(&mut blake3_handle, &data).await, get_uri);
+
+        let resource_id = get_uri.rsplit('/').next().unwrap();
+
+        let meta_req = Request::builder()
+            .uri(format!("https://test.com/meta/{}", resource_id))
+            .body(Body::empty())
+            .unwrap();
+
+        let meta: serde_json::Value = serde_json::from_slice(
+            &body::to_bytes(blake3_handle.call(meta_req).await.unwrap())
+                .await
+                .unwrap(),
+        )
+            .unwrap();
+
+        let hash = meta["hash"].as_str().unwrap();
+
+        assert_eq!(hash, HashAlgorithm::Blake3.hash(data.as_bytes()));
+        assert_eq!(hash.len(), HashAlgorithm::Blake3.hash_len());
+
+        // not deduped against the resource of the other algorithm
+        assert_ne!(upload(&mut sha256_handle, &data).await, get_uri);
+    }
+
+    #[tokio::test]
+    async fn memory_gzip_upload() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = media::testing::png(32, 32);
+
+        let post_req = |encoding: &str, body: Vec<u8>| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .header("content-encoding", encoding)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let mut post_resp = handle
+            .call(post_req("gzip", decompress::testing::gzip(&data)))
+            .await
+            .unwrap();
+        assert_eq!(post_resp.status(), StatusCode::OK);
+
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.headers()["content-type"], "image/png");
+        assert_eq!(
+            get_resp.headers()["etag"],
+            format!("\"{}\"", hex::encode(Sha256::digest(&data)))
+        );
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), data);
+
+        // the uncompressed upload of the same image is deduped to it
+        let mut post_resp = handle.call(post_req("identity", data.clone())).await.unwrap();
+        assert_eq!(post_resp.headers()[DEDUP_HEADER], "hit");
+        assert_eq!(body::to_bytes(post_resp.body_mut()).await.unwrap(), resp_data);
+
+        let post_resp = handle.call(post_req("gzip", data.clone())).await.unwrap();
+        assert_eq!(post_resp.status(), StatusCode::BAD_REQUEST);
+
+        let post_resp = handle.call(post_req("br", data)).await.unwrap();
+        assert_eq!(post_resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn memory_upload_spill() {
+        let temp_dir =
+            env::temp_dir().join(format!("image_bed-upload-spill-{}", rand::random::<u64>()));
+        fs::create_dir(&temp_dir).unwrap();
+
+        let mut handler = new_memory_test_handler().await;
+        handler.temp_dir = Arc::new(temp_dir.clone());
+        handler.memory_spill_threshold = Some(16);
+        let mut handle = handler.call(()).await.unwrap();
+
+        let temp_files = || fs::read_dir(&temp_dir).unwrap().count();
+
+        async fn wait_spilled(temp_dir: &Path) {
+            for _ in 0..100 {
+                if fs::read_dir(temp_dir).unwrap().count() > 0 {
+                    return;
+                }
+
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+
+            panic!("upload is not spilled");
+        }
+
+        let data = format!("upload-spill-{:032}", rand::random::<u64>());
+        let (mut sender, body) = Body::channel();
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(body)
+            .unwrap();
+
+        let post_task = task::spawn(handle.call(post_req));
+
+        sender
+            .send_data(Bytes::copy_from_slice(&data.as_bytes()[..24]))
+            .await
+            .unwrap();
+        wait_spilled(&temp_dir).await;
+
+        sender
+            .send_data(Bytes::copy_from_slice(&data.as_bytes()[24..]))
+            .await
+            .unwrap();
+        drop(sender);
+
+        let mut post_resp = post_task.await.unwrap().unwrap();
+        assert_eq!(post_resp.status(), StatusCode::OK);
+        assert_eq!(temp_files(), 0);
+
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), data.as_bytes());
+
+        // the aborted upload removes its temp file too
+        let (mut sender, body) = Body::channel();
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(body)
+            .unwrap();
+
+        let post_task = task::spawn(handle.call(post_req));
+
+        sender
+            .send_data(Bytes::copy_from_slice(&data.as_bytes()[..24]))
+            .await
+            .unwrap();
+        wait_spilled(&temp_dir).await;
+
+        sender.abort();
+
+        let post_result = post_task.await.unwrap();
+        assert!(post_result.map_or(true, |resp| resp.status() != StatusCode::OK));
+        assert_eq!(temp_files(), 0);
+
+        fs::remove_dir(&temp_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_sweep() {
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let sweep_req = |query: &str, token: Option<&str>| {
+            let mut builder = Request::builder()
+                .method(Method::POST)
+                .uri(format!("https://test.com/sweep{}", query));
+
+            if let Some(token) = token {
+                builder = builder.header("authorization", format!("Bearer {}", token));
+            }
+
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let resp = handle.call(sweep_req("", None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // no resource ttl to default to
+        let resp = handle.call(sweep_req("", Some("test-token"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = handle
+            .call(sweep_req("?before=yesterday", Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let mut old_resources = vec![];
+        let mut old_bytes = 0;
+
+        for (i, create_time) in [1000u64, 1500].iter().enumerate() {
+            let data = format!("sweep-{}-{}", i, rand::random::<u64>());
+            old_bytes += data.len() as u64;
+
+            let post_req = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data))
+                .unwrap();
+
+            let mut post_resp = handle.call(post_req).await.unwrap();
+            let get_uri =
+                String::from_utf8_lossy(&body::to_bytes(post_resp.body_mut()).await.unwrap())
+                    .to_string();
+            let resource_id = get_uri.rsplit('/').next().unwrap().to_owned();
+
+            handler
+                .db
+                .set_resource_create_time(
+                    &resource_id,
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(*create_time),
+                )
+                .await
+                .unwrap();
+
+            old_resources.push((resource_id, get_uri));
+        }
+
+        let new_data = format!("sweep-new-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(new_data))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let new_uri =
+            String::from_utf8_lossy(&body::to_bytes(post_resp.body_mut()).await.unwrap())
+                .to_string();
+
+        let resp = handle
+            .call(sweep_req("?before=2000", Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let result: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(resp).await.unwrap()).unwrap();
+
+        assert_eq!(result["before"], 2000);
+        assert_eq!(result["deleted_resources"], 2);
+        assert_eq!(result["deleted_bytes"], old_bytes);
+        assert_eq!(result["failed_objects"], serde_json::json!([]));
+
+        let bucket = Local::now().format("%Y-%m").to_string();
+
+        for (resource_id, get_uri) in &old_resources {
+            assert!(!store_backend.contains(&bucket, resource_id));
+
+            let get_req = Request::builder()
+                .uri(Uri::from_str(get_uri).unwrap())
+                .body(Body::empty())
+                .unwrap();
+
+            assert_eq!(
+                handle.call(get_req).await.unwrap().status(),
+                StatusCode::NOT_FOUND
+            );
+        }
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&new_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(handle.call(get_req).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn memory_cdn_purge() {
+        let (addr, bodies) = webhook::testing::mock_server(vec![StatusCode::BAD_GATEWAY]);
+
+        let mut handler = new_memory_test_handler().await;
+        handler.cdn_purge = Some(Webhook::new(&format!("http://{}/purge", addr)).unwrap());
+        let mut handle = handler.call(()).await.unwrap();
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(format!("purge-{}", rand::random::<u64>())))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let get_uri =
+            String::from_utf8_lossy(&body::to_bytes(post_resp.body_mut()).await.unwrap())
+                .to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_owned();
+
+        let delete_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/delete-batch")
+            .header("authorization", "Bearer test-token")
+            .body(Body::from(
+                serde_json::to_vec(&[resource_id.as_str(), "not-exist"]).unwrap(),
+            ))
+            .unwrap();
+
+        // the delete is not blocked by the purge
+        let delete_resp = handle.call(delete_req).await.unwrap();
+
+        assert_eq!(delete_resp.status(), StatusCode::OK);
+
+        // the first purge fails, the retry is after the backoff
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+
+        let bodies = bodies.lock().unwrap();
+
+        assert_eq!(bodies.len(), 2);
+
+        let payload: serde_json::Value = serde_json::from_slice(&bodies[1]).unwrap();
+
+        assert_eq!(payload, serde_json::json!({ "urls": [get_uri] }));
+    }
+
+    #[tokio::test]
+    async fn memory_upload_webhook() {
+        let (addr, bodies) = webhook::testing::mock_server(vec![]);
+
+        let mut handler = new_memory_test_handler().await;
+        handler.upload_webhook = Some(Webhook::new(&format!("http://{}/hook", addr)).unwrap());
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("webhook-{}", rand::random::<u64>());
+
+        let mut get_uris = vec![];
+
+        // the second upload is a dedup hit
+        for _ in 0..2 {
+            let post_req = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data.clone()))
+                .unwrap();
+
+            let mut post_resp = handle.call(post_req).await.unwrap();
+            let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+
+            get_uris.push(String::from_utf8_lossy(&resp_data).to_string());
+        }
+
+        assert_eq!(get_uris[0], get_uris[1]);
+
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+
+        let bodies = bodies.lock().unwrap();
+
+        assert_eq!(bodies.len(), 1);
+
+        let payload: serde_json::Value = serde_json::from_slice(&bodies[0]).unwrap();
+
+        assert_eq!(payload["url"], get_uris[0].as_str());
+        assert_eq!(payload["id"], get_uris[0].rsplit('/').next().unwrap());
+        assert_eq!(payload["size"], data.len() as u64);
+        assert_eq!(payload["hash"], hex::encode(Sha256::digest(data.as_bytes())));
+        assert_eq!(payload["content_type"], media::DEFAULT_CONTENT_TYPE);
+        assert!(payload["created_at"].is_i64());
+    }
+
+    #[tokio::test]
+    async fn memory_storage_quota() {
+        let mut handler = new_memory_test_handler().await;
+
+        let first = format!("quota-first-{:020}", rand::random::<u64>());
+        let second = format!("quota-other-{:020}", rand::random::<u64>());
+        assert_eq!(first.len(), second.len());
+
+        // the second upload crosses the quota by 1 byte
+        handler
+            .db
+            .set_quota(StorageQuota::new((first.len() * 2 - 1) as _, 0));
+        let db = handler.db.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let upload = |data: String| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data))
+                .unwrap()
+        };
+
+        let mut first_resp = handle.call(upload(first.clone())).await.unwrap();
+        assert_eq!(first_resp.status(), StatusCode::OK);
+
+        let resp_data = body::to_bytes(first_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+
+        // dedup hit adds no bytes
+        let dedup_resp = handle.call(upload(first.clone())).await.unwrap();
+        assert_eq!(dedup_resp.status(), StatusCode::OK);
+
+        let second_resp = handle.call(upload(second.clone())).await.unwrap();
+        assert_eq!(second_resp.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        let err_resp: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(second_resp).await.unwrap()).unwrap();
+        assert_eq!(err_resp.code, "insufficient_storage");
+
+        // deleting gives back the bytes
+        let log_cx = LogContext::builder().request_id("test").build();
+        db.delete_resources(&[resource_id], &log_cx).await.unwrap();
+
+        let second_resp = handle.call(upload(second)).await.unwrap();
+        assert_eq!(second_resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn memory_upload_max_body_size() {
+        let mut handler = new_memory_test_handler().await;
+        handler.size_limits = size_limits(64, DEFAULT_UPLOAD_PATH, Some(8), None);
+
+        let mut handle = handler.call(()).await.unwrap();
+
+        let req = |method: Method, path: &str, data: Vec<u8>| {
+            Request::builder()
+                .method(method)
+                .uri(format!("https://test.com{}", path))
+                .header("authorization", "Bearer test-token")
+                .body(Body::from(data))
+                .unwrap()
+        };
+
+        let large = rand::random::<[u8; 16]>().to_vec();
+
+        for (method, path) in &[
+            (Method::POST, "/upload"),
+            (Method::POST, "/v1/upload"),
+            (Method::PUT, "/upload/max-body-size"),
+        ] {
+            let resp = handle
+                .call(req(method.clone(), path, large.clone()))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE, "{} {}", method, path);
+
+            let err_resp: ErrorResponse =
+                serde_json::from_slice(&body::to_bytes(resp).await.unwrap()).unwrap();
+            assert_eq!(err_resp.code, "payload_too_large");
+        }
+
+        let small = rand::random::<[u8; 8]>().to_vec();
+        let resp = handle
+            .call(req(Method::POST, "/upload", small))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the other routes use the default limit
+        let delete_body = serde_json::to_vec(&["max-body-size-not-exist"]).unwrap();
+        assert!(delete_body.len() > 8);
+
+        let resp = handle
+            .call(req(Method::POST, "/delete-batch", delete_body))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn admin_token_eq() {
+        assert!(token_eq("test-token", "test-token"));
+        assert!(!token_eq("test-tokem", "test-token"));
+        assert!(!token_eq("test", "test-token"));
+        assert!(!token_eq("", "test-token"));
+    }
+
+    #[test]
+    fn client_id_validation() {
+        for valid in &["a", "my-key_01", "ABCDEF0123", "0123456789a", "k".repeat(64).as_str()] {
+            assert!(is_valid_client_id(valid, IdEncoding::Counter), "{}", valid);
+        }
+
+        for invalid in &["", "a.webp", "a/b", "中文", "0123456789", "k".repeat(65).as_str()] {
+            assert!(!is_valid_client_id(invalid, IdEncoding::Counter), "{}", invalid);
+        }
+
+        assert!(is_valid_client_id("0123456789", IdEncoding::Snowflake));
+        assert!(!is_valid_client_id("0123456789a", IdEncoding::Snowflake));
+    }
+
+    #[tokio::test]
+    async fn memory_upload_with_id() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let resource_id = format!("client-{}", rand::random::<u64>());
+        let data = format!("put-{}", rand::random::<u64>());
+
+        let put = |id: &str, data: String| {
+            Request::builder()
+                .method(Method::PUT)
+                .uri(format!("https://test.com/upload/{}", id))
+                .body(Body::from(data))
+                .unwrap()
+        };
+
+        // create
+        let mut resp = handle.call(put(&resource_id, data.clone())).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resource_uri = body::to_bytes(resp.body_mut()).await.unwrap();
+
+        assert_eq!(
+            resource_uri,
+            format!("https://test.com/get/{}", resource_id).as_bytes()
+        );
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&String::from_utf8_lossy(&resource_uri)).unwrap())
+            .body(Body::empty())
+            .unwrap();
+        let mut get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(
+            body::to_bytes(get_resp.body_mut()).await.unwrap(),
+            data.as_bytes()
+        );
+
+        // idempotent repeat
+        let mut resp = handle.call(put(&resource_id, data.clone())).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body::to_bytes(resp.body_mut()).await.unwrap(), resource_uri);
+
+        // conflicting content
+        let mut resp = handle
+            .call(put(&resource_id, format!("{}-other", data)))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let error_response: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+
+        assert_eq!(error_response.code, "resource_conflict");
+
+        // invalid id
+        let resp = handle.call(put("a.webp", data.clone())).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        handler
+            .db
+            .delete_resources(&[resource_id], &log_context(&Request::new(Body::empty())))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_method_not_allowed() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        for (method, path, allow) in &[
+            (Method::POST, "/get/x", "GET, HEAD"),
+            (Method::PUT, "/get/x", "GET, HEAD"),
+            (Method::GET, "/upload", "POST, HEAD"),
+            (Method::PUT, "/upload", "POST, HEAD"),
+            (Method::DELETE, "/upload/x", "POST, PUT"),
+            (Method::DELETE, "/meta/x", "GET"),
+            (Method::GET, "/delete-batch", "POST"),
+            (Method::POST, "/dedup-stats", "GET"),
+            (Method::POST, "/usage", "GET"),
+            (Method::POST, "/id-stats", "GET"),
+            (Method::GET, "/by-hash/x", "DELETE"),
+            (Method::DELETE, "/hash/x", "GET"),
+            (Method::GET, "/copy", "POST"),
+            (Method::GET, "/reconcile", "POST"),
+            (Method::GET, "/bucket/x", "DELETE"),
+            (Method::POST, "/list", "GET"),
+            (Method::POST, "/ready", "GET"),
+            (Method::GET, "/sweep", "POST"),
+            (Method::POST, "/sign/x", "GET"),
+            (Method::GET, "/files", "OPTIONS, POST"),
+            (Method::GET, "/files/x", "HEAD, PATCH"),
+            (Method::POST, "/upload-policy", "GET"),
+            (Method::GET, "/upload-policy/complete", "POST"),
+            (Method::POST, "/v1/get/x", "GET, HEAD"),
+        ] {
+            let req = Request::builder()
+                .method(method.clone())
+                .uri(format!("https://test.com{}", path))
+                .body(Body::empty())
+                .unwrap();
+
+            let mut resp = handle.call(req).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, path);
+            assert_eq!(resp.headers()["allow"], *allow, "{} {}", method, path);
+
+            let error_response: ErrorResponse =
+                serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+
+            assert_eq!(error_response.code, "method_not_allowed");
+        }
+
+        for path in &["/unknown", "/v1/unknown"] {
+            let req = Request::builder()
+                .method(Method::GET)
+                .uri(format!("https://test.com{}", path))
+                .body(Body::empty())
+                .unwrap();
+
+            let resp = handle.call(req).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", path);
+            assert!(resp.headers().get("allow").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_tus_upload() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("tus-{}", rand::random::<u128>()).into_bytes();
+        let (first, second) = data.split_at(data.len() / 2);
+
+        let options_req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("https://test.com/files")
+            .body(Body::empty())
+            .unwrap();
+
+        let options_resp = handle.call(options_req).await.unwrap();
+        assert_eq!(options_resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(options_resp.headers()[tus::TUS_VERSION_HEADER], tus::TUS_VERSION);
+
+        let create_req = |version: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/files")
+                .header(tus::TUS_RESUMABLE_HEADER, version)
+                .header(tus::UPLOAD_LENGTH_HEADER, data.len())
+                // "test.txt"
+                .header(tus::UPLOAD_METADATA_HEADER, "filename dGVzdC50eHQ=")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let create_resp = handle.call(create_req("0.2.2")).await.unwrap();
+        assert_eq!(create_resp.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(create_resp.headers()[tus::TUS_VERSION_HEADER], tus::TUS_VERSION);
+
+        let create_resp = handle.call(create_req(tus::TUS_VERSION)).await.unwrap();
+        assert_eq!(create_resp.status(), StatusCode::CREATED);
+        assert_eq!(create_resp.headers()[tus::TUS_RESUMABLE_HEADER], tus::TUS_VERSION);
+
+        let location = create_resp.headers()["location"].to_str().unwrap().to_string();
+        assert!(location.starts_with("https://test.com/files/"));
+
+        let head_req = || {
+            Request::builder()
+                .method(Method::HEAD)
+                .uri(location.as_str())
+                .header(tus::TUS_RESUMABLE_HEADER, tus::TUS_VERSION)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let patch_req = |offset: usize, chunk: &[u8]| {
+            Request::builder()
+                .method(Method::PATCH)
+                .uri(location.as_str())
+                .header(tus::TUS_RESUMABLE_HEADER, tus::TUS_VERSION)
+                .header("content-type", tus::OFFSET_CONTENT_TYPE)
+                .header(tus::UPLOAD_OFFSET_HEADER, offset)
+                .body(Body::from(chunk.to_vec()))
+                .unwrap()
+        };
+
+        let head_resp = handle.call(head_req()).await.unwrap();
+        assert_eq!(head_resp.status(), StatusCode::OK);
+        assert_eq!(head_resp.headers()[tus::UPLOAD_OFFSET_HEADER], "0");
+        assert_eq!(
+            head_resp.headers()[tus::UPLOAD_LENGTH_HEADER],
+            data.len().to_string().as_str()
+        );
+
+        let patch_resp = handle.call(patch_req(0, first)).await.unwrap();
+        assert_eq!(patch_resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            patch_resp.headers()[tus::UPLOAD_OFFSET_HEADER],
+            first.len().to_string().as_str()
+        );
+        assert!(patch_resp.headers().get("content-location").is_none());
+
+        // the chunk is sent again after a lost response
+        let patch_resp = handle.call(patch_req(0, first)).await.unwrap();
+        assert_eq!(patch_resp.status(), StatusCode::CONFLICT);
+
+        // resume on a restarted handler, the state is kept in the database and the store
+        let mut handler = new_test_handler_with(handler.store_backend.clone()).await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let head_resp = handle.call(head_req()).await.unwrap();
+        assert_eq!(
+            head_resp.headers()[tus::UPLOAD_OFFSET_HEADER],
+            first.len().to_string().as_str()
+        );
+
+        let patch_resp = handle.call(patch_req(first.len(), second)).await.unwrap();
+        assert_eq!(patch_resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            patch_resp.headers()[tus::UPLOAD_OFFSET_HEADER],
+            data.len().to_string().as_str()
+        );
+
+        let get_uri = patch_resp.headers()["content-location"].to_str().unwrap().to_string();
+
+        let get_req = Request::builder()
+            .uri(get_uri.as_str())
+            .body(Body::empty())
+            .unwrap();
+
+        let get_resp = handle.call(get_req).await.unwrap();
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), data);
+
+        let resource_id = get_uri.rsplit('/').next().unwrap();
+        let log_cx = LogContext::builder().request_id("test").build();
+        let resource = handler
+            .db
+            .get_resource_by_id(resource_id, &log_cx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resource.get_filename(), Some("test.txt"));
+
+        // the finished upload is removed
+        let head_resp = handle.call(head_req()).await.unwrap();
+        assert_eq!(head_resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn memory_copy() {
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("copy-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let source_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let source_id = source_uri.rsplit('/').next().unwrap().to_string();
+
+        let copy_req = |body: serde_json::Value, token: Option<&str>| {
+            let mut builder = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/copy");
+
+            if let Some(token) = token {
+                builder = builder.header("authorization", format!("Bearer {}", token));
+            }
+
+            builder.body(Body::from(body.to_string())).unwrap()
+        };
+
+        let destination = format!("copy-{}", rand::random::<u64>());
+        let body = serde_json::json!({ "source": source_id, "destination": destination });
+
+        let resp = handle.call(copy_req(body.clone(), None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let mut resp = handle
+            .call(copy_req(body.clone(), Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let copied_uri = body::to_bytes(resp.body_mut()).await.unwrap();
+        let copied_uri = String::from_utf8_lossy(&copied_uri).to_string();
+        assert_eq!(copied_uri, format!("https://test.com/get/{}", destination));
+
+        let bucket = Local::now().format("%Y-%m").to_string();
+        assert!(store_backend.contains(&bucket, &destination));
+
+        for uri in &[&source_uri, &copied_uri] {
+            let get_req = Request::builder()
+                .uri(Uri::from_str(uri).unwrap())
+                .body(Body::empty())
+                .unwrap();
+            let mut get_resp = handle.call(get_req).await.unwrap();
+
+            assert_eq!(get_resp.status(), StatusCode::OK);
+            assert_eq!(
+                body::to_bytes(get_resp.body_mut()).await.unwrap(),
+                data.as_bytes()
+            );
+        }
+
+        // the destination is taken
+        let resp = handle
+            .call(copy_req(body, Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // generated destination
+        let mut resp = handle
+            .call(copy_req(
+                serde_json::json!({ "source": source_id }),
+                Some("test-token"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let generated_uri = body::to_bytes(resp.body_mut()).await.unwrap();
+        let generated_id = String::from_utf8_lossy(&generated_uri)
+            .rsplit('/')
+            .next()
+            .unwrap()
+            .to_string();
+
+        let resp = handle
+            .call(copy_req(
+                serde_json::json!({ "source": "not-exist" }),
+                Some("test-token"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        handler
+            .db
+            .delete_resources(
+                &[source_id, destination, generated_id],
+                &log_context(&Request::new(Body::empty())),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_default_content_type() {
+        let mut handler = new_memory_test_handler().await;
+        handler.default_content_type = Arc::new("image/jpeg".to_string());
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("not an image {}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        assert_eq!(post_resp.status(), StatusCode::OK);
+
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        let get_req = Request::builder().uri(uri.as_str()).body(Body::empty()).unwrap();
+
+        let mut get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(get_resp.headers()["content-type"], "image/jpeg");
+        assert_eq!(
+            body::to_bytes(get_resp.body_mut()).await.unwrap(),
+            data.as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_verify_on_read() {
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        handler.verify_on_read = true;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("verify-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = uri.rsplit('/').next().unwrap().to_string();
+
+        let get_req = |range: Option<&str>| {
+            let mut builder = Request::builder().uri(uri.as_str());
+
+            if let Some(range) = range {
+                builder = builder.header("range", range);
+            }
+
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let mut resp = handle.call(get_req(None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            body::to_bytes(resp.body_mut()).await.unwrap(),
+            data.as_bytes()
+        );
+
+        // the backend returns the tampered bytes with the same size
+        let mut tampered = data.clone().into_bytes();
+        tampered[0] ^= 1;
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        let bucket = Local::now().format("%Y-%m").to_string();
+        store_backend
+            .delete(&bucket, &resource_id, &log_cx)
+            .await
+            .unwrap();
+        store_backend
+            .put(&bucket, &resource_id, tampered.as_slice(), &log_cx)
+            .await
+            .unwrap();
+
+        let mut resp = handle.call(get_req(None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let error_response: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+        assert_eq!(error_response.code, "integrity_check_failed");
+
+        // the range reads are not verified
+        let resp = handle.call(get_req(Some("bytes=1-"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+
+        handler
+            .db
+            .delete_resources(&[resource_id], &log_cx)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_hotlink_protection() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("hotlink-{}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = uri.rsplit('/').next().unwrap().to_string();
+
+        let get_req = |referer: Option<&str>| {
+            let mut builder = Request::builder().uri(uri.as_str());
+
+            if let Some(referer) = referer {
+                builder = builder.header("referer", referer);
+            }
+
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let mut hotlink_protection = HotlinkProtection::new(["blog.test.com"], true);
+        handler.hotlink_protection = Some(Arc::new(hotlink_protection));
+        let mut handle = handler.call(()).await.unwrap();
+
+        for referer in &[Some("https://BLOG.test.com/post"), None] {
+            let mut resp = handle.call(get_req(*referer)).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::OK, "{:?}", referer);
+            assert_eq!(
+                body::to_bytes(resp.body_mut()).await.unwrap(),
+                data.as_bytes()
+            );
+        }
+
+        let mut resp = handle.call(get_req(Some("https://evil.com/"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let error_response: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+        assert_eq!(error_response.code, "hotlink_forbidden");
+
+        let head_req = Request::builder()
+            .method(Method::HEAD)
+            .uri(uri.as_str())
+            .header("referer", "https://evil.com/")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = handle.call(head_req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        hotlink_protection = HotlinkProtection::new(["blog.test.com"], false);
+        hotlink_protection.set_placeholder(Bytes::from_static(b"GIF89a-placeholder"));
+        handler.hotlink_protection = Some(Arc::new(hotlink_protection));
+        let mut handle = handler.call(()).await.unwrap();
+
+        for referer in &[Some("https://evil.com/"), None] {
+            let mut resp = handle.call(get_req(*referer)).await.unwrap();
+
+            assert_eq!(resp.status(), StatusCode::OK, "{:?}", referer);
+            assert_eq!(resp.headers()["content-type"], "image/gif");
+            assert_eq!(resp.headers()["cache-control"], "no-store");
+            assert_eq!(
+                body::to_bytes(resp.body_mut()).await.unwrap(),
+                "GIF89a-placeholder"
+            );
+        }
+
+        handler
+            .db
+            .delete_resources(&[resource_id], &log_context(&Request::new(Body::empty())))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_concurrent_dedup_upload() {
+        const UPLOADS: usize = 8;
+
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        let data = format!("concurrent-{}", rand::random::<u64>());
+
+        let mut uploads = vec![];
+        for _ in 0..UPLOADS {
+            let mut handle = handler.call(()).await.unwrap();
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data.clone()))
+                .unwrap();
+
+            uploads.push(tokio::spawn(async move {
+                let mut resp = handle.call(req).await.unwrap();
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let uri = body::to_bytes(resp.body_mut()).await.unwrap();
+                String::from_utf8_lossy(&uri).to_string()
+            }));
+        }
+
+        let mut uris = vec![];
+        for upload in uploads {
+            uris.push(upload.await.unwrap());
+        }
+
+        // the losers get the resource of the winner instead of inserting their own
+        assert!(uris.iter().all(|uri| *uri == uris[0]), "{:?}", uris);
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        let bucket = Local::now().format("%Y-%m").to_string();
+        let objects = store_backend
+            .list(&bucket, None, UPLOADS * 2, &log_cx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(objects.len(), 1);
+
+        let resource_id = uris[0].rsplit('/').next().unwrap().to_string();
+        assert_eq!(objects[0].id, resource_id);
+
+        handler
+            .db
+            .delete_resources(&[resource_id], &log_cx)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_reconcile() {
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        // a bucket of its own, so the resources of the other tests are not touched
+        let bucket = format!("reconcile-{}", rand::random::<u32>());
+        let suffix = rand::random::<u64>();
+        let missing_id = format!("missing-{}", suffix);
+        let orphan_id = format!("orphan-{}", suffix);
+        let normal_id = format!("normal-{}", suffix);
+
+        for resource_id in &[&missing_id, &normal_id] {
+            handler
+                .db
+                .insert_resource(
+                    &bucket,
+                    resource_id,
+                    "hash",
+                    4,
+                    "text/plain",
+                    None,
+                    None,
+                    &log_cx,
+                )
+                .await
+                .unwrap();
+        }
+
+        for resource_id in &[orphan_id.clone(), normal_id.clone(), format!("{}.webp", normal_id)] {
+            store_backend
+                .put(&bucket, resource_id, &b"test"[..], &log_cx)
+                .await
+                .unwrap();
+        }
+
+        let reconcile_req = |delete: bool, token: Option<&str>| {
+            let mut builder = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/reconcile");
+
+            if let Some(token) = token {
+                builder = builder.header("authorization", format!("Bearer {}", token));
+            }
+
+            let body = serde_json::json!({
+                "bucket": bucket,
+                "delete": delete,
+                "batch_size": 1,
+                "min_age": 0,
+            });
+
+            builder.body(Body::from(body.to_string())).unwrap()
+        };
+
+        let resp = handle.call(reconcile_req(false, None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        for delete in &[false, true] {
+            let mut resp = handle
+                .call(reconcile_req(*delete, Some("test-token")))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let report: serde_json::Value =
+                serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+
+            assert_eq!(report["checked_resources"], 2);
+            assert_eq!(report["checked_objects"], 3);
+            assert_eq!(report["missing_object_count"], 1);
+            assert_eq!(report["missing_objects"][0]["id"], missing_id.as_str());
+            assert_eq!(report["orphan_object_count"], 1);
+            assert_eq!(report["orphan_objects"][0]["id"], orphan_id.as_str());
+            assert_eq!(report["deleted"], *delete);
+
+            // the orphans are only reported without delete
+            let missing_resource = handler.db.get_resource_by_id(&missing_id, &log_cx).await.unwrap();
+
+            assert_eq!(missing_resource.is_some(), !*delete);
+            assert_eq!(store_backend.contains(&bucket, &orphan_id), !*delete);
+        }
+
+        assert!(handler.db.get_resource_by_id(&normal_id, &log_cx).await.unwrap().is_some());
+        assert!(store_backend.contains(&bucket, &normal_id));
+        assert!(store_backend.contains(&bucket, &format!("{}.webp", normal_id)));
+
+        handler
+            .db
+            .delete_resources(&[normal_id], &log_cx)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_upload_policy() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let policy_req = |token: Option<&str>| {
+            let mut builder = Request::builder().uri("https://test.com/upload-policy");
+
+            if let Some(token) = token {
+                builder = builder.header("authorization", format!("Bearer {}", token));
+            }
+
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let resp = handle.call(policy_req(None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let mut resp = handle.call(policy_req(Some("test-token"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+
+        let policy: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+
+        let resource_id = policy["id"].as_str().unwrap();
+        let bucket = Local::now().format("%Y-%m").to_string();
+
+        assert!(IdEncoding::Counter.is_generated(resource_id));
+        assert_eq!(policy["bucket"], bucket.as_str());
+        assert_eq!(
+            policy["resource_url"],
+            format!("https://test.com/get/{}", resource_id).as_str()
+        );
+        assert!(policy["expires_at"].as_i64().unwrap() > Local::now().timestamp());
+        assert_eq!(policy["form"]["url"], format!("memory://{}", bucket).as_str());
+        assert_eq!(policy["form"]["fields"]["key"], resource_id);
+    }
+
+    #[tokio::test]
+    async fn memory_upload_policy_complete() {
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        let resource_id = handler.id_generator.get_id(&log_cx).await.unwrap();
+        let bucket = Local::now().format("%Y-%m").to_string();
+        let data = format!("presigned-{}", rand::random::<u64>());
+
+        let complete_req = |body: serde_json::Value| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload-policy/complete")
+                .header("authorization", "Bearer test-token")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let body = serde_json::json!({ "id": resource_id, "bucket": bucket });
+
+        // the browser hasn't posted the object yet
+        let resp = handle.call(complete_req(body.clone())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = handle
+            .call(complete_req(serde_json::json!({ "id": resource_id, "bucket": "../x" })))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        store_backend
+            .put(&bucket, &resource_id, data.as_bytes(), &log_cx)
+            .await
+            .unwrap();
+
+        let mut resp = handle.call(complete_req(body.clone())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let uri = body::to_bytes(resp.body_mut()).await.unwrap();
+        let uri = String::from_utf8_lossy(&uri).to_string();
+        assert_eq!(uri, format!("https://test.com/get/{}", resource_id));
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+        let mut get_resp = handle.call(get_req).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(
+            body::to_bytes(get_resp.body_mut()).await.unwrap(),
+            data.as_bytes()
+        );
+
+        // the upload is recorded once
+        let resp = handle.call(complete_req(body)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        handler
+            .db
+            .delete_resources(&[resource_id], &log_cx)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_delete_by_hash() {
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("by-hash-{}", rand::random::<u64>());
+        let hash = hex::encode(Sha256::digest(data.as_bytes()));
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+        let resource_id = get_uri.rsplit('/').next().unwrap().to_string();
+
+        let delete_req = |hash: &str, token: Option<&str>| {
+            let mut builder = Request::builder()
+                .method(Method::DELETE)
+                .uri(format!("https://test.com/by-hash/{}", hash));
+
+            if let Some(token) = token {
+                builder = builder.header("authorization", format!("Bearer {}", token));
+            }
+
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let resp = handle.call(delete_req(&hash, None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        for invalid_hash in &["not-hex", &hash[1..], format!("{}zz", &hash[2..]).as_str()] {
+            let resp = handle
+                .call(delete_req(invalid_hash, Some("test-token")))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", invalid_hash);
+        }
+
+        let resp = handle
+            .call(delete_req(&hash.to_ascii_uppercase(), Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let bucket = Local::now().format("%Y-%m").to_string();
+        assert!(!store_backend.contains(&bucket, &resource_id));
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .body(Body::empty())
+            .unwrap();
+        let get_resp = handle.call(get_req).await.unwrap();
+        assert_eq!(get_resp.status(), StatusCode::NOT_FOUND);
 
-        Ok(resp_builder.body(Body::empty())?)
+        let resp = handle
+            .call(delete_req(&hash, Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
-}
 
-fn get_request_id(req: &Request<Body>) -> &str {
-    req.headers()
-        .get("X-image-bed-request-id")
-        .map(|value| value.to_str().unwrap_or(""))
-        .unwrap_or("")
-}
+    #[tokio::test]
+    async fn memory_get_by_hash() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
 
-#[cfg(test)]
-mod tests {
-    use std::env;
-    use std::str::FromStr;
+        let data = media::testing::png(32, 32);
+        let hash = hex::encode(Sha256::digest(&data));
 
-    use sqlx::postgres::PgPoolOptions;
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
 
-    use crate::store::cos::CosBackend;
+        let post_resp = handle.call(post_req).await.unwrap();
+        assert_eq!(post_resp.status(), StatusCode::OK);
 
-    use super::*;
+        let get_req = |hash: &str, range: Option<&str>| {
+            let mut builder = Request::builder().uri(format!("https://test.com/hash/{}", hash));
 
-    #[tokio::test]
-    async fn put_resource() {
-        let access_key = env::var("COS_ACCESS_KEY").expect("need set COS_ACCESS_KEY env");
-        let secret_key = env::var("COS_SECRET_KEY").expect("need set COS_SECRET_KEY env");
-        let region = env::var("COS_REGION").expect("need set COS_REGION env");
-        let app_id = env::var("COS_APP_ID").expect("need set COS_APP_ID env");
-        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
-        let id_type = env::var("ID_TYPE").expect("must set environment ID_TYPE");
+            if let Some(range) = range {
+                builder = builder.header("range", range);
+            }
 
-        let pg_pool = PgPoolOptions::new()
-            .max_connections(1)
-            .connect(&pg_uri)
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let get_resp = handle.call(get_req(&hash, None)).await.unwrap();
+
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(get_resp.headers()["content-type"], "image/png");
+        assert_eq!(get_resp.headers()["etag"], format!("\"{}\"", hash));
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), data);
+
+        let range_resp = handle
+            .call(get_req(&hash, Some("bytes=0-7")))
             .await
             .unwrap();
 
-        let id_generator = Generator::new(&pg_pool, &id_type).await.unwrap();
-        let store_backend = CosBackend::new(&access_key, &secret_key, &region, &app_id);
-        let db = Database::new(&pg_pool).await.unwrap();
+        assert_eq!(range_resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body::to_bytes(range_resp).await.unwrap(), data[..8]);
 
-        let mut handler = Handler {
-            store_backend: Arc::new(store_backend),
-            id_generator,
-            db,
-            domain: Arc::new("test.com".to_string()),
-            max_body_size: 10 * 1024 * 1024,
+        // the hex is case insensitive
+        let get_resp = handle
+            .call(get_req(&hash.to_ascii_uppercase(), None))
+            .await
+            .unwrap();
+        assert_eq!(get_resp.status(), StatusCode::OK);
+
+        for invalid_hash in &["not-hex", &hash[1..], format!("md5:{}", hash).as_str()] {
+            let resp = handle.call(get_req(invalid_hash, None)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", invalid_hash);
+        }
+
+        let missing_hash = hex::encode(Sha256::digest(
+            format!("missing-{}", rand::random::<u64>()).as_bytes(),
+        ));
+
+        let resp = handle.call(get_req(&missing_hash, None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn memory_get_with_extension() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let upload = |data: Vec<u8>| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data))
+                .unwrap()
         };
 
-        let data = b"test";
+        let get_req = |method: Method, uri: String| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
 
-        let mut req = Request::new(Body::from(&data[..]));
-        *req.method_mut() = Method::POST;
-        *req.uri_mut() = Uri::from_static("https://test.com/upload");
+        let png = media::testing::png(16, 16);
+        let mut post_resp = handle.call(upload(png.clone())).await.unwrap();
+        let png_uri = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let png_uri = String::from_utf8_lossy(&png_uri).to_string();
+
+        for method in &[Method::GET, Method::HEAD] {
+            for extension in &["png", "PNG"] {
+                let resp = handle
+                    .call(get_req(method.clone(), format!("{}.{}", png_uri, extension)))
+                    .await
+                    .unwrap();
+
+                assert_eq!(resp.status(), StatusCode::OK, "{} {}", method, extension);
+                assert_eq!(resp.headers()["content-type"], "image/png");
+
+                if *method == Method::GET {
+                    assert_eq!(body::to_bytes(resp).await.unwrap(), png);
+                }
+            }
+
+            // the extension doesn't match the stored type, or is not an image extension
+            for extension in &["gif", "txt"] {
+                let resp = handle
+                    .call(get_req(method.clone(), format!("{}.{}", png_uri, extension)))
+                    .await
+                    .unwrap();
+
+                assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{} {}", method, extension);
+            }
+        }
+
+        // the default content type is overridden by the extension
+        let data = format!("extension-{}", rand::random::<u64>());
+        let mut post_resp = handle.call(upload(data.clone().into_bytes())).await.unwrap();
+        let data_uri = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let data_uri = String::from_utf8_lossy(&data_uri).to_string();
+
+        let resp = handle
+            .call(get_req(Method::GET, format!("{}.jpg", data_uri)))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "image/jpeg");
+        assert_eq!(body::to_bytes(resp).await.unwrap(), data.as_bytes());
+
+        let resp = handle.call(get_req(Method::GET, data_uri.clone())).await.unwrap();
+        assert_eq!(resp.headers()["content-type"], media::DEFAULT_CONTENT_TYPE);
 
+        handler.extension_content_type = false;
         let mut handle = handler.call(()).await.unwrap();
 
-        let mut resp = handle.call(req).await.unwrap();
+        let resp = handle
+            .call(get_req(Method::GET, format!("{}.jpg", data_uri)))
+            .await
+            .unwrap();
 
         assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], media::DEFAULT_CONTENT_TYPE);
+    }
 
-        eprintln!(
-            "result {}",
-            String::from_utf8_lossy(&body::to_bytes(resp.body_mut()).await.unwrap())
-        );
+    #[tokio::test]
+    async fn memory_delete_bucket() {
+        let mut handler = new_memory_test_handler().await;
+        let store_backend = handler.store_backend.clone();
+        let mut handle = handler.call(()).await.unwrap();
+
+        let log_cx = LogContext::builder().request_id("test").build();
+        // a bucket of its own, so the resources of the other tests are not touched
+        let bucket = format!("delete-bucket-{}", rand::random::<u32>());
+        let resource_id = format!("delete-bucket-{}", rand::random::<u64>());
+
+        handler
+            .db
+            .insert_resource(&bucket, &resource_id, "hash", 4, "text/plain", None, None, &log_cx)
+            .await
+            .unwrap();
+        store_backend
+            .put(&bucket, &resource_id, &b"test"[..], &log_cx)
+            .await
+            .unwrap();
+
+        let delete_req = |query: &str, token: Option<&str>| {
+            let mut builder = Request::builder()
+                .method(Method::DELETE)
+                .uri(format!("https://test.com/bucket/{}{}", bucket, query));
+
+            if let Some(token) = token {
+                builder = builder.header("authorization", format!("Bearer {}", token));
+            }
+
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let resp = handle.call(delete_req("", None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        for query in &["?empty=maybe", "?empty=false&dry_run=maybe"] {
+            let resp = handle
+                .call(delete_req(query, Some("test-token")))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
+
+        // the dry run returns the plan and deletes nothing
+        let mut resp = handle
+            .call(delete_req("?empty=false&dry_run=true", Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let plan: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+        assert_eq!(plan["bucket"], bucket.as_str());
+        assert_eq!(plan["dry_run"], true);
+        assert_eq!(plan["need_empty"], false);
+        assert_eq!(plan["would_reject"], false);
+        assert_eq!(plan["total_resources"], 1);
+        assert_eq!(plan["total_bytes"], 4);
+        assert_eq!(plan["resources"][0]["id"], resource_id.as_str());
+
+        // the default flag rejects the bucket with resources
+        let mut resp = handle
+            .call(delete_req("?dry_run=true", Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let plan: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+        assert_eq!(plan["need_empty"], true);
+        assert_eq!(plan["would_reject"], true);
+        assert_eq!(plan["total_resources"], 1);
+
+        assert!(store_backend.contains(&bucket, &resource_id));
+        assert!(handler
+            .db
+            .get_resource_by_id(&resource_id, &log_cx)
+            .await
+            .unwrap()
+            .is_some());
+
+        // the bucket must be empty by default
+        for query in &["", "?empty=true"] {
+            let mut resp = handle
+                .call(delete_req(query, Some("test-token")))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::CONFLICT, "{}", query);
+
+            let error_response: ErrorResponse =
+                serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+            assert_eq!(error_response.code, "bucket_not_empty");
+        }
+
+        assert!(store_backend.contains(&bucket, &resource_id));
+        assert!(handler
+            .db
+            .get_resource_by_id(&resource_id, &log_cx)
+            .await
+            .unwrap()
+            .is_some());
+
+        let mut resp = handle
+            .call(delete_req("?empty=false", Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let result: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(resp.body_mut()).await.unwrap()).unwrap();
+        assert_eq!(result["bucket"], bucket.as_str());
+        assert_eq!(result["deleted_resources"], 1);
+
+        assert!(!store_backend.contains(&bucket, &resource_id));
+        assert!(handler
+            .db
+            .get_resource_by_id(&resource_id, &log_cx)
+            .await
+            .unwrap()
+            .is_none());
+
+        // the empty bucket is deleted with the default flag
+        let resp = handle
+            .call(delete_req("", Some("test-token")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
     }
 
     #[tokio::test]
-    async fn get_resource() {
-        let access_key = env::var("COS_ACCESS_KEY").expect("need set COS_ACCESS_KEY env");
-        let secret_key = env::var("COS_SECRET_KEY").expect("need set COS_SECRET_KEY env");
-        let region = env::var("COS_REGION").expect("need set COS_REGION env");
-        let app_id = env::var("COS_APP_ID").expect("need set COS_APP_ID env");
-        let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
-        let id_type = env::var("ID_TYPE").expect("must set environment ID_TYPE");
+    async fn memory_list_resources() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
 
-        let pg_pool = PgPoolOptions::new()
-            .max_connections(1)
-            .connect(&pg_uri)
+        let log_cx = LogContext::builder().request_id("test").build();
+        // the buckets of its own, so the total is not changed by the other tests
+        let bucket = format!("list-{}", rand::random::<u32>());
+        let other_bucket = format!("list-other-{}", rand::random::<u32>());
+
+        let mut resource_ids = HashSet::new();
+        for _ in 0..5 {
+            let resource_id = format!("list-{}", rand::random::<u64>());
+
+            handler
+                .db
+                .insert_resource(
+                    &bucket,
+                    &resource_id,
+                    "hash",
+                    4,
+                    "text/plain",
+                    None,
+                    None,
+                    &log_cx,
+                )
+                .await
+                .unwrap();
+
+            resource_ids.insert(resource_id);
+        }
+
+        let other_resource_id = format!("list-{}", rand::random::<u64>());
+        handler
+            .db
+            .insert_resource(
+                &other_bucket,
+                &other_resource_id,
+                "hash",
+                4,
+                "text/plain",
+                None,
+                None,
+                &log_cx,
+            )
             .await
             .unwrap();
 
-        let id_generator = Generator::new(&pg_pool, &id_type).await.unwrap();
-        let store_backend = CosBackend::new(&access_key, &secret_key, &region, &app_id);
-        let db = Database::new(&pg_pool).await.unwrap();
+        let list_req = |query: &str, token: Option<&str>| {
+            let mut builder = Request::builder().uri(format!("https://test.com/list?{}", query));
 
-        let mut handler = Handler {
-            store_backend: Arc::new(store_backend),
-            id_generator,
-            db,
-            domain: Arc::new("test.com".to_string()),
-            max_body_size: 10 * 1024 * 1024,
+            if let Some(token) = token {
+                builder = builder.header("authorization", format!("Bearer {}", token));
+            }
+
+            builder.body(Body::empty()).unwrap()
         };
 
-        let data = b"test";
+        let resp = handle.call(list_req("", None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        for query in &[
+            "limit=-1",
+            "offset=x",
+            "bucket=Not_Valid",
+            "from=yesterday",
+            "from=20&to=10",
+        ] {
+            let resp = handle
+                .call(list_req(query, Some("test-token")))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", query);
+        }
 
-        let mut post_req = Request::new(Body::from(&data[..]));
-        *post_req.method_mut() = Method::POST;
-        *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+        let mut listed_ids = HashSet::new();
+        for (offset, count) in &[(0, 2), (2, 2), (4, 1), (5, 0)] {
+            let resp = handle
+                .call(list_req(
+                    &format!("bucket={}&limit=2&offset={}", bucket, offset),
+                    Some("test-token"),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let page: serde_json::Value =
+                serde_json::from_slice(&body::to_bytes(resp).await.unwrap()).unwrap();
+            assert_eq!(page["total"], 5);
+            assert_eq!(page["limit"], 2);
+            assert_eq!(page["offset"], *offset);
+
+            let resources = page["resources"].as_array().unwrap();
+            assert_eq!(resources.len(), *count, "offset {}", offset);
+
+            for resource in resources {
+                assert_eq!(resource["bucket"], bucket.as_str());
+                assert!(resource["resource_size"].is_i64());
+                assert!(resource["create_time"].is_i64());
+
+                // the pages don't overlap
+                assert!(listed_ids.insert(resource["id"].as_str().unwrap().to_owned()));
+            }
+        }
+
+        assert_eq!(listed_ids, resource_ids);
+
+        let resp = handle
+            .call(list_req(&format!("bucket={}", other_bucket), Some("test-token")))
+            .await
+            .unwrap();
+        let page: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(resp).await.unwrap()).unwrap();
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["resources"][0]["id"], other_resource_id.as_str());
+
+        let resp = handle
+            .call(list_req("limit=100000", Some("test-token")))
+            .await
+            .unwrap();
+        let page: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(resp).await.unwrap()).unwrap();
+        assert_eq!(page["limit"], MAX_LIST_LIMIT);
+        assert!(page["total"].as_u64().unwrap() >= 6);
+
+        resource_ids.insert(other_resource_id);
+        handler
+            .db
+            .delete_resources(&resource_ids.into_iter().collect::<Vec<_>>(), &log_cx)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_trace_spans() {
+        let exporter = telemetry::testing::exporter();
+
+        let mut handler = new_test_handler_with(TracedBackend::new(MemoryBackend::new())).await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(format!("trace-{}", rand::random::<u64>())))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        let trace_id = hex::encode(rand::random::<[u8; 16]>());
+        let parent_span_id = "00f067aa0ba902b7";
+
+        let get_req = Request::builder()
+            .uri(Uri::from_str(&get_uri).unwrap())
+            .header(TRACE_PARENT_HEADER, format!("00-{}-{}-01", trace_id, parent_span_id))
+            .body(Body::empty())
+            .unwrap();
+
+        let mut get_resp = handle.call(get_req).await.unwrap();
+        body::to_bytes(get_resp.body_mut()).await.unwrap();
+
+        let spans = exporter.spans(&trace_id);
+        let span = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap_or_else(|| panic!("span {} not in {:?}", name, spans))
+        };
+
+        let server = span("HTTP GET");
+        let route = span("get");
+        let db = span("db.get_resource_by_id");
+        let store = span("store.get_stream");
+
+        assert_eq!(server.kind, SpanKind::Server);
+        assert_eq!(server.parent_span_id.as_deref(), Some(parent_span_id));
+        assert!(server
+            .attributes
+            .contains(&("http.status_code", "200".to_string())));
+        assert_eq!(route.parent_span_id.as_deref(), Some(server.span_id.as_str()));
+        assert_eq!(db.parent_span_id.as_deref(), Some(route.span_id.as_str()));
+        assert_eq!(store.parent_span_id.as_deref(), Some(route.span_id.as_str()));
+        assert!(spans.iter().all(|span| !span.error));
+
+        assert_eq!(
+            get_resp.headers()[TRACE_PARENT_HEADER].to_str().unwrap(),
+            format!("00-{}-{}-01", trace_id, server.span_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_head_matches_get() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        let data = format!("head-{:020}", rand::random::<u64>());
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let mut post_resp = handle.call(post_req).await.unwrap();
+        let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        for (range, status, content_range, expect) in &[
+            (None, StatusCode::OK, None, &data[..]),
+            (Some("bytes=0-4"), StatusCode::PARTIAL_CONTENT, Some("bytes 0-4/25"), &data[..5]),
+            (Some("bytes=20-"), StatusCode::PARTIAL_CONTENT, Some("bytes 20-24/25"), &data[20..]),
+            (Some("bytes=-3"), StatusCode::PARTIAL_CONTENT, Some("bytes 22-24/25"), &data[22..]),
+            (Some("bytes=10-1000"), StatusCode::PARTIAL_CONTENT, Some("bytes 10-24/25"), &data[10..]),
+            (Some("bytes=25-"), StatusCode::RANGE_NOT_SATISFIABLE, Some("bytes */25"), ""),
+        ] {
+            let mut resps = vec![];
+
+            for method in &[Method::HEAD, Method::GET] {
+                let mut builder = Request::builder()
+                    .method(method.clone())
+                    .uri(Uri::from_str(&get_uri).unwrap());
+
+                if let Some(range) = range {
+                    builder = builder.header("range", *range);
+                }
+
+                resps.push(handle.call(builder.body(Body::empty()).unwrap()).await.unwrap());
+            }
+
+            let get_resp = resps.pop().unwrap();
+            let head_resp = resps.pop().unwrap();
+
+            assert_eq!(head_resp.status(), *status, "{:?}", range);
+            assert_eq!(get_resp.status(), *status, "{:?}", range);
+            assert_eq!(
+                head_resp.headers().get("content-range"),
+                get_resp.headers().get("content-range"),
+                "{:?}",
+                range
+            );
+            assert_eq!(
+                head_resp
+                    .headers()
+                    .get("content-range")
+                    .map(|value| value.to_str().unwrap()),
+                *content_range
+            );
+
+            if *status == StatusCode::RANGE_NOT_SATISFIABLE {
+                continue;
+            }
+
+            assert_eq!(
+                head_resp.headers()["content-length"],
+                get_resp.headers()["content-length"]
+            );
+            assert_eq!(
+                head_resp.headers()["content-type"],
+                get_resp.headers()["content-type"]
+            );
+            assert_eq!(
+                head_resp.headers()["content-length"],
+                expect.len().to_string().as_str()
+            );
+            assert!(body::to_bytes(head_resp).await.unwrap().is_empty());
+            assert_eq!(body::to_bytes(get_resp).await.unwrap(), expect.as_bytes());
+        }
+    }
 
+    #[tokio::test]
+    async fn memory_custom_path_prefixes() {
+        let mut handler = new_memory_test_handler().await;
+        handler.upload_path = Arc::new("/api/upload".to_string());
+        handler.get_path = Arc::new("/api/get".to_string());
         let mut handle = handler.call(()).await.unwrap();
 
+        let data = format!("custom-path-{}", rand::random::<u64>());
+
+        let old_post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
+        let old_post_resp = handle.call(old_post_req).await.unwrap();
+
+        assert_eq!(old_post_resp.status(), StatusCode::NOT_FOUND);
+
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/api/upload")
+            .body(Body::from(data.clone()))
+            .unwrap();
+
         let mut post_resp = handle.call(post_req).await.unwrap();
 
         assert_eq!(post_resp.status(), StatusCode::OK);
 
         let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
-        let get_uri = String::from_utf8_lossy(&resp_data);
-        eprintln!("get uri {}", get_uri);
+        let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+
+        assert!(get_uri.starts_with("https://test.com/api/get/"), "{}", get_uri);
 
         let get_req = Request::builder()
             .uri(Uri::from_str(&get_uri).unwrap())
@@ -646,8 +9458,138 @@ mod tests {
 
         let get_resp = handle.call(get_req).await.unwrap();
 
-        eprintln!("get resp {:?}", get_resp);
+        assert_eq!(get_resp.status(), StatusCode::OK);
+        assert_eq!(body::to_bytes(get_resp).await.unwrap(), data.as_bytes());
+    }
 
-        assert_eq!(body::to_bytes(get_resp).await.unwrap().as_ref(), b"test");
+    #[tokio::test]
+    async fn upload_allowed_content_types() {
+        let mut handler = new_memory_test_handler().await;
+        handler.allowed_content_types =
+            Arc::new(vec!["image/png".to_string(), "image/jpeg".to_string()]);
+
+        let mut handle = handler.call(()).await.unwrap();
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend_from_slice(&rand::random::<u64>().to_be_bytes());
+
+        let mut post_req = Request::new(Body::from(png));
+        *post_req.method_mut() = Method::POST;
+        *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+        let post_resp = handle.call(post_req).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::OK);
+
+        // the client supplied content type is ignored
+        let post_req = Request::builder()
+            .method(Method::POST)
+            .uri("https://test.com/upload")
+            .header("content-type", "image/png")
+            .body(Body::from(&b"GIF89a not allowed"[..]))
+            .unwrap();
+
+        let post_resp = handle.call(post_req).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn upload_max_image_dimensions() {
+        let mut handler = new_memory_test_handler().await;
+        handler.max_image_width = Some(20);
+        handler.max_image_height = Some(10);
+
+        let mut handle = handler.call(()).await.unwrap();
+
+        for (width, height, status) in &[
+            (20, 10, StatusCode::OK),
+            (21, 10, StatusCode::UNPROCESSABLE_ENTITY),
+            (20, 11, StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            let mut post_req = Request::new(Body::from(media::testing::png(*width, *height)));
+            *post_req.method_mut() = Method::POST;
+            *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+            let post_resp = handle.call(post_req).await.unwrap();
+
+            assert_eq!(post_resp.status(), *status, "{}x{}", width, height);
+        }
+
+        // not an image, skip the check
+        let mut post_req = Request::new(Body::from(rand::random::<u64>().to_string()));
+        *post_req.method_mut() = Method::POST;
+        *post_req.uri_mut() = Uri::from_static("https://test.com/upload");
+
+        let post_resp = handle.call(post_req).await.unwrap();
+
+        assert_eq!(post_resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn memory_upload_dimensions() {
+        let mut handler = new_memory_test_handler().await;
+        let mut handle = handler.call(()).await.unwrap();
+
+        for (data, dimensions) in [
+            (media::testing::png(30, 20), Some((30, 20))),
+            (rand::random::<u64>().to_string().into_bytes(), None),
+        ] {
+            let post_req = Request::builder()
+                .method(Method::POST)
+                .uri("https://test.com/upload")
+                .body(Body::from(data))
+                .unwrap();
+
+            let mut post_resp = handle.call(post_req).await.unwrap();
+            let resp_data = body::to_bytes(post_resp.body_mut()).await.unwrap();
+            let get_uri = String::from_utf8_lossy(&resp_data).to_string();
+            let resource_id = get_uri.rsplit('/').next().unwrap();
+
+            let resource = handler
+                .db
+                .get_resource_by_id(resource_id, &LogContext::builder().request_id("").build())
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(resource.get_dimensions(), dimensions);
+
+            let head_req = Request::builder()
+                .method(Method::HEAD)
+                .uri(Uri::from_str(&get_uri).unwrap())
+                .body(Body::empty())
+                .unwrap();
+
+            let head_resp = handle.call(head_req).await.unwrap();
+
+            assert_eq!(head_resp.status(), StatusCode::OK);
+            assert_eq!(
+                head_resp
+                    .headers()
+                    .get(IMAGE_WIDTH_HEADER)
+                    .map(|value| value.to_str().unwrap().to_string()),
+                dimensions.map(|(width, _)| width.to_string())
+            );
+            assert_eq!(
+                head_resp
+                    .headers()
+                    .get(IMAGE_HEIGHT_HEADER)
+                    .map(|value| value.to_str().unwrap().to_string()),
+                dimensions.map(|(_, height)| height.to_string())
+            );
+
+            let meta_req = Request::builder()
+                .uri(format!("https://test.com/meta/{}", resource_id))
+                .body(Body::empty())
+                .unwrap();
+
+            let meta_resp = handle.call(meta_req).await.unwrap();
+            let meta: serde_json::Value =
+                serde_json::from_slice(&body::to_bytes(meta_resp).await.unwrap()).unwrap();
+
+            assert_eq!(meta["width"], serde_json::json!(dimensions.map(|(width, _)| width)));
+            assert_eq!(meta["height"], serde_json::json!(dimensions.map(|(_, height)| height)));
+        }
     }
 }
@@ -0,0 +1,134 @@
+use bytes::Bytes;
+use hyper::http::HeaderValue;
+use hyper::Uri;
+
+use crate::media;
+
+/// Reject the downloads embedded by other sites, the `Referer` host must be in the allowlist.
+#[derive(Debug)]
+pub struct HotlinkProtection {
+    allowed_hosts: Vec<String>,
+    allow_empty_referer: bool,
+    placeholder: Option<Placeholder>,
+}
+
+/// The image which is served to the rejected downloads instead of 403.
+#[derive(Debug)]
+pub struct Placeholder {
+    pub data: Bytes,
+    pub content_type: &'static str,
+}
+
+impl HotlinkProtection {
+    pub fn new<I, T>(allowed_hosts: I, allow_empty_referer: bool) -> Self
+        where
+            I: IntoIterator<Item=T>,
+            T: AsRef<str>,
+    {
+        Self {
+            allowed_hosts: allowed_hosts
+                .into_iter()
+                .map(|host| host.as_ref().to_ascii_lowercase())
+                .collect(),
+            allow_empty_referer,
+            placeholder: None,
+        }
+    }
+
+    pub fn set_placeholder(&mut self, data: Bytes) -> &mut Self {
+        let content_type = media::detect_content_type(&data).unwrap_or(media::DEFAULT_CONTENT_TYPE);
+
+        self.placeholder.replace(Placeholder { data, content_type });
+
+        self
+    }
+
+    pub fn placeholder(&self) -> Option<&Placeholder> {
+        self.placeholder.as_ref()
+    }
+
+    /// The invalid `Referer` is rejected, the missing or empty one is allowed only when
+    /// `allow_empty_referer` is set.
+    pub fn is_allowed(&self, referer: Option<&HeaderValue>) -> bool {
+        let referer = match referer.map(HeaderValue::to_str) {
+            None => return self.allow_empty_referer,
+            Some(Ok(referer)) if referer.trim().is_empty() => return self.allow_empty_referer,
+            Some(Ok(referer)) => referer,
+            Some(Err(_)) => return false,
+        };
+
+        let host = match referer.parse::<Uri>() {
+            Ok(uri) => match uri.host() {
+                None => return false,
+                Some(host) => host.to_ascii_lowercase(),
+            },
+
+            Err(_) => return false,
+        };
+
+        self.allowed_hosts.contains(&host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protection(allow_empty_referer: bool) -> HotlinkProtection {
+        HotlinkProtection::new(["Blog.Example.com", "example.org"], allow_empty_referer)
+    }
+
+    #[test]
+    fn test_allowed_referer() {
+        let protection = protection(false);
+
+        for referer in &[
+            "https://blog.example.com/posts/1",
+            "http://BLOG.EXAMPLE.COM:8080/",
+            "https://example.org",
+        ] {
+            assert!(
+                protection.is_allowed(Some(&HeaderValue::from_static(referer))),
+                "{}",
+                referer
+            );
+        }
+    }
+
+    #[test]
+    fn test_disallowed_referer() {
+        let protection = protection(true);
+
+        for referer in &[
+            "https://evil.com/blog.example.com",
+            "https://example.org.evil.com/",
+            "https://sub.example.org/",
+            "not a url",
+            "/relative",
+        ] {
+            assert!(
+                !protection.is_allowed(Some(&HeaderValue::from_static(referer))),
+                "{}",
+                referer
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_referer() {
+        assert!(protection(true).is_allowed(None));
+        assert!(protection(true).is_allowed(Some(&HeaderValue::from_static(""))));
+
+        assert!(!protection(false).is_allowed(None));
+        assert!(!protection(false).is_allowed(Some(&HeaderValue::from_static(""))));
+    }
+
+    #[test]
+    fn test_placeholder() {
+        let mut protection = protection(false);
+        assert!(protection.placeholder().is_none());
+
+        protection.set_placeholder(Bytes::from_static(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(protection.placeholder().unwrap().content_type, "image/png");
+    }
+}
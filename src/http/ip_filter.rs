@@ -0,0 +1,277 @@
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::service::Service;
+use ipnet::IpNet;
+use slog::warn;
+
+use crate::http::{error, log_context, ServiceResult};
+use crate::http::client_ip::TrustedProxies;
+use crate::http::handle::API_VERSION_PREFIX;
+use crate::log;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Only let the allowed peers reach the paths, such as the admin ones. The deny list takes
+/// precedence, and an empty allow list allows every peer which is not denied.
+#[derive(Debug)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    paths: Vec<String>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>, paths: Vec<String>) -> Self {
+        Self { allow, deny, paths }
+    }
+
+    /// The paths are matched with their sub paths, with or without the api version prefix.
+    pub fn is_filtered_path(&self, path: &str) -> bool {
+        let path = path
+            .strip_prefix(API_VERSION_PREFIX)
+            .filter(|path| path.starts_with('/'))
+            .unwrap_or(path);
+
+        self.paths.iter().any(|filtered| {
+            path.strip_prefix(filtered.trim_end_matches('/'))
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Parse the CIDR, the plain address is the network of itself.
+pub fn parse_net(net: &str) -> Result<IpNet, String> {
+    net.parse::<IpNet>()
+        .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{:?} is not a valid CIDR or ip address", net))
+}
+
+/// Reject the requests of the filtered paths from the disallowed peers with 403.
+#[derive(Debug)]
+pub struct IpFilterService<S> {
+    filter: Option<Arc<IpFilter>>,
+    remote_addr: Option<SocketAddr>,
+    trusted_proxies: Arc<TrustedProxies>,
+    service: S,
+}
+
+impl<S> IpFilterService<S> {
+    pub fn new(
+        filter: Option<Arc<IpFilter>>,
+        remote_addr: Option<SocketAddr>,
+        trusted_proxies: Arc<TrustedProxies>,
+        service: S,
+    ) -> Self {
+        Self {
+            filter,
+            remote_addr,
+            trusted_proxies,
+            service,
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for IpFilterService<S>
+    where
+        S: Service<Request<Body>, Response=Response<Body>>,
+        S::Future: Send + 'static,
+        S::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = ServiceResult<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let filter = match &self.filter {
+            Some(filter) if filter.is_filtered_path(req.uri().path()) => filter,
+            _ => {
+                let fut = self.service.call(req);
+
+                return Box::pin(async move { fut.await.map_err(Into::into) });
+            }
+        };
+
+        let client_ip = self
+            .trusted_proxies
+            .client_ip(self.remote_addr, req.headers());
+
+        if client_ip.is_none_or(|ip| !filter.is_allowed(ip)) {
+            let log_cx = log_context(&req);
+
+            warn!(
+                log::get_logger(),
+                "client is not allowed";
+                &log_cx,
+                "path" => req.uri().path(),
+                "client_ip" => client_ip.map(|ip| ip.to_string())
+            );
+
+            let result = error::error_response(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "client address is not allowed",
+                &log_cx,
+            )
+                .map_err(|err| err.into());
+
+            return Box::pin(async move { result });
+        }
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move { fut.await.map_err(Into::into) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future;
+    use std::future::Ready;
+
+    use super::*;
+
+    struct MockService;
+
+    impl Service<Request<Body>> for MockService {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: Request<Body>) -> Self::Future {
+            future::ready(Ok(Response::new(Body::from("ok"))))
+        }
+    }
+
+    fn filter() -> Arc<IpFilter> {
+        Arc::new(IpFilter::new(
+            vec![parse_net("10.0.0.0/8").unwrap(), parse_net("::1").unwrap()],
+            vec![parse_net("10.0.1.0/24").unwrap()],
+            vec!["/list".to_string(), "/bucket/".to_string()],
+        ))
+    }
+
+    fn service(remote_addr: &str, trusted_proxies: Vec<IpNet>) -> IpFilterService<MockService> {
+        IpFilterService::new(
+            Some(filter()),
+            Some(remote_addr.parse().unwrap()),
+            Arc::new(TrustedProxies::new(trusted_proxies)),
+            MockService,
+        )
+    }
+
+    fn request(path: &str, forwarded_for: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri(path);
+        if let Some(forwarded_for) = forwarded_for {
+            builder = builder.header("x-forwarded-for", forwarded_for);
+        }
+
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_net() {
+        assert_eq!(parse_net("10.0.0.1").unwrap(), "10.0.0.1/32".parse().unwrap());
+        assert_eq!(parse_net("fd00::/8").unwrap(), "fd00::/8".parse().unwrap());
+        assert!(parse_net("10.0.0.0/33").is_err());
+        assert!(parse_net("internal").is_err());
+    }
+
+    #[test]
+    fn test_is_filtered_path() {
+        let filter = filter();
+
+        for path in &["/list", "/v1/list", "/bucket/2021-01", "/v1/bucket/2021-01"] {
+            assert!(filter.is_filtered_path(path), "{}", path);
+        }
+
+        for path in &["/listing", "/get/id", "/v1", "/v1list"] {
+            assert!(!filter.is_filtered_path(path), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_allowed_client() {
+        for (remote_addr, path) in &[
+            ("10.0.0.1:1234", "/list"),
+            ("[::1]:1234", "/v1/bucket/2021-01"),
+            // the unfiltered paths are open to everyone
+            ("192.168.0.1:1234", "/get/id"),
+        ] {
+            let resp = service(remote_addr, vec![])
+                .call(request(path, None))
+                .await
+                .unwrap();
+
+            assert_eq!(resp.status(), StatusCode::OK, "{} {}", remote_addr, path);
+        }
+
+        // forwarded by the trusted proxy
+        let resp = service("192.168.0.1:1234", vec!["192.168.0.0/24".parse().unwrap()])
+            .call(request("/list", Some("203.0.113.1, 10.0.0.2")))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // forwarded by the trusted proxies, the hops of the proxies are skipped
+        let resp = service("192.168.0.1:1234", vec!["192.168.0.0/24".parse().unwrap()])
+            .call(request("/list", Some("203.0.113.1, 10.0.0.2, 192.168.0.2")))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_denied_client() {
+        for (remote_addr, trusted_proxies, forwarded_for) in [
+            ("192.168.0.1:1234", vec![], None),
+            // denied takes precedence
+            ("10.0.1.1:1234", vec![], None),
+            // the header of the untrusted peer is ignored
+            ("192.168.0.1:1234", vec![], Some("10.0.0.2")),
+            ("192.168.0.1:1234", vec!["192.168.0.1/32".parse().unwrap()], Some("203.0.113.1")),
+            ("192.168.0.1:1234", vec!["192.168.0.1/32".parse().unwrap()], Some("unknown")),
+            // the spoofed hop left of the untrusted one is never reached
+            (
+                "192.168.0.1:1234",
+                vec!["192.168.0.1/32".parse().unwrap()],
+                Some("10.0.0.2, 203.0.113.1"),
+            ),
+        ] {
+            let resp = service(remote_addr, trusted_proxies)
+                .call(request("/list", forwarded_for))
+                .await
+                .unwrap();
+
+            assert_eq!(
+                resp.status(),
+                StatusCode::FORBIDDEN,
+                "{} {:?}",
+                remote_addr,
+                forwarded_for
+            );
+        }
+    }
+}
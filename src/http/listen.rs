@@ -0,0 +1,752 @@
+use std::future::Future;
+use std::io;
+use std::net::{self, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures_util::{future, ready};
+use hyper::server::accept::Accept;
+use hyper::server::Builder;
+use hyper::Server;
+use slog::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Delay, Instant};
+
+use crate::http::RemoteAddr;
+use crate::log;
+
+/// The unacknowledged HTTP/2 ping closes the connection after it.
+const HTTP2_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+/// The kernel lowers it to the `somaxconn`.
+pub const DEFAULT_BACKLOG: u32 = 1024;
+/// The accept errors like too many open files are likely to happen again right away.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// The connection settings of all the listeners.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ConnectionOptions {
+    /// Zero disables the HTTP/1 keep-alive, otherwise the idle HTTP/1 connection is closed after
+    /// it, and it's the TCP keepalive time and the HTTP/2 ping interval, so the dead peers are
+    /// found.
+    pub keep_alive_timeout: Option<Duration>,
+    /// The HTTP/1 request head must be read in it, counted from the accept or the first byte
+    /// after the idle time, so the slow clients can't hold the connections. Unlimited by default.
+    pub header_read_timeout: Option<Duration>,
+    /// Shared by all the listeners, the excess connections wait in the listen backlog until a
+    /// connection is closed.
+    pub max_connections: Option<usize>,
+    /// The accept queue length of the listeners, `DEFAULT_BACKLOG` by default.
+    pub backlog: Option<u32>,
+    /// Enabled by default on unix, the same as the std listener, so the restarted server can
+    /// bind the port with the connections in `TIME_WAIT`.
+    pub reuse_address: Option<bool>,
+    /// Let the worker processes bind the same port, the kernel balances the connections between
+    /// them. Not supported on windows, disabled by default.
+    pub reuse_port: Option<bool>,
+}
+
+type AcquireFuture = Pin<Box<dyn Future<Output=OwnedSemaphorePermit> + Send>>;
+
+/// The listener which holds a permit of the connection limit for every accepted connection.
+pub struct Incoming {
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    tcp_keep_alive: Option<Duration>,
+    header_read_timeout: Option<Duration>,
+    semaphore: Option<Arc<Semaphore>>,
+    acquire: Option<AcquireFuture>,
+    permit: Option<OwnedSemaphorePermit>,
+    error_delay: Option<Delay>,
+}
+
+impl Incoming {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn accept_stream(&mut self, cx: &mut Context<'_>) -> Poll<(TcpStream, SocketAddr)> {
+        if let Some(delay) = &mut self.error_delay {
+            ready!(Pin::new(delay).poll(cx));
+
+            self.error_delay = None;
+        }
+
+        loop {
+            match ready!(self.listener.poll_accept(cx)) {
+                Ok(accepted) => return Poll::Ready(accepted),
+
+                // the peer is gone before it's accepted, only this connection is affected
+                Err(err) if is_connection_error(&err) => continue,
+
+                // keep serving instead of failing the server, like the hyper listener
+                Err(err) => {
+                    warn!(
+                        log::get_logger(),
+                        "accept on {} failed, retry after {:?}: {}",
+                        self.local_addr, ACCEPT_ERROR_DELAY, err
+                    );
+
+                    let mut delay = time::delay_for(ACCEPT_ERROR_DELAY);
+
+                    if Pin::new(&mut delay).poll(cx).is_pending() {
+                        self.error_delay = Some(delay);
+
+                        return Poll::Pending;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+impl Accept for Incoming {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        // the permit is acquired before accepting, so the excess connections are not accepted
+        if this.permit.is_none() {
+            if let Some(semaphore) = &this.semaphore {
+                let acquire = this
+                    .acquire
+                    .get_or_insert_with(|| Box::pin(semaphore.clone().acquire_owned()));
+
+                this.permit = Some(ready!(acquire.as_mut().poll(cx)));
+                this.acquire = None;
+            }
+        }
+
+        let (stream, remote_addr) = ready!(this.accept_stream(cx));
+
+        if let Err(err) = stream.set_nodelay(true) {
+            warn!(log::get_logger(), "set nodelay of {} failed: {}", remote_addr, err);
+        }
+
+        if let Err(err) = stream.set_keepalive(this.tcp_keep_alive) {
+            warn!(log::get_logger(), "set keepalive of {} failed: {}", remote_addr, err);
+        }
+
+        Poll::Ready(Some(Ok(Connection {
+            stream,
+            remote_addr,
+            read_timeout: ReadTimeout::new(this.tcp_keep_alive, this.header_read_timeout),
+            _permit: this.permit.take(),
+        })))
+    }
+}
+
+/// Where the HTTP/1 connection is, told from the bytes on the wire.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ReadState {
+    /// The response is written, waiting for the next request.
+    Idle,
+    /// Reading the request head, `newlines` is the count of the trailing line ends read, the
+    /// empty line ends the head.
+    Head { newlines: u8 },
+    /// The request is handled, its body is read at the pace of the handle.
+    Busy,
+}
+
+/// The HTTP/1 timeouts hyper doesn't have, the pending read fails when the idle connection
+/// passes the keep-alive timeout or the request head is not read in the header read timeout.
+/// The h2c connection is never idle by it, there is no HTTP/1 response head written.
+struct ReadTimeout {
+    keep_alive: Option<Duration>,
+    header_read: Option<Duration>,
+    state: ReadState,
+    deadline: Option<Delay>,
+    /// The pending read is woken up to wait for the deadline set by the write.
+    read_waker: Option<Waker>,
+}
+
+impl ReadTimeout {
+    fn new(keep_alive: Option<Duration>, header_read: Option<Duration>) -> Self {
+        let mut read_timeout = ReadTimeout {
+            keep_alive,
+            header_read,
+            state: ReadState::Head { newlines: 0 },
+            deadline: None,
+            read_waker: None,
+        };
+
+        read_timeout.set_deadline(header_read);
+
+        read_timeout
+    }
+
+    fn set_deadline(&mut self, timeout: Option<Duration>) {
+        match (timeout, &mut self.deadline) {
+            (None, _) => self.deadline = None,
+            (Some(timeout), Some(deadline)) => deadline.reset(Instant::now() + timeout),
+            (Some(timeout), None) => self.deadline = Some(time::delay_for(timeout)),
+        }
+    }
+
+    fn on_read(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let mut newlines = match self.state {
+            ReadState::Busy => return,
+            ReadState::Idle => {
+                self.set_deadline(self.header_read);
+
+                0
+            }
+            ReadState::Head { newlines } => newlines,
+        };
+
+        for byte in data {
+            match byte {
+                b'\n' => newlines += 1,
+                b'\r' => {}
+                _ => newlines = 0,
+            }
+
+            if newlines == 2 {
+                self.state = ReadState::Busy;
+                self.deadline = None;
+
+                return;
+            }
+        }
+
+        self.state = ReadState::Head { newlines };
+    }
+
+    fn on_write(&mut self, data: &[u8]) {
+        // the informational responses like `100 Continue` are followed by the request body
+        let is_response_head = data.starts_with(b"HTTP/1.") && data.get(9) != Some(&b'1');
+
+        if is_response_head || self.state == ReadState::Idle {
+            // the long response keeps the connection busy, the idle time starts after its last
+            // write
+            self.state = ReadState::Idle;
+            self.set_deadline(self.keep_alive);
+
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        self.read_waker = Some(cx.waker().clone());
+
+        match &mut self.deadline {
+            None => Poll::Pending,
+
+            Some(deadline) => {
+                ready!(Pin::new(deadline).poll(cx));
+
+                let message = match self.state {
+                    ReadState::Idle => "keep-alive timeout",
+                    _ => "header read timeout",
+                };
+
+                Poll::Ready(io::Error::new(io::ErrorKind::TimedOut, message))
+            }
+        }
+    }
+}
+
+/// The accepted connection, the permit is released when it's closed.
+pub struct Connection {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    read_timeout: ReadTimeout,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl RemoteAddr for &Connection {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr)
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.stream).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                this.read_timeout.on_read(&buf[..n]);
+
+                Poll::Ready(Ok(n))
+            }
+
+            Poll::Pending => this.read_timeout.poll_expired(cx).map(Err),
+
+            result => result,
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+
+        this.read_timeout.on_write(&buf[..n]);
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// The HTTP versions the server speaks. There is no TLS in the server, so HTTP/2 is only the
+/// prior knowledge h2c, the ALPN negotiation is left to the TLS terminating proxy.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HttpVersions {
+    Http1,
+    /// HTTP/1 and h2c, the version is detected by the connection preface.
+    Http1AndH2c,
+    H2c,
+}
+
+impl HttpVersions {
+    pub fn new(http2: bool, http2_only: bool) -> Self {
+        match (http2, http2_only) {
+            (_, true) => HttpVersions::H2c,
+            (true, false) => HttpVersions::Http1AndH2c,
+            (false, false) => HttpVersions::Http1,
+        }
+    }
+
+    pub fn server_builder(
+        self,
+        incoming: Incoming,
+        options: &ConnectionOptions,
+    ) -> Builder<Incoming> {
+        let mut builder = Server::builder(incoming);
+
+        match options.keep_alive_timeout {
+            None => {}
+            Some(timeout) if timeout == Duration::from_secs(0) => {
+                builder = builder.http1_keepalive(false);
+            }
+            Some(timeout) => {
+                builder = builder
+                    .http2_keep_alive_interval(timeout)
+                    .http2_keep_alive_timeout(HTTP2_KEEP_ALIVE_TIMEOUT);
+            }
+        }
+
+        match self {
+            HttpVersions::Http1 => builder.http1_only(true),
+            HttpVersions::Http1AndH2c => builder,
+            HttpVersions::H2c => builder.http2_only(true),
+        }
+    }
+}
+
+/// Bind all addresses before serving, so a bad address fails the startup instead of leaving the
+/// server partially listening.
+pub fn bind_all(
+    addrs: &[SocketAddr],
+    options: &ConnectionOptions,
+) -> anyhow::Result<Vec<Incoming>> {
+    let semaphore = options
+        .max_connections
+        .map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+
+    // the zero keep-alive closes the connection after the response, it's never idle
+    let tcp_keep_alive = options
+        .keep_alive_timeout
+        .filter(|timeout| *timeout > Duration::from_secs(0));
+
+    addrs
+        .iter()
+        .map(|addr| {
+            let listener = bind(addr, options)
+                .and_then(TcpListener::from_std)
+                .map_err(|err| anyhow::anyhow!("bind {} failed: {}", addr, err))?;
+            let local_addr = listener.local_addr()?;
+
+            Ok(Incoming {
+                listener,
+                local_addr,
+                tcp_keep_alive,
+                header_read_timeout: options.header_read_timeout,
+                semaphore: semaphore.clone(),
+                acquire: None,
+                permit: None,
+                error_delay: None,
+            })
+        })
+        .collect()
+}
+
+/// Build the listener by hand, the std listener has no options of the backlog and the reuse.
+fn bind(addr: &SocketAddr, options: &ConnectionOptions) -> io::Result<net::TcpListener> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+
+    socket.set_reuse_address(options.reuse_address.unwrap_or(cfg!(unix)))?;
+
+    if options.reuse_port.unwrap_or(false) {
+        set_reuse_port(&socket)?;
+    }
+
+    socket.bind(&(*addr).into())?;
+    socket.listen(options.backlog.unwrap_or(DEFAULT_BACKLOG).min(i32::MAX as u32) as i32)?;
+
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "reuse_port is not supported on this platform",
+    ))
+}
+
+/// Spawn a server for every incoming, return when any server fails, and the runtime shutting
+/// down stops the rest of them.
+pub async fn serve_all<F, Fut>(incomings: Vec<Incoming>, serve: F) -> anyhow::Result<()>
+    where
+        F: Fn(Incoming) -> Fut,
+        Fut: Future<Output=hyper::Result<()>> + Send + 'static,
+{
+    let servers = incomings.into_iter().map(|incoming| {
+        let local_addr = incoming.local_addr();
+
+        info!(log::get_logger(), "listen on {}", local_addr);
+
+        let server = tokio::spawn(serve(incoming));
+
+        async move {
+            server
+                .await?
+                .map_err(|err| anyhow::anyhow!("server on {} failed: {}", local_addr, err))
+        }
+    });
+
+    future::try_join_all(servers).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use bytes::Bytes;
+    use futures_util::stream;
+    use hyper::{body, Body, Client, Response, StatusCode, Version};
+    use hyper::service::{make_service_fn, service_fn};
+    use tokio::task;
+
+    use super::*;
+
+    /// Serve a streamed partial content on a random port.
+    fn serve_stream(http_versions: HttpVersions, options: &ConnectionOptions) -> SocketAddr {
+        let mut incomings = bind_all(&["127.0.0.1:0".parse().unwrap()], options).unwrap();
+        let incoming = incomings.remove(0);
+        let local_addr = incoming.local_addr();
+
+        let builder = http_versions.server_builder(incoming, options);
+
+        tokio::spawn(builder.serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                let chunks = vec![Ok::<_, Infallible>(Bytes::from("he")), Ok(Bytes::from("llo"))];
+
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header("content-range", "bytes 0-4/11")
+                        .body(Body::wrap_stream(stream::iter(chunks)))
+                        .unwrap(),
+                )
+            }))
+        })));
+
+        local_addr
+    }
+
+    /// Read the chunked response of `serve_stream` to its end.
+    fn read_response(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+        let mut response = vec![];
+        let mut buf = [0; 256];
+
+        while !response.ends_with(b"0\r\n\r\n") {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            response.extend_from_slice(&buf[..n]);
+        }
+
+        Ok(response)
+    }
+
+    /// The server closes the connection without a response.
+    fn is_closed(stream: &mut TcpStream) -> bool {
+        match stream.read(&mut [0; 256]) {
+            Ok(n) => n == 0,
+            Err(err) => err.kind() == io::ErrorKind::ConnectionReset,
+        }
+    }
+
+    #[test]
+    fn test_http_versions() {
+        assert_eq!(HttpVersions::new(false, false), HttpVersions::Http1);
+        assert_eq!(HttpVersions::new(true, false), HttpVersions::Http1AndH2c);
+        assert_eq!(HttpVersions::new(false, true), HttpVersions::H2c);
+    }
+
+    #[tokio::test]
+    async fn test_serve_h2c() {
+        let h2_client = Client::builder().http2_only(true).build_http::<Body>();
+        let http1_client = Client::new();
+
+        for http_versions in &[HttpVersions::Http1AndH2c, HttpVersions::H2c] {
+            let uri = format!(
+                "http://{}/",
+                serve_stream(*http_versions, &ConnectionOptions::default())
+            );
+
+            let resp = h2_client.get(uri.parse().unwrap()).await.unwrap();
+
+            assert_eq!(resp.version(), Version::HTTP_2);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(resp.headers()["content-range"], "bytes 0-4/11");
+            assert_eq!(body::to_bytes(resp).await.unwrap().as_ref(), b"hello");
+
+            let http1_result = http1_client.get(uri.parse().unwrap()).await;
+
+            assert_eq!(http1_result.is_ok(), *http_versions == HttpVersions::Http1AndH2c);
+        }
+
+        let uri = format!(
+            "http://{}/",
+            serve_stream(HttpVersions::Http1, &ConnectionOptions::default())
+        );
+
+        assert!(h2_client.get(uri.parse().unwrap()).await.is_err());
+        assert_eq!(
+            http1_client.get(uri.parse().unwrap()).await.unwrap().version(),
+            Version::HTTP_11
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let options = ConnectionOptions {
+            max_connections: Some(1),
+            ..ConnectionOptions::default()
+        };
+        let addr = serve_stream(HttpVersions::Http1, &options);
+
+        // the blocking sockets run outside of the runtime, so the server keeps serving
+        let (blocked, response) = task::spawn_blocking(move || -> io::Result<_> {
+            // the idle connection holds the only permit
+            let held = TcpStream::connect(addr)?;
+
+            let mut probe = TcpStream::connect(addr)?;
+            probe.write_all(b"GET / HTTP/1.1\r\nhost: test\r\n\r\n")?;
+
+            let mut buf = [0; 64];
+
+            probe.set_read_timeout(Some(Duration::from_millis(300)))?;
+            let blocked = probe.read(&mut buf).is_err();
+
+            drop(held);
+
+            probe.set_read_timeout(Some(Duration::from_secs(5)))?;
+            let n = probe.read(&mut buf)?;
+
+            Ok((blocked, buf[..n].to_vec()))
+        })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(blocked);
+        assert!(response.starts_with(b"HTTP/1.1 206"));
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_timeout() {
+        let options = ConnectionOptions {
+            keep_alive_timeout: Some(Duration::from_secs(1)),
+            ..ConnectionOptions::default()
+        };
+        let addr = serve_stream(HttpVersions::Http1, &options);
+
+        let (response, closed) = task::spawn_blocking(move || -> io::Result<_> {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+            // the connection is kept between the requests shorter than the timeout
+            let mut response = vec![];
+            for _ in 0..2 {
+                stream.write_all(b"GET / HTTP/1.1\r\nhost: test\r\n\r\n")?;
+                response = read_response(&mut stream)?;
+
+                std::thread::sleep(Duration::from_millis(300));
+            }
+
+            // then the idle one is closed
+            Ok((response, is_closed(&mut stream)))
+        })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(response.starts_with(b"HTTP/1.1 206"));
+        assert!(closed);
+    }
+
+    #[tokio::test]
+    async fn test_header_read_timeout() {
+        let options = ConnectionOptions {
+            header_read_timeout: Some(Duration::from_millis(300)),
+            ..ConnectionOptions::default()
+        };
+        let addr = serve_stream(HttpVersions::Http1, &options);
+
+        let (response, closed) = task::spawn_blocking(move || -> io::Result<_> {
+            // the whole head in time is served
+            let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            stream.write_all(b"GET / HTTP/1.1\r\nhost: test\r\n")?;
+            std::thread::sleep(Duration::from_millis(100));
+            stream.write_all(b"\r\n")?;
+
+            let response = read_response(&mut stream)?;
+
+            // the slow head is closed without a response
+            let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            stream.write_all(b"GET / HTTP/1.1\r\nhost: test\r\n")?;
+
+            Ok((response, is_closed(&mut stream)))
+        })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(response.starts_with(b"HTTP/1.1 206"));
+        assert!(closed);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listen_options() {
+        let options = ConnectionOptions {
+            backlog: Some(16),
+            reuse_address: Some(true),
+            reuse_port: Some(true),
+            ..ConnectionOptions::default()
+        };
+
+        let first = bind_all(&["127.0.0.1:0".parse().unwrap()], &options)
+            .unwrap()
+            .remove(0);
+        let local_addr = first.local_addr();
+
+        // another worker binds the same port
+        let second = bind_all(&[local_addr], &options).unwrap().remove(0);
+
+        tokio::spawn(serve_all(vec![first, second], |incoming| {
+            Server::builder(incoming).serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Body::from("ok")))
+                }))
+            }))
+        }));
+
+        let resp = Client::new()
+            .get(format!("http://{}/", local_addr).parse().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(body::to_bytes(resp).await.unwrap().as_ref(), b"ok");
+
+        // the port is taken without reuse_port
+        assert!(bind_all(&[local_addr], &ConnectionOptions::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_multi_listen() {
+        let addrs = ["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+
+        let incomings = bind_all(&addrs, &ConnectionOptions::default()).unwrap();
+        let local_addrs = incomings
+            .iter()
+            .map(|incoming| incoming.local_addr())
+            .collect::<Vec<_>>();
+
+        tokio::spawn(serve_all(incomings, |incoming| {
+            Server::builder(incoming).serve(make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Body::from("ok")))
+                }))
+            }))
+        }));
+
+        let client = Client::new();
+
+        for local_addr in local_addrs {
+            let resp = client
+                .get(format!("http://{}/", local_addr).parse().unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(body::to_bytes(resp).await.unwrap().as_ref(), b"ok");
+        }
+    }
+}
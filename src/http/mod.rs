@@ -1,8 +1,74 @@
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
 
+use hyper::{Body, Request};
+use hyper::server::conn::AddrStream;
+
+use crate::http::request_id::REQUEST_ID_HEADER;
+use crate::http::trace::{TRACE_PARENT_HEADER, TraceParent};
+use crate::log::LogContext;
+
+mod access_log;
+pub(crate) mod client_ip;
+mod compression;
+mod conditional;
+pub(crate) mod cors;
+mod decompress;
+mod disposition;
+mod error;
+mod hotlink;
+pub(crate) mod ip_filter;
 pub mod handle;
+pub mod listen;
+mod range;
+mod signed_url;
 mod size_limit;
+mod spill;
 mod request_id;
+mod trace;
+mod tus;
+pub(crate) mod webhook;
 
 type ServiceResult<T, E> = Pin<Box<dyn Future<Output=Result<T, E>> + 'static + Send>>;
+
+/// The connection target which is passed to the make service, used to find the peer address.
+pub trait RemoteAddr {
+    fn remote_addr(&self) -> Option<SocketAddr>;
+}
+
+impl RemoteAddr for &AddrStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(AddrStream::remote_addr(self))
+    }
+}
+
+impl RemoteAddr for () {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Build the log context from the request id and traceparent set by the outer services.
+fn log_context(req: &Request<Body>) -> LogContext {
+    let headers = req.headers();
+
+    let mut builder = LogContext::builder().request_id(
+        headers
+            .get(REQUEST_ID_HEADER)
+            .map(|value| value.to_str().unwrap_or(""))
+            .unwrap_or(""),
+    );
+
+    if let Some(trace_parent) = headers
+        .get(TRACE_PARENT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(TraceParent::parse)
+    {
+        builder = builder
+            .trace_id(trace_parent.trace_id())
+            .span_id(trace_parent.span_id());
+    }
+
+    builder.build()
+}
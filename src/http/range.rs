@@ -0,0 +1,301 @@
+use bytes::{Bytes, BytesMut};
+
+/// More ranges in one request are ignored, so the full resource is served.
+const MAX_RANGES: usize = 16;
+
+/// A satisfiable byte range of the resource, both ends are included.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The `content-range` header value.
+    pub fn content_range(&self, resource_size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, resource_size)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RangeRequest {
+    /// No range or the range is ignored, serve the full resource.
+    Full,
+    Partial(ByteRange),
+    /// Serve the ranges as the parts of a `multipart/byteranges` body, in the requested order.
+    Multiple(Vec<ByteRange>),
+    /// Respond 416 with `content-range: bytes */<size>`.
+    Unsatisfiable,
+}
+
+/// Normalize the `range` header against the resource size as RFC 7233. The malformed ranges are
+/// ignored, so the full resource is served. The unsatisfiable ones of the multiple ranges are
+/// skipped, the request is unsatisfiable only when none of them is satisfiable. Too many
+/// ranges, or the ranges asking for more than the resource size in total, are ignored too, so
+/// the overlapped ranges can't make the response larger than the resource.
+pub fn parse_range(range: Option<&str>, resource_size: u64) -> RangeRequest {
+    let range = match range.and_then(|range| range.trim().strip_prefix("bytes=")) {
+        None => return RangeRequest::Full,
+        Some(range) => range.trim(),
+    };
+
+    let specs = range
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect::<Vec<_>>();
+
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::with_capacity(specs.len());
+    for spec in specs {
+        match parse_spec(spec, resource_size) {
+            None => return RangeRequest::Full,
+            Some(None) => {}
+            Some(Some(range)) => ranges.push(range),
+        }
+    }
+
+    match ranges.len() {
+        0 => RangeRequest::Unsatisfiable,
+        1 => RangeRequest::Partial(ranges[0]),
+        _ if ranges.iter().map(ByteRange::len).sum::<u64>() > resource_size => RangeRequest::Full,
+        _ => RangeRequest::Multiple(ranges),
+    }
+}
+
+/// Parse one range like `0-5`, `5-` or `-3`. `None` if it's malformed, `Some(None)` if it's
+/// unsatisfiable.
+fn parse_spec(spec: &str, resource_size: u64) -> Option<Option<ByteRange>> {
+    let mut start_end = spec.splitn(2, '-');
+    let start_str = start_end.next().unwrap_or_default().trim();
+    let end_str = start_end.next()?.trim();
+
+    match (start_str.parse::<u64>().ok(), end_str.parse::<u64>().ok()) {
+        // `-n` means the last n bytes
+        (None, Some(suffix)) if start_str.is_empty() => {
+            if suffix == 0 || resource_size == 0 {
+                Some(None)
+            } else {
+                Some(Some(ByteRange {
+                    start: resource_size.saturating_sub(suffix),
+                    end: resource_size - 1,
+                }))
+            }
+        }
+
+        (Some(start), end) if end.is_some() || end_str.is_empty() => {
+            if end.is_some_and(|end| end < start) {
+                return None;
+            }
+
+            if start >= resource_size {
+                return Some(None);
+            }
+
+            Some(Some(ByteRange {
+                start,
+                end: end.map_or(resource_size - 1, |end| end.min(resource_size - 1)),
+            }))
+        }
+
+        _ => None,
+    }
+}
+
+/// The `multipart/byteranges` body of the multiple ranges. Its length is known without the
+/// data, so HEAD can send the same `content-length` as GET.
+#[derive(Debug)]
+pub struct MultipartRanges {
+    boundary: String,
+    content_type: String,
+    resource_size: u64,
+    ranges: Vec<ByteRange>,
+}
+
+impl MultipartRanges {
+    pub fn new(content_type: &str, resource_size: u64, ranges: Vec<ByteRange>) -> Self {
+        let boundary = format!("{:016x}", rand::random::<u64>());
+
+        Self::with_boundary(boundary, content_type, resource_size, ranges)
+    }
+
+    fn with_boundary(
+        boundary: String,
+        content_type: &str,
+        resource_size: u64,
+        ranges: Vec<ByteRange>,
+    ) -> Self {
+        Self {
+            boundary,
+            content_type: content_type.to_string(),
+            resource_size,
+            ranges,
+        }
+    }
+
+    pub fn ranges(&self) -> &[ByteRange] {
+        &self.ranges
+    }
+
+    /// The `content-type` header value of the response.
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    pub fn content_length(&self) -> u64 {
+        let parts = self
+            .ranges
+            .iter()
+            .map(|range| self.part_head(range).len() as u64 + range.len() + 2)
+            .sum::<u64>();
+
+        parts + self.tail().len() as u64
+    }
+
+    /// Build the body from the data of the ranges, which are in the same order as the ranges.
+    pub fn body(&self, parts: &[Bytes]) -> Bytes {
+        let mut body = BytesMut::with_capacity(self.content_length() as usize);
+
+        for (range, data) in self.ranges.iter().zip(parts) {
+            body.extend_from_slice(self.part_head(range).as_bytes());
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+
+        body.extend_from_slice(self.tail().as_bytes());
+
+        body.freeze()
+    }
+
+    fn part_head(&self, range: &ByteRange) -> String {
+        format!(
+            "--{}\r\ncontent-type: {}\r\ncontent-range: {}\r\n\r\n",
+            self.boundary,
+            self.content_type,
+            range.content_range(self.resource_size)
+        )
+    }
+
+    fn tail(&self) -> String {
+        format!("--{}--\r\n", self.boundary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(start: u64, end: u64) -> RangeRequest {
+        RangeRequest::Partial(ByteRange { start, end })
+    }
+
+    fn multiple(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Multiple(
+            ranges
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse_range() {
+        for (range, expect) in &[
+            (None, RangeRequest::Full),
+            (Some("bytes=0-5"), partial(0, 5)),
+            (Some("bytes=5-"), partial(5, 9)),
+            (Some("bytes=5-100"), partial(5, 9)),
+            (Some("bytes=-3"), partial(7, 9)),
+            (Some("bytes=-100"), partial(0, 9)),
+            (Some("bytes=9-9"), partial(9, 9)),
+            (Some("bytes=10-"), RangeRequest::Unsatisfiable),
+            (Some("bytes=10-20"), RangeRequest::Unsatisfiable),
+            (Some("bytes=-0"), RangeRequest::Unsatisfiable),
+            (Some("bytes=5-3"), RangeRequest::Full),
+            (Some("bytes=0-1,3-4"), multiple(&[(0, 1), (3, 4)])),
+            (Some("bytes=6-, -2, 0-0"), multiple(&[(6, 9), (8, 9), (0, 0)])),
+            // the unsatisfiable ranges are skipped
+            (Some("bytes=0-1,20-30"), partial(0, 1)),
+            (Some("bytes=10-,20-"), RangeRequest::Unsatisfiable),
+            // a malformed range ignores all
+            (Some("bytes=0-1,a-b"), RangeRequest::Full),
+            // more than the resource size in total
+            (Some("bytes=0-8,1-9"), RangeRequest::Full),
+            (Some("bytes=a-b"), RangeRequest::Full),
+            (Some("bytes=-"), RangeRequest::Full),
+            (Some("items=0-5"), RangeRequest::Full),
+        ] {
+            assert_eq!(parse_range(*range, 10), *expect, "{:?}", range);
+        }
+    }
+
+    #[test]
+    fn test_parse_too_many_ranges() {
+        let ranges = (0..MAX_RANGES as u64)
+            .map(|i| format!("{}-{}", i * 2, i * 2))
+            .collect::<Vec<_>>();
+        let range = format!("bytes={}", ranges.join(","));
+
+        match parse_range(Some(&range), 100) {
+            RangeRequest::Multiple(ranges) => assert_eq!(ranges.len(), MAX_RANGES),
+            range => panic!("unexpected range {:?}", range),
+        }
+
+        let range = format!("{},99-99", range);
+
+        assert_eq!(parse_range(Some(&range), 100), RangeRequest::Full);
+    }
+
+    #[test]
+    fn test_parse_range_empty_resource() {
+        assert_eq!(parse_range(Some("bytes=0-"), 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-1"), 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn test_multipart_ranges() {
+        let multipart = MultipartRanges::with_boundary(
+            "b0undary".to_string(),
+            "text/plain",
+            10,
+            vec![ByteRange { start: 0, end: 1 }, ByteRange { start: 5, end: 7 }],
+        );
+
+        let body = multipart.body(&[Bytes::from_static(b"01"), Bytes::from_static(b"567")]);
+
+        assert_eq!(
+            multipart.content_type(),
+            "multipart/byteranges; boundary=b0undary"
+        );
+        assert_eq!(
+            body,
+            Bytes::from_static(
+                b"--b0undary\r\n\
+                  content-type: text/plain\r\n\
+                  content-range: bytes 0-1/10\r\n\r\n\
+                  01\r\n\
+                  --b0undary\r\n\
+                  content-type: text/plain\r\n\
+                  content-range: bytes 5-7/10\r\n\r\n\
+                  567\r\n\
+                  --b0undary--\r\n"
+            )
+        );
+        assert_eq!(multipart.content_length(), body.len() as u64);
+    }
+
+    #[test]
+    fn test_content_range() {
+        let range = ByteRange { start: 2, end: 5 };
+
+        assert_eq!(range.len(), 4);
+        assert_eq!(range.content_range(10), "bytes 2-5/10");
+    }
+}
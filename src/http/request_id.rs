@@ -4,12 +4,19 @@ use std::task::{Context, Poll};
 
 use hyper::{Body, Request, Response};
 use hyper::http::HeaderValue;
+use hyper::http::header::HeaderName;
 use hyper::service::Service;
 use rand::{RngCore, SeedableRng};
 use rand::rngs::StdRng;
 
+/// The header used to pass the request id to the inner services.
+pub const REQUEST_ID_HEADER: &str = "X-image-bed-request-id";
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
 #[derive(Debug)]
 pub struct RequestIdFuture<F: Future> {
+    header_name: HeaderName,
     request_id: String,
     fut: F,
 }
@@ -24,7 +31,7 @@ impl<F, E> Future for RequestIdFuture<F>
         let mut resp = futures_util::ready!(Pin::new(&mut self.fut).poll(cx))?;
 
         resp.headers_mut().insert(
-            "X-image-bed-request-id",
+            self.header_name.clone(),
             HeaderValue::from_str(&self.request_id)
                 .unwrap_or_else(|_| panic!("request id {} is invalid head value", self.request_id)),
         );
@@ -35,18 +42,25 @@ impl<F, E> Future for RequestIdFuture<F>
 
 #[derive(Debug)]
 pub struct RequestIdService<S> {
+    header_name: HeaderName,
     service: S,
 }
 
 impl<S> RequestIdService<S> {
-    pub fn new(service: S) -> Self {
-        Self { service }
+    pub fn new(header_name: HeaderName, service: S) -> Self {
+        Self {
+            header_name,
+            service,
+        }
     }
 }
 
 impl<S> From<S> for RequestIdService<S> {
     fn from(service: S) -> Self {
-        Self { service }
+        Self {
+            header_name: HeaderName::from_static("x-image-bed-request-id"),
+            service,
+        }
     }
 }
 
@@ -64,20 +78,141 @@ impl<S> Service<Request<Body>> for RequestIdService<S>
     }
 
     fn call(&mut self, mut req: Request<Body>) -> Self::Future {
-        let mut request_id_buf = vec![0; 8];
-
-        StdRng::from_entropy().fill_bytes(&mut request_id_buf);
-
-        let request_id = "s".to_owned() + &hex::encode(request_id_buf);
+        let request_id = req
+            .headers()
+            .get(&self.header_name)
+            .and_then(|value| value.to_str().ok())
+            .filter(|request_id| is_valid_request_id(request_id))
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(generate_request_id);
 
         req.headers_mut().insert(
-            "X-image-bed-request-id",
+            REQUEST_ID_HEADER,
             HeaderValue::from_str(&request_id)
                 .unwrap_or_else(|_| panic!("request id {} is invalid head value", request_id)),
         );
 
         let fut = self.service.call(req);
 
-        RequestIdFuture { request_id, fut }
+        RequestIdFuture {
+            header_name: self.header_name.clone(),
+            request_id,
+            fut,
+        }
+    }
+}
+
+fn generate_request_id() -> String {
+    let mut request_id_buf = vec![0; 8];
+
+    StdRng::from_entropy().fill_bytes(&mut request_id_buf);
+
+    "s".to_owned() + &hex::encode(request_id_buf)
+}
+
+fn is_valid_request_id(request_id: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':');
+
+    !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LEN && request_id.chars().all(valid_char)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future;
+    use std::future::Ready;
+
+    use super::*;
+
+    /// Return the request id it received in the response body.
+    struct MockService;
+
+    impl Service<Request<Body>> for MockService {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<Body>) -> Self::Future {
+            let request_id = req.headers()[REQUEST_ID_HEADER].to_str().unwrap().to_owned();
+
+            future::ready(Ok(Response::new(Body::from(request_id))))
+        }
+    }
+
+    async fn call(service: &mut RequestIdService<MockService>, req: Request<Body>) -> (String, String) {
+        let resp = service.call(req).await.unwrap();
+
+        let echo = resp.headers()[&service.header_name]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let received = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        (echo, String::from_utf8(received.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_reuse_request_id() {
+        let mut service = RequestIdService::from(MockService);
+
+        let req = Request::builder()
+            .header(REQUEST_ID_HEADER, "upstream-id")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(
+            call(&mut service, req).await,
+            ("upstream-id".to_owned(), "upstream-id".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_request_id() {
+        let mut service = RequestIdService::from(MockService);
+
+        let (echo, received) = call(&mut service, Request::new(Body::empty())).await;
+
+        assert_eq!(echo, received);
+        assert!(echo.starts_with('s'));
+        assert_eq!(echo.len(), 17);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_id() {
+        let mut service = RequestIdService::from(MockService);
+
+        let too_long = "x".repeat(MAX_REQUEST_ID_LEN + 1);
+
+        for invalid in ["has space", "a/b", too_long.as_str()] {
+            let req = Request::builder()
+                .header(REQUEST_ID_HEADER, invalid)
+                .body(Body::empty())
+                .unwrap();
+
+            let (echo, received) = call(&mut service, req).await;
+
+            assert_eq!(echo, received);
+            assert_ne!(&echo, invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_header_name() {
+        let mut service =
+            RequestIdService::new(HeaderName::from_static("x-request-id"), MockService);
+
+        let req = Request::builder()
+            .header("x-request-id", "trace-1")
+            .body(Body::empty())
+            .unwrap();
+
+        let (echo, received) = call(&mut service, req).await;
+
+        assert_eq!(echo, "trace-1");
+        assert_eq!(received, "trace-1");
     }
 }
@@ -1,25 +1,101 @@
 use std::error::Error;
+use std::io;
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Duration;
 
-use bytes::{BufMut, BytesMut};
-use futures_util::StreamExt;
-use hyper::{Body, Request, Response, StatusCode};
+use futures_util::{stream, StreamExt};
+use hyper::{Body, Method, Request, Response, StatusCode};
 use hyper::service::Service;
 use slog::warn;
+use thiserror::Error;
+use tokio::time;
 
-use crate::http::ServiceResult;
-use crate::log::{self, LogContext};
+use crate::http::{error, log_context, ServiceResult};
+use crate::log;
 
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Returned by the limited or decompressed body, the handle error is mapped to the status code.
+#[derive(Debug, Error)]
+pub enum BodyError {
+    #[error("request body is larger than {0} bytes")]
+    TooLarge(u64),
+
+    #[error("request body is not received in {0:?}")]
+    ReadTimeout(Duration),
+
+    #[error("decompressed request body is larger than {0} bytes")]
+    DecompressedTooLarge(u64),
+
+    #[error("request body is not valid gzip: {0}")]
+    InvalidGzip(io::Error),
+}
+
+impl BodyError {
+    pub fn status_code(&self) -> (StatusCode, &'static str) {
+        match self {
+            BodyError::TooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+            BodyError::ReadTimeout(_) => (StatusCode::REQUEST_TIMEOUT, "request_timeout"),
+            BodyError::DecompressedTooLarge(_) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large")
+            }
+            BodyError::InvalidGzip(_) => (StatusCode::BAD_REQUEST, "invalid_content_encoding"),
+        }
+    }
+}
+
+/// The max body size of the routes, the requests of the other routes use the default size.
+#[derive(Debug, Clone)]
+pub struct SizeLimits {
+    default: u64,
+    routes: Arc<Vec<(Method, String, u64)>>,
+}
+
+impl SizeLimits {
+    pub fn new(default: u64) -> Self {
+        Self {
+            default,
+            routes: Arc::new(vec![]),
+        }
+    }
+
+    /// Limit the requests of the method to the path and its sub paths, the first added matched
+    /// route is used.
+    pub fn add_route(&mut self, method: Method, path: &str, max_size: u64) -> &mut Self {
+        Arc::make_mut(&mut self.routes).push((method, path.to_owned(), max_size));
+
+        self
+    }
+
+    pub fn max_size(&self, method: &Method, path: &str) -> u64 {
+        self.routes
+            .iter()
+            .find(|(route_method, route_path, _)| {
+                route_method == method && is_sub_path(path, route_path)
+            })
+            .map_or(self.default, |(_, _, max_size)| *max_size)
+    }
+}
+
+/// Limit the request body size without buffering it, the body stream fails as soon as the
+/// received bytes exceed the max size, or nothing is received in the read timeout, so a slow
+/// client can't hold the connection.
 #[derive(Debug)]
 pub struct SizeLimitService<S> {
-    max_size: u64,
+    size_limits: SizeLimits,
+    read_timeout: Duration,
     service: S,
 }
 
 impl<S> SizeLimitService<S> {
-    pub fn new(max_size: u64, service: S) -> Self {
-        Self { max_size, service }
+    pub fn new(size_limits: SizeLimits, read_timeout: Duration, service: S) -> Self {
+        Self {
+            size_limits,
+            read_timeout,
+            service,
+        }
     }
 }
 
@@ -27,10 +103,10 @@ impl<S> Service<Request<Body>> for SizeLimitService<S>
     where
         S: Service<Request<Body>, Response=Response<Body>> + Send + Clone + 'static,
         S::Future: Send,
-        S::Error: Into<Box<dyn Error + Send + Sync>>,
+        S::Error: Into<BoxError>,
 {
     type Response = Response<Body>;
-    type Error = Box<dyn Error + Send + Sync>;
+    type Error = BoxError;
     type Future = ServiceResult<Self::Response, Self::Error>;
 
     fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -39,103 +115,258 @@ impl<S> Service<Request<Body>> for SizeLimitService<S>
 
     fn call(&mut self, mut req: Request<Body>) -> Self::Future {
         let mut inner_service = self.service.clone();
-        let max_size = self.max_size;
-
-        let log_cx = LogContext::builder()
-            .request_id(get_request_id(&req))
-            .build();
+        let max_size = self.size_limits.max_size(req.method(), req.uri().path());
 
-        Box::pin(async move {
-            let mut buf = BytesMut::with_capacity(max_size as _);
+        let log_cx = log_context(&req);
 
-            while let Some(result) = req.body_mut().next().await {
-                let data = result?;
+        // reject early if the client tells the size
+        let content_length = req
+            .headers()
+            .get("content-length")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
 
-                buf.put(data);
+        if content_length.is_some_and(|length| length > max_size) {
+            warn!(log::get_logger(), "request body is too large"; &log_cx, "content_length" => content_length);
 
-                if buf.len() > max_size as _ {
-                    let err_resp = Response::builder()
-                        .status(StatusCode::PAYLOAD_TOO_LARGE)
-                        .body(Body::empty())?;
+            let (status, code) = BodyError::TooLarge(max_size).status_code();
 
-                    warn!(log::get_logger(), "request body is too large"; log_cx);
+            let result = error::error_response(
+                status,
+                code,
+                &BodyError::TooLarge(max_size).to_string(),
+                &log_cx,
+            )
+                .map_err(|err| err.into());
 
-                    return Ok(err_resp);
-                }
-            }
+            return Box::pin(async move { result });
+        }
 
-            *req.body_mut() = Body::from(buf.freeze());
+        let body = std::mem::replace(req.body_mut(), Body::empty());
+        *req.body_mut() = limit_body(body, max_size, self.read_timeout);
 
-            inner_service.call(req).await.map_err(|err| err.into())
-        })
+        Box::pin(async move { inner_service.call(req).await.map_err(|err| err.into()) })
     }
 }
 
 impl<S: Clone> Clone for SizeLimitService<S> {
     fn clone(&self) -> Self {
         SizeLimitService {
-            max_size: self.max_size,
+            size_limits: self.size_limits.clone(),
+            read_timeout: self.read_timeout,
             service: self.service.clone(),
         }
     }
 
     fn clone_from(&mut self, source: &Self) {
-        self.max_size = source.max_size;
+        self.size_limits = source.size_limits.clone();
+        self.read_timeout = source.read_timeout;
         self.service = source.service.clone()
     }
 }
 
-fn get_request_id(req: &Request<Body>) -> &str {
-    req.headers()
-        .get("X-image-bed-request-id")
-        .map(|value| value.to_str().unwrap_or(""))
-        .unwrap_or("")
+fn is_sub_path(path: &str, parent: &str) -> bool {
+    path.strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Wrap the body so it fails with `BodyError`, the stream ends after the error.
+fn limit_body(body: Body, max_size: u64, read_timeout: Duration) -> Body {
+    let limited = stream::unfold(Some((body, 0u64)), move |state| async move {
+        let (mut body, received) = state?;
+
+        let err: BoxError = match time::timeout(read_timeout, body.next()).await {
+            Err(_) => BodyError::ReadTimeout(read_timeout).into(),
+            Ok(None) => return None,
+            Ok(Some(Err(err))) => err.into(),
+
+            Ok(Some(Ok(data))) => {
+                let received = received + data.len() as u64;
+
+                if received <= max_size {
+                    return Some((Ok(data), Some((body, received))));
+                }
+
+                BodyError::TooLarge(max_size).into()
+            }
+        };
+
+        Some((Err(err), None))
+    });
+
+    Body::wrap_stream(limited)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::convert::Infallible;
-    use std::future;
-    use std::future::Ready;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use hyper::body;
+
+    use crate::http::error::ErrorResponse;
+    use crate::http::request_id::REQUEST_ID_HEADER;
 
     use super::*;
 
+    const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Echo the request body, the read error is handled like the handler does.
     #[derive(Clone)]
     struct MockService;
 
     impl Service<Request<Body>> for MockService {
         type Response = Response<Body>;
-        type Error = Infallible;
-        type Future = Ready<Result<Self::Response, Self::Error>>;
+        type Error = BoxError;
+        type Future = ServiceResult<Self::Response, Self::Error>;
 
         fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
             Poll::Ready(Ok(()))
         }
 
-        fn call(&mut self, _req: Request<Body>) -> Self::Future {
-            future::ready(Ok(Response::new(Body::empty())))
+        fn call(&mut self, req: Request<Body>) -> Self::Future {
+            let log_cx = log_context(&req);
+
+            Box::pin(async move {
+                match body::to_bytes(req.into_body()).await {
+                    Err(err) => error::handle_error_response(err.into(), &log_cx),
+                    Ok(data) => Ok(Response::new(Body::from(data))),
+                }
+            })
         }
     }
 
+    async fn error_code(resp: Response<Body>) -> String {
+        let error_response: ErrorResponse =
+            serde_json::from_slice(&body::to_bytes(resp).await.unwrap()).unwrap();
+
+        assert_eq!(error_response.request_id, "request-id");
+
+        error_response.code
+    }
+
     #[tokio::test]
     async fn test_normal() {
-        let mut service = SizeLimitService::new(100, MockService);
+        let mut service = SizeLimitService::new(SizeLimits::new(100), READ_TIMEOUT, MockService);
 
         let resp = service
             .call(Request::new(Body::from(&b"test"[..])))
             .await
             .unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body::to_bytes(resp).await.unwrap(), &b"test"[..]);
     }
 
     #[tokio::test]
     async fn test_out_size() {
-        let mut service = SizeLimitService::new(1, MockService);
+        let mut service = SizeLimitService::new(SizeLimits::new(1), READ_TIMEOUT, MockService);
 
-        let resp = service
-            .call(Request::new(Body::from(&b"test"[..])))
-            .await
+        let req = Request::builder()
+            .header(REQUEST_ID_HEADER, "request-id")
+            .body(Body::from(&b"test"[..]))
+            .unwrap();
+
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        assert_eq!(error_code(resp).await, "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn test_out_size_content_length() {
+        let mut service = SizeLimitService::new(SizeLimits::new(1), READ_TIMEOUT, MockService);
+
+        let req = Request::builder()
+            .header(REQUEST_ID_HEADER, "request-id")
+            .header("content-length", "1000")
+            .body(Body::empty())
             .unwrap();
+
+        let resp = service.call(req).await.unwrap();
         assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error_code(resp).await, "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn test_out_size_early_termination() {
+        let mut service = SizeLimitService::new(SizeLimits::new(4), READ_TIMEOUT, MockService);
+
+        let polled = Arc::new(AtomicUsize::new(0));
+        let counter = polled.clone();
+
+        // an endless body, only the chunks until the limit should be read
+        let chunks = stream::repeat(()).map(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+
+            Ok::<_, BoxError>(Bytes::from_static(b"abc"))
+        });
+
+        let req = Request::builder()
+            .header(REQUEST_ID_HEADER, "request-id")
+            .body(Body::wrap_stream(chunks))
+            .unwrap();
+
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(polled.load(Ordering::SeqCst), 2);
+        assert_eq!(error_code(resp).await, "payload_too_large");
+    }
+
+    #[test]
+    fn test_route_max_size() {
+        let mut size_limits = SizeLimits::new(100);
+        size_limits
+            .add_route(Method::POST, "/upload", 10)
+            .add_route(Method::PUT, "/upload", 20);
+
+        assert_eq!(size_limits.max_size(&Method::POST, "/upload"), 10);
+        assert_eq!(size_limits.max_size(&Method::PUT, "/upload/id"), 20);
+        assert_eq!(size_limits.max_size(&Method::POST, "/upload-other"), 100);
+        assert_eq!(size_limits.max_size(&Method::POST, "/delete-batch"), 100);
+    }
+
+    #[tokio::test]
+    async fn test_route_out_size() {
+        let mut size_limits = SizeLimits::new(100);
+        size_limits.add_route(Method::POST, "/upload", 1);
+
+        let mut service = SizeLimitService::new(size_limits, READ_TIMEOUT, MockService);
+
+        let req = |path: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri(path)
+                .header(REQUEST_ID_HEADER, "request-id")
+                .body(Body::from(&b"test"[..]))
+                .unwrap()
+        };
+
+        let resp = service.call(req("/upload")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let resp = service.call(req("/delete-batch")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        let mut service =
+            SizeLimitService::new(SizeLimits::new(100), Duration::from_millis(50), MockService);
+
+        let (mut sender, req_body) = Body::channel();
+        sender.send_data(Bytes::from_static(b"test")).await.unwrap();
+
+        let req = Request::builder()
+            .header(REQUEST_ID_HEADER, "request-id")
+            .body(req_body)
+            .unwrap();
+
+        // the sender is kept but sends nothing more
+        let resp = service.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(error_code(resp).await, "request_timeout");
+
+        drop(sender);
     }
 }
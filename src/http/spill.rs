@@ -0,0 +1,356 @@
+use std::fs;
+use std::io::{self, BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use futures_util::io::{AsyncRead, AsyncReadExt};
+use slog::warn;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::task;
+
+use crate::hash::HashAlgorithm;
+use crate::log;
+use crate::media;
+
+/// The chunk size to read the spilled body back.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Buffer the upload body in memory until it's larger than the threshold, then spill it into a
+/// temp file, so the slow uploads hold the disk instead of the memory while they're received.
+#[derive(Debug)]
+pub struct SpillBuffer<'a> {
+    temp_dir: &'a Path,
+    threshold: Option<u64>,
+    memory: BytesMut,
+    file: Option<(File, SpilledBody)>,
+}
+
+impl<'a> SpillBuffer<'a> {
+    /// The body is never spilled when the threshold is `None`.
+    pub fn new(temp_dir: &'a Path, threshold: Option<u64>, capacity: u64) -> Self {
+        let capacity = threshold.map_or(capacity, |threshold| capacity.min(threshold));
+
+        Self {
+            temp_dir,
+            threshold,
+            memory: BytesMut::with_capacity(capacity as usize),
+            file: None,
+        }
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if let Some((file, spilled)) = &mut self.file {
+            file.write_all(chunk).await?;
+            spilled.size += chunk.len() as u64;
+
+            return Ok(());
+        }
+
+        if self.threshold.is_none_or(|threshold| {
+            (self.memory.len() + chunk.len()) as u64 <= threshold
+        }) {
+            self.memory.extend_from_slice(chunk);
+
+            return Ok(());
+        }
+
+        let path = self
+            .temp_dir
+            .join(format!("image_bed-upload-{:016x}", rand::random::<u64>()));
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        // the body may be private, the other users sharing the temp dir can't read it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            options.mode(0o600);
+        }
+
+        let mut file = OpenOptions::from(options).open(&path).await?;
+        // removed from now on, even if the writes fail
+        let spill_file = SpillFile { path };
+
+        file.write_all(&self.memory).await?;
+        file.write_all(chunk).await?;
+
+        let size = (self.memory.len() + chunk.len()) as u64;
+
+        // the bytes within the threshold are kept as the head for the sniffing
+        let threshold = self.threshold.unwrap_or_default() as usize;
+        let head_len = threshold.saturating_sub(self.memory.len()).min(chunk.len());
+        self.memory.extend_from_slice(&chunk[..head_len]);
+
+        let spilled = SpilledBody {
+            size,
+            head: std::mem::take(&mut self.memory).freeze(),
+            file: spill_file,
+        };
+
+        self.file = Some((file, spilled));
+
+        Ok(())
+    }
+
+    /// Return the whole body, the spilled one is left in the file until it's dropped.
+    pub async fn finish(self) -> io::Result<UploadBody> {
+        match self.file {
+            None => Ok(UploadBody::Memory(self.memory.freeze())),
+
+            Some((mut file, spilled)) => {
+                file.flush().await?;
+
+                Ok(UploadBody::Spilled(spilled))
+            }
+        }
+    }
+}
+
+/// The received upload body, the spilled one is read from its file when it's needed, so it's
+/// streamed to the store backend instead of being held in the memory.
+#[derive(Debug)]
+pub enum UploadBody {
+    Memory(Bytes),
+    Spilled(SpilledBody),
+}
+
+impl From<Bytes> for UploadBody {
+    fn from(data: Bytes) -> Self {
+        UploadBody::Memory(data)
+    }
+}
+
+impl UploadBody {
+    pub fn size(&self) -> u64 {
+        match self {
+            UploadBody::Memory(data) => data.len() as u64,
+            UploadBody::Spilled(spilled) => spilled.size,
+        }
+    }
+
+    /// The first bytes of the body, the spilled one keeps up to the memory spill threshold of
+    /// them, enough to sniff the content type.
+    pub fn head(&self) -> &[u8] {
+        match self {
+            UploadBody::Memory(data) => data,
+            UploadBody::Spilled(spilled) => &spilled.head,
+        }
+    }
+
+    /// Read the whole body into the memory, for the processing which needs all of it.
+    pub async fn into_bytes(self) -> io::Result<Bytes> {
+        match self {
+            UploadBody::Memory(data) => Ok(data),
+            UploadBody::Spilled(spilled) => {
+                Ok(Bytes::from(tokio::fs::read(&spilled.file.path).await?))
+            }
+        }
+    }
+
+    pub async fn reader(&self) -> io::Result<UploadReader<'_>> {
+        match self {
+            UploadBody::Memory(data) => Ok(UploadReader::Memory(data)),
+            UploadBody::Spilled(spilled) => {
+                Ok(UploadReader::File(File::open(&spilled.file.path).await?))
+            }
+        }
+    }
+
+    /// Only the image header is read, see `media::image_dimensions`.
+    pub async fn image_dimensions(&self) -> io::Result<Option<(u32, u32)>> {
+        match self {
+            UploadBody::Memory(data) => Ok(media::image_dimensions(data)),
+            UploadBody::Spilled(spilled) => {
+                let path = spilled.file.path.clone();
+
+                task::spawn_blocking(move || {
+                    let file = fs::File::open(path)?;
+
+                    Ok(media::image_dimensions_of(BufReader::new(file)))
+                })
+                    .await?
+            }
+        }
+    }
+
+    pub async fn hash(&self, hash_algorithm: HashAlgorithm) -> io::Result<String> {
+        let mut hasher = hash_algorithm.hasher();
+        let mut reader = self.reader().await?;
+        let mut buf = vec![0; READ_CHUNK_SIZE];
+
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(hasher.finalize());
+            }
+
+            hasher.update(&buf[..n]);
+        }
+    }
+
+    /// Compare the body with the data chunk by chunk.
+    pub async fn eq_bytes(&self, data: &[u8]) -> io::Result<bool> {
+        if self.size() != data.len() as u64 {
+            return Ok(false);
+        }
+
+        let mut reader = self.reader().await?;
+        let mut buf = vec![0; READ_CHUNK_SIZE];
+        let mut offset = 0;
+
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(offset == data.len());
+            }
+
+            if data.get(offset..offset + n) != Some(&buf[..n]) {
+                return Ok(false);
+            }
+
+            offset += n;
+        }
+    }
+}
+
+/// The body spilled into the temp file, the file is removed when it's dropped.
+#[derive(Debug)]
+pub struct SpilledBody {
+    size: u64,
+    head: Bytes,
+    file: SpillFile,
+}
+
+/// Read the upload body, from the memory or its spilled file, it's the reader of the store
+/// backends.
+pub enum UploadReader<'a> {
+    Memory(&'a [u8]),
+    File(File),
+}
+
+impl AsyncRead for UploadReader<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UploadReader::Memory(data) => Pin::new(data).poll_read(cx, buf),
+            UploadReader::File(file) => tokio::io::AsyncRead::poll_read(Pin::new(file), cx, buf),
+        }
+    }
+}
+
+/// The temp file of the spilled body, it's removed when dropped.
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            if err.kind() != ErrorKind::NotFound {
+                warn!(
+                    log::get_logger(),
+                    "remove spilled upload {} failed: {:?}",
+                    self.path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let temp_dir = env::temp_dir().join(format!("image_bed-spill-{}", rand::random::<u64>()));
+        fs::create_dir(&temp_dir).unwrap();
+
+        temp_dir
+    }
+
+    fn temp_files(temp_dir: &Path) -> usize {
+        fs::read_dir(temp_dir).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn test_in_memory() {
+        let temp_dir = temp_dir();
+
+        for threshold in &[None, Some(8)] {
+            let mut buffer = SpillBuffer::new(&temp_dir, *threshold, 0);
+
+            buffer.write(b"0123").await.unwrap();
+            buffer.write(b"4567").await.unwrap();
+            assert_eq!(temp_files(&temp_dir), 0);
+
+            let body = buffer.finish().await.unwrap();
+            assert_eq!(body.head(), b"01234567");
+            assert_eq!(body.into_bytes().await.unwrap(), &b"01234567"[..]);
+        }
+
+        fs::remove_dir(&temp_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spill() {
+        let temp_dir = temp_dir();
+        let mut buffer = SpillBuffer::new(&temp_dir, Some(8), 0);
+
+        buffer.write(b"0123").await.unwrap();
+        buffer.write(b"45678").await.unwrap();
+        assert_eq!(temp_files(&temp_dir), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let entry = fs::read_dir(&temp_dir).unwrap().next().unwrap().unwrap();
+            assert_eq!(entry.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        buffer.write(b"9").await.unwrap();
+
+        // the spilled body is read from the file until it's dropped
+        let body = buffer.finish().await.unwrap();
+        assert!(matches!(body, UploadBody::Spilled(_)));
+        assert_eq!(body.size(), 10);
+        assert_eq!(body.head(), b"01234567");
+        assert!(body.eq_bytes(b"0123456789").await.unwrap());
+        assert!(!body.eq_bytes(b"0123456780").await.unwrap());
+        assert_eq!(
+            body.hash(HashAlgorithm::Sha256).await.unwrap(),
+            HashAlgorithm::Sha256.hash(b"0123456789")
+        );
+
+        let mut data = vec![];
+        body.reader().await.unwrap().read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"0123456789");
+        assert_eq!(temp_files(&temp_dir), 1);
+
+        assert_eq!(body.into_bytes().await.unwrap(), &b"0123456789"[..]);
+        assert_eq!(temp_files(&temp_dir), 0);
+
+        // the failed upload removes the file too
+        let mut buffer = SpillBuffer::new(&temp_dir, Some(8), 0);
+
+        buffer.write(b"0123456789").await.unwrap();
+        assert_eq!(temp_files(&temp_dir), 1);
+
+        drop(buffer);
+        assert_eq!(temp_files(&temp_dir), 0);
+
+        fs::remove_dir(&temp_dir).unwrap();
+    }
+}
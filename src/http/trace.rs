@@ -0,0 +1,287 @@
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::{Body, Request, Response};
+use hyper::http::HeaderValue;
+use hyper::service::Service;
+
+use crate::http::request_id::REQUEST_ID_HEADER;
+use crate::telemetry::{Span, SpanKind};
+
+pub const TRACE_PARENT_HEADER: &str = "traceparent";
+
+const VERSION: &str = "00";
+const SAMPLED_FLAG: u8 = 0x01;
+
+/// The W3C trace context `traceparent` header.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TraceParent {
+    trace_id: String,
+    span_id: String,
+    flags: u8,
+}
+
+impl TraceParent {
+    pub fn parse(value: &str) -> Option<Self> {
+        let parts = value.trim().split('-').collect::<Vec<_>>();
+
+        if parts.len() != 4 || parts[0] != VERSION {
+            return None;
+        }
+
+        let (trace_id, span_id, flags) = (parts[1], parts[2], parts[3]);
+
+        if !is_valid_id(trace_id, 32) || !is_valid_id(span_id, 16) || flags.len() != 2 {
+            return None;
+        }
+
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+
+        Some(Self {
+            trace_id: trace_id.to_owned(),
+            span_id: span_id.to_owned(),
+            flags,
+        })
+    }
+
+    /// Start a new trace when the client didn't send a `traceparent`.
+    pub fn new_trace() -> Self {
+        Self {
+            trace_id: hex::encode(rand::random::<[u8; 16]>()),
+            span_id: new_span_id(),
+            flags: SAMPLED_FLAG,
+        }
+    }
+
+    /// Create the span of this service, which is the child of current span.
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id: new_span_id(),
+            flags: self.flags,
+        }
+    }
+
+    pub fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    pub fn span_id(&self) -> &str {
+        &self.span_id
+    }
+}
+
+impl Display for TraceParent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}-{}-{:02x}",
+            VERSION, self.trace_id, self.span_id, self.flags
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct TraceFuture<F: Future> {
+    trace_parent: HeaderValue,
+    span: Span,
+    fut: F,
+}
+
+impl<F, E> Future for TraceFuture<F>
+    where
+        F: Future<Output=Result<Response<Body>, E>> + Unpin,
+{
+    type Output = Result<Response<Body>, E>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut resp = match futures_util::ready!(Pin::new(&mut self.fut).poll(cx)) {
+            Err(err) => {
+                self.span.set_error();
+                self.span.end();
+
+                return Poll::Ready(Err(err));
+            }
+
+            Ok(resp) => resp,
+        };
+
+        self.span
+            .set_attribute("http.status_code", resp.status().as_u16());
+        if resp.status().is_server_error() {
+            self.span.set_error();
+        }
+        self.span.end();
+
+        resp.headers_mut()
+            .insert(TRACE_PARENT_HEADER, self.trace_parent.clone());
+
+        Poll::Ready(Ok(resp))
+    }
+}
+
+/// Continue the trace of the incoming `traceparent`, or start a new one, then replace the
+/// request `traceparent` with the span of this service so the inner services can log it. The
+/// span of this service is recorded as the server span of the request.
+#[derive(Debug)]
+pub struct TraceService<S> {
+    service: S,
+}
+
+impl<S> TraceService<S> {
+    pub fn new(service: S) -> Self {
+        Self { service }
+    }
+}
+
+impl<S> Service<Request<Body>> for TraceService<S>
+    where
+        S: Service<Request<Body>, Response=Response<Body>>,
+        S::Future: Unpin,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TraceFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let parent = req
+            .headers()
+            .get(TRACE_PARENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(TraceParent::parse);
+
+        let trace_parent = parent
+            .as_ref()
+            .map_or_else(TraceParent::new_trace, TraceParent::child);
+
+        let mut span = Span::with_ids(
+            &format!("HTTP {}", req.method()),
+            SpanKind::Server,
+            trace_parent.trace_id(),
+            trace_parent.span_id(),
+            parent.as_ref().map(TraceParent::span_id),
+        );
+        span.set_attribute("http.method", req.method());
+        span.set_attribute("http.target", req.uri().path());
+        if let Some(request_id) = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+        {
+            span.set_attribute("request_id", request_id);
+        }
+
+        let trace_parent = HeaderValue::from_str(&trace_parent.to_string())
+            .unwrap_or_else(|_| panic!("traceparent {} is invalid head value", trace_parent));
+
+        req.headers_mut()
+            .insert(TRACE_PARENT_HEADER, trace_parent.clone());
+
+        let fut = self.service.call(req);
+
+        TraceFuture {
+            trace_parent,
+            span,
+            fut,
+        }
+    }
+}
+
+fn new_span_id() -> String {
+    hex::encode(rand::random::<[u8; 8]>())
+}
+
+fn is_valid_id(id: &str, len: usize) -> bool {
+    id.len() == len
+        && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+        && id.chars().any(|c| c != '0')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future;
+    use std::future::Ready;
+
+    use super::*;
+
+    const TRACE_PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    /// Return the traceparent it received in the response body.
+    struct MockService;
+
+    impl Service<Request<Body>> for MockService {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<Body>) -> Self::Future {
+            let trace_parent = req.headers()[TRACE_PARENT_HEADER].to_str().unwrap().to_owned();
+
+            future::ready(Ok(Response::new(Body::from(trace_parent))))
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let trace_parent = TraceParent::parse(TRACE_PARENT).unwrap();
+
+        assert_eq!(trace_parent.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace_parent.span_id(), "00f067aa0ba902b7");
+        assert_eq!(trace_parent.to_string(), TRACE_PARENT);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for invalid in &[
+            "",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(TraceParent::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_continue_trace() {
+        let mut service = TraceService::new(MockService);
+
+        let req = Request::builder()
+            .header(TRACE_PARENT_HEADER, TRACE_PARENT)
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = service.call(req).await.unwrap();
+
+        let echo = TraceParent::parse(resp.headers()[TRACE_PARENT_HEADER].to_str().unwrap()).unwrap();
+        let received = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        assert_eq!(echo.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(echo.span_id(), "00f067aa0ba902b7");
+        assert_eq!(echo.to_string().as_bytes(), received.as_ref());
+    }
+
+    #[tokio::test]
+    async fn test_new_trace() {
+        let mut service = TraceService::new(MockService);
+
+        let resp = service.call(Request::new(Body::empty())).await.unwrap();
+
+        let echo = resp.headers()[TRACE_PARENT_HEADER].to_str().unwrap();
+
+        assert!(TraceParent::parse(echo).is_some());
+    }
+}
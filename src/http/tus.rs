@@ -0,0 +1,160 @@
+use hyper::{Body, HeaderMap, Response};
+use hyper::http::HeaderValue;
+
+/// The endpoint of the tus 1.0 core protocol, see https://tus.io/protocols/resumable-upload.
+pub const TUS_PATH: &str = "/files";
+/// The chunks of the unfinished uploads are stored in this bucket.
+pub const TUS_BUCKET: &str = "tus";
+pub const TUS_VERSION: &str = "1.0.0";
+
+pub const TUS_RESUMABLE_HEADER: &str = "tus-resumable";
+pub const TUS_VERSION_HEADER: &str = "tus-version";
+pub const TUS_MAX_SIZE_HEADER: &str = "tus-max-size";
+pub const UPLOAD_LENGTH_HEADER: &str = "upload-length";
+pub const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+pub const UPLOAD_METADATA_HEADER: &str = "upload-metadata";
+
+/// The content type of the `PATCH` requests.
+pub const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+const UPLOAD_ID_LENGTH: usize = 32;
+
+pub fn new_upload_id() -> String {
+    hex::encode(rand::random::<[u8; UPLOAD_ID_LENGTH / 2]>())
+}
+
+/// The chunk id is unique, so a concurrent `PATCH` at the same offset can't overwrite the chunk
+/// which is recorded already.
+pub fn new_chunk_id(upload_id: &str, offset: u64) -> String {
+    format!(
+        "{}-{}-{}",
+        upload_id,
+        offset,
+        hex::encode(rand::random::<[u8; 4]>())
+    )
+}
+
+pub fn is_valid_upload_id(upload_id: &str) -> bool {
+    upload_id.len() == UPLOAD_ID_LENGTH
+        && upload_id
+        .chars()
+        .all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Only the tus 1.0.0 requests are accepted.
+pub fn is_supported_version(headers: &HeaderMap) -> bool {
+    headers
+        .get(TUS_RESUMABLE_HEADER)
+        .is_some_and(|value| value == TUS_VERSION)
+}
+
+/// Parse the `Upload-Length` or `Upload-Offset` header, which must be a non-negative integer.
+pub fn parse_size_header(headers: &HeaderMap, name: &str) -> Option<u64> {
+    let value = headers.get(name)?.to_str().ok()?;
+
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    value.parse().ok()
+}
+
+/// Get the `filename` from the `Upload-Metadata` header, the pairs are separated by comma, and
+/// the key and the base64 encoded value are separated by space.
+pub fn metadata_filename(headers: &HeaderMap) -> Option<String> {
+    let metadata = headers.get(UPLOAD_METADATA_HEADER)?.to_str().ok()?;
+
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+
+        if parts.next()? != "filename" {
+            return None;
+        }
+
+        let value = base64::decode(parts.next()?.trim()).ok()?;
+        let filename = String::from_utf8(value).ok()?;
+
+        if filename.is_empty() {
+            None
+        } else {
+            Some(filename)
+        }
+    })
+}
+
+/// Every tus response carries the protocol version.
+pub fn set_resumable(resp: &mut Response<Body>) {
+    resp.headers_mut().insert(
+        TUS_RESUMABLE_HEADER,
+        HeaderValue::from_static(TUS_VERSION),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+
+        headers
+    }
+
+    #[test]
+    fn test_upload_id() {
+        let upload_id = new_upload_id();
+
+        assert!(is_valid_upload_id(&upload_id));
+        assert!(!is_valid_upload_id("../upload"));
+        assert!(!is_valid_upload_id(&upload_id.to_uppercase()));
+        assert_ne!(new_chunk_id(&upload_id, 0), new_chunk_id(&upload_id, 0));
+    }
+
+    #[test]
+    fn test_parse_size_header() {
+        assert_eq!(
+            parse_size_header(&headers(UPLOAD_OFFSET_HEADER, "100"), UPLOAD_OFFSET_HEADER),
+            Some(100)
+        );
+
+        for invalid in &["", "-1", "+1", "1.0", "abc"] {
+            assert_eq!(
+                parse_size_header(&headers(UPLOAD_OFFSET_HEADER, invalid), UPLOAD_OFFSET_HEADER),
+                None,
+                "{}",
+                invalid
+            );
+        }
+
+        assert_eq!(parse_size_header(&HeaderMap::new(), UPLOAD_OFFSET_HEADER), None);
+    }
+
+    #[test]
+    fn test_metadata_filename() {
+        // "test.png" and "image/png"
+        let metadata = headers(
+            UPLOAD_METADATA_HEADER,
+            "filetype aW1hZ2UvcG5n, filename dGVzdC5wbmc=,is_confidential",
+        );
+
+        assert_eq!(metadata_filename(&metadata).as_deref(), Some("test.png"));
+
+        assert_eq!(
+            metadata_filename(&headers(UPLOAD_METADATA_HEADER, "filename !!!")),
+            None
+        );
+        assert_eq!(
+            metadata_filename(&headers(UPLOAD_METADATA_HEADER, "filename")),
+            None
+        );
+        assert_eq!(metadata_filename(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_version() {
+        assert!(is_supported_version(&headers(TUS_RESUMABLE_HEADER, "1.0.0")));
+        assert!(!is_supported_version(&headers(TUS_RESUMABLE_HEADER, "0.2.2")));
+        assert!(!is_supported_version(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,353 @@
+use std::fmt::{self, Debug, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use serde::Serialize;
+use slog::{error, info, warn};
+
+use crate::db::Resource;
+use crate::log::{self, LogContext};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(10);
+/// The urls of a delete are posted in the batches of it.
+const MAX_PURGE_URLS: usize = 100;
+
+/// The payload posted to the webhook when a new resource is uploaded.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct UploadEvent {
+    pub id: String,
+    pub url: String,
+    pub size: u64,
+    pub hash: String,
+    pub content_type: String,
+    /// Unix timestamp in seconds.
+    pub created_at: i64,
+}
+
+impl UploadEvent {
+    pub fn new(resource: &Resource, url: &str) -> Self {
+        let created_at = resource
+            .get_create_time()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs() as i64);
+
+        Self {
+            id: resource.get_id().to_owned(),
+            url: url.to_owned(),
+            size: resource.get_resource_size(),
+            hash: resource.get_hash().to_owned(),
+            content_type: resource.get_content_type().to_owned(),
+            created_at,
+        }
+    }
+}
+
+/// The payload posted to the CDN purge url when resources are deleted, so the CDN stops serving
+/// the cached urls before the TTL.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct PurgeEvent {
+    pub urls: Vec<String>,
+}
+
+/// Notify the integrations about the new uploads or the deleted resources, failed requests are
+/// retried with exponential backoff.
+#[derive(Clone)]
+pub struct Webhook {
+    url: Uri,
+    client: Client<HttpsConnector<HttpConnector>>,
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+impl Debug for Webhook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url)
+            .field("max_attempts", &self.max_attempts)
+            .finish()
+    }
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let url = Uri::from_str(url)
+            .map_err(|err| anyhow::anyhow!("webhook url {} is invalid: {}", url, err))?;
+
+        if !matches!(url.scheme_str(), Some("http") | Some("https")) || url.host().is_none() {
+            return Err(anyhow::anyhow!("webhook url {} must be a http or https url", url));
+        }
+
+        Ok(Self {
+            url,
+            client: Client::builder().build(HttpsConnector::new()),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+        })
+    }
+
+    /// Post the event in a background task, so the upload response is not blocked.
+    pub fn notify(&self, event: UploadEvent, log_cx: LogContext) {
+        let webhook = self.clone();
+
+        tokio::spawn(async move { webhook.send(&event, &event.id, &log_cx).await });
+    }
+
+    /// Post the urls of the deleted resources in a background task, so the delete response is
+    /// not blocked.
+    pub fn purge(&self, urls: Vec<String>, log_cx: LogContext) {
+        if urls.is_empty() {
+            return;
+        }
+
+        let webhook = self.clone();
+
+        tokio::spawn(async move { webhook.send_purge(urls, &log_cx).await });
+    }
+
+    /// Return true if all the batches are accepted.
+    async fn send_purge(&self, urls: Vec<String>, log_cx: &LogContext) -> bool {
+        let mut all_accepted = true;
+
+        for batch in urls.chunks(MAX_PURGE_URLS) {
+            let event = PurgeEvent {
+                urls: batch.to_vec(),
+            };
+
+            // the later batches are still sent when a batch is given up
+            all_accepted &= self
+                .send(&event, &format!("{} urls", batch.len()), log_cx)
+                .await;
+        }
+
+        all_accepted
+    }
+
+    /// Return true if the event is accepted by the webhook in the attempts, the subject is
+    /// logged.
+    async fn send<T>(&self, event: &T, subject: &str, log_cx: &LogContext) -> bool
+        where
+            T: Serialize + Debug,
+    {
+        let payload = match serde_json::to_vec(event) {
+            Err(err) => {
+                error!(log::get_logger(), "encode webhook event {:?} failed: {}", event, err; log_cx);
+
+                return false;
+            }
+
+            Ok(payload) => payload,
+        };
+
+        let mut attempt = 1;
+
+        loop {
+            let result = match Request::builder()
+                .method(Method::POST)
+                .uri(self.url.clone())
+                .header("content-type", "application/json")
+                .body(Body::from(payload.clone()))
+            {
+                Err(err) => Err(err.to_string()),
+                Ok(req) => match self.client.request(req).await {
+                    Err(err) => Err(err.to_string()),
+                    Ok(resp) if resp.status().is_success() => Ok(()),
+                    Ok(resp) => Err(format!("webhook responds {}", resp.status())),
+                },
+            };
+
+            match result {
+                Ok(_) => {
+                    info!(
+                        log::get_logger(),
+                        "webhook is notified";
+                        log_cx,
+                        "subject" => subject,
+                        "attempt" => attempt
+                    );
+
+                    return true;
+                }
+
+                Err(err) if attempt < self.max_attempts => {
+                    let delay = self.backoff(attempt);
+
+                    warn!(
+                        log::get_logger(),
+                        "notify webhook failed, retry after {:?}: {}",
+                        delay, err;
+                        log_cx,
+                        "subject" => subject,
+                        "attempt" => attempt
+                    );
+
+                    tokio::time::delay_for(delay).await;
+
+                    attempt += 1;
+                }
+
+                Err(err) => {
+                    error!(
+                        log::get_logger(),
+                        "notify webhook failed, give up: {}", err;
+                        log_cx,
+                        "subject" => subject,
+                        "attempt" => attempt
+                    );
+
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Exponential backoff, `attempt` starts from 1.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .checked_mul(1 << (attempt - 1).min(16))
+            .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY))
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{body, Response, Server, StatusCode};
+
+    /// A local webhook server which records the request bodies, responds the statuses in order
+    /// and then 200.
+    pub fn mock_server(statuses: Vec<StatusCode>) -> (SocketAddr, Arc<Mutex<Vec<Bytes>>>) {
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let statuses = Arc::new(Mutex::new(statuses));
+
+        let server_bodies = bodies.clone();
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            move |_| {
+                let bodies = server_bodies.clone();
+                let statuses = statuses.clone();
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let bodies = bodies.clone();
+                        let statuses = statuses.clone();
+
+                        async move {
+                            let data = body::to_bytes(req.into_body()).await.unwrap();
+                            bodies.lock().unwrap().push(data);
+
+                            let mut statuses = statuses.lock().unwrap();
+                            let status = if statuses.is_empty() {
+                                StatusCode::OK
+                            } else {
+                                statuses.remove(0)
+                            };
+
+                            Ok::<_, Infallible>(
+                                Response::builder().status(status).body(hyper::Body::empty()).unwrap(),
+                            )
+                        }
+                    }))
+                }
+            },
+        ));
+        let addr = server.local_addr();
+
+        tokio::spawn(server);
+
+        (addr, bodies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+
+    use super::*;
+
+    fn event() -> UploadEvent {
+        UploadEvent {
+            id: "id".to_string(),
+            url: "https://test.com/get/id".to_string(),
+            size: 10,
+            hash: "hash".to_string(),
+            content_type: "image/png".to_string(),
+            created_at: 1_600_000_000,
+        }
+    }
+
+    #[test]
+    fn test_new_invalid_url() {
+        assert!(Webhook::new("ftp://test.com/hook").is_err());
+        assert!(Webhook::new("/hook").is_err());
+        assert!(Webhook::new("https://test.com/hook").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_retry() {
+        let (addr, bodies) = testing::mock_server(vec![
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+        ]);
+
+        let mut webhook = Webhook::new(&format!("http://{}/hook", addr)).unwrap();
+        webhook.base_delay = Duration::from_millis(1);
+
+        let log_cx = LogContext::builder().request_id("test").build();
+
+        assert!(webhook.send(&event(), "id", &log_cx).await);
+
+        let bodies = bodies.lock().unwrap();
+
+        assert_eq!(bodies.len(), 3);
+
+        let payload: serde_json::Value = serde_json::from_slice(&bodies[2]).unwrap();
+
+        assert_eq!(payload, serde_json::to_value(event()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_send_give_up() {
+        let (addr, bodies) = testing::mock_server(vec![StatusCode::INTERNAL_SERVER_ERROR; 5]);
+
+        let mut webhook = Webhook::new(&format!("http://{}/hook", addr)).unwrap();
+        webhook.base_delay = Duration::from_millis(1);
+
+        let log_cx = LogContext::builder().request_id("test").build();
+
+        assert!(!webhook.send(&event(), "id", &log_cx).await);
+        assert_eq!(bodies.lock().unwrap().len(), DEFAULT_MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn test_send_purge_batches() {
+        let (addr, bodies) = testing::mock_server(vec![]);
+
+        let webhook = Webhook::new(&format!("http://{}/purge", addr)).unwrap();
+        let log_cx = LogContext::builder().request_id("test").build();
+
+        let urls = (0..MAX_PURGE_URLS + 1)
+            .map(|i| format!("https://test.com/get/{}", i))
+            .collect::<Vec<_>>();
+
+        assert!(webhook.send_purge(urls.clone(), &log_cx).await);
+
+        let bodies = bodies.lock().unwrap();
+
+        assert_eq!(bodies.len(), 2);
+
+        let first: serde_json::Value = serde_json::from_slice(&bodies[0]).unwrap();
+        let second: serde_json::Value = serde_json::from_slice(&bodies[1]).unwrap();
+
+        assert_eq!(first, serde_json::json!({ "urls": &urls[..MAX_PURGE_URLS] }));
+        assert_eq!(second, serde_json::json!({ "urls": &urls[MAX_PURGE_URLS..] }));
+    }
+}
@@ -1,106 +1,351 @@
 use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::Instant;
 
+use async_trait::async_trait;
 use futures_util::lock::Mutex;
 use md5::{Digest, Md5};
-use slog::error;
-use sqlx::PgPool;
+use slog::{error, info};
+use sqlx::{Done, PgPool};
 
 use crate::log::{self, LogContext};
 
+use super::{IdEncoding, IdGenerator, IdStats};
+
+const STEP: i64 = 10;
+/// Start refilling in background when the buffered ids are fewer than it.
+const LOW_WATER: usize = STEP as usize / 2;
+/// The hex md5 prefix length.
+pub const ID_LENGTH: usize = 10;
+
+#[derive(Debug)]
+struct Buffer {
+    id_list: VecDeque<String>,
+    refilling: bool,
+}
+
 #[derive(Debug)]
 struct InnerGenerator {
     db_pool: PgPool,
     id_type: String,
-    id_list: VecDeque<String>,
-    step: i8,
+    step: i64,
+    /// The counter value when the generator is created, to compute the consumption rate.
+    start_value: i64,
+    started: Instant,
+    /// Only held shortly to pop or push ids, never across the db queries.
+    buffer: Mutex<Buffer>,
+    /// Serialize the batch fetches, so the batches are pushed in the counter order.
+    fetch_lock: Mutex<()>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Generator {
-    inner: Arc<Mutex<InnerGenerator>>,
+    inner: Arc<InnerGenerator>,
 }
 
 impl Generator {
-    pub async fn new(db: &PgPool, id_type: &str) -> anyhow::Result<Self> {
-        sqlx::query("select from id_generate where id_type = $1")
+    /// Seed the counter of the id type with `id_seed` if it doesn't exist, otherwise the first
+    /// batch would find no row to update. The existing counter is kept whatever the seed is, the
+    /// first id is generated from `id_seed + 1`.
+    pub async fn new(db: &PgPool, id_type: &str, id_seed: i64) -> anyhow::Result<Self> {
+        let seeded = sqlx::query(
+            "insert into id_generate (id_type, id_value) values ($1, $2) on conflict (id_type) do nothing",
+        )
             .bind(id_type)
+            .bind(id_seed)
             .execute(db)
-            .await?;
+            .await?
+            .rows_affected()
+            > 0;
+
+        if seeded {
+            info!(
+                log::get_logger(),
+                "id generator counter is seeded";
+                "id_type" => id_type,
+                "id_seed" => id_seed
+            );
+        }
 
-        let step = 10i8;
+        let start_value = current_value(db, id_type).await?;
 
         Ok(Self {
-            inner: Arc::new(Mutex::new(InnerGenerator {
+            inner: Arc::new(InnerGenerator {
                 db_pool: db.clone(),
                 id_type: id_type.to_owned(),
-                id_list: VecDeque::with_capacity(step as _),
-                step,
-            })),
+                step: STEP,
+                start_value,
+                started: Instant::now(),
+                buffer: Mutex::new(Buffer {
+                    id_list: VecDeque::with_capacity(STEP as usize * 2),
+                    refilling: false,
+                }),
+                fetch_lock: Mutex::new(()),
+            }),
         })
     }
 
     pub async fn get_id(&self, log_cx: &LogContext) -> anyhow::Result<String> {
-        let mut inner = self.inner.lock().await;
+        loop {
+            {
+                let mut buffer = self.inner.buffer.lock().await;
+
+                if let Some(id) = buffer.id_list.pop_front() {
+                    if buffer.id_list.len() < LOW_WATER && !buffer.refilling {
+                        buffer.refilling = true;
+
+                        self.spawn_refill(log_cx.clone());
+                    }
 
-        if let Some(id) = inner.id_list.pop_front() {
-            return Ok(id);
+                    return Ok(id);
+                }
+            }
+
+            // the buffer is drained, fetch a batch unless another task has done it meanwhile
+            let _fetch_guard = self.inner.fetch_lock.lock().await;
+
+            if !self.inner.buffer.lock().await.id_list.is_empty() {
+                continue;
+            }
+
+            let ids = self.inner.fetch_batch(log_cx).await?;
+
+            self.inner.buffer.lock().await.id_list.extend(ids);
         }
+    }
+
+    pub async fn stats(&self, log_cx: &LogContext) -> anyhow::Result<IdStats> {
+        let id_value = current_value(&self.inner.db_pool, &self.inner.id_type)
+            .await
+            .map_err(|err| {
+                error!(log::get_logger(), "get id value failed: {:?}", err; log_cx);
+                err
+            })?;
+
+        let consumed = id_value - self.inner.start_value;
+        let elapsed = self.inner.started.elapsed().as_secs_f64();
+
+        Ok(IdStats {
+            id_type: self.inner.id_type.clone(),
+            id_value,
+            step: self.inner.step,
+            consumed,
+            consumed_per_second: if elapsed > 0.0 {
+                consumed as f64 / elapsed
+            } else {
+                0.0
+            },
+        })
+    }
+
+    fn spawn_refill(&self, log_cx: LogContext) {
+        let inner = self.inner.clone();
+
+        tokio::spawn(async move {
+            let result = {
+                let _fetch_guard = inner.fetch_lock.lock().await;
+
+                match inner.fetch_batch(&log_cx).await {
+                    Err(err) => Err(err),
+                    Ok(ids) => {
+                        inner.buffer.lock().await.id_list.extend(ids);
+
+                        Ok(())
+                    }
+                }
+            };
+
+            inner.buffer.lock().await.refilling = false;
+
+            if let Err(err) = result {
+                error!(log::get_logger(), "refill id buffer failed: {:?}", err; &log_cx);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl IdGenerator for Generator {
+    fn encoding(&self) -> IdEncoding {
+        IdEncoding::Counter
+    }
+
+    async fn get_id(&self, log_cx: &LogContext) -> anyhow::Result<String> {
+        Generator::get_id(self, log_cx).await
+    }
+
+    async fn stats(&self, log_cx: &LogContext) -> anyhow::Result<Option<IdStats>> {
+        Generator::stats(self, log_cx).await.map(Some)
+    }
+}
+
+async fn current_value(db: &PgPool, id_type: &str) -> sqlx::Result<i64> {
+    let (id_value, ) =
+        sqlx::query_as::<_, (i64, )>("select id_value from id_generate where id_type=$1")
+            .bind(id_type)
+            .fetch_one(db)
+            .await?;
+
+    Ok(id_value)
+}
+
+/// The generated ids are the lowercase hex, the uppercase ones can only be given by the client.
+pub fn is_counter_id(id: &str) -> bool {
+    id.len() == ID_LENGTH && id.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
 
+impl InnerGenerator {
+    /// Take the next `step` values from the counter, the caller must hold `fetch_lock`.
+    async fn fetch_batch(&self, log_cx: &LogContext) -> anyhow::Result<Vec<String>> {
         let (max_id, ) = sqlx::query_as::<_, (i64, )>(
             "update id_generate set id_value=id_value+$1 where id_type=$2 returning id_value",
         )
-            .bind(inner.step as i32)
-            .bind(&inner.id_type)
-            .fetch_one(&inner.db_pool)
+            .bind(self.step as i32)
+            .bind(&self.id_type)
+            .fetch_one(&self.db_pool)
             .await
             .map_err(|err| {
                 error!(log::get_logger(), "get id value failed: {:?}", err; log_cx);
                 err
             })?;
 
-        let start_id: i64 = max_id - (inner.step as i64) + 1;
+        let start_id: i64 = max_id - self.step + 1;
 
         let mut hasher = Md5::new();
 
-        (start_id..=max_id).for_each(|id| {
-            hasher.update(id.to_be_bytes());
+        Ok((start_id..=max_id)
+            .map(|id| {
+                hasher.update(id.to_be_bytes());
 
-            let id = hex::encode(hasher.finalize_reset())
-                .chars()
-                .take(10)
-                .collect::<String>();
-
-            inner.id_list.push_back(id);
-        });
-
-        Ok(inner.id_list.pop_front().unwrap())
+                hex::encode(hasher.finalize_reset())
+                    .chars()
+                    .take(ID_LENGTH)
+                    .collect::<String>()
+            })
+            .collect())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::env;
+    use std::time::{Duration, Instant};
 
     use sqlx::postgres::PgPoolOptions;
 
     use super::*;
 
-    #[tokio::test]
-    async fn get_id() {
+    async fn new_pg_pool() -> PgPool {
         let pg_uri = env::var("PG_URI").expect("must set environment PG_URI");
-        let id_type = env::var("ID_TYPE").expect("must set environment ID_TYPE");
 
-        let pg_pool = PgPoolOptions::new()
-            .max_connections(1)
+        PgPoolOptions::new()
+            .max_connections(2)
             .connect(&pg_uri)
             .await
-            .unwrap();
+            .unwrap()
+    }
+
+    async fn new_generator() -> Generator {
+        let id_type = env::var("ID_TYPE").expect("must set environment ID_TYPE");
+
+        Generator::new(&new_pg_pool().await, &id_type, 0).await.unwrap()
+    }
+
+    fn counter_id(id_value: i64) -> String {
+        hex::encode(Md5::digest(&id_value.to_be_bytes()))[..ID_LENGTH].to_string()
+    }
 
-        let generator = Generator::new(&pg_pool, &id_type).await.unwrap();
+    #[tokio::test]
+    async fn get_id() {
+        let generator = new_generator().await;
 
         let log_cx = LogContext::builder().request_id("").build();
 
         println!("id is {}", generator.get_id(&log_cx).await.unwrap());
     }
+
+    #[tokio::test]
+    async fn get_id_seeded() {
+        let pg_pool = new_pg_pool().await;
+        // a type without the counter row
+        let id_type = format!("test-seed-{}", rand::random::<u64>());
+        let log_cx = LogContext::builder().request_id("").build();
+
+        let generator = Generator::new(&pg_pool, &id_type, 0).await.unwrap();
+
+        assert_eq!(generator.get_id(&log_cx).await.unwrap(), counter_id(1));
+
+        // the existing counter is not seeded again
+        let generator = Generator::new(&pg_pool, &id_type, 0).await.unwrap();
+
+        assert_eq!(generator.get_id(&log_cx).await.unwrap(), counter_id(STEP + 1));
+
+        sqlx::query("delete from id_generate where id_type = $1")
+            .bind(&id_type)
+            .execute(&pg_pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_id_seed_offset() {
+        let pg_pool = new_pg_pool().await;
+        let id_type = format!("test-seed-{}", rand::random::<u64>());
+        let log_cx = LogContext::builder().request_id("").build();
+
+        let generator = Generator::new(&pg_pool, &id_type, 1000).await.unwrap();
+
+        let stats = generator.stats(&log_cx).await.unwrap();
+        assert_eq!((stats.id_value, stats.consumed), (1000, 0));
+
+        assert_eq!(generator.get_id(&log_cx).await.unwrap(), counter_id(1001));
+
+        // the batch advances the counter by the step
+        let stats = generator.stats(&log_cx).await.unwrap();
+        assert_eq!(stats.id_type, id_type);
+        assert_eq!(stats.step, STEP);
+        assert_eq!((stats.id_value, stats.consumed), (1000 + STEP, STEP));
+        assert!(stats.consumed_per_second > 0.0);
+
+        // the seed only applies to the new counter
+        let generator = Generator::new(&pg_pool, &id_type, 5000).await.unwrap();
+
+        assert_eq!(generator.get_id(&log_cx).await.unwrap(), counter_id(1001 + STEP));
+
+        sqlx::query("delete from id_generate where id_type = $1")
+            .bind(&id_type)
+            .execute(&pg_pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_id_concurrently() {
+        const TASKS: usize = 200;
+
+        let generator = new_generator().await;
+
+        let start = Instant::now();
+
+        let tasks = (0..TASKS)
+            .map(|_| {
+                let generator = generator.clone();
+
+                tokio::spawn(async move {
+                    let log_cx = LogContext::builder().request_id("").build();
+
+                    generator.get_id(&log_cx).await.unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut ids = HashSet::new();
+        for task in tasks {
+            ids.insert(task.await.unwrap());
+        }
+
+        assert_eq!(ids.len(), TASKS);
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", start.elapsed());
+    }
 }
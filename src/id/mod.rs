@@ -1 +1,76 @@
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::log::LogContext;
+
 pub mod generate;
+pub mod snowflake;
+
+/// How the resource ids are generated.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IdEncoding {
+    /// The md5 prefix of the counter in the `id_generate` table.
+    Counter,
+    /// The time sortable ids without the db counter, see `snowflake::SnowflakeGenerator`.
+    Snowflake,
+}
+
+impl IdEncoding {
+    pub const COUNTER: &'static str = "counter";
+    pub const SNOWFLAKE: &'static str = "snowflake";
+
+    /// Check the id looks like a generated one, the client ids must not, otherwise a later
+    /// generated id may be taken.
+    pub fn is_generated(&self, id: &str) -> bool {
+        match self {
+            IdEncoding::Counter => generate::is_counter_id(id),
+            IdEncoding::Snowflake => snowflake::is_snowflake_id(id),
+        }
+    }
+}
+
+impl FromStr for IdEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            Self::COUNTER => Ok(IdEncoding::Counter),
+            Self::SNOWFLAKE => Ok(IdEncoding::Snowflake),
+            s => Err(anyhow::anyhow!(
+                "id encoding {} is invalid, must be {} or {}",
+                s,
+                Self::COUNTER,
+                Self::SNOWFLAKE
+            )),
+        }
+    }
+}
+
+/// The consumption of the id counter, for the capacity planning.
+#[derive(Debug, Serialize)]
+pub struct IdStats {
+    pub id_type: String,
+    /// The last reserved counter value.
+    pub id_value: i64,
+    /// The counter is advanced by it for every batch of ids.
+    pub step: i64,
+    /// The counter values reserved since the generator is created, including the ones reserved
+    /// by the other instances sharing the counter.
+    pub consumed: i64,
+    pub consumed_per_second: f64,
+}
+
+#[async_trait]
+pub trait IdGenerator: Debug + Send + Sync {
+    fn encoding(&self) -> IdEncoding;
+
+    async fn get_id(&self, log_cx: &LogContext) -> anyhow::Result<String>;
+
+    /// `None` if the ids are not taken from a counter.
+    async fn stats(&self, _log_cx: &LogContext) -> anyhow::Result<Option<IdStats>> {
+        Ok(None)
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+
+use crate::log::LogContext;
+
+use super::{IdEncoding, IdGenerator};
+
+const MACHINE_ID_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const TIMESTAMP_BITS: u32 = 63 - MACHINE_ID_BITS - SEQUENCE_BITS;
+
+pub const MAX_MACHINE_ID: u16 = (1 << MACHINE_ID_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+/// 2021-01-01T00:00:00Z, the 41 bits timestamp lasts about 69 years from it.
+const EPOCH: Duration = Duration::from_secs(1_609_459_200);
+
+/// The ids are left padded, so the string order is the same as the number order.
+pub const ID_LENGTH: usize = 11;
+/// In the ascii order.
+const BASE62_ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+#[derive(Debug, Default)]
+struct State {
+    last_timestamp: u64,
+    sequence: u64,
+}
+
+/// Generate the 63 bits ids composed of the milliseconds since `EPOCH`, the machine id and the
+/// sequence in the millisecond, without the db counter. The instances sharing a db must have
+/// different machine ids.
+#[derive(Debug)]
+pub struct SnowflakeGenerator {
+    machine_id: u64,
+    state: Mutex<State>,
+}
+
+impl SnowflakeGenerator {
+    pub fn new(machine_id: u16) -> anyhow::Result<Self> {
+        if machine_id > MAX_MACHINE_ID {
+            return Err(anyhow::anyhow!(
+                "machine id {} must not be greater than {}",
+                machine_id,
+                MAX_MACHINE_ID
+            ));
+        }
+
+        Ok(Self {
+            machine_id: machine_id as _,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    fn next_id(&self, now: SystemTime) -> u64 {
+        let timestamp = now
+            .duration_since(SystemTime::UNIX_EPOCH + EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64);
+
+        let mut state = self.state.lock().unwrap();
+
+        // never go back when the clock is moved back, and borrow the next millisecond when the
+        // sequence is used up, so the ids are always increasing
+        if timestamp > state.last_timestamp {
+            state.last_timestamp = timestamp;
+            state.sequence = 0;
+        } else if state.sequence < MAX_SEQUENCE {
+            state.sequence += 1;
+        } else {
+            state.last_timestamp += 1;
+            state.sequence = 0;
+        }
+
+        ((state.last_timestamp & ((1 << TIMESTAMP_BITS) - 1)) << (MACHINE_ID_BITS + SEQUENCE_BITS))
+            | (self.machine_id << SEQUENCE_BITS)
+            | state.sequence
+    }
+}
+
+#[async_trait]
+impl IdGenerator for SnowflakeGenerator {
+    fn encoding(&self) -> IdEncoding {
+        IdEncoding::Snowflake
+    }
+
+    async fn get_id(&self, _log_cx: &LogContext) -> anyhow::Result<String> {
+        Ok(encode_base62(self.next_id(SystemTime::now())))
+    }
+}
+
+fn encode_base62(mut value: u64) -> String {
+    let mut buf = [BASE62_ALPHABET[0]; ID_LENGTH];
+
+    for byte in buf.iter_mut().rev() {
+        *byte = BASE62_ALPHABET[(value % 62) as usize];
+        value /= 62;
+    }
+
+    String::from_utf8(buf.to_vec()).expect("base62 is ascii")
+}
+
+pub fn is_snowflake_id(id: &str) -> bool {
+    id.len() == ID_LENGTH && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_base62() {
+        assert_eq!(encode_base62(0), "00000000000");
+        assert_eq!(encode_base62(61), "0000000000z");
+        assert_eq!(encode_base62(62), "00000000010");
+        assert_eq!(encode_base62(i64::MAX as u64).len(), ID_LENGTH);
+        assert!(encode_base62(61) < encode_base62(62));
+    }
+
+    #[test]
+    fn test_machine_id() {
+        assert!(SnowflakeGenerator::new(MAX_MACHINE_ID).is_ok());
+        assert!(SnowflakeGenerator::new(MAX_MACHINE_ID + 1).is_err());
+
+        let now = SystemTime::now();
+        let id = SnowflakeGenerator::new(5).unwrap().next_id(now);
+
+        assert_eq!((id >> SEQUENCE_BITS) & MAX_MACHINE_ID as u64, 5);
+    }
+
+    #[tokio::test]
+    async fn test_get_id_ordered_unique() {
+        const IDS: usize = 20_000;
+
+        let generator = SnowflakeGenerator::new(1).unwrap();
+        let log_cx = LogContext::builder().request_id("").build();
+
+        let mut ids = Vec::with_capacity(IDS);
+        for _ in 0..IDS {
+            ids.push(generator.get_id(&log_cx).await.unwrap());
+        }
+
+        assert!(ids.iter().all(|id| is_snowflake_id(id)));
+        assert!(ids.windows(2).all(|window| window[0] < window[1]));
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), IDS);
+    }
+
+    #[test]
+    fn test_clock_moved_back() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+        let now = SystemTime::now();
+
+        let first = generator.next_id(now);
+        let second = generator.next_id(now - Duration::from_secs(1));
+
+        assert!(second > first);
+    }
+
+    #[test]
+    fn test_sequence_used_up() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+        let now = SystemTime::now();
+
+        let ids = (0..=MAX_SEQUENCE + 1)
+            .map(|_| generator.next_id(now))
+            .collect::<Vec<_>>();
+
+        assert!(ids.windows(2).all(|window| window[0] < window[1]));
+    }
+}
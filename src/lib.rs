@@ -1,35 +1,118 @@
+use std::env;
 use std::fs::File;
-use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 
-use hyper::Server;
+use slog::info;
+use sqlx::postgres::PgConnectOptions;
 
 use crate::argument::Argument;
 use crate::config::Config;
 use crate::http::handle::HandlerBuilder;
-use crate::store::cos::CosBackend;
+use crate::http::listen::{self, HttpVersions};
+use crate::log::LogFormat;
+use crate::store::circuit_breaker::{self, CircuitBreaker};
+use crate::store::traced::TracedBackend;
+use crate::store::StoreBackend;
 
 mod argument;
+mod backend;
+mod check;
 mod config;
 mod db;
+mod hash;
 mod http;
 mod id;
 mod log;
+mod media;
+mod reconcile;
 mod store;
+mod telemetry;
 
 pub async fn run() -> anyhow::Result<()> {
     let argument = Argument::new();
 
-    let config: Config = if argument.config == Path::new("-") {
-        let stdin = std::io::stdin();
+    let mut config: Config = match &argument.config {
+        None => Config::default(),
 
-        serde_yaml::from_reader(stdin.lock())?
-    } else {
-        let file = File::open(argument.config)?;
-        serde_yaml::from_reader(file)?
+        Some(path) if path == Path::new("-") => {
+            let stdin = std::io::stdin();
+
+            serde_yaml::from_reader(stdin.lock())?
+        }
+
+        Some(path) => {
+            let file = File::open(path)?;
+            serde_yaml::from_reader(file)?
+        }
     };
 
+    config.apply_env(|name| env::var(name).ok())?;
+    config.load_secret_files()?;
+    config.validate()?;
+
+    log::init(
+        config
+            .log_format
+            .as_deref()
+            .map_or(Ok(LogFormat::Json), LogFormat::from_str)?,
+        config
+            .log_level
+            .as_deref()
+            .map_or(Ok(log::DEFAULT_LEVEL), log::parse_level)?,
+    )?;
+
+    let backend = backend::from_config(&config)?;
+
+    if argument.check {
+        let connect_options = PgConnectOptions::new()
+            .database(&config.database_name)
+            .host(&config.host)
+            .username(&config.user)
+            .password(&config.password)
+            .port(config.port.unwrap_or(5432));
+
+        return check::run(connect_options, &backend).await;
+    }
+
+    #[cfg(feature = "otlp")]
+    if let Some(endpoint) = &config.otlp_endpoint {
+        telemetry::set_tracer(telemetry::Tracer::new(telemetry::otlp::OtlpExporter::new(
+            endpoint,
+        )?));
+    }
+
+    serve_with_circuit_breaker(&config, backend).await
+}
+
+async fn serve_with_circuit_breaker<S>(config: &Config, backend: S) -> anyhow::Result<()>
+    where
+        S: StoreBackend + Send + Sync + 'static,
+        S::Error: Send + Sync + 'static,
+{
+    if config.circuit_breaker.unwrap_or(false) {
+        let backend = CircuitBreaker::new(
+            backend,
+            config
+                .circuit_breaker_failure_threshold
+                .unwrap_or(circuit_breaker::DEFAULT_FAILURE_THRESHOLD),
+            config
+                .circuit_breaker_cooldown
+                .map_or(circuit_breaker::DEFAULT_COOLDOWN, Duration::from_secs),
+        );
+
+        serve(config, backend).await
+    } else {
+        serve(config, backend).await
+    }
+}
+
+async fn serve<S>(config: &Config, backend: S) -> anyhow::Result<()>
+    where
+        S: StoreBackend + Send + Sync + 'static,
+        S::Error: Send + Sync + 'static,
+{
     let mut handler_builder = HandlerBuilder::new();
 
     handler_builder
@@ -43,24 +126,211 @@ pub async fn run() -> anyhow::Result<()> {
     config
         .max_body_size
         .map(|size| handler_builder.set_max_body_size(size));
+    config
+        .upload_max_body_size
+        .map(|size| handler_builder.set_upload_max_body_size(size));
+    config
+        .batch_max_body_size
+        .map(|size| handler_builder.set_batch_max_body_size(size));
+    config
+        .strip_exif
+        .map(|strip_exif| handler_builder.set_strip_exif(strip_exif));
+    config
+        .cache_control_max_age
+        .map(|max_age| handler_builder.set_cache_control_max_age(max_age));
+    config
+        .access_log
+        .map(|access_log| handler_builder.set_access_log(access_log));
+    config
+        .compression
+        .map(|compression| handler_builder.set_compression(compression));
+    config
+        .request_id_header
+        .as_ref()
+        .map(|header| handler_builder.set_request_id_header(header));
+    config
+        .admin_token
+        .as_ref()
+        .map(|token| handler_builder.set_admin_token(token));
+    config
+        .db_max_connections
+        .map(|max_connections| handler_builder.set_db_max_connections(max_connections));
+    config
+        .db_acquire_timeout
+        .map(|timeout| handler_builder.set_db_acquire_timeout(timeout));
+    config
+        .db_idle_timeout
+        .map(|timeout| handler_builder.set_db_idle_timeout(timeout));
+    config
+        .db_connect_max_attempts
+        .map(|max_attempts| handler_builder.set_db_connect_max_attempts(max_attempts));
+    config
+        .db_connect_retry_delay
+        .map(|retry_delay| handler_builder.set_db_connect_retry_delay(retry_delay));
+    config
+        .max_image_width
+        .map(|width| handler_builder.set_max_image_width(width));
+    config
+        .max_image_height
+        .map(|height| handler_builder.set_max_image_height(height));
+    config
+        .default_scheme
+        .as_ref()
+        .map(|scheme| handler_builder.set_default_scheme(scheme));
+    config
+        .upload_path
+        .as_ref()
+        .map(|path| handler_builder.set_upload_path(path));
+    config
+        .get_path
+        .as_ref()
+        .map(|path| handler_builder.set_get_path(path));
+    config
+        .resource_cache_capacity
+        .map(|capacity| handler_builder.set_resource_cache_capacity(capacity));
+    config
+        .resource_cache_ttl
+        .map(|ttl| handler_builder.set_resource_cache_ttl(ttl));
+    config
+        .migrate
+        .map(|migrate| handler_builder.set_migrate(migrate));
+    config
+        .transcode
+        .map(|transcode| handler_builder.set_transcode(transcode));
+    config
+        .verify_on_read
+        .map(|verify_on_read| handler_builder.set_verify_on_read(verify_on_read));
+    config
+        .verify_dedup_bytes
+        .as_ref()
+        .map(|verify| handler_builder.set_verify_dedup_bytes(verify));
+    config
+        .hash_algorithm
+        .as_ref()
+        .map(|algorithm| handler_builder.set_hash_algorithm(algorithm));
+    config
+        .upload_webhook_url
+        .as_ref()
+        .map(|url| handler_builder.set_upload_webhook_url(url));
+    config
+        .cdn_purge_url
+        .as_ref()
+        .map(|url| handler_builder.set_cdn_purge_url(url));
+    config
+        .max_total_bytes
+        .map(|max_total_bytes| handler_builder.set_max_total_bytes(max_total_bytes));
+    config
+        .max_objects_per_bucket
+        .map(|max_objects| handler_builder.set_max_objects_per_bucket(max_objects));
+    config
+        .precreate_bucket
+        .map(|precreate| handler_builder.set_precreate_bucket(precreate));
+    config
+        .fixed_bucket
+        .as_ref()
+        .map(|fixed_bucket| handler_builder.set_fixed_bucket(fixed_bucket));
+    config
+        .body_read_timeout
+        .map(|timeout| handler_builder.set_body_read_timeout(timeout));
+    config
+        .request_timeout
+        .map(|timeout| handler_builder.set_request_timeout(timeout));
+    config
+        .resource_ttl
+        .map(|ttl| handler_builder.set_resource_ttl(ttl));
+    config
+        .temp_dir
+        .as_ref()
+        .map(|temp_dir| handler_builder.set_temp_dir(temp_dir));
+    config
+        .memory_spill_threshold
+        .map(|threshold| handler_builder.set_memory_spill_threshold(threshold));
+    config
+        .max_concurrent_uploads
+        .map(|max| handler_builder.set_max_concurrent_uploads(max));
+    config
+        .cors_max_age
+        .map(|max_age| handler_builder.set_cors_max_age(max_age));
+    config
+        .cors_allow_credentials
+        .map(|allow| handler_builder.set_cors_allow_credentials(allow));
+    config
+        .allow_empty_referer
+        .map(|allow| handler_builder.set_allow_empty_referer(allow));
+    config
+        .hotlink_placeholder
+        .as_ref()
+        .map(|path| handler_builder.set_hotlink_placeholder(path));
+    config
+        .watermark_path
+        .as_ref()
+        .map(|path| handler_builder.set_watermark(path));
+    config
+        .watermark_position
+        .as_ref()
+        .map(|position| handler_builder.set_watermark_position(position));
+    config
+        .watermark_opacity
+        .map(|opacity| handler_builder.set_watermark_opacity(opacity));
+    config
+        .watermark_always
+        .map(|always| handler_builder.set_watermark_always(always));
+    config
+        .url_signing_secret
+        .as_ref()
+        .map(|secret| handler_builder.set_url_signing_secret(secret));
+    config
+        .id_encoding
+        .as_ref()
+        .map(|encoding| handler_builder.set_id_encoding(encoding));
+    config
+        .default_content_type
+        .as_ref()
+        .map(|content_type| handler_builder.set_default_content_type(content_type));
+    config
+        .extension_content_type
+        .map(|extension_content_type| {
+            handler_builder.set_extension_content_type(extension_content_type)
+        });
+    config
+        .machine_id
+        .map(|machine_id| handler_builder.set_machine_id(machine_id));
+    config
+        .id_seed
+        .map(|id_seed| handler_builder.set_id_seed(id_seed));
 
-    let backend = CosBackend::new(
-        &config.access_key,
-        &config.secret_key,
-        &config.region,
-        &config.app_id,
-    );
+    handler_builder
+        .set_trusted_proxies(&config.trusted_proxies)
+        .set_ip_filter_allow(&config.ip_filter_allow)
+        .set_ip_filter_deny(&config.ip_filter_deny)
+        .set_ip_filter_paths(&config.ip_filter_paths)
+        .set_allowed_content_types(&config.allowed_content_types)
+        .set_allowed_referers(&config.allowed_referers)
+        .set_cors_allowed_origins(&config.cors_allowed_origins);
 
-    handler_builder.set_store_backend(backend);
+    handler_builder.set_store_backend(TracedBackend::new(backend));
+
+    let connection_options = config.connection_options();
+    let incomings = listen::bind_all(&config.listen_addrs()?, &connection_options)?;
 
     let handler = handler_builder.build().await?;
 
-    let ip_addr = IpAddr::from_str(&config.listen_addr)?;
+    let http_versions = HttpVersions::new(
+        config.http2.unwrap_or(false),
+        config.http2_only.unwrap_or(false),
+    );
+
+    info!(
+        log::get_logger(),
+        "http versions";
+        "http_versions" => format!("{:?}", http_versions),
+        "connection_options" => format!("{:?}", connection_options)
+    );
 
-    Ok(
-        Server::bind(&SocketAddr::from((ip_addr, config.listen_port)))
-            .tcp_nodelay(true)
-            .serve(handler)
-            .await?,
-    )
+    listen::serve_all(incomings, |incoming| {
+        http_versions
+            .server_builder(incoming, &connection_options)
+            .serve(handler.clone())
+    })
+        .await
 }
@@ -1,12 +1,53 @@
 use std::io;
+use std::str::FromStr;
 use std::sync::Mutex;
 
 use once_cell::sync::OnceCell;
-use slog::{Drain, FnValue, KV, Logger, Record, Result, Serializer};
+use slog::{Drain, FnValue, KV, Level, Logger, Record, Result, Serializer};
+
+static LOGGER: OnceCell<Logger> = OnceCell::new();
+
+pub const DEFAULT_LEVEL: Level = Level::Info;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LogFormat {
+    /// One json object per line, for the log aggregation.
+    Json,
+    /// The human readable lines, for the local development.
+    Text,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            s => Err(anyhow::anyhow!("log format {} is invalid, must be json or text", s)),
+        }
+    }
+}
+
+/// Slog only knows the `warn` name, `warning` is accepted too as the level is named so.
+pub fn parse_level(level: &str) -> anyhow::Result<Level> {
+    if level.eq_ignore_ascii_case("warning") {
+        return Ok(Level::Warning);
+    }
+
+    Level::from_str(level).map_err(|_| {
+        anyhow::anyhow!(
+            "log level {} is invalid, must be trace, debug, info, warning, error or critical",
+            level
+        )
+    })
+}
 
 #[derive(Debug, Clone)]
 pub struct LogContext {
     request_id: String,
+    trace_id: Option<String>,
+    span_id: Option<String>,
 }
 
 impl LogContext {
@@ -14,24 +55,48 @@ impl LogContext {
         &self.request_id
     }
 
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_deref()
+    }
+
+    pub fn span_id(&self) -> Option<&str> {
+        self.span_id.as_deref()
+    }
+
     pub fn builder() -> LogContextBuilder {
         LogContextBuilder::new()
     }
 
-    fn new(request_id: String) -> Self {
-        Self { request_id }
+    fn new(request_id: String, trace_id: Option<String>, span_id: Option<String>) -> Self {
+        Self {
+            request_id,
+            trace_id,
+            span_id,
+        }
     }
 }
 
 impl KV for LogContext {
     fn serialize(&self, _record: &Record, serializer: &mut dyn Serializer) -> Result {
-        serializer.emit_str("requestId", &self.request_id)
+        serializer.emit_str("requestId", &self.request_id)?;
+
+        if let Some(trace_id) = &self.trace_id {
+            serializer.emit_str("traceId", trace_id)?;
+        }
+
+        if let Some(span_id) = &self.span_id {
+            serializer.emit_str("spanId", span_id)?;
+        }
+
+        Ok(())
     }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct LogContextBuilder {
     request_id: Option<String>,
+    trace_id: Option<String>,
+    span_id: Option<String>,
 }
 
 impl LogContextBuilder {
@@ -45,22 +110,269 @@ impl LogContextBuilder {
         self
     }
 
+    pub fn trace_id(mut self, trace_id: &str) -> Self {
+        self.trace_id.replace(trace_id.to_owned());
+
+        self
+    }
+
+    pub fn span_id(mut self, span_id: &str) -> Self {
+        self.span_id.replace(span_id.to_owned());
+
+        self
+    }
+
     pub fn build(mut self) -> LogContext {
-        LogContext::new(self.request_id.take().unwrap_or_else(|| "".to_owned()))
+        LogContext::new(
+            self.request_id.take().unwrap_or_else(|| "".to_owned()),
+            self.trace_id.take(),
+            self.span_id.take(),
+        )
+    }
+}
+
+/// Set up the logger, it must be called before the first `get_logger`, otherwise the default
+/// json logger at info level is already in use. The `log` crate records, such as the sqlx ones,
+/// are routed to the same logger.
+pub fn init(format: LogFormat, level: Level) -> anyhow::Result<()> {
+    let logger = new_logger(format, level, io::stderr());
+
+    LOGGER
+        .set(logger.clone())
+        .map_err(|_| anyhow::anyhow!("logger is already initialized"))?;
+
+    // the logger lives as long as the process, never reset it
+    slog_scope::set_global_logger(logger).cancel_reset();
+    slog_stdlog::init_with_level(std_level(level))?;
+
+    Ok(())
+}
+
+/// The `log` crate has no critical level, it's folded into error.
+fn std_level(level: Level) -> ::log::Level {
+    match level {
+        Level::Critical | Level::Error => ::log::Level::Error,
+        Level::Warning => ::log::Level::Warn,
+        Level::Info => ::log::Level::Info,
+        Level::Debug => ::log::Level::Debug,
+        Level::Trace => ::log::Level::Trace,
     }
 }
 
 pub fn get_logger() -> &'static Logger {
-    static LOGGER: OnceCell<Logger> = OnceCell::new();
-
-    LOGGER.get_or_init(|| {
-        let json = slog_json::Json::new(io::stderr())
-            .add_default_keys()
-            .add_key_value(slog::o!(
-                "file"=> FnValue(move |rinfo: &Record| format!("{}:{}", rinfo.file(), rinfo.line()))
-            ))
+    LOGGER.get_or_init(|| new_logger(LogFormat::Json, DEFAULT_LEVEL, io::stderr()))
+}
+
+fn new_logger<W>(format: LogFormat, level: Level, writer: W) -> Logger
+    where
+        W: io::Write + Send + 'static,
+{
+    match format {
+        LogFormat::Json => {
+            let json = slog_json::Json::new(writer)
+                .add_default_keys()
+                .add_key_value(slog::o!(
+                    "file"=> FnValue(move |rinfo: &Record| format!("{}:{}", rinfo.file(), rinfo.line()))
+                ))
+                .build();
+
+            Logger::root(
+                Mutex::new(json).filter_level(level).map(slog::Fuse),
+                slog::o!(),
+            )
+        }
+
+        LogFormat::Text => {
+            let decorator = slog_term::PlainSyncDecorator::new(writer);
+            let text = slog_term::FullFormat::new(decorator).build();
+
+            Logger::root(text.filter_level(level).fuse(), slog::o!())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::testing::CaptureDrain;
+    use super::*;
+
+    #[derive(Debug, Default, Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn output(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_logger() {
+        let buffer = SharedBuffer::default();
+        let logger = new_logger(LogFormat::Json, Level::Info, buffer.clone());
+
+        let log_cx = LogContext::builder().request_id("request-id").build();
+        slog::info!(logger, "hello {}", "json"; log_cx);
+
+        let line: serde_json::Value = serde_json::from_str(buffer.output().trim()).unwrap();
+
+        assert_eq!(line["msg"], "hello json");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["requestId"], "request-id");
+        assert!(line["file"].as_str().unwrap().starts_with("src/log/mod.rs:"));
+    }
+
+    #[test]
+    fn test_text_logger() {
+        let buffer = SharedBuffer::default();
+        let logger = new_logger(LogFormat::Text, Level::Info, buffer.clone());
+
+        let log_cx = LogContext::builder().request_id("request-id").build();
+        slog::info!(logger, "hello {}", "text"; log_cx);
+
+        let output = buffer.output();
+
+        assert!(output.contains("INFO hello text"), "{}", output);
+        assert!(output.contains("requestId: request-id"), "{}", output);
+        assert!(serde_json::from_str::<serde_json::Value>(output.trim()).is_err());
+    }
+
+    #[test]
+    fn test_filter_level() {
+        for (level, emitted) in &[(Level::Info, false), (Level::Debug, true)] {
+            let buffer = SharedBuffer::default();
+            let logger = new_logger(LogFormat::Json, *level, buffer.clone());
+
+            slog::debug!(logger, "debug message");
+            slog::info!(logger, "info message");
+
+            let output = buffer.output();
+
+            assert_eq!(output.contains("debug message"), *emitted, "{:?}", level);
+            assert!(output.contains("info message"), "{:?}", level);
+        }
+    }
+
+    #[test]
+    fn test_std_level() {
+        assert_eq!(std_level(Level::Critical), ::log::Level::Error);
+        assert_eq!(std_level(Level::Warning), ::log::Level::Warn);
+        assert_eq!(std_level(Level::Debug), ::log::Level::Debug);
+    }
+
+    #[test]
+    fn test_parse_log_options() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert!("yaml".parse::<LogFormat>().is_err());
+
+        assert_eq!(parse_level("debug").unwrap(), Level::Debug);
+        assert_eq!(parse_level("warning").unwrap(), Level::Warning);
+        assert!(parse_level("loud").is_err());
+    }
+
+    #[test]
+    fn test_log_context_kv() {
+        let drain = CaptureDrain::default();
+        let logger = Logger::root(drain.clone(), slog::o!());
+
+        let log_cx = LogContext::builder()
+            .request_id("request-id")
+            .trace_id("4bf92f3577b34da6a3ce929d0e0e4736")
+            .span_id("00f067aa0ba902b7")
             .build();
 
-        Logger::root(Mutex::new(json).map(slog::Fuse), slog::o!())
-    })
+        slog::info!(logger, "test"; log_cx);
+
+        let records = drain.records();
+        let kv = &records[0].kv;
+
+        assert_eq!(kv["requestId"], "request-id");
+        assert_eq!(kv["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(kv["spanId"], "00f067aa0ba902b7");
+    }
+
+    #[test]
+    fn test_log_context_without_trace() {
+        let drain = CaptureDrain::default();
+        let logger = Logger::root(drain.clone(), slog::o!());
+
+        let log_cx = LogContext::builder().request_id("request-id").build();
+
+        slog::info!(logger, "test"; log_cx);
+
+        let records = drain.records();
+        let kv = &records[0].kv;
+
+        assert_eq!(kv["requestId"], "request-id");
+        assert!(!kv.contains_key("traceId"));
+        assert!(!kv.contains_key("spanId"));
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::collections::HashMap;
+    use std::fmt::Arguments;
+    use std::sync::{Arc, Mutex};
+
+    use slog::{Drain, Key, KV, Never, OwnedKVList, Record, Serializer};
+
+    #[derive(Debug, Clone)]
+    pub struct CapturedRecord {
+        pub msg: String,
+        pub kv: HashMap<String, String>,
+    }
+
+    /// A drain collects every record in memory, so tests can assert on log output.
+    #[derive(Debug, Default, Clone)]
+    pub struct CaptureDrain {
+        records: Arc<Mutex<Vec<CapturedRecord>>>,
+    }
+
+    impl CaptureDrain {
+        pub fn records(&self) -> Vec<CapturedRecord> {
+            self.records.lock().unwrap().clone()
+        }
+    }
+
+    struct MapSerializer(HashMap<String, String>);
+
+    impl Serializer for MapSerializer {
+        fn emit_arguments(&mut self, key: Key, val: &Arguments) -> slog::Result {
+            self.0.insert(key.to_string(), val.to_string());
+
+            Ok(())
+        }
+    }
+
+    impl Drain for CaptureDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+            let mut serializer = MapSerializer(HashMap::new());
+
+            let _ = values.serialize(record, &mut serializer);
+            let _ = record.kv().serialize(record, &mut serializer);
+
+            self.records.lock().unwrap().push(CapturedRecord {
+                msg: record.msg().to_string(),
+                kv: serializer.0,
+            });
+
+            Ok(())
+        }
+    }
 }
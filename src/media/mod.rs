@@ -0,0 +1,303 @@
+use std::io::{BufRead, Cursor, Seek};
+
+use bytes::Bytes;
+use image::io::Reader;
+use img_parts::{DynImage, ImageEXIF};
+
+pub use self::watermark::{Watermark, WatermarkPosition};
+
+mod tiff;
+pub mod watermark;
+
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+const WEBP_QUALITY: f32 = 75.0;
+
+/// The formats the JPEG and PNG images can be transcoded to on download.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TranscodeFormat {
+    Webp,
+}
+
+impl TranscodeFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TranscodeFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TranscodeFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Detect the image content type from the leading magic bytes.
+pub fn detect_content_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if data.len() >= 12 && &data[4..12] == b"ftypavif" {
+        Some("image/avif")
+    } else {
+        None
+    }
+}
+
+/// The image content type of the file extension, the extension is case insensitive.
+pub fn extension_content_type(extension: &str) -> Option<&'static str> {
+    match extension.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        "avif" => Some("image/avif"),
+        _ => None,
+    }
+}
+
+/// Check the content type is a `type/subtype` MIME type without parameters.
+pub fn is_valid_content_type(content_type: &str) -> bool {
+    let is_token = |part: &str| {
+        !part.is_empty()
+            && part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+
+    let mut parts = content_type.splitn(2, '/');
+
+    matches!(
+        (parts.next(), parts.next()),
+        (Some(ty), Some(subtype)) if is_token(ty) && is_token(subtype)
+    )
+}
+
+/// Read the width and height from the image header without decoding the pixels.
+///
+/// Returns `None` for the non-raster or unsupported formats.
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    image_dimensions_of(Cursor::new(data))
+}
+
+/// Read the dimensions like `image_dimensions`, only the header is read from the reader.
+pub fn image_dimensions_of<R: BufRead + Seek>(reader: R) -> Option<(u32, u32)> {
+    Reader::new(reader)
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Remove the EXIF metadata from a JPEG, PNG, WebP or TIFF image.
+///
+/// Returns `Ok(None)` when the data isn't in a supported format, so the caller can keep the
+/// original bytes untouched.
+pub fn strip_exif(data: &[u8]) -> Result<Option<Bytes>, img_parts::Error> {
+    if tiff::is_tiff(data) {
+        return Ok(tiff::strip_exif(data).map(Bytes::from));
+    }
+
+    let mut image = match DynImage::from_bytes(img_parts::Bytes::copy_from_slice(data))? {
+        None => return Ok(None),
+        Some(image) => image,
+    };
+
+    if image.exif().is_none() {
+        return Ok(None);
+    }
+
+    image.set_exif(None);
+
+    Ok(Some(Bytes::from(image.encoder().bytes().to_vec())))
+}
+
+pub fn is_transcodable(content_type: &str) -> bool {
+    content_type == "image/jpeg" || content_type == "image/png"
+}
+
+/// The raster formats which can be decoded and encoded again to overlay the watermark.
+pub fn is_watermarkable(content_type: &str) -> bool {
+    content_type == "image/jpeg" || content_type == "image/png"
+}
+
+/// Choose the transcode format from the `accept` header, the format with the highest q value
+/// wins.
+pub fn negotiate_transcode(accept: &str, content_type: &str) -> Option<TranscodeFormat> {
+    if !is_transcodable(content_type) {
+        return None;
+    }
+
+    let mut best: Option<(TranscodeFormat, f32)> = None;
+
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let mime = params.next().unwrap_or_default().trim();
+
+        let format = if mime.eq_ignore_ascii_case("image/webp") {
+            TranscodeFormat::Webp
+        } else {
+            continue;
+        };
+
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .next()
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+
+        let q = match q {
+            Some(q) if q > 0.0 => q,
+            _ => continue,
+        };
+
+        let better = match best {
+            None => true,
+            Some((_, best_q)) => q > best_q,
+        };
+
+        if better {
+            best = Some((format, q));
+        }
+    }
+
+    best.map(|(format, _)| format)
+}
+
+/// Decode the image and encode it again in the format, it is cpu heavy, so call it in a
+/// blocking thread.
+pub fn transcode(data: &[u8], format: TranscodeFormat) -> anyhow::Result<Bytes> {
+    let image = image::load_from_memory(data)?.to_rgba8();
+    let (width, height) = image.dimensions();
+
+    let data = match format {
+        TranscodeFormat::Webp => webp::Encoder::from_rgba(image.as_raw(), width, height)
+            .encode(WEBP_QUALITY)
+            .to_vec(),
+    };
+
+    Ok(Bytes::from(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXIF: &[u8] = b"Exif\0\0II*\0\x08\0\0\0\0\0";
+
+    fn jpeg_with_exif() -> Vec<u8> {
+        let mut data = vec![0xff, 0xd8, 0xff, 0xe1];
+        data.extend_from_slice(&((EXIF.len() + 2) as u16).to_be_bytes());
+        data.extend_from_slice(EXIF);
+        data.extend_from_slice(&[0xff, 0xd9]);
+
+        data
+    }
+
+    #[test]
+    fn test_strip_jpeg_exif() {
+        let stripped = strip_exif(&jpeg_with_exif()).unwrap().unwrap();
+
+        assert!(!stripped.windows(4).any(|window| window == b"Exif"));
+        assert!(stripped.starts_with(&[0xff, 0xd8]));
+    }
+
+    #[test]
+    fn test_detect_content_type() {
+        assert_eq!(detect_content_type(&jpeg_with_exif()), Some("image/jpeg"));
+        assert_eq!(
+            detect_content_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(detect_content_type(b"GIF89a\x01\0"), Some("image/gif"));
+        assert_eq!(
+            detect_content_type(b"RIFF\x24\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(detect_content_type(b"not an image"), None);
+    }
+
+    #[test]
+    fn test_extension_content_type() {
+        assert_eq!(extension_content_type("png"), Some("image/png"));
+        assert_eq!(extension_content_type("JPG"), Some("image/jpeg"));
+        assert_eq!(extension_content_type("jpeg"), Some("image/jpeg"));
+        assert_eq!(extension_content_type("txt"), None);
+        assert_eq!(extension_content_type(""), None);
+    }
+
+    #[test]
+    fn test_is_valid_content_type() {
+        for valid in &["image/jpeg", DEFAULT_CONTENT_TYPE, "image/svg+xml", "image/vnd.ms-photo"] {
+            assert!(is_valid_content_type(valid), "{}", valid);
+        }
+
+        for invalid in &["", "image", "image/", "/jpeg", "image/jpeg; q=1", "image/a/b"] {
+            assert!(!is_valid_content_type(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_image_dimensions() {
+        assert_eq!(image_dimensions(&testing::png(30, 20)), Some((30, 20)));
+        assert_eq!(image_dimensions(b"not an image"), None);
+    }
+
+    #[test]
+    fn test_strip_unknown_format() {
+        assert!(strip_exif(b"not an image").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_negotiate_transcode() {
+        for (accept, content_type, format) in &[
+            ("image/avif,image/webp,*/*", "image/png", Some(TranscodeFormat::Webp)),
+            ("image/webp,*/*", "image/jpeg", Some(TranscodeFormat::Webp)),
+            ("image/avif,image/webp;q=0.5", "image/png", Some(TranscodeFormat::Webp)),
+            ("image/avif,image/webp;q=0", "image/png", None),
+            ("image/avif,*/*", "image/png", None),
+            ("image/png,*/*", "image/png", None),
+            ("image/avif,image/webp", "image/gif", None),
+        ] {
+            assert_eq!(
+                negotiate_transcode(accept, content_type),
+                *format,
+                "{} {}",
+                accept,
+                content_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_transcode() {
+        let png = testing::png(16, 16);
+
+        let webp = transcode(&png, TranscodeFormat::Webp).unwrap();
+        assert_eq!(detect_content_type(&webp), Some("image/webp"));
+
+        assert!(transcode(b"not an image", TranscodeFormat::Webp).is_err());
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+
+    /// Encode a random PNG image, so it is never deduped with the others.
+    pub fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |_, _| image::Rgb(rand::random()));
+
+        let mut buf = vec![];
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut buf, ImageOutputFormat::Png)
+            .unwrap();
+
+        buf
+    }
+}
@@ -0,0 +1,239 @@
+//! The TIFF file is an EXIF container itself, which img-parts doesn't support, the EXIF and GPS
+//! tags live in the sub IFDs pointed by the tags of the image IFDs.
+
+const EXIF_IFD_TAG: u16 = 0x8769;
+const GPS_IFD_TAG: u16 = 0x8825;
+const IFD_ENTRY_LEN: usize = 12;
+/// Stop at the looped IFD chain of the malformed files.
+const MAX_IFDS: usize = 64;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    fn read_u16(self, data: &[u8], offset: usize) -> Option<u16> {
+        let bytes = [*data.get(offset)?, *data.get(offset + 1)?];
+
+        Some(match self {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn read_u32(self, data: &[u8], offset: usize) -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(data.get(offset..offset + 4)?);
+
+        Some(match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        })
+    }
+
+    fn write_u16(self, data: &mut [u8], offset: usize, value: u16) {
+        let bytes = match self {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big => value.to_be_bytes(),
+        };
+
+        data[offset..offset + 2].copy_from_slice(&bytes);
+    }
+}
+
+pub fn is_tiff(data: &[u8]) -> bool {
+    data.starts_with(b"II*\0") || data.starts_with(b"MM\0*")
+}
+
+/// Remove the EXIF and GPS sub IFDs of the TIFF image. Their pointers are dropped from the image
+/// IFDs and their entries and values are zeroed, so the rest of the file keeps its layout.
+///
+/// Returns `None` when it isn't a TIFF image, it has no such metadata or it's malformed.
+pub fn strip_exif(data: &[u8]) -> Option<Vec<u8>> {
+    let endian = if data.starts_with(b"II*\0") {
+        Endian::Little
+    } else if data.starts_with(b"MM\0*") {
+        Endian::Big
+    } else {
+        return None;
+    };
+
+    let mut data = data.to_vec();
+    let mut stripped = false;
+    let mut ifd = endian.read_u32(&data, 4)? as usize;
+
+    for _ in 0..MAX_IFDS {
+        if ifd == 0 {
+            break;
+        }
+
+        let count = endian.read_u16(&data, ifd)? as usize;
+        let entries = ifd + 2;
+        let entries_end = entries + count * IFD_ENTRY_LEN;
+        let next_ifd = endian.read_u32(&data, entries_end)?;
+
+        let mut kept = 0;
+        for i in 0..count {
+            let entry = entries + i * IFD_ENTRY_LEN;
+            let tag = endian.read_u16(&data, entry)?;
+
+            if tag == EXIF_IFD_TAG || tag == GPS_IFD_TAG {
+                // the pointer is dropped even if the sub IFD is malformed, so the metadata is
+                // never kept by the failed stripping
+                if let Some(sub_ifd) = endian.read_u32(&data, entry + 8) {
+                    clear_ifd(&mut data, endian, sub_ifd as usize);
+                }
+                stripped = true;
+
+                continue;
+            }
+
+            // the entries are sorted by tag, shifting the kept ones keeps the order
+            data.copy_within(entry..entry + IFD_ENTRY_LEN, entries + kept * IFD_ENTRY_LEN);
+            kept += 1;
+        }
+
+        // the next IFD offset follows the entries, the room of the removed ones is zeroed
+        let kept_end = entries + kept * IFD_ENTRY_LEN;
+        data.copy_within(entries_end..entries_end + 4, kept_end);
+        data[kept_end + 4..entries_end + 4].iter_mut().for_each(|byte| *byte = 0);
+        endian.write_u16(&mut data, ifd, kept as u16);
+
+        ifd = next_ifd as usize;
+    }
+
+    if stripped {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+/// Zero the entries of the IFD and the values stored out of them, the malformed parts are left
+/// as is.
+fn clear_ifd(data: &mut [u8], endian: Endian, ifd: usize) -> Option<()> {
+    let count = endian.read_u16(data, ifd)? as usize;
+    let entries = ifd + 2;
+    let ifd_end = entries + count * IFD_ENTRY_LEN + 4;
+
+    if ifd_end > data.len() {
+        return None;
+    }
+
+    for i in 0..count {
+        let entry = entries + i * IFD_ENTRY_LEN;
+        let field_type = endian.read_u16(data, entry + 2)?;
+        let value_count = endian.read_u32(data, entry + 4)? as usize;
+        let value_len = type_len(field_type).checked_mul(value_count)?;
+
+        // the values up to 4 bytes are stored in the entry
+        if value_len > 4 {
+            let value = endian.read_u32(data, entry + 8)? as usize;
+            let value_end = value.checked_add(value_len)?;
+
+            data.get_mut(value..value_end)?
+                .iter_mut()
+                .for_each(|byte| *byte = 0);
+        }
+    }
+
+    data[ifd..ifd_end].iter_mut().for_each(|byte| *byte = 0);
+
+    Some(())
+}
+
+/// The byte length of the TIFF field type, the unknown types have no value to clear.
+fn type_len(field_type: u16) -> usize {
+    match field_type {
+        // BYTE, ASCII, SBYTE, UNDEFINED
+        1 | 2 | 6 | 7 => 1,
+        // SHORT, SSHORT
+        3 | 8 => 2,
+        // LONG, SLONG, FLOAT, IFD
+        4 | 9 | 11 | 13 => 4,
+        // RATIONAL, SRATIONAL, DOUBLE
+        5 | 10 | 12 => 8,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GPS_LATITUDE: &[u8] = b"GPSLAT-SECRET";
+
+    /// A little endian TIFF with an image IFD holding the width, the GPS pointer and the height,
+    /// and a GPS IFD holding an ASCII value stored out of the entry.
+    fn tiff_with_gps() -> Vec<u8> {
+        let mut data = b"II*\0".to_vec();
+        data.extend_from_slice(&8u32.to_le_bytes());
+
+        let entry = |data: &mut Vec<u8>, tag: u16, field_type: u16, count: u32, value: u32| {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&field_type.to_le_bytes());
+            data.extend_from_slice(&count.to_le_bytes());
+            data.extend_from_slice(&value.to_le_bytes());
+        };
+
+        // image IFD at 8, 3 entries, ends at 8 + 2 + 36 + 4 = 50
+        data.extend_from_slice(&3u16.to_le_bytes());
+        entry(&mut data, 0x0100, 4, 1, 30);
+        entry(&mut data, 0x0101, 4, 1, 20);
+        entry(&mut data, GPS_IFD_TAG, 4, 1, 50);
+        data.extend_from_slice(&0u32.to_le_bytes());
+
+        // GPS IFD at 50, 1 entry, ends at 50 + 2 + 12 + 4 = 68
+        data.extend_from_slice(&1u16.to_le_bytes());
+        entry(&mut data, 0x0002, 2, GPS_LATITUDE.len() as u32, 68);
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(GPS_LATITUDE);
+
+        data
+    }
+
+    #[test]
+    fn test_strip_gps() {
+        let data = tiff_with_gps();
+        let stripped = strip_exif(&data).unwrap();
+
+        assert_eq!(stripped.len(), data.len());
+        assert!(!stripped.windows(GPS_LATITUDE.len()).any(|window| window == GPS_LATITUDE));
+
+        let endian = Endian::Little;
+        assert_eq!(endian.read_u16(&stripped, 8), Some(2));
+        assert_eq!(endian.read_u16(&stripped, 10), Some(0x0100));
+        assert_eq!(endian.read_u16(&stripped, 22), Some(0x0101));
+        // the next IFD offset follows the kept entries
+        assert_eq!(endian.read_u32(&stripped, 34), Some(0));
+
+        // nothing is left to strip
+        assert!(strip_exif(&stripped).is_none());
+    }
+
+    #[test]
+    fn test_strip_out_of_range_gps() {
+        let mut data = tiff_with_gps();
+        // point the GPS IFD out of the file
+        data[42..46].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+
+        let stripped = strip_exif(&data).unwrap();
+
+        // the GPS pointer is dropped though its IFD can't be cleared
+        let endian = Endian::Little;
+        assert_eq!(endian.read_u16(&stripped, 8), Some(2));
+        assert_eq!(endian.read_u16(&stripped, 10), Some(0x0100));
+        assert_eq!(endian.read_u16(&stripped, 22), Some(0x0101));
+    }
+
+    #[test]
+    fn test_not_tiff_or_malformed() {
+        assert!(strip_exif(b"not an image").is_none());
+
+        let mut data = tiff_with_gps();
+        data.truncate(40);
+        assert!(strip_exif(&data).is_none());
+    }
+}
@@ -0,0 +1,194 @@
+use std::str::FromStr;
+
+use bytes::Bytes;
+use image::{imageops, DynamicImage, RgbaImage};
+use sha2::{Digest, Sha256};
+
+/// Where the watermark is placed on the image.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl WatermarkPosition {
+    pub const TOP_LEFT: &'static str = "top-left";
+    pub const TOP_RIGHT: &'static str = "top-right";
+    pub const BOTTOM_LEFT: &'static str = "bottom-left";
+    pub const BOTTOM_RIGHT: &'static str = "bottom-right";
+    pub const CENTER: &'static str = "center";
+
+    fn as_str(self) -> &'static str {
+        match self {
+            WatermarkPosition::TopLeft => Self::TOP_LEFT,
+            WatermarkPosition::TopRight => Self::TOP_RIGHT,
+            WatermarkPosition::BottomLeft => Self::BOTTOM_LEFT,
+            WatermarkPosition::BottomRight => Self::BOTTOM_RIGHT,
+            WatermarkPosition::Center => Self::CENTER,
+        }
+    }
+
+    /// The top left corner of the watermark, the watermark larger than the image is clipped.
+    fn offset(
+        self,
+        (width, height): (u32, u32),
+        (mark_width, mark_height): (u32, u32),
+    ) -> (u32, u32) {
+        let right = width.saturating_sub(mark_width);
+        let bottom = height.saturating_sub(mark_height);
+
+        match self {
+            WatermarkPosition::TopLeft => (0, 0),
+            WatermarkPosition::TopRight => (right, 0),
+            WatermarkPosition::BottomLeft => (0, bottom),
+            WatermarkPosition::BottomRight => (right, bottom),
+            WatermarkPosition::Center => (right / 2, bottom / 2),
+        }
+    }
+}
+
+impl FromStr for WatermarkPosition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            Self::TOP_LEFT => Ok(WatermarkPosition::TopLeft),
+            Self::TOP_RIGHT => Ok(WatermarkPosition::TopRight),
+            Self::BOTTOM_LEFT => Ok(WatermarkPosition::BottomLeft),
+            Self::BOTTOM_RIGHT => Ok(WatermarkPosition::BottomRight),
+            Self::CENTER => Ok(WatermarkPosition::Center),
+            s => Err(anyhow::anyhow!(
+                "watermark position {} is invalid, must be one of {}, {}, {}, {} or {}",
+                s,
+                Self::TOP_LEFT,
+                Self::TOP_RIGHT,
+                Self::BOTTOM_LEFT,
+                Self::BOTTOM_RIGHT,
+                Self::CENTER
+            )),
+        }
+    }
+}
+
+/// The image overlaid on the served images.
+#[derive(Debug)]
+pub struct Watermark {
+    image: RgbaImage,
+    position: WatermarkPosition,
+    variant: String,
+}
+
+impl Watermark {
+    /// Decode the watermark image, its alpha is scaled by the opacity, which is in `[0, 1]`.
+    pub fn new(data: &[u8], position: WatermarkPosition, opacity: f32) -> anyhow::Result<Self> {
+        if !(0.0..=1.0).contains(&opacity) {
+            return Err(anyhow::anyhow!(
+                "watermark opacity {} must be in [0, 1]",
+                opacity
+            ));
+        }
+
+        let mut image = image::load_from_memory(data)?.to_rgba8();
+
+        for pixel in image.pixels_mut() {
+            pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+        }
+
+        // the variant changes with the watermark, so the images marked by the old one are not
+        // served from the cache
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hasher.update(position.as_str());
+        hasher.update(opacity.to_be_bytes());
+
+        let variant = format!("wm-{}", &hex::encode(hasher.finalize())[..16]);
+
+        Ok(Self {
+            image,
+            position,
+            variant,
+        })
+    }
+
+    /// Tag the watermarked variants, it's used in the cached variant id and the etag.
+    pub fn variant(&self) -> &str {
+        &self.variant
+    }
+
+    /// Overlay the watermark and encode the image in its original format again, it is cpu heavy,
+    /// so call it in a blocking thread.
+    pub fn apply(&self, data: &[u8]) -> anyhow::Result<Bytes> {
+        let format = image::guess_format(data)?;
+        let original = image::load_from_memory_with_format(data, format)?;
+        let has_alpha = original.color().has_alpha();
+
+        // blend on rgba, the images without alpha would be overwritten by the watermark pixels
+        let mut canvas = original.to_rgba8();
+        let (x, y) = self
+            .position
+            .offset(canvas.dimensions(), self.image.dimensions());
+
+        imageops::overlay(&mut canvas, &self.image, x, y);
+
+        let image = if has_alpha {
+            DynamicImage::ImageRgba8(canvas)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+        };
+
+        let mut buf = vec![];
+        image.write_to(&mut buf, format)?;
+
+        Ok(Bytes::from(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::media::{self, testing};
+
+    use super::*;
+
+    #[test]
+    fn test_watermark_position_from_str() {
+        assert_eq!(
+            WatermarkPosition::from_str("bottom-right").unwrap(),
+            WatermarkPosition::BottomRight
+        );
+        assert_eq!(
+            WatermarkPosition::from_str("center").unwrap(),
+            WatermarkPosition::Center
+        );
+        assert!(WatermarkPosition::from_str("middle").is_err());
+    }
+
+    #[test]
+    fn test_watermark_offset() {
+        assert_eq!(
+            WatermarkPosition::BottomRight.offset((40, 30), (10, 5)),
+            (30, 25)
+        );
+        assert_eq!(WatermarkPosition::Center.offset((40, 30), (10, 10)), (15, 10));
+        // clipped
+        assert_eq!(WatermarkPosition::BottomRight.offset((5, 5), (10, 10)), (0, 0));
+    }
+
+    #[test]
+    fn test_apply_watermark() {
+        let watermark =
+            Watermark::new(&testing::png(8, 8), WatermarkPosition::BottomRight, 0.5).unwrap();
+        let original = testing::png(40, 30);
+
+        let marked = watermark.apply(&original).unwrap();
+
+        assert_ne!(marked, original);
+        assert_eq!(media::image_dimensions(&marked), Some((40, 30)));
+        assert_eq!(media::detect_content_type(&marked), Some("image/png"));
+
+        assert!(watermark.apply(b"not an image").is_err());
+        assert!(Watermark::new(&testing::png(8, 8), WatermarkPosition::Center, 1.5).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+use slog::{info, warn};
+
+use crate::db::Database;
+use crate::log::{self, LogContext};
+use crate::store::StoreBackend;
+
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+/// The younger resources may be in the middle of an upload, the row is inserted before the
+/// object is put, and the presigned uploads put the object before the row is inserted.
+pub const DEFAULT_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+/// Only the first orphans are listed in the report, all of them are counted.
+const MAX_REPORTED_ORPHANS: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Only reconcile the bucket, all buckets which have resources in the db by default.
+    pub bucket: Option<String>,
+    /// Delete the orphans, otherwise only report them.
+    pub delete: bool,
+    pub batch_size: usize,
+    pub min_age: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            bucket: None,
+            delete: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+            min_age: DEFAULT_MIN_AGE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Orphan {
+    pub bucket: String,
+    pub id: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub checked_resources: u64,
+    pub checked_objects: u64,
+    /// The db resources without the stored object.
+    pub missing_object_count: u64,
+    pub missing_objects: Vec<Orphan>,
+    /// The stored objects without the db resource.
+    pub orphan_object_count: u64,
+    pub orphan_objects: Vec<Orphan>,
+    /// The buckets which the backend can't list, their orphan objects are not checked.
+    pub unlisted_buckets: Vec<String>,
+    pub deleted: bool,
+}
+
+impl Report {
+    fn add_missing_object(&mut self, bucket: &str, resource_id: &str) {
+        self.missing_object_count += 1;
+
+        if self.missing_objects.len() < MAX_REPORTED_ORPHANS {
+            self.missing_objects.push(Orphan {
+                bucket: bucket.to_owned(),
+                id: resource_id.to_owned(),
+            });
+        }
+    }
+
+    fn add_orphan_object(&mut self, bucket: &str, resource_id: &str) {
+        self.orphan_object_count += 1;
+
+        if self.orphan_objects.len() < MAX_REPORTED_ORPHANS {
+            self.orphan_objects.push(Orphan {
+                bucket: bucket.to_owned(),
+                id: resource_id.to_owned(),
+            });
+        }
+    }
+}
+
+/// Find the db resources without the stored object and the stored objects without the db
+/// resource, in batches. Only the buckets which have resources in the db are listed, so the
+/// objects of a bucket whose resources are all deleted are not found.
+pub async fn reconcile<S>(
+    db: &Database,
+    backend: &S,
+    options: &Options,
+    log_cx: &LogContext,
+) -> anyhow::Result<Report>
+    where
+        S: StoreBackend + Sync,
+        S::Error: Send + Sync + 'static,
+{
+    let cutoff = SystemTime::now()
+        .checked_sub(options.min_age)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let batch_size = options.batch_size.max(1);
+
+    let mut report = Report {
+        deleted: options.delete,
+        ..Report::default()
+    };
+
+    info!(
+        log::get_logger(),
+        "reconcile is started";
+        log_cx,
+        "bucket" => &options.bucket,
+        "delete" => options.delete,
+        "min_age" => format!("{:?}", options.min_age)
+    );
+
+    let mut after: Option<String> = None;
+
+    loop {
+        let resources = db
+            .list_resources_after(
+                options.bucket.as_deref(),
+                after.as_deref(),
+                batch_size,
+                log_cx,
+            )
+            .await?;
+
+        let last_id = match resources.last() {
+            None => break,
+            Some(resource) => resource.get_id().to_owned(),
+        };
+
+        let mut missing_ids = vec![];
+
+        for resource in resources
+            .iter()
+            .filter(|resource| resource.get_create_time() <= cutoff)
+        {
+            report.checked_resources += 1;
+
+            if !backend
+                .exists(resource.get_bucket(), resource.get_id(), log_cx)
+                .await
+                .map_err(anyhow::Error::new)?
+            {
+                warn!(
+                    log::get_logger(),
+                    "resource object is missing";
+                    log_cx,
+                    "bucket" => resource.get_bucket(),
+                    "resource" => resource.get_id()
+                );
+
+                report.add_missing_object(resource.get_bucket(), resource.get_id());
+                missing_ids.push(resource.get_id().to_owned());
+            }
+        }
+
+        if options.delete && !missing_ids.is_empty() {
+            db.delete_resources(&missing_ids, log_cx).await?;
+        }
+
+        info!(
+            log::get_logger(),
+            "reconcile resources progress";
+            log_cx,
+            "checked" => report.checked_resources,
+            "missing" => report.missing_object_count,
+            "last_id" => &last_id
+        );
+
+        after = Some(last_id);
+    }
+
+    let buckets = match &options.bucket {
+        None => db.list_buckets(log_cx).await?,
+        Some(bucket) => vec![bucket.clone()],
+    };
+
+    for bucket in &buckets {
+        reconcile_bucket_objects(db, backend, bucket, options, cutoff, &mut report, log_cx)
+            .await?;
+    }
+
+    info!(
+        log::get_logger(),
+        "reconcile is finished";
+        log_cx,
+        "checked_resources" => report.checked_resources,
+        "checked_objects" => report.checked_objects,
+        "missing_objects" => report.missing_object_count,
+        "orphan_objects" => report.orphan_object_count
+    );
+
+    Ok(report)
+}
+
+async fn reconcile_bucket_objects<S>(
+    db: &Database,
+    backend: &S,
+    bucket: &str,
+    options: &Options,
+    cutoff: SystemTime,
+    report: &mut Report,
+    log_cx: &LogContext,
+) -> anyhow::Result<()>
+    where
+        S: StoreBackend + Sync,
+        S::Error: Send + Sync + 'static,
+{
+    let mut after: Option<String> = None;
+
+    loop {
+        let objects = match backend
+            .list(bucket, after.as_deref(), options.batch_size.max(1), log_cx)
+            .await
+            .map_err(anyhow::Error::new)?
+        {
+            None => {
+                warn!(log::get_logger(), "store backend can't list bucket {}", bucket; log_cx);
+
+                report.unlisted_buckets.push(bucket.to_owned());
+
+                return Ok(());
+            }
+
+            Some(objects) => objects,
+        };
+
+        let last_id = match objects.last() {
+            None => return Ok(()),
+            Some(object) => object.id.clone(),
+        };
+
+        let object_ids = objects
+            .into_iter()
+            .filter(|object| object.last_modified.is_none_or(|time| time <= cutoff))
+            .map(|object| object.id)
+            .collect::<Vec<_>>();
+
+        let resource_ids = object_ids
+            .iter()
+            .map(|object_id| resource_id(object_id).to_owned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let recorded_ids = db
+            .get_resources_by_ids(&resource_ids, log_cx)
+            .await?
+            .into_iter()
+            .filter(|resource| resource.get_bucket() == bucket)
+            .map(|resource| resource.get_id().to_owned())
+            .collect::<HashSet<_>>();
+
+        report.checked_objects += object_ids.len() as u64;
+
+        let orphan_ids = object_ids
+            .into_iter()
+            .filter(|object_id| !recorded_ids.contains(resource_id(object_id)))
+            .collect::<Vec<_>>();
+
+        for orphan_id in &orphan_ids {
+            warn!(
+                log::get_logger(),
+                "object is not recorded";
+                log_cx,
+                "bucket" => bucket,
+                "resource" => orphan_id
+            );
+
+            report.add_orphan_object(bucket, orphan_id);
+        }
+
+        if options.delete && !orphan_ids.is_empty() {
+            let failed_ids = backend
+                .delete_many(bucket, &orphan_ids, log_cx)
+                .await
+                .map_err(anyhow::Error::new)?;
+
+            if !failed_ids.is_empty() {
+                warn!(
+                    log::get_logger(),
+                    "delete orphan objects failed";
+                    log_cx,
+                    "bucket" => bucket,
+                    "resources" => format!("{:?}", failed_ids)
+                );
+            }
+        }
+
+        info!(
+            log::get_logger(),
+            "reconcile objects progress";
+            log_cx,
+            "bucket" => bucket,
+            "checked" => report.checked_objects,
+            "orphan" => report.orphan_object_count,
+            "last_id" => &last_id
+        );
+
+        after = Some(last_id);
+    }
+}
+
+/// The transcoded variants `<id>.<extension>` belong to the original resource.
+fn resource_id(object_id: &str) -> &str {
+    object_id.split('.').next().unwrap_or(object_id)
+}
@@ -0,0 +1,575 @@
+use std::fmt::{self, Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::io::AsyncRead;
+use futures_util::StreamExt;
+use slog::{info, warn};
+use thiserror::Error;
+
+use crate::log::{self, LogContext};
+use crate::store::{
+    ErrorKind, PostConditions, PresignedPost, ResourceStream, StoreBackend, StoreError,
+    StoredObject,
+};
+
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Returned without calling the backend while the circuit is open.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CircuitOpenError;
+
+impl Display for CircuitOpenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("store backend circuit is open")
+    }
+}
+
+impl std::error::Error for CircuitOpenError {}
+
+#[derive(Debug, Error)]
+pub enum Error<E: std::error::Error + 'static> {
+    #[error("{0}")]
+    Open(#[source] CircuitOpenError),
+
+    #[error(transparent)]
+    Backend(E),
+}
+
+impl<E: StoreError + 'static> StoreError for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Open(_) => ErrorKind::Unavailable,
+            Error::Backend(err) => err.kind(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    opened_total: u64,
+    closed_total: u64,
+}
+
+/// Fast fail the store requests after too many consecutive failures, and probe the backend
+/// again after the cooldown.
+#[derive(Debug)]
+pub struct CircuitBreaker<S> {
+    backend: S,
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl<S> CircuitBreaker<S> {
+    pub fn new(backend: S, failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            backend,
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            inner: Mutex::new(Inner {
+                state: State::Closed { failures: 0 },
+                opened_total: 0,
+                closed_total: 0,
+            }),
+        }
+    }
+
+    fn acquire(&self, log_context: &LogContext) -> Result<(), CircuitOpenError> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        match inner.state {
+            State::Closed { .. } => Ok(()),
+
+            State::Open { until } if now >= until => {
+                inner.state = State::HalfOpen { since: now };
+
+                info!(log::get_logger(), "store circuit is half open"; log_context);
+
+                Ok(())
+            }
+
+            // the probe request may be dropped before finishing, allow another one
+            State::HalfOpen { since } if now.duration_since(since) >= self.cooldown => {
+                inner.state = State::HalfOpen { since: now };
+
+                Ok(())
+            }
+
+            _ => Err(CircuitOpenError),
+        }
+    }
+
+    fn record<T, E: StoreError>(&self, result: &Result<T, E>, log_context: &LogContext) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let failed = matches!(result, Err(err) if err.is_unavailable());
+
+        match (inner.state, failed) {
+            (State::Closed { .. }, false) => inner.state = State::Closed { failures: 0 },
+
+            (State::Closed { failures }, true) => {
+                let failures = failures + 1;
+
+                if failures >= self.failure_threshold {
+                    self.open(&mut inner, log_context);
+                } else {
+                    inner.state = State::Closed { failures };
+                }
+            }
+
+            (State::HalfOpen { .. }, false) => {
+                inner.state = State::Closed { failures: 0 };
+                inner.closed_total += 1;
+
+                info!(
+                    log::get_logger(),
+                    "store circuit is closed";
+                    log_context,
+                    "opened_total" => inner.opened_total,
+                    "closed_total" => inner.closed_total
+                );
+            }
+
+            (State::HalfOpen { .. }, true) => self.open(&mut inner, log_context),
+
+            (State::Open { .. }, _) => {}
+        }
+    }
+
+    fn open(&self, inner: &mut Inner, log_context: &LogContext) {
+        inner.state = State::Open {
+            until: Instant::now() + self.cooldown,
+        };
+        inner.opened_total += 1;
+
+        warn!(
+            log::get_logger(),
+            "store circuit is open";
+            log_context,
+            "cooldown" => format!("{:?}", self.cooldown),
+            "opened_total" => inner.opened_total,
+            "closed_total" => inner.closed_total
+        );
+    }
+}
+
+#[async_trait]
+impl<B> StoreBackend for CircuitBreaker<B>
+    where
+        B: StoreBackend + Send + Sync,
+        B::Error: Send + 'static,
+{
+    type Error = Error<B::Error>;
+
+    async fn put<R: AsyncRead + Send>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource: R,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self
+            .backend
+            .put(bucket, resource_id, resource, log_context)
+            .await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn get<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<Bytes, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self
+            .backend
+            .get(bucket, resource_id, start, end, log_context)
+            .await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self
+            .backend
+            .get_stream(bucket, resource_id, start, end, log_context)
+            .await;
+        self.record(&result, log_context);
+
+        result
+            .map(|stream| {
+                stream
+                    .map(|result| result.map_err(Error::Backend))
+                    .boxed()
+            })
+            .map_err(Error::Backend)
+    }
+
+    async fn delete(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self.backend.delete(bucket, resource_id, log_context).await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self
+            .backend
+            .delete_many(bucket, resource_ids, log_context)
+            .await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self.backend.create_bucket(bucket, log_context).await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn delete_bucket(
+        &self,
+        bucket: &str,
+        need_empty: bool,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self
+            .backend
+            .delete_bucket(bucket, need_empty, log_context)
+            .await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self
+            .backend
+            .copy(
+                src_bucket,
+                src_resource_id,
+                dst_bucket,
+                dst_resource_id,
+                log_context,
+            )
+            .await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn ping(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        self.backend.ping(log_context).await.map_err(Error::Backend)
+    }
+
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self
+            .backend
+            .presign_post(bucket, resource_id, conditions, log_context)
+            .await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self.backend.exists(bucket, resource_id, log_context).await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self.backend.size(bucket, resource_id, log_context).await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        self.acquire(log_context).map_err(Error::Open)?;
+
+        let result = self.backend.list(bucket, after, limit, log_context).await;
+        self.record(&result, log_context);
+
+        result.map_err(Error::Backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    use futures_util::stream;
+
+    use super::*;
+
+    #[derive(Debug, Error)]
+    #[error("backend is down")]
+    struct DownError;
+
+    impl StoreError for DownError {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Unavailable
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct FlakyBackend {
+        down: AtomicBool,
+        calls: AtomicU32,
+    }
+
+    impl FlakyBackend {
+        fn call(&self) -> Result<(), DownError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            if self.down.load(Ordering::SeqCst) {
+                Err(DownError)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[async_trait]
+    impl StoreBackend for FlakyBackend {
+        type Error = DownError;
+
+        async fn put<R: AsyncRead + Send>(
+            &self,
+            _bucket: &str,
+            _resource_id: &str,
+            _resource: R,
+            _log_context: &LogContext,
+        ) -> Result<(), Self::Error> {
+            self.call()
+        }
+
+        async fn get<S, E>(
+            &self,
+            _bucket: &str,
+            _resource_id: &str,
+            _start: S,
+            _end: E,
+            _log_context: &LogContext,
+        ) -> Result<Bytes, Self::Error>
+            where
+                S: Into<Option<u64>> + Send,
+                E: Into<Option<u64>> + Send,
+        {
+            self.call().map(|_| Bytes::from_static(b"test"))
+        }
+
+        async fn get_stream<S, E>(
+            &self,
+            _bucket: &str,
+            _resource_id: &str,
+            _start: S,
+            _end: E,
+            _log_context: &LogContext,
+        ) -> Result<ResourceStream<Self::Error>, Self::Error>
+            where
+                S: Into<Option<u64>> + Send,
+                E: Into<Option<u64>> + Send,
+        {
+            Ok(stream::empty().boxed())
+        }
+
+        async fn delete(
+            &self,
+            _bucket: &str,
+            _resource_id: &str,
+            _log_context: &LogContext,
+        ) -> Result<(), Self::Error> {
+            self.call()
+        }
+
+        async fn delete_bucket(
+            &self,
+            _bucket: &str,
+            _need_empty: bool,
+            _log_context: &LogContext,
+        ) -> Result<(), Self::Error> {
+            self.call()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_transitions() {
+        let log_context = LogContext::builder().request_id("").build();
+        let cooldown = Duration::from_millis(50);
+
+        let circuit_breaker = CircuitBreaker::new(FlakyBackend::default(), 2, cooldown);
+        let backend = &circuit_breaker.backend;
+
+        // closed
+        circuit_breaker
+            .get("bucket", "id", None, None, &log_context)
+            .await
+            .unwrap();
+
+        // trip open after 2 consecutive failures
+        backend.down.store(true, Ordering::SeqCst);
+
+        for _ in 0..2 {
+            let err = circuit_breaker
+                .get("bucket", "id", None, None, &log_context)
+                .await
+                .unwrap_err();
+
+            assert!(matches!(err, Error::Backend(DownError)));
+        }
+
+        // open, fast fail without calling the backend
+        let calls = backend.calls.load(Ordering::SeqCst);
+
+        let err = circuit_breaker
+            .get("bucket", "id", None, None, &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Open(_)));
+        assert!(err.is_unavailable());
+        assert_eq!(backend.calls.load(Ordering::SeqCst), calls);
+
+        // half open, the failed probe opens the circuit again
+        tokio::time::delay_for(cooldown).await;
+
+        circuit_breaker
+            .get("bucket", "id", None, None, &log_context)
+            .await
+            .unwrap_err();
+
+        assert_eq!(backend.calls.load(Ordering::SeqCst), calls + 1);
+        assert!(matches!(
+            circuit_breaker
+                .get("bucket", "id", None, None, &log_context)
+                .await,
+            Err(Error::Open(_))
+        ));
+
+        // half open, the successful probe closes the circuit
+        tokio::time::delay_for(cooldown).await;
+        backend.down.store(false, Ordering::SeqCst);
+
+        circuit_breaker
+            .get("bucket", "id", None, None, &log_context)
+            .await
+            .unwrap();
+
+        // closed
+        circuit_breaker
+            .get("bucket", "id", None, None, &log_context)
+            .await
+            .unwrap();
+
+        assert_eq!(backend.calls.load(Ordering::SeqCst), calls + 3);
+
+        let inner = circuit_breaker.inner.lock().unwrap();
+
+        assert_eq!(inner.state, State::Closed { failures: 0 });
+        assert_eq!(inner.opened_total, 2);
+        assert_eq!(inner.closed_total, 1);
+    }
+}
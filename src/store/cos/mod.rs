@@ -1,23 +1,89 @@
+use std::collections::HashSet;
 use std::fmt::{self, Debug, Formatter};
 use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use async_trait::async_trait;
 use bytes::{BufMut, Bytes, BytesMut};
-use futures_util::{AsyncReadExt, StreamExt};
+use chrono::{DateTime, Utc};
+use futures_util::{AsyncReadExt, stream, StreamExt};
 use futures_util::io::AsyncRead;
 use hyper::StatusCode;
+use hyper_rustls::HttpsConnector;
 use rusoto_core::{ByteStream, HttpClient, Region, RusotoError};
 use rusoto_core::credential::StaticProvider;
 use rusoto_s3::{
-    CreateBucketRequest, Delete, DeleteBucketRequest, DeleteObjectRequest, DeleteObjectsRequest,
+    CopyObjectRequest, CreateBucketError, CreateBucketRequest, Delete, DeleteBucketRequest, DeleteObjectRequest, DeleteObjectsRequest,
     GetObjectRequest, HeadBucketRequest, HeadObjectRequest, ListObjectsRequest, ObjectIdentifier,
     PutObjectRequest, S3, S3Client, S3Error,
 };
-use slog::error;
+use slog::{error, warn};
 use thiserror::Error;
+use tokio::sync::Mutex;
 
 use crate::log::{self, LogContext};
-use crate::store::StoreBackend;
+use crate::store::{
+    ErrorKind, PostConditions, PresignedPost, ResourceStream, StoreBackend, StoreError,
+    StoredObject,
+};
+
+use self::presign::Credential;
+pub use self::retry::RetryConfig;
+use self::retry::retry;
+pub use self::timeout::TimeoutConfig;
+use self::timeout::{TimeoutConnector, TimeoutDispatcher};
+
+mod presign;
+mod retry;
+mod timeout;
+
+const MAX_DELETE_OBJECTS: usize = 1000;
+const MAX_LIST_OBJECTS: usize = 1000;
+
+/// The storage classes accepted by cos.
+pub const STORAGE_CLASSES: &[&str] = &[
+    "STANDARD",
+    "STANDARD_IA",
+    "INTELLIGENT_TIERING",
+    "ARCHIVE",
+    "DEEP_ARCHIVE",
+    "MAZ_STANDARD",
+    "MAZ_STANDARD_IA",
+    "MAZ_INTELLIGENT_TIERING",
+];
+
+/// Encrypt the objects at rest.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ServerSideEncryption {
+    Aes256,
+    Kms { key_id: String },
+}
+
+impl ServerSideEncryption {
+    pub const AES256: &'static str = "AES256";
+    pub const KMS: &'static str = "aws:kms";
+
+    pub fn new(mode: &str, kms_key_id: Option<&str>) -> anyhow::Result<Self> {
+        match mode {
+            Self::AES256 => Ok(ServerSideEncryption::Aes256),
+            Self::KMS => match kms_key_id {
+                Some(key_id) if !key_id.trim().is_empty() => Ok(ServerSideEncryption::Kms {
+                    key_id: key_id.to_owned(),
+                }),
+                _ => Err(anyhow::anyhow!("kms_key_id is required by {}", Self::KMS)),
+            },
+            mode => Err(anyhow::anyhow!("server_side_encryption {} is invalid", mode)),
+        }
+    }
+
+    fn mode(&self) -> &'static str {
+        match self {
+            ServerSideEncryption::Aes256 => Self::AES256,
+            ServerSideEncryption::Kms { .. } => Self::KMS,
+        }
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -27,17 +93,17 @@ pub enum Error {
     #[error("resource {0} not found")]
     ResourceNotFound(String),
 
-    #[error("bucket {0} is exist")]
-    BucketExist(String),
-
     #[error("resource {0} is exist")]
     ResourceExist(String),
 
     #[error("io error {0}")]
-    IoError(#[from] io::Error),
+    Io(#[from] io::Error),
 
     #[error("cos error: {0:?}")]
-    CosError(Box<dyn Debug + Send + Sync>),
+    Cos(Box<dyn Debug + Send + Sync>),
+
+    #[error("cos is unavailable: {0:?}")]
+    Unavailable(Box<dyn Debug + Send + Sync>),
 
     #[error("bucket {0} is not empty")]
     BucketNotEmpty(String),
@@ -45,20 +111,46 @@ pub enum Error {
 
 impl<E: 'static + std::error::Error + Send + Sync> From<RusotoError<E>> for Error {
     fn from(err: RusotoError<E>) -> Self {
-        Error::CosError(Box::new(err))
+        if retry::is_retriable(&err) {
+            Error::Unavailable(Box::new(err))
+        } else {
+            Error::Cos(Box::new(err))
+        }
+    }
+}
+
+impl StoreError for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::BucketNotFound(_) | Error::ResourceNotFound(_) => ErrorKind::NotFound,
+            Error::ResourceExist(_) => ErrorKind::Exist,
+            Error::Io(_) | Error::Unavailable(_) => ErrorKind::Unavailable,
+            Error::BucketNotEmpty(_) => ErrorKind::NotEmpty,
+            Error::Cos(_) => ErrorKind::Other,
+        }
     }
 }
 
 impl From<S3Error> for Error {
     fn from(err: S3Error) -> Self {
-        Error::CosError(Box::new(format!("{:?}", err)))
+        Error::Cos(Box::new(format!("{:?}", err)))
     }
 }
 
 #[derive(Clone)]
 pub struct CosBackend {
     client: S3Client,
+    credential: Credential,
+    endpoint: String,
     app_id: String,
+    retry_config: RetryConfig,
+    storage_class: Option<String>,
+    server_side_encryption: Option<ServerSideEncryption>,
+    /// The objects are stored as `<key_prefix>/<resource_id>`, so the apps can share a bucket.
+    key_prefix: Option<String>,
+    /// The real buckets known to exist, so the puts don't head them every time. It's locked while
+    /// a bucket is created, so the concurrent first uploads of a bucket create it once.
+    existing_buckets: Arc<Mutex<HashSet<String>>>,
 }
 
 impl Debug for CosBackend {
@@ -80,21 +172,8 @@ impl StoreBackend for CosBackend {
     ) -> Result<(), Self::Error> {
         let real_bucket = self.get_real_bucket_name(bucket);
 
-        if !self.is_bucket_exist(&real_bucket, log_context).await? {
-            self.client
-                .create_bucket(CreateBucketRequest {
-                    acl: None,
-                    bucket: real_bucket.clone(),
-                    create_bucket_configuration: None,
-                    grant_full_control: None,
-                    grant_read: None,
-                    grant_read_acp: None,
-                    grant_write: None,
-                    grant_write_acp: None,
-                    object_lock_enabled_for_bucket: None,
-                })
-                .await?;
-        }
+        self.create_bucket_if_not_exist(&real_bucket, log_context)
+            .await?;
 
         if self
             .is_resource_exist(&real_bucket, resource_id, log_context)
@@ -109,40 +188,30 @@ impl StoreBackend for CosBackend {
 
         resource.read_to_end(&mut buf).await?;
 
-        self.client
-            .put_object(PutObjectRequest {
-                acl: None,
-                body: Some(ByteStream::from(buf)),
-                bucket: real_bucket,
-                cache_control: None,
-                content_disposition: None,
-                content_encoding: None,
-                content_language: None,
-                content_length: None,
-                content_md5: None,
-                content_type: None,
-                expires: None,
-                grant_full_control: None,
-                grant_read: None,
-                grant_read_acp: None,
-                grant_write_acp: None,
-                key: resource_id.to_owned(),
-                metadata: None,
-                object_lock_legal_hold_status: None,
-                object_lock_mode: None,
-                object_lock_retain_until_date: None,
-                request_payer: None,
-                sse_customer_algorithm: None,
-                sse_customer_key: None,
-                sse_customer_key_md5: None,
-                ssekms_encryption_context: None,
-                ssekms_key_id: None,
-                server_side_encryption: None,
-                storage_class: None,
-                tagging: None,
-                website_redirect_location: None,
-            })
-            .await?;
+        let buf = Bytes::from(buf);
+
+        if let Err(err) = retry(
+            &self.retry_config,
+            || {
+                self.client
+                    .put_object(self.put_object_request(&real_bucket, resource_id, &buf))
+            },
+            log_context,
+        )
+            .await
+        {
+            // the bucket is deleted by another instance, or its creation is not visible yet, it's
+            // created again by the retried upload
+            if is_not_found(&err) {
+                warn!(log::get_logger(), "bucket {} is missing on put", real_bucket; log_context);
+
+                self.existing_buckets.lock().await.remove(&real_bucket);
+
+                return Err(Error::Unavailable(Box::new(err)));
+            }
+
+            return Err(err.into());
+        }
 
         Ok(())
     }
@@ -159,55 +228,10 @@ impl StoreBackend for CosBackend {
             S: Into<Option<u64>> + Send,
             E: Into<Option<u64>> + Send,
     {
-        let real_bucket = self.get_real_bucket_name(bucket);
-
-        let start = start.into();
-        let end = end.into();
-
-        if !self.is_bucket_exist(&real_bucket, log_context).await? {
-            return Err(Error::BucketNotFound(bucket.to_owned()));
-        }
-
-        if !self
-            .is_resource_exist(&real_bucket, resource_id, log_context)
+        match self
+            .get_object_body(bucket, resource_id, start.into(), end.into(), log_context)
             .await?
         {
-            return Err(Error::ResourceNotFound(resource_id.to_owned()));
-        }
-
-        let range = match (start, end) {
-            (None, None) => None,
-            (Some(start), None) => Some(format!("bytes={}-", start)),
-            (Some(start), Some(end)) => Some(format!("bytes={}-{}", start, end)),
-            (None, Some(end)) => Some(format!("bytes=-{}", end)),
-        };
-
-        let object_output = self
-            .client
-            .get_object(GetObjectRequest {
-                bucket: real_bucket,
-                if_match: None,
-                if_modified_since: None,
-                if_none_match: None,
-                if_unmodified_since: None,
-                key: resource_id.to_owned(),
-                part_number: None,
-                range,
-                request_payer: None,
-                response_cache_control: None,
-                response_content_disposition: None,
-                response_content_encoding: None,
-                response_content_language: None,
-                response_content_type: None,
-                response_expires: None,
-                sse_customer_algorithm: None,
-                sse_customer_key: None,
-                sse_customer_key_md5: None,
-                version_id: None,
-            })
-            .await?;
-
-        match object_output.body {
             None => Ok(Bytes::new()),
             Some(mut body) => {
                 let mut buf = BytesMut::new();
@@ -223,6 +247,27 @@ impl StoreBackend for CosBackend {
         }
     }
 
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        match self
+            .get_object_body(bucket, resource_id, start.into(), end.into(), log_context)
+            .await?
+        {
+            None => Ok(stream::empty().boxed()),
+            Some(body) => Ok(body.map(|result| result.map_err(Error::from)).boxed()),
+        }
+    }
+
     async fn delete(
         &self,
         bucket: &str,
@@ -235,16 +280,20 @@ impl StoreBackend for CosBackend {
             return Ok(());
         }
 
-        if let Err(err) = self
-            .client
-            .delete_object(DeleteObjectRequest {
-                bucket,
-                bypass_governance_retention: None,
-                key: resource_id.to_owned(),
-                mfa: None,
-                request_payer: None,
-                version_id: None,
-            })
+        let request = DeleteObjectRequest {
+            bucket,
+            bypass_governance_retention: None,
+            key: self.object_key(resource_id),
+            mfa: None,
+            request_payer: None,
+            version_id: None,
+        };
+
+        if let Err(err) = retry(
+            &self.retry_config,
+            || self.client.delete_object(request.clone()),
+            log_context,
+        )
             .await
         {
             match &err {
@@ -264,6 +313,71 @@ impl StoreBackend for CosBackend {
         }
     }
 
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        let real_bucket = self.get_real_bucket_name(bucket);
+
+        if !self.is_bucket_exist(&real_bucket, log_context).await? {
+            return Ok(vec![]);
+        }
+
+        let mut failed_ids = vec![];
+
+        for resource_ids in resource_ids.chunks(MAX_DELETE_OBJECTS) {
+            let request = DeleteObjectsRequest {
+                bucket: real_bucket.clone(),
+                bypass_governance_retention: None,
+                delete: Delete {
+                    objects: resource_ids
+                        .iter()
+                        .map(|resource_id| ObjectIdentifier {
+                            key: self.object_key(resource_id),
+                            version_id: None,
+                        })
+                        .collect(),
+                    quiet: Some(true),
+                },
+                mfa: None,
+                request_payer: None,
+            };
+
+            let delete_objects_output = retry(
+                &self.retry_config,
+                || self.client.delete_objects(request.clone()),
+                log_context,
+            )
+                .await?;
+
+            for err in delete_objects_output.errors.unwrap_or_default() {
+                error!(
+                    log::get_logger(),
+                    "delete bucket {} resource {:?} failed: {:?}",
+                    bucket, err.key, err.message;
+                    log_context
+                );
+
+                if let Some(resource_id) = err.key.and_then(|key| self.resource_id_of_key(key)) {
+                    failed_ids.push(resource_id);
+                }
+            }
+        }
+
+        Ok(failed_ids)
+    }
+
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.create_bucket_if_not_exist(&self.get_real_bucket_name(bucket), log_context)
+            .await
+    }
+
     async fn delete_bucket(
         &self,
         bucket: &str,
@@ -276,18 +390,22 @@ impl StoreBackend for CosBackend {
             return Ok(());
         }
 
+        let list_request = ListObjectsRequest {
+            bucket: real_bucket.to_owned(),
+            delimiter: None,
+            encoding_type: None,
+            marker: None,
+            max_keys: None,
+            prefix: self.list_prefix(),
+            request_payer: None,
+        };
+
         loop {
-            let list_objects_output = match self
-                .client
-                .list_objects(ListObjectsRequest {
-                    bucket: real_bucket.to_owned(),
-                    delimiter: None,
-                    encoding_type: None,
-                    marker: None,
-                    max_keys: None,
-                    prefix: None,
-                    request_payer: None,
-                })
+            let list_objects_output = match retry(
+                &self.retry_config,
+                || self.client.list_objects(list_request.clone()),
+                log_context,
+            )
                 .await
             {
                 Err(err) => {
@@ -312,26 +430,30 @@ impl StoreBackend for CosBackend {
                         return Err(Error::BucketNotEmpty(bucket.to_owned()));
                     }
 
-                    match self
-                        .client
-                        .delete_objects(DeleteObjectsRequest {
-                            bucket: real_bucket.clone(),
-                            bypass_governance_retention: None,
-                            delete: Delete {
-                                objects: contents
-                                    .into_iter()
-                                    .filter_map(|content| {
-                                        content.key.map(|key| ObjectIdentifier {
-                                            key,
-                                            version_id: None,
-                                        })
+                    let delete_request = DeleteObjectsRequest {
+                        bucket: real_bucket.clone(),
+                        bypass_governance_retention: None,
+                        delete: Delete {
+                            objects: contents
+                                .into_iter()
+                                .filter_map(|content| {
+                                    content.key.map(|key| ObjectIdentifier {
+                                        key,
+                                        version_id: None,
                                     })
-                                    .collect(),
-                                quiet: None,
-                            },
-                            mfa: None,
-                            request_payer: None,
-                        })
+                                })
+                                .collect(),
+                            quiet: None,
+                        },
+                        mfa: None,
+                        request_payer: None,
+                    };
+
+                    match retry(
+                        &self.retry_config,
+                        || self.client.delete_objects(delete_request.clone()),
+                        log_context,
+                    )
                         .await
                     {
                         Err(err) => {
@@ -356,34 +478,423 @@ impl StoreBackend for CosBackend {
             }
         }
 
+        // the bucket is shared with the other prefixes, only the objects under ours are deleted
+        if self.key_prefix.is_some() {
+            return Ok(());
+        }
+
         self.delete_bucket(&real_bucket, log_context).await
     }
+
+    /// Copy on the cos side, the bytes are not passed through this service.
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let real_src_bucket = self.get_real_bucket_name(src_bucket);
+
+        if !self.is_bucket_exist(&real_src_bucket, log_context).await? {
+            return Err(Error::BucketNotFound(src_bucket.to_owned()));
+        }
+
+        if !self
+            .is_resource_exist(&real_src_bucket, src_resource_id, log_context)
+            .await?
+        {
+            return Err(Error::ResourceNotFound(src_resource_id.to_owned()));
+        }
+
+        let real_dst_bucket = self.get_real_bucket_name(dst_bucket);
+
+        self.create_bucket_if_not_exist(&real_dst_bucket, log_context)
+            .await?;
+
+        if self
+            .is_resource_exist(&real_dst_bucket, dst_resource_id, log_context)
+            .await?
+        {
+            return Err(Error::ResourceExist(dst_resource_id.to_owned()));
+        }
+
+        let request = self.copy_object_request(
+            &real_src_bucket,
+            src_resource_id,
+            &real_dst_bucket,
+            dst_resource_id,
+        );
+
+        retry(
+            &self.retry_config,
+            || self.client.copy_object(request.clone()),
+            log_context,
+        )
+            .await?;
+
+        Ok(())
+    }
+
+    /// The browser posts to the bucket url directly, the bucket is created first, so the upload
+    /// is not rejected by a missing bucket.
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        let real_bucket = self.get_real_bucket_name(bucket);
+
+        self.create_bucket_if_not_exist(&real_bucket, log_context)
+            .await?;
+
+        Ok(Some(presign::presign_post(
+            &self.credential,
+            &self.endpoint,
+            &real_bucket,
+            &self.object_key(resource_id),
+            conditions,
+            &self.post_fields(),
+            Utc::now(),
+        )))
+    }
+
+    /// Head a bucket which may not exist, the not found response still proves the endpoint is
+    /// reachable and the request is signed correctly.
+    async fn ping(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        let real_bucket = self.get_real_bucket_name("image-bed-ping");
+
+        self.is_bucket_exist(&real_bucket, log_context).await?;
+
+        Ok(())
+    }
+
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        let real_bucket = self.get_real_bucket_name(bucket);
+
+        self.is_resource_exist(&real_bucket, resource_id, log_context)
+            .await
+    }
+
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        let real_bucket = self.get_real_bucket_name(bucket);
+
+        self.get_resource_size(&real_bucket, resource_id, log_context)
+            .await
+    }
+
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        let real_bucket = self.get_real_bucket_name(bucket);
+
+        if !self.is_bucket_exist(&real_bucket, log_context).await? {
+            return Ok(Some(vec![]));
+        }
+
+        let request = ListObjectsRequest {
+            bucket: real_bucket,
+            delimiter: None,
+            encoding_type: None,
+            marker: after.map(|after| self.object_key(after)),
+            max_keys: Some(limit.min(MAX_LIST_OBJECTS) as i64),
+            prefix: self.list_prefix(),
+            request_payer: None,
+        };
+
+        let list_objects_output = retry(
+            &self.retry_config,
+            || self.client.list_objects(request.clone()),
+            log_context,
+        )
+            .await?;
+
+        Ok(Some(
+            list_objects_output
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|content| {
+                    let last_modified = content
+                        .last_modified
+                        .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+                        .map(SystemTime::from);
+
+                    content
+                        .key
+                        .and_then(|key| self.resource_id_of_key(key))
+                        .map(|id| StoredObject { id, last_modified })
+                })
+                .collect(),
+        ))
+    }
 }
 
 impl CosBackend {
     pub fn new(access_key: &str, secret_key: &str, region: &str, app_id: &str) -> Self {
-        let http_client = HttpClient::new().expect("create http client failed");
+        Self::with_endpoint(
+            access_key,
+            secret_key,
+            region,
+            &format!("https://cos.{}.myqcloud.com", region),
+            app_id,
+        )
+    }
 
-        let region = Region::Custom {
-            name: region.to_owned(),
-            endpoint: format!("https://cos.{}.myqcloud.com", region),
+    /// Use the endpoint as is, such as the accelerate domain or a private endpoint, the region is
+    /// still used to sign the requests.
+    pub fn with_endpoint(
+        access_key: &str,
+        secret_key: &str,
+        region: &str,
+        endpoint: &str,
+        app_id: &str,
+    ) -> Self {
+        let credential = Credential {
+            access_key: access_key.to_owned(),
+            secret_key: secret_key.to_owned(),
+            region: region.to_owned(),
         };
 
-        let credential =
-            StaticProvider::new(access_key.to_owned(), secret_key.to_owned(), None, None);
-
         Self {
-            client: S3Client::new_with(http_client, credential, region),
+            client: s3_client(&credential, endpoint, TimeoutConfig::default()),
+            credential,
+            endpoint: endpoint.to_owned(),
             app_id: app_id.to_owned(),
+            retry_config: RetryConfig::default(),
+            storage_class: None,
+            server_side_encryption: None,
+            key_prefix: None,
+            existing_buckets: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn set_retry_config(&mut self, retry_config: RetryConfig) -> &mut Self {
+        self.retry_config = retry_config;
+
+        self
+    }
+
+    /// Bound the connecting and the requests of cos, the client is rebuilt with the timeouts.
+    pub fn set_timeout_config(&mut self, timeout_config: TimeoutConfig) -> &mut Self {
+        self.client = s3_client(&self.credential, &self.endpoint, timeout_config);
+
+        self
+    }
+
+    /// Put the resources with the storage class, the bucket default is used when it is not set.
+    pub fn set_storage_class(&mut self, storage_class: &str) -> anyhow::Result<&mut Self> {
+        if !STORAGE_CLASSES.contains(&storage_class) {
+            return Err(anyhow::anyhow!("storage_class {} is invalid", storage_class));
+        }
+
+        self.storage_class.replace(storage_class.to_owned());
+
+        Ok(self)
+    }
+
+    pub fn set_server_side_encryption(
+        &mut self,
+        server_side_encryption: ServerSideEncryption,
+    ) -> &mut Self {
+        self.server_side_encryption.replace(server_side_encryption);
+
+        self
+    }
+
+    /// Store the objects under the prefix, the resource ids are still the bare ones, only the
+    /// object keys are prefixed.
+    pub fn set_key_prefix(&mut self, key_prefix: &str) -> &mut Self {
+        let key_prefix = key_prefix.trim_matches('/');
+
+        if key_prefix.is_empty() {
+            self.key_prefix.take();
+        } else {
+            self.key_prefix.replace(key_prefix.to_owned());
+        }
+
+        self
+    }
+
+    fn object_key(&self, resource_id: &str) -> String {
+        match &self.key_prefix {
+            None => resource_id.to_owned(),
+            Some(key_prefix) => format!("{}/{}", key_prefix, resource_id),
+        }
+    }
+
+    /// The resource id of the object key, the keys out of the prefix are not ours.
+    fn resource_id_of_key(&self, key: String) -> Option<String> {
+        match &self.key_prefix {
+            None => Some(key),
+            Some(key_prefix) => key
+                .strip_prefix(key_prefix.as_str())
+                .and_then(|key| key.strip_prefix('/'))
+                .map(ToOwned::to_owned),
+        }
+    }
+
+    /// List only the objects under the key prefix.
+    fn list_prefix(&self) -> Option<String> {
+        self.key_prefix
+            .as_ref()
+            .map(|key_prefix| format!("{}/", key_prefix))
+    }
+
+    fn put_object_request(
+        &self,
+        real_bucket: &str,
+        resource_id: &str,
+        body: &Bytes,
+    ) -> PutObjectRequest {
+        PutObjectRequest {
+            body: Some(ByteStream::from(body.to_vec())),
+            bucket: real_bucket.to_owned(),
+            key: self.object_key(resource_id),
+            storage_class: self.storage_class.clone(),
+            server_side_encryption: self
+                .server_side_encryption
+                .as_ref()
+                .map(|sse| sse.mode().to_owned()),
+            ssekms_key_id: match &self.server_side_encryption {
+                Some(ServerSideEncryption::Kms { key_id }) => Some(key_id.clone()),
+                _ => None,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// The form fields of the presigned POST, so the posted object is stored like a put.
+    fn post_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![];
+
+        if let Some(storage_class) = &self.storage_class {
+            fields.push(("x-amz-storage-class", storage_class.clone()));
+        }
+
+        if let Some(sse) = &self.server_side_encryption {
+            fields.push(("x-amz-server-side-encryption", sse.mode().to_owned()));
+
+            if let ServerSideEncryption::Kms { key_id } = sse {
+                fields.push(("x-amz-server-side-encryption-aws-kms-key-id", key_id.clone()));
+            }
+        }
+
+        fields
+    }
+
+    /// The copied object is stored with the configured storage class and encryption, like a put.
+    fn copy_object_request(
+        &self,
+        real_src_bucket: &str,
+        src_resource_id: &str,
+        real_dst_bucket: &str,
+        dst_resource_id: &str,
+    ) -> CopyObjectRequest {
+        CopyObjectRequest {
+            bucket: real_dst_bucket.to_owned(),
+            copy_source: format!("{}/{}", real_src_bucket, self.object_key(src_resource_id)),
+            key: self.object_key(dst_resource_id),
+            storage_class: self.storage_class.clone(),
+            server_side_encryption: self
+                .server_side_encryption
+                .as_ref()
+                .map(|sse| sse.mode().to_owned()),
+            ssekms_key_id: match &self.server_side_encryption {
+                Some(ServerSideEncryption::Kms { key_id }) => Some(key_id.clone()),
+                _ => None,
+            },
+            ..Default::default()
+        }
+    }
+
+    async fn get_object_body(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: Option<u64>,
+        end: Option<u64>,
+        log_context: &LogContext,
+    ) -> Result<Option<ByteStream>, Error> {
+        let real_bucket = self.get_real_bucket_name(bucket);
+
+        if !self.is_bucket_exist(&real_bucket, log_context).await? {
+            return Err(Error::BucketNotFound(bucket.to_owned()));
         }
+
+        if !self
+            .is_resource_exist(&real_bucket, resource_id, log_context)
+            .await?
+        {
+            return Err(Error::ResourceNotFound(resource_id.to_owned()));
+        }
+
+        let range = match (start, end) {
+            (None, None) => None,
+            (Some(start), None) => Some(format!("bytes={}-", start)),
+            (Some(start), Some(end)) => Some(format!("bytes={}-{}", start, end)),
+            (None, Some(end)) => Some(format!("bytes=-{}", end)),
+        };
+
+        let request = GetObjectRequest {
+            bucket: real_bucket,
+            if_match: None,
+            if_modified_since: None,
+            if_none_match: None,
+            if_unmodified_since: None,
+            key: self.object_key(resource_id),
+            part_number: None,
+            range,
+            request_payer: None,
+            response_cache_control: None,
+            response_content_disposition: None,
+            response_content_encoding: None,
+            response_content_language: None,
+            response_content_type: None,
+            response_expires: None,
+            sse_customer_algorithm: None,
+            sse_customer_key: None,
+            sse_customer_key_md5: None,
+            version_id: None,
+        };
+
+        let object_output = retry(
+            &self.retry_config,
+            || self.client.get_object(request.clone()),
+            log_context,
+        )
+            .await?;
+
+        Ok(object_output.body)
     }
 
     async fn is_bucket_exist(&self, bucket: &str, log_cx: &LogContext) -> Result<bool, Error> {
-        if let Err(err) = self
-            .client
-            .head_bucket(HeadBucketRequest {
-                bucket: bucket.to_owned(),
-            })
+        let request = HeadBucketRequest {
+            bucket: bucket.to_owned(),
+        };
+
+        if let Err(err) = retry(
+            &self.retry_config,
+            || self.client.head_bucket(request.clone()),
+            log_cx,
+        )
             .await
         {
             match &err {
@@ -411,28 +922,49 @@ impl CosBackend {
         resource_id: &str,
         log_cx: &LogContext,
     ) -> Result<bool, Error> {
-        if let Err(err) = self
-            .client
-            .head_object(HeadObjectRequest {
-                bucket: bucket.to_owned(),
-                if_match: None,
-                if_modified_since: None,
-                if_none_match: None,
-                if_unmodified_since: None,
-                key: resource_id.to_owned(),
-                part_number: None,
-                range: None,
-                request_payer: None,
-                sse_customer_algorithm: None,
-                sse_customer_key: None,
-                sse_customer_key_md5: None,
-                version_id: None,
-            })
+        Ok(self
+            .get_resource_size(bucket, resource_id, log_cx)
+            .await?
+            .is_some())
+    }
+
+    /// Head the resource for its size, `None` if it is not found.
+    async fn get_resource_size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_cx: &LogContext,
+    ) -> Result<Option<u64>, Error> {
+        let request = HeadObjectRequest {
+            bucket: bucket.to_owned(),
+            if_match: None,
+            if_modified_since: None,
+            if_none_match: None,
+            if_unmodified_since: None,
+            key: self.object_key(resource_id),
+            part_number: None,
+            range: None,
+            request_payer: None,
+            sse_customer_algorithm: None,
+            sse_customer_key: None,
+            sse_customer_key_md5: None,
+            version_id: None,
+        };
+
+        match retry(
+            &self.retry_config,
+            || self.client.head_object(request.clone()),
+            log_cx,
+        )
             .await
         {
-            if is_service_err_or_not_found(&err) {
-                Ok(false)
-            } else {
+            Ok(head_object_output) => Ok(Some(
+                head_object_output.content_length.unwrap_or_default() as u64,
+            )),
+
+            Err(err) if is_service_err_or_not_found(&err) => Ok(None),
+
+            Err(err) => {
                 error!(
                     log::get_logger(),
                     "check bucket {} resource {} exist failed: {:?}",
@@ -442,12 +974,69 @@ impl CosBackend {
 
                 Err(err.into())
             }
-        } else {
-            Ok(true)
         }
     }
 
+    async fn create_bucket_if_not_exist(
+        &self,
+        real_bucket: &str,
+        log_cx: &LogContext,
+    ) -> Result<(), Error> {
+        let mut existing_buckets = self.existing_buckets.lock().await;
+
+        // created by the concurrent upload which held the lock before
+        if existing_buckets.contains(real_bucket) {
+            return Ok(());
+        }
+
+        if self.is_bucket_exist(real_bucket, log_cx).await? {
+            existing_buckets.insert(real_bucket.to_owned());
+
+            return Ok(());
+        }
+
+        match self
+            .client
+            .create_bucket(CreateBucketRequest {
+                acl: None,
+                bucket: real_bucket.to_owned(),
+                create_bucket_configuration: None,
+                grant_full_control: None,
+                grant_read: None,
+                grant_read_acp: None,
+                grant_write: None,
+                grant_write_acp: None,
+                object_lock_enabled_for_bucket: None,
+            })
+            .await
+        {
+            Ok(_) => {}
+
+            // another instance is still creating it, the upload can be retried soon
+            Err(err) if is_bucket_creating_err(&err) => {
+                warn!(log::get_logger(), "bucket {} is being created", real_bucket; log_cx);
+
+                return Err(Error::Unavailable(Box::new(err)));
+            }
+
+            // the other instances may create it between the head and the create
+            Err(err) if is_bucket_exist_err(&err) => {}
+
+            Err(err) => {
+                error!(log::get_logger(), "create bucket {} failed: {:?}", real_bucket, err; log_cx);
+
+                return Err(err.into());
+            }
+        }
+
+        existing_buckets.insert(real_bucket.to_owned());
+
+        Ok(())
+    }
+
     async fn delete_bucket(&self, bucket: &str, log_cx: &LogContext) -> Result<(), Error> {
+        self.existing_buckets.lock().await.remove(bucket);
+
         if let Err(err) = self
             .client
             .delete_bucket(DeleteBucketRequest {
@@ -472,6 +1061,58 @@ impl CosBackend {
     }
 }
 
+/// The client of the endpoint, its connecting and requests are bounded by the timeouts.
+fn s3_client(credential: &Credential, endpoint: &str, timeout_config: TimeoutConfig) -> S3Client {
+    let connector = TimeoutConnector::new(HttpsConnector::new(), timeout_config.connect_timeout);
+    let dispatcher = TimeoutDispatcher::new(
+        HttpClient::from_connector(connector),
+        timeout_config.request_timeout,
+    );
+
+    let region = Region::Custom {
+        name: credential.region.clone(),
+        endpoint: endpoint.to_owned(),
+    };
+
+    let provider = StaticProvider::new(
+        credential.access_key.clone(),
+        credential.secret_key.clone(),
+        None,
+        None,
+    );
+
+    S3Client::new_with(dispatcher, provider, region)
+}
+
+fn is_bucket_exist_err(err: &RusotoError<CreateBucketError>) -> bool {
+    match err {
+        RusotoError::Service(CreateBucketError::BucketAlreadyExists(_))
+        | RusotoError::Service(CreateBucketError::BucketAlreadyOwnedByYou(_)) => true,
+        RusotoError::Unknown(raw_resp) => raw_resp.status == StatusCode::CONFLICT,
+        _ => false,
+    }
+}
+
+/// Another creation of the bucket is in progress, it's done in seconds.
+fn is_bucket_creating_err(err: &RusotoError<CreateBucketError>) -> bool {
+    match err {
+        RusotoError::Unknown(raw_resp) => {
+            raw_resp.status == StatusCode::CONFLICT
+                && raw_resp
+                .body_as_str()
+                .contains("<Code>OperationAborted</Code>")
+        }
+        _ => false,
+    }
+}
+
+fn is_not_found<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::Unknown(raw_resp) => raw_resp.status == StatusCode::NOT_FOUND,
+        _ => false,
+    }
+}
+
 fn is_service_err_or_not_found<E>(err: &RusotoError<E>) -> bool {
     match &err {
         RusotoError::Service(_) => true,
@@ -482,10 +1123,485 @@ fn is_service_err_or_not_found<E>(err: &RusotoError<E>) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::Infallible;
     use std::env;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use futures_util::future;
+    use hyper::{Body, Response, Server};
+    use hyper::service::{make_service_fn, service_fn};
 
     use super::*;
 
+    #[tokio::test]
+    async fn test_custom_endpoint() {
+        let paths = Arc::new(Mutex::new(vec![]));
+
+        let server_paths = paths.clone();
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            move |_| {
+                let paths = server_paths.clone();
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        paths.lock().unwrap().push(req.uri().path().to_owned());
+
+                        async {
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(StatusCode::NOT_FOUND)
+                                    .body(Body::empty())
+                                    .unwrap(),
+                            )
+                        }
+                    }))
+                }
+            },
+        ));
+        let endpoint = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let cos_backend = CosBackend::with_endpoint(
+            "access-key",
+            "secret-key",
+            "ap-guangzhou",
+            &endpoint,
+            "1250000000",
+        );
+
+        let log_context = LogContext::builder().request_id("").build();
+
+        let err = cos_backend
+            .get("bucket", "id", None, None, &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::BucketNotFound(_)));
+
+        let paths = paths.lock().unwrap();
+
+        assert!(!paths.is_empty());
+        assert!(paths[0].starts_with("/bucket-1250000000"), "{:?}", paths);
+    }
+
+    #[tokio::test]
+    async fn test_create_bucket_race() {
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        // the bucket is missing on head, but created by another instance before the create
+        let server_requests = requests.clone();
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            move |_| {
+                let requests = server_requests.clone();
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        requests
+                            .lock()
+                            .unwrap()
+                            .push(format!("{} {}", req.method(), req.uri().path()));
+
+                        let resp = if req.method() == hyper::Method::HEAD {
+                            Response::builder()
+                                .status(StatusCode::NOT_FOUND)
+                                .body(Body::empty())
+                        } else {
+                            Response::builder().status(StatusCode::CONFLICT).body(Body::from(
+                                "<Error><Code>BucketAlreadyOwnedByYou</Code>\
+                                 <Message>exist</Message></Error>",
+                            ))
+                        };
+
+                        async { Ok::<_, Infallible>(resp.unwrap()) }
+                    }))
+                }
+            },
+        ));
+        let endpoint = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let cos_backend = CosBackend::with_endpoint(
+            "access-key",
+            "secret-key",
+            "ap-guangzhou",
+            &endpoint,
+            "1250000000",
+        );
+
+        let log_context = LogContext::builder().request_id("").build();
+
+        cos_backend
+            .create_bucket("2021-01", &log_context)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["HEAD /2021-01-1250000000", "PUT /2021-01-1250000000"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_first_puts() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let created = Arc::new(AtomicBool::new(false));
+
+        // the objects can't be put before the bucket is created
+        let (server_requests, server_created) = (requests.clone(), created.clone());
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            move |_| {
+                let (requests, created) = (server_requests.clone(), server_created.clone());
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let is_bucket = req.uri().path().matches('/').count() == 1;
+
+                        if is_bucket {
+                            requests
+                                .lock()
+                                .unwrap()
+                                .push(format!("{} {}", req.method(), req.uri().path()));
+                        }
+
+                        let status = match (is_bucket, req.method() == hyper::Method::PUT) {
+                            // create the bucket
+                            (true, true) if created.swap(true, Ordering::SeqCst) => {
+                                StatusCode::CONFLICT
+                            }
+                            (true, true) => StatusCode::OK,
+                            // head the object
+                            (false, false) => StatusCode::NOT_FOUND,
+                            _ if created.load(Ordering::SeqCst) => StatusCode::OK,
+                            _ => StatusCode::NOT_FOUND,
+                        };
+
+                        async move {
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .body(Body::empty())
+                                    .unwrap(),
+                            )
+                        }
+                    }))
+                }
+            },
+        ));
+        let endpoint = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let cos_backend = CosBackend::with_endpoint(
+            "access-key",
+            "secret-key",
+            "ap-guangzhou",
+            &endpoint,
+            "1250000000",
+        );
+
+        let log_context = LogContext::builder().request_id("").build();
+
+        let results = future::join_all((0..16).map(|i| {
+            let cos_backend = cos_backend.clone();
+            let log_context = log_context.clone();
+
+            async move {
+                cos_backend
+                    .put("2021-01", &format!("id-{}", i), &b"test"[..], &log_context)
+                    .await
+            }
+        }))
+            .await;
+
+        for result in results {
+            result.unwrap();
+        }
+
+        // the bucket is headed and created once
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec!["HEAD /2021-01-1250000000", "PUT /2021-01-1250000000"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bucket_creating() {
+        // another instance is creating the bucket
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| {
+            async {
+                Ok::<_, Infallible>(service_fn(|req| {
+                    let resp = if req.method() == hyper::Method::HEAD {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                    } else {
+                        Response::builder().status(StatusCode::CONFLICT).body(Body::from(
+                            "<Error><Code>OperationAborted</Code>\
+                             <Message>conflicting operation</Message></Error>",
+                        ))
+                    };
+
+                    async { Ok::<_, Infallible>(resp.unwrap()) }
+                }))
+            }
+        }));
+        let endpoint = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let cos_backend = CosBackend::with_endpoint(
+            "access-key",
+            "secret-key",
+            "ap-guangzhou",
+            &endpoint,
+            "1250000000",
+        );
+
+        let log_context = LogContext::builder().request_id("").build();
+
+        let err = cos_backend
+            .put("2021-01", "id", &b"test"[..], &log_context)
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // the slow cos which responds after the timeout
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| {
+            async {
+                Ok::<_, Infallible>(service_fn(|_req| async {
+                    tokio::time::delay_for(Duration::from_secs(10)).await;
+
+                    Ok::<_, Infallible>(Response::new(Body::empty()))
+                }))
+            }
+        }));
+        let endpoint = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let mut cos_backend = CosBackend::with_endpoint(
+            "access-key",
+            "secret-key",
+            "ap-guangzhou",
+            &endpoint,
+            "1250000000",
+        );
+        cos_backend
+            .set_retry_config(RetryConfig::new(Some(1), None).unwrap())
+            .set_timeout_config(TimeoutConfig {
+                connect_timeout: Duration::from_secs(1),
+                request_timeout: Duration::from_millis(300),
+            });
+
+        let log_context = LogContext::builder().request_id("").build();
+
+        let start = Instant::now();
+        let err = cos_backend
+            .get("2021-01", "id", None, None, &log_context)
+            .await
+            .unwrap_err();
+        let elapsed = start.elapsed();
+
+        assert_eq!(err.kind(), ErrorKind::Unavailable);
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_put_object_request_storage_class() {
+        let mut cos_backend =
+            CosBackend::new("access-key", "secret-key", "ap-guangzhou", "1250000000");
+        let body = Bytes::from_static(b"test");
+
+        let request = cos_backend.put_object_request("bucket-1250000000", "id", &body);
+
+        assert_eq!(request.storage_class, None);
+
+        cos_backend.set_storage_class("STANDARD_IA").unwrap();
+
+        let request = cos_backend.put_object_request("bucket-1250000000", "id", &body);
+
+        assert_eq!(request.bucket, "bucket-1250000000");
+        assert_eq!(request.key, "id");
+        assert_eq!(request.storage_class.as_deref(), Some("STANDARD_IA"));
+
+        assert!(cos_backend.set_storage_class("COLD").is_err());
+    }
+
+    #[test]
+    fn test_put_object_request_server_side_encryption() {
+        let mut cos_backend =
+            CosBackend::new("access-key", "secret-key", "ap-guangzhou", "1250000000");
+        let body = Bytes::from_static(b"test");
+
+        let request = cos_backend.put_object_request("bucket-1250000000", "id", &body);
+
+        assert_eq!(request.server_side_encryption, None);
+        assert_eq!(request.ssekms_key_id, None);
+
+        cos_backend.set_server_side_encryption(ServerSideEncryption::new("AES256", None).unwrap());
+
+        let request = cos_backend.put_object_request("bucket-1250000000", "id", &body);
+
+        assert_eq!(request.server_side_encryption.as_deref(), Some("AES256"));
+        assert_eq!(request.ssekms_key_id, None);
+
+        cos_backend.set_server_side_encryption(
+            ServerSideEncryption::new("aws:kms", Some("key-id")).unwrap(),
+        );
+
+        let request = cos_backend.put_object_request("bucket-1250000000", "id", &body);
+
+        assert_eq!(request.server_side_encryption.as_deref(), Some("aws:kms"));
+        assert_eq!(request.ssekms_key_id.as_deref(), Some("key-id"));
+
+        assert!(ServerSideEncryption::new("aws:kms", None).is_err());
+        assert!(ServerSideEncryption::new("aes256", None).is_err());
+    }
+
+    #[test]
+    fn test_post_fields() {
+        let mut cos_backend =
+            CosBackend::new("access-key", "secret-key", "ap-guangzhou", "1250000000");
+
+        assert!(cos_backend.post_fields().is_empty());
+
+        cos_backend.set_storage_class("STANDARD_IA").unwrap();
+        cos_backend.set_server_side_encryption(
+            ServerSideEncryption::new("aws:kms", Some("key-id")).unwrap(),
+        );
+
+        assert_eq!(
+            cos_backend.post_fields(),
+            vec![
+                ("x-amz-storage-class", "STANDARD_IA".to_string()),
+                ("x-amz-server-side-encryption", "aws:kms".to_string()),
+                ("x-amz-server-side-encryption-aws-kms-key-id", "key-id".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_copy_object_request() {
+        let mut cos_backend =
+            CosBackend::new("access-key", "secret-key", "ap-guangzhou", "1250000000");
+        cos_backend.set_storage_class("STANDARD_IA").unwrap();
+
+        let request = cos_backend.copy_object_request(
+            "src-1250000000",
+            "src-id",
+            "dst-1250000000",
+            "dst-id",
+        );
+
+        assert_eq!(request.bucket, "dst-1250000000");
+        assert_eq!(request.copy_source, "src-1250000000/src-id");
+        assert_eq!(request.key, "dst-id");
+        assert_eq!(request.storage_class.as_deref(), Some("STANDARD_IA"));
+    }
+
+    #[test]
+    fn test_key_prefix() {
+        let mut cos_backend =
+            CosBackend::new("access-key", "secret-key", "ap-guangzhou", "1250000000");
+        let body = Bytes::from_static(b"test");
+
+        assert_eq!(cos_backend.object_key("id"), "id");
+        assert_eq!(cos_backend.list_prefix(), None);
+
+        cos_backend.set_key_prefix("/app/");
+
+        assert_eq!(cos_backend.object_key("id"), "app/id");
+        assert_eq!(cos_backend.list_prefix().as_deref(), Some("app/"));
+
+        let request = cos_backend.put_object_request("bucket-1250000000", "id", &body);
+        assert_eq!(request.key, "app/id");
+
+        let request = cos_backend.copy_object_request(
+            "src-1250000000",
+            "src-id",
+            "dst-1250000000",
+            "dst-id",
+        );
+        assert_eq!(request.copy_source, "src-1250000000/app/src-id");
+        assert_eq!(request.key, "app/dst-id");
+
+        assert_eq!(
+            cos_backend.resource_id_of_key("app/id".to_string()).as_deref(),
+            Some("id")
+        );
+        assert_eq!(cos_backend.resource_id_of_key("apps/id".to_string()), None);
+        assert_eq!(cos_backend.resource_id_of_key("other/id".to_string()), None);
+
+        cos_backend.set_key_prefix("");
+        assert_eq!(cos_backend.object_key("id"), "id");
+    }
+
+    #[tokio::test]
+    async fn test_key_prefix_requests() {
+        let paths = Arc::new(Mutex::new(vec![]));
+
+        let server_paths = paths.clone();
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(
+            move |_| {
+                let paths = server_paths.clone();
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        paths.lock().unwrap().push(req.uri().path().to_owned());
+
+                        async { Ok::<_, Infallible>(Response::new(Body::from("data"))) }
+                    }))
+                }
+            },
+        ));
+        let endpoint = format!("http://{}", server.local_addr());
+
+        tokio::spawn(server);
+
+        let mut cos_backend = CosBackend::with_endpoint(
+            "access-key",
+            "secret-key",
+            "ap-guangzhou",
+            &endpoint,
+            "1250000000",
+        );
+        cos_backend.set_key_prefix("app");
+
+        let log_context = LogContext::builder().request_id("").build();
+
+        let data = cos_backend
+            .get("bucket", "id", 0u64, 3u64, &log_context)
+            .await
+            .unwrap();
+        assert_eq!(data, "data");
+
+        cos_backend.delete("bucket", "id", &log_context).await.unwrap();
+
+        let paths = paths.lock().unwrap();
+        let object_paths = paths
+            .iter()
+            .filter(|path| path.trim_end_matches('/') != "/bucket-1250000000")
+            .collect::<Vec<_>>();
+
+        assert!(!object_paths.is_empty());
+        assert!(
+            object_paths
+                .iter()
+                .all(|path| path.as_str() == "/bucket-1250000000/app/id"),
+            "{:?}",
+            paths
+        );
+    }
+
     #[tokio::test]
     async fn test_get_exist_resource() {
         let access_key = env::var("COS_ACCESS_KEY").expect("need set COS_ACCESS_KEY env");
@@ -745,4 +1861,47 @@ mod tests {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn test_get_stream_multi_chunk() {
+        let access_key = env::var("COS_ACCESS_KEY").expect("need set COS_ACCESS_KEY env");
+        let secret_key = env::var("COS_SECRET_KEY").expect("need set COS_SECRET_KEY env");
+        let region = env::var("COS_REGION").expect("need set COS_REGION env");
+        let app_id = env::var("COS_APP_ID").expect("need set COS_APP_ID env");
+
+        let cos_backend = CosBackend::new(&access_key, &secret_key, &region, &app_id);
+
+        let log_context = LogContext::builder().request_id("").build();
+
+        let res_id = format!("test-resource-id-{}", rand::random::<u64>());
+        let data = (0..4 * 1024 * 1024)
+            .map(|_| rand::random::<u8>())
+            .collect::<Vec<_>>();
+
+        cos_backend
+            .put("test-bucket", &res_id, data.as_slice(), &log_context)
+            .await
+            .unwrap();
+
+        let mut stream = cos_backend
+            .get_stream("test-bucket", &res_id, None, None, &log_context)
+            .await
+            .unwrap();
+
+        let mut chunks = 0;
+        let mut buf = Vec::with_capacity(data.len());
+
+        while let Some(chunk) = stream.next().await {
+            chunks += 1;
+            buf.extend_from_slice(&chunk.unwrap());
+        }
+
+        assert!(chunks > 1);
+        assert_eq!(buf, data);
+
+        cos_backend
+            .delete("test-bucket", &res_id, &log_context)
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+
+use crate::store::{PostConditions, PresignedPost};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const SERVICE: &str = "s3";
+
+/// The credential which signs the POST policy, cos accepts the AWS signature v4.
+#[derive(Clone)]
+pub struct Credential {
+    pub access_key: String,
+    pub secret_key: String,
+    pub region: String,
+}
+
+/// Sign the POST policy of the object, the `extra_fields` are posted as is and must match.
+pub fn presign_post(
+    credential: &Credential,
+    endpoint: &str,
+    real_bucket: &str,
+    resource_id: &str,
+    conditions: &PostConditions,
+    extra_fields: &[(&str, String)],
+    now: DateTime<Utc>,
+) -> PresignedPost {
+    let date = now.format("%Y%m%d").to_string();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let amz_credential = format!(
+        "{}/{}/{}/{}/aws4_request",
+        credential.access_key, date, credential.region, SERVICE
+    );
+
+    let mut fields = BTreeMap::new();
+    fields.insert("key".to_string(), resource_id.to_owned());
+    fields.insert("x-amz-algorithm".to_string(), ALGORITHM.to_string());
+    fields.insert("x-amz-credential".to_string(), amz_credential);
+    fields.insert("x-amz-date".to_string(), amz_date);
+
+    for (name, value) in extra_fields {
+        fields.insert((*name).to_owned(), value.clone());
+    }
+
+    let mut policy_conditions = vec![
+        json!({ "bucket": real_bucket }),
+        json!(["content-length-range", 1, conditions.max_size]),
+    ];
+
+    policy_conditions.extend(fields.iter().map(|(name, value)| {
+        let mut condition = Map::new();
+        condition.insert(name.clone(), Value::String(value.clone()));
+
+        Value::Object(condition)
+    }));
+
+    if let Some(prefix) = &conditions.content_type_prefix {
+        policy_conditions.push(json!(["starts-with", "$Content-Type", prefix]));
+    }
+
+    let expiration = now
+        + Duration::from_std(conditions.expires_in).unwrap_or_else(|_| Duration::zero());
+
+    let policy = json!({
+        "expiration": expiration.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        "conditions": Value::Array(policy_conditions),
+    });
+
+    let policy = base64::encode(policy.to_string());
+
+    let signing_key = [credential.region.as_str(), SERVICE, "aws4_request"].iter().fold(
+        hmac_sha256(
+            format!("AWS4{}", credential.secret_key).as_bytes(),
+            date.as_bytes(),
+        ),
+        |key, data| hmac_sha256(&key, data.as_bytes()),
+    );
+
+    let signature = hex::encode(hmac_sha256(&signing_key, policy.as_bytes()));
+
+    fields.insert("policy".to_string(), policy);
+    fields.insert("x-amz-signature".to_string(), signature);
+
+    PresignedPost {
+        url: format!("{}/{}", endpoint.trim_end_matches('/'), real_bucket),
+        fields,
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts any key length");
+    mac.update(data);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credential() -> Credential {
+        Credential {
+            access_key: "access-key".to_string(),
+            secret_key: "secret-key".to_string(),
+            region: "ap-guangzhou".to_string(),
+        }
+    }
+
+    fn conditions() -> PostConditions {
+        PostConditions {
+            content_type_prefix: Some("image/".to_string()),
+            max_size: 1024,
+            expires_in: std::time::Duration::from_secs(600),
+        }
+    }
+
+    #[test]
+    fn test_presign_post() {
+        let now = "2021-01-02T03:04:05Z".parse::<DateTime<Utc>>().unwrap();
+
+        let presigned = presign_post(
+            &credential(),
+            "https://cos.ap-guangzhou.myqcloud.com/",
+            "bucket-1250000000",
+            "id",
+            &conditions(),
+            &[("x-amz-storage-class", "STANDARD_IA".to_string())],
+            now,
+        );
+
+        assert_eq!(
+            presigned.url,
+            "https://cos.ap-guangzhou.myqcloud.com/bucket-1250000000"
+        );
+
+        let fields = &presigned.fields;
+
+        assert_eq!(fields["key"], "id");
+        assert_eq!(fields["x-amz-algorithm"], ALGORITHM);
+        assert_eq!(
+            fields["x-amz-credential"],
+            "access-key/20210102/ap-guangzhou/s3/aws4_request"
+        );
+        assert_eq!(fields["x-amz-date"], "20210102T030405Z");
+        assert_eq!(fields["x-amz-storage-class"], "STANDARD_IA");
+
+        let signature = &fields["x-amz-signature"];
+        assert_eq!(signature.len(), 64);
+        assert!(signature.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')));
+
+        let policy: Value =
+            serde_json::from_slice(&base64::decode(&fields["policy"]).unwrap()).unwrap();
+
+        assert_eq!(policy["expiration"], "2021-01-02T03:14:05.000Z");
+
+        let policy_conditions = policy["conditions"].as_array().unwrap();
+
+        for condition in &[
+            json!({ "bucket": "bucket-1250000000" }),
+            json!({ "key": "id" }),
+            json!({ "x-amz-storage-class": "STANDARD_IA" }),
+            json!(["content-length-range", 1, 1024]),
+            json!(["starts-with", "$Content-Type", "image/"]),
+        ] {
+            assert!(policy_conditions.contains(condition), "{}", condition);
+        }
+    }
+
+    #[test]
+    fn test_presign_post_signature() {
+        let now = "2021-01-02T03:04:05Z".parse::<DateTime<Utc>>().unwrap();
+
+        let sign = |secret_key: &str| {
+            let credential = Credential {
+                secret_key: secret_key.to_string(),
+                ..credential()
+            };
+
+            presign_post(
+                &credential,
+                "https://cos.ap-guangzhou.myqcloud.com",
+                "bucket-1250000000",
+                "id",
+                &conditions(),
+                &[],
+                now,
+            )
+                .fields["x-amz-signature"]
+                .clone()
+        };
+
+        // the signature is stable and bound to the secret key
+        assert_eq!(sign("secret-key"), sign("secret-key"));
+        assert_ne!(sign("secret-key"), sign("other-secret-key"));
+    }
+}
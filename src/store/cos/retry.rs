@@ -0,0 +1,210 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+use rusoto_core::RusotoError;
+use slog::warn;
+
+use crate::log::{self, LogContext};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(100);
+const MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Retry policy of the idempotent cos operations.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Build the retry config, the base delay is in milliseconds and unset values use the
+    /// defaults.
+    pub fn new(max_attempts: Option<u32>, base_delay: Option<u64>) -> anyhow::Result<Self> {
+        if max_attempts == Some(0) {
+            return Err(anyhow::anyhow!("cos_retry_max_attempts must be positive"));
+        }
+
+        Ok(Self {
+            max_attempts: max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            base_delay: base_delay.map_or(DEFAULT_BASE_DELAY, Duration::from_millis),
+        })
+    }
+
+    /// Exponential backoff with jitter, `attempt` starts from 1.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .checked_mul(1 << (attempt - 1).min(16))
+            .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY));
+
+        let delay = delay.as_millis() as u64;
+
+        Duration::from_millis(rand::thread_rng().gen_range(delay / 2..=delay))
+    }
+}
+
+/// Only the server side and network errors are worth retrying, 4xx errors never succeed.
+pub fn is_retriable<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(resp) => resp.status.is_server_error(),
+        _ => false,
+    }
+}
+
+pub async fn retry<T, E, F, Fut>(
+    retry_config: &RetryConfig,
+    mut operation: F,
+    log_context: &LogContext,
+) -> Result<T, RusotoError<E>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output=Result<T, RusotoError<E>>>,
+        E: Debug,
+{
+    let mut attempt = 1;
+
+    loop {
+        match operation().await {
+            Err(err) if attempt < retry_config.max_attempts && is_retriable(&err) => {
+                let delay = retry_config.backoff(attempt);
+
+                warn!(
+                    log::get_logger(),
+                    "cos operation failed, retry after {:?}: {:?}",
+                    delay, err;
+                    log_context,
+                    "attempt" => attempt
+                );
+
+                tokio::time::delay_for(delay).await;
+
+                attempt += 1;
+            }
+
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use bytes::Bytes;
+    use hyper::{HeaderMap, StatusCode};
+    use rusoto_core::HttpDispatchError;
+    use rusoto_core::request::BufferedHttpResponse;
+
+    use super::*;
+
+    fn unknown_err(status: StatusCode) -> RusotoError<()> {
+        RusotoError::Unknown(BufferedHttpResponse {
+            status,
+            body: Bytes::new(),
+            headers: HeaderMap::default(),
+        })
+    }
+
+    fn test_config() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+        }
+    }
+
+    fn test_log_context() -> LogContext {
+        LogContext::builder().request_id("test").build()
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let calls = AtomicU32::new(0);
+        let calls = &calls;
+
+        let result = retry(
+            &test_config(),
+            move || async move {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(unknown_err(StatusCode::SERVICE_UNAVAILABLE)),
+                    1 => Err(RusotoError::HttpDispatch(HttpDispatchError::new(
+                        "timeout".to_string(),
+                    ))),
+                    _ => Ok("ok"),
+                }
+            },
+            &test_log_context(),
+        )
+            .await;
+
+        assert_eq!(result.unwrap(), "ok");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_exhausted() {
+        let calls = AtomicU32::new(0);
+        let calls = &calls;
+
+        let result: Result<(), _> = retry(
+            &test_config(),
+            move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+
+                Err(unknown_err(StatusCode::INTERNAL_SERVER_ERROR))
+            },
+            &test_log_context(),
+        )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_not_retry_client_error() {
+        let calls = AtomicU32::new(0);
+        let calls = &calls;
+
+        let result: Result<(), _> = retry(
+            &test_config(),
+            move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+
+                Err(unknown_err(StatusCode::NOT_FOUND))
+            },
+            &test_log_context(),
+        )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let retry_config = RetryConfig {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+        };
+
+        for attempt in 1..=5 {
+            let delay = retry_config.backoff(attempt);
+            let max = Duration::from_millis(100 << (attempt - 1)).min(MAX_DELAY);
+
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        }
+    }
+}
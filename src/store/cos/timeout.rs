@@ -0,0 +1,181 @@
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::service::Service;
+use hyper::Uri;
+use rusoto_core::request::{DispatchSignedRequest, DispatchSignedRequestFuture};
+use rusoto_core::signature::SignedRequest;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bound the cos requests, so a hung cos connection can't block the handling forever. The timed
+/// out requests fail as the dispatch errors, which are retried and make the store unavailable.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TimeoutConfig {
+    /// Covers the tcp connecting and the tls handshaking.
+    pub connect_timeout: Duration,
+    /// Covers the request until the response head is received, the response body is not
+    /// covered.
+    pub request_timeout: Duration,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// Build the timeout config, the timeouts are in seconds and unset values use the defaults.
+    pub fn new(connect_timeout: Option<u64>, request_timeout: Option<u64>) -> anyhow::Result<Self> {
+        if connect_timeout == Some(0) {
+            return Err(anyhow::anyhow!("cos_connect_timeout must be positive"));
+        }
+
+        if request_timeout == Some(0) {
+            return Err(anyhow::anyhow!("cos_request_timeout must be positive"));
+        }
+
+        Ok(Self {
+            connect_timeout: connect_timeout.map_or(DEFAULT_CONNECT_TIMEOUT, Duration::from_secs),
+            request_timeout: request_timeout.map_or(DEFAULT_REQUEST_TIMEOUT, Duration::from_secs),
+        })
+    }
+}
+
+/// Fail the connecting which is not done in the timeout.
+#[derive(Debug, Clone)]
+pub struct TimeoutConnector<C> {
+    connector: C,
+    timeout: Duration,
+}
+
+impl<C> TimeoutConnector<C> {
+    pub fn new(connector: C, timeout: Duration) -> Self {
+        Self { connector, timeout }
+    }
+}
+
+impl<C> Service<Uri> for TimeoutConnector<C>
+    where
+        C: Service<Uri>,
+        C::Response: Send + 'static,
+        C::Future: Send + 'static,
+        C::Error: Into<BoxError>,
+{
+    type Response = C::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output=Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.connector.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let timeout = self.timeout;
+        let fut = self.connector.call(dst);
+
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, fut).await {
+                Ok(result) => result.map_err(Into::into),
+                Err(_) => {
+                    let message = format!("connect timed out after {:?}", timeout);
+
+                    Err(io::Error::new(io::ErrorKind::TimedOut, message).into())
+                }
+            }
+        })
+    }
+}
+
+/// Dispatch the requests with the request timeout, the rusoto clients never give their own.
+pub struct TimeoutDispatcher<D> {
+    dispatcher: D,
+    timeout: Duration,
+}
+
+impl<D> TimeoutDispatcher<D> {
+    pub fn new(dispatcher: D, timeout: Duration) -> Self {
+        Self {
+            dispatcher,
+            timeout,
+        }
+    }
+}
+
+impl<D: DispatchSignedRequest> DispatchSignedRequest for TimeoutDispatcher<D> {
+    fn dispatch(
+        &self,
+        request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        self.dispatcher.dispatch(request, Some(timeout.unwrap_or(self.timeout)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+    use std::time::Instant;
+
+    use super::*;
+
+    /// Never connects.
+    #[derive(Clone)]
+    struct HangingConnector;
+
+    impl Service<Uri> for HangingConnector {
+        type Response = ();
+        type Error = io::Error;
+        type Future = future::Pending<Result<(), io::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _dst: Uri) -> Self::Future {
+            future::pending()
+        }
+    }
+
+    #[test]
+    fn test_timeout_config() {
+        assert_eq!(TimeoutConfig::new(None, None).unwrap(), TimeoutConfig::default());
+
+        let timeout_config = TimeoutConfig::new(Some(3), Some(20)).unwrap();
+        assert_eq!(timeout_config.connect_timeout, Duration::from_secs(3));
+        assert_eq!(timeout_config.request_timeout, Duration::from_secs(20));
+
+        assert!(TimeoutConfig::new(Some(0), None).is_err());
+        assert!(TimeoutConfig::new(None, Some(0)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let mut connector = TimeoutConnector::new(HangingConnector, Duration::from_millis(100));
+
+        let start = Instant::now();
+        let err = connector
+            .call(Uri::from_static("https://cos.test.com"))
+            .await
+            .unwrap_err();
+        let elapsed = start.elapsed();
+
+        assert_eq!(
+            err.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::TimedOut
+        );
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,355 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::io::AsyncRead;
+use slog::info;
+
+use crate::log::{self, LogContext};
+use crate::store::{
+    ErrorKind, PostConditions, PresignedPost, ResourceStream, StoreBackend, StoreError,
+    StoredObject,
+};
+
+/// Read the resources which are not found in the primary backend from the secondary one, so
+/// the resources can be migrated to the primary backend without downtime. The secondary
+/// backend is read only, the writes and the listing only go to the primary backend.
+#[derive(Debug)]
+pub struct FallbackBackend<P, S> {
+    primary: P,
+    secondary: S,
+}
+
+impl<P, S> FallbackBackend<P, S> {
+    pub fn new(primary: P, secondary: S) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+fn log_fallback(op: &str, bucket: &str, resource_id: &str, log_context: &LogContext) {
+    info!(
+        log::get_logger(),
+        "resource is not found in primary backend, fall back to secondary backend";
+        log_context,
+        "op" => op,
+        "bucket" => bucket,
+        "resource" => resource_id
+    );
+}
+
+#[async_trait]
+impl<P, S> StoreBackend for FallbackBackend<P, S>
+    where
+        P: StoreBackend + Send + Sync,
+        P::Error: Send + 'static,
+        S: StoreBackend<Error=P::Error> + Send + Sync,
+{
+    type Error = P::Error;
+
+    async fn put<R: AsyncRead + Send>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource: R,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.primary
+            .put(bucket, resource_id, resource, log_context)
+            .await
+    }
+
+    async fn get<ST, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: ST,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<Bytes, Self::Error>
+        where
+            ST: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        let (start, end) = (start.into(), end.into());
+
+        match self
+            .primary
+            .get(bucket, resource_id, start, end, log_context)
+            .await
+        {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                log_fallback("get", bucket, resource_id, log_context);
+
+                self.secondary
+                    .get(bucket, resource_id, start, end, log_context)
+                    .await
+            }
+
+            result => result,
+        }
+    }
+
+    async fn get_stream<ST, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: ST,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            ST: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        let (start, end) = (start.into(), end.into());
+
+        match self
+            .primary
+            .get_stream(bucket, resource_id, start, end, log_context)
+            .await
+        {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                log_fallback("get_stream", bucket, resource_id, log_context);
+
+                self.secondary
+                    .get_stream(bucket, resource_id, start, end, log_context)
+                    .await
+            }
+
+            result => result,
+        }
+    }
+
+    async fn delete(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.primary.delete(bucket, resource_id, log_context).await
+    }
+
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        self.primary
+            .delete_many(bucket, resource_ids, log_context)
+            .await
+    }
+
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.primary.create_bucket(bucket, log_context).await
+    }
+
+    async fn delete_bucket(
+        &self,
+        bucket: &str,
+        need_empty: bool,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.primary
+            .delete_bucket(bucket, need_empty, log_context)
+            .await
+    }
+
+    /// The resources only in the secondary backend are copied through this service into the
+    /// primary backend.
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        match self
+            .primary
+            .copy(
+                src_bucket,
+                src_resource_id,
+                dst_bucket,
+                dst_resource_id,
+                log_context,
+            )
+            .await
+        {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                log_fallback("copy", src_bucket, src_resource_id, log_context);
+
+                let data = self
+                    .secondary
+                    .get(src_bucket, src_resource_id, None::<u64>, None::<u64>, log_context)
+                    .await?;
+
+                self.primary
+                    .put(dst_bucket, dst_resource_id, data.as_ref(), log_context)
+                    .await
+            }
+
+            result => result,
+        }
+    }
+
+    async fn ping(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        self.primary.ping(log_context).await?;
+
+        self.secondary.ping(log_context).await
+    }
+
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        self.primary
+            .presign_post(bucket, resource_id, conditions, log_context)
+            .await
+    }
+
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        if self.primary.exists(bucket, resource_id, log_context).await? {
+            return Ok(true);
+        }
+
+        self.secondary.exists(bucket, resource_id, log_context).await
+    }
+
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        if let Some(size) = self.primary.size(bucket, resource_id, log_context).await? {
+            return Ok(Some(size));
+        }
+
+        self.secondary.size(bucket, resource_id, log_context).await
+    }
+
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        self.primary.list(bucket, after, limit, log_context).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+    use crate::store::memory::{Error, MemoryBackend};
+
+    fn log_context() -> LogContext {
+        LogContext::builder().request_id("").build()
+    }
+
+    async fn backend() -> FallbackBackend<MemoryBackend, MemoryBackend> {
+        let secondary = MemoryBackend::new();
+
+        secondary
+            .put("bucket", "old", &b"0123456789"[..], &log_context())
+            .await
+            .unwrap();
+
+        FallbackBackend::new(MemoryBackend::new(), secondary)
+    }
+
+    #[tokio::test]
+    async fn test_get_from_secondary() {
+        let backend = backend().await;
+        let log_context = log_context();
+
+        assert_eq!(
+            backend.get("bucket", "old", None, None, &log_context).await.unwrap(),
+            &b"0123456789"[..]
+        );
+        assert_eq!(
+            backend.get("bucket", "old", 2, 4, &log_context).await.unwrap(),
+            &b"234"[..]
+        );
+
+        let data = backend
+            .get_stream("bucket", "old", None, None, &log_context)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap()
+            .concat();
+
+        assert_eq!(data, b"0123456789");
+        assert!(backend.exists("bucket", "old", &log_context).await.unwrap());
+
+        let err = backend
+            .get("bucket", "missing", None, None, &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ResourceNotFound(_)));
+        assert!(!backend.exists("bucket", "missing", &log_context).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_primary_first() {
+        let backend = backend().await;
+        let log_context = log_context();
+
+        backend
+            .put("bucket", "new", &b"new"[..], &log_context)
+            .await
+            .unwrap();
+
+        assert!(backend.primary.contains("bucket", "new"));
+        assert!(!backend.secondary.contains("bucket", "new"));
+        assert_eq!(
+            backend.get("bucket", "new", None, None, &log_context).await.unwrap(),
+            &b"new"[..]
+        );
+
+        // the unavailable primary backend is not fallen back
+        backend.primary.inject_error(Error::Unavailable);
+
+        let err = backend
+            .get("bucket", "old", None, None, &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Unavailable));
+
+        // the secondary backend is read only
+        backend.delete("bucket", "old", &log_context).await.unwrap();
+        assert!(backend.secondary.contains("bucket", "old"));
+    }
+
+    #[tokio::test]
+    async fn test_copy_from_secondary() {
+        let backend = backend().await;
+        let log_context = log_context();
+
+        backend
+            .copy("bucket", "old", "other", "copied", &log_context)
+            .await
+            .unwrap();
+
+        assert!(backend.primary.contains("other", "copied"));
+        assert!(!backend.secondary.contains("other", "copied"));
+    }
+}
@@ -0,0 +1,548 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::io::AsyncRead;
+use futures_util::{stream, AsyncReadExt, StreamExt};
+use thiserror::Error;
+
+use crate::log::LogContext;
+use crate::store::{
+    ErrorKind, PostConditions, PresignedPost, ResourceStream, StoreBackend, StoreError,
+    StoredObject,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("bucket {0} not found")]
+    BucketNotFound(String),
+
+    #[error("resource {0} not found")]
+    ResourceNotFound(String),
+
+    #[error("resource {0} is exist")]
+    ResourceExist(String),
+
+    #[error("io error {0}")]
+    Io(#[from] io::Error),
+
+    #[error("bucket {0} is not empty")]
+    BucketNotEmpty(String),
+
+    #[error("memory backend is unavailable")]
+    Unavailable,
+}
+
+impl StoreError for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::BucketNotFound(_) | Error::ResourceNotFound(_) => ErrorKind::NotFound,
+            Error::ResourceExist(_) => ErrorKind::Exist,
+            Error::Io(_) | Error::Unavailable => ErrorKind::Unavailable,
+            Error::BucketNotEmpty(_) => ErrorKind::NotEmpty,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    buckets: HashSet<String>,
+    resources: HashMap<(String, String), Bytes>,
+    injected_error: Option<Error>,
+    delay: Option<Duration>,
+}
+
+/// Keep the resources in memory with the same semantics as the cos backend, so the handler can
+/// be tested without the network.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    inner: Mutex<Inner>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, bucket: &str, resource_id: &str) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .resources
+            .contains_key(&(bucket.to_owned(), resource_id.to_owned()))
+    }
+
+    pub fn contains_bucket(&self, bucket: &str) -> bool {
+        self.inner.lock().unwrap().buckets.contains(bucket)
+    }
+
+    /// Make the next backend call fail with the error.
+    pub fn inject_error(&self, err: Error) {
+        self.inner.lock().unwrap().injected_error.replace(err);
+    }
+
+    /// Delay the puts and the gets, like a stuck backend.
+    pub fn set_delay(&self, delay: Duration) {
+        self.inner.lock().unwrap().delay.replace(delay);
+    }
+
+    async fn wait_delay(&self) {
+        let delay = self.inner.lock().unwrap().delay;
+
+        if let Some(delay) = delay {
+            tokio::time::delay_for(delay).await;
+        }
+    }
+
+    fn take_injected_error(&self) -> Result<(), Error> {
+        match self.inner.lock().unwrap().injected_error.take() {
+            None => Ok(()),
+            Some(err) => Err(err),
+        }
+    }
+
+    fn get_range(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: Option<u64>,
+        end: Option<u64>,
+    ) -> Result<Bytes, Error> {
+        self.take_injected_error()?;
+
+        let inner = self.inner.lock().unwrap();
+
+        if !inner.buckets.contains(bucket) {
+            return Err(Error::BucketNotFound(bucket.to_owned()));
+        }
+
+        let data = match inner
+            .resources
+            .get(&(bucket.to_owned(), resource_id.to_owned()))
+        {
+            None => return Err(Error::ResourceNotFound(resource_id.to_owned())),
+            Some(data) => data,
+        };
+
+        let len = data.len() as u64;
+
+        // the same as the http range, end is included and `-end` means the last end bytes
+        let (start, end) = match (start, end) {
+            (None, None) => (0, len),
+            (Some(start), None) => (start.min(len), len),
+            (Some(start), Some(end)) => (start.min(len), end.saturating_add(1).min(len)),
+            (None, Some(end)) => (len.saturating_sub(end), len),
+        };
+
+        if start >= end {
+            return Ok(Bytes::new());
+        }
+
+        Ok(data.slice(start as usize..end as usize))
+    }
+}
+
+#[async_trait]
+impl StoreBackend for MemoryBackend {
+    type Error = Error;
+
+    async fn put<R: AsyncRead + Send>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource: R,
+        _log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.wait_delay().await;
+        self.take_injected_error()?;
+
+        let mut buf = Vec::with_capacity(4096);
+
+        futures_util::pin_mut!(resource);
+
+        resource.read_to_end(&mut buf).await?;
+
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.buckets.insert(bucket.to_owned());
+
+        let key = (bucket.to_owned(), resource_id.to_owned());
+        if inner.resources.contains_key(&key) {
+            return Err(Error::ResourceExist(resource_id.to_owned()));
+        }
+
+        inner.resources.insert(key, Bytes::from(buf));
+
+        Ok(())
+    }
+
+    async fn get<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        _log_context: &LogContext,
+    ) -> Result<Bytes, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        self.wait_delay().await;
+
+        self.get_range(bucket, resource_id, start.into(), end.into())
+    }
+
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        _log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        self.wait_delay().await;
+
+        let data = self.get_range(bucket, resource_id, start.into(), end.into())?;
+
+        Ok(stream::once(async move { Ok(data) }).boxed())
+    }
+
+    async fn delete(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        _log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.take_injected_error()?;
+
+        self.inner
+            .lock()
+            .unwrap()
+            .resources
+            .remove(&(bucket.to_owned(), resource_id.to_owned()));
+
+        Ok(())
+    }
+
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        _log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.take_injected_error()?;
+
+        self.inner.lock().unwrap().buckets.insert(bucket.to_owned());
+
+        Ok(())
+    }
+
+    async fn delete_bucket(
+        &self,
+        bucket: &str,
+        need_empty: bool,
+        _log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.take_injected_error()?;
+
+        let mut inner = self.inner.lock().unwrap();
+
+        let not_empty = inner
+            .resources
+            .keys()
+            .any(|(resource_bucket, _)| resource_bucket == bucket);
+
+        if not_empty && need_empty {
+            return Err(Error::BucketNotEmpty(bucket.to_owned()));
+        }
+
+        inner
+            .resources
+            .retain(|(resource_bucket, _), _| resource_bucket != bucket);
+        inner.buckets.remove(bucket);
+
+        Ok(())
+    }
+
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        _log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let data = self.get_range(src_bucket, src_resource_id, None, None)?;
+
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.buckets.insert(dst_bucket.to_owned());
+
+        let key = (dst_bucket.to_owned(), dst_resource_id.to_owned());
+        if inner.resources.contains_key(&key) {
+            return Err(Error::ResourceExist(dst_resource_id.to_owned()));
+        }
+
+        inner.resources.insert(key, data);
+
+        Ok(())
+    }
+
+    async fn ping(&self, _log_context: &LogContext) -> Result<(), Self::Error> {
+        self.take_injected_error()
+    }
+
+    /// The form is not signed, the tests put the resource directly instead of posting it.
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        _conditions: &PostConditions,
+        _log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        self.take_injected_error()?;
+
+        Ok(Some(PresignedPost {
+            url: format!("memory://{}", bucket),
+            fields: vec![("key".to_string(), resource_id.to_owned())]
+                .into_iter()
+                .collect(),
+        }))
+    }
+
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        _log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        self.take_injected_error()?;
+
+        let inner = self.inner.lock().unwrap();
+
+        let mut resource_ids = inner
+            .resources
+            .keys()
+            .filter(|(resource_bucket, resource_id)| {
+                resource_bucket == bucket && after.is_none_or(|after| resource_id.as_str() > after)
+            })
+            .map(|(_, resource_id)| resource_id.clone())
+            .collect::<Vec<_>>();
+
+        resource_ids.sort();
+        resource_ids.truncate(limit);
+
+        Ok(Some(
+            resource_ids
+                .into_iter()
+                .map(|id| StoredObject {
+                    id,
+                    last_modified: None,
+                })
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_context() -> LogContext {
+        LogContext::builder().request_id("").build()
+    }
+
+    #[tokio::test]
+    async fn test_put_get() {
+        let backend = MemoryBackend::new();
+        let log_context = log_context();
+
+        backend
+            .put("bucket", "id", &b"0123456789"[..], &log_context)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            backend.get("bucket", "id", None, None, &log_context).await.unwrap(),
+            &b"0123456789"[..]
+        );
+        assert_eq!(
+            backend.get("bucket", "id", 2, 4, &log_context).await.unwrap(),
+            &b"234"[..]
+        );
+        assert_eq!(
+            backend.get("bucket", "id", 8, None, &log_context).await.unwrap(),
+            &b"89"[..]
+        );
+        assert_eq!(
+            backend.get("bucket", "id", None, 3, &log_context).await.unwrap(),
+            &b"789"[..]
+        );
+
+        let err = backend
+            .put("bucket", "id", &b"other"[..], &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ResourceExist(_)));
+    }
+
+    #[tokio::test]
+    async fn test_get_not_exist() {
+        let backend = MemoryBackend::new();
+        let log_context = log_context();
+
+        let err = backend
+            .get("bucket", "id", None, None, &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::BucketNotFound(_)));
+
+        backend
+            .put("bucket", "id", &b"test"[..], &log_context)
+            .await
+            .unwrap();
+
+        let err = backend
+            .get("bucket", "not-exist", None, None, &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ResourceNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_delete_bucket() {
+        let backend = MemoryBackend::new();
+        let log_context = log_context();
+
+        backend
+            .put("bucket", "id", &b"test"[..], &log_context)
+            .await
+            .unwrap();
+
+        let err = backend
+            .delete_bucket("bucket", true, &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::BucketNotEmpty(_)));
+
+        backend
+            .delete_bucket("bucket", false, &log_context)
+            .await
+            .unwrap();
+
+        assert!(!backend.contains("bucket", "id"));
+    }
+
+    #[tokio::test]
+    async fn test_copy() {
+        let backend = MemoryBackend::new();
+        let log_context = log_context();
+
+        backend
+            .put("bucket", "id", &b"test"[..], &log_context)
+            .await
+            .unwrap();
+
+        backend
+            .copy("bucket", "id", "other", "copied", &log_context)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            backend
+                .get("other", "copied", None, None, &log_context)
+                .await
+                .unwrap(),
+            &b"test"[..]
+        );
+
+        let err = backend
+            .copy("bucket", "id", "other", "copied", &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ResourceExist(_)));
+
+        let err = backend
+            .copy("bucket", "missing", "other", "new", &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ResourceNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_exists_list() {
+        let backend = MemoryBackend::new();
+        let log_context = log_context();
+
+        for resource_id in &["c", "a", "b"] {
+            backend
+                .put("bucket", resource_id, &b"test"[..], &log_context)
+                .await
+                .unwrap();
+        }
+
+        backend
+            .put("other", "d", &b"test"[..], &log_context)
+            .await
+            .unwrap();
+
+        assert!(backend.exists("bucket", "a", &log_context).await.unwrap());
+        assert!(!backend.exists("bucket", "d", &log_context).await.unwrap());
+        assert!(!backend.exists("missing", "a", &log_context).await.unwrap());
+
+        let list = |after: Option<&'static str>| {
+            let backend = &backend;
+            let log_context = &log_context;
+
+            async move {
+                backend
+                    .list("bucket", after, 2, log_context)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .into_iter()
+                    .map(|object| object.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(list(None).await, vec!["a", "b"]);
+        assert_eq!(list(Some("b")).await, vec!["c"]);
+        assert!(list(Some("c")).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_inject_error() {
+        let backend = MemoryBackend::new();
+        let log_context = log_context();
+
+        backend.inject_error(Error::Unavailable);
+
+        let err = backend
+            .put("bucket", "id", &b"test"[..], &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(err.is_unavailable());
+
+        // only the next call fails
+        backend
+            .put("bucket", "id", &b"test"[..], &log_context)
+            .await
+            .unwrap();
+    }
+}
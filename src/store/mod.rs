@@ -1,18 +1,82 @@
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::fmt::Debug;
 use std::ops::Deref;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures_util::io::AsyncRead;
+use futures_util::stream::BoxStream;
+use serde::Serialize;
+use slog::error;
 
-use crate::log::LogContext;
+use crate::log::{self, LogContext};
 
+pub mod circuit_breaker;
 pub mod cos;
+pub mod fallback;
+#[cfg(test)]
+pub mod memory;
+pub mod replicated;
+pub mod traced;
+
+pub type ResourceStream<E> = BoxStream<'static, Result<Bytes, E>>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The bucket or the resource is not found.
+    NotFound,
+
+    /// The bucket or the resource is already exist.
+    Exist,
+
+    /// The backend can't serve requests now, such as network, io or server side errors.
+    Unavailable,
+
+    /// The bucket still has resources.
+    NotEmpty,
+
+    Other,
+}
+
+/// The restrictions of the presigned POST upload.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PostConditions {
+    /// The `Content-Type` field must start with it, the field is not required if it is not set.
+    pub content_type_prefix: Option<String>,
+    pub max_size: u64,
+    pub expires_in: Duration,
+}
+
+/// A presigned POST form, the browser posts the fields with the file to the url.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct PresignedPost {
+    pub url: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+/// A resource listed from the backend.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StoredObject {
+    pub id: String,
+    /// `None` if the backend doesn't record it.
+    pub last_modified: Option<SystemTime>,
+}
+
+pub trait StoreError: Error {
+    fn kind(&self) -> ErrorKind;
+
+    fn is_unavailable(&self) -> bool {
+        self.kind() == ErrorKind::Unavailable
+    }
+}
 
 #[async_trait]
 pub trait StoreBackend {
-    type Error: Error;
+    type Error: StoreError;
 
     async fn put<R: AsyncRead + Send>(
         &self,
@@ -34,6 +98,19 @@ pub trait StoreBackend {
             S: Into<Option<u64>> + Send,
             E: Into<Option<u64>> + Send;
 
+    /// Like `get`, but return the resource as a stream instead of buffering all of it.
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send;
+
     async fn delete(
         &self,
         bucket: &str,
@@ -41,12 +118,125 @@ pub trait StoreBackend {
         log_context: &LogContext,
     ) -> Result<(), Self::Error>;
 
+    /// Delete resources in the same bucket, return the resource ids which are failed to delete.
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        let mut failed_ids = vec![];
+
+        for resource_id in resource_ids {
+            if let Err(err) = self.delete(bucket, resource_id, log_context).await {
+                error!(
+                    log::get_logger(),
+                    "delete bucket {} resource {} failed: {:?}",
+                    bucket, resource_id, err;
+                    log_context
+                );
+
+                failed_ids.push(resource_id.clone());
+            }
+        }
+
+        Ok(failed_ids)
+    }
+
+    /// Create the bucket if it doesn't exist, so the first put to it doesn't pay for creating
+    /// it. The backends which need no buckets keep the default one.
+    async fn create_bucket(
+        &self,
+        _bucket: &str,
+        _log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     async fn delete_bucket(
         &self,
         bucket: &str,
         need_empty: bool,
         log_context: &LogContext,
     ) -> Result<(), Self::Error>;
+
+    /// Copy the resource to another bucket or id, the destination must not exist. The backends
+    /// which can copy on the server side should override it, the default one passes the bytes
+    /// through this service.
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let data = self
+            .get(src_bucket, src_resource_id, None::<u64>, None::<u64>, log_context)
+            .await?;
+
+        self.put(dst_bucket, dst_resource_id, data.as_ref(), log_context)
+            .await
+    }
+
+    /// Check the backend is reachable and the credential is accepted.
+    async fn ping(&self, _log_context: &LogContext) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Presign a POST form, so the browser can upload the resource to the backend directly,
+    /// return `None` if the backend doesn't support it.
+    async fn presign_post(
+        &self,
+        _bucket: &str,
+        _resource_id: &str,
+        _conditions: &PostConditions,
+        _log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Check the resource exists, the backends which can check it without fetching the resource
+    /// should override it.
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        match self.get(bucket, resource_id, 0u64, 0u64, log_context).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Get the size of the resource, `None` if it is not found, the backends which can get it
+    /// without fetching the resource should override it.
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        match self.get(bucket, resource_id, None, None, log_context).await {
+            Ok(data) => Ok(Some(data.len() as u64)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// List at most `limit` resources of the bucket in the id order, starting after the `after`
+    /// id, return `None` if the backend doesn't support listing.
+    async fn list(
+        &self,
+        _bucket: &str,
+        _after: Option<&str>,
+        _limit: usize,
+        _log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        Ok(None)
+    }
 }
 
 #[async_trait]
@@ -84,6 +274,24 @@ impl<T: StoreBackend + Send + Sync> StoreBackend for &T {
             .await
     }
 
+    #[inline]
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+    where
+        S: Into<Option<u64>> + Send,
+        E: Into<Option<u64>> + Send,
+    {
+        (*self)
+            .get_stream(bucket, resource_id, start, end, log_context)
+            .await
+    }
+
     #[inline]
     async fn delete(
         &self,
@@ -94,6 +302,25 @@ impl<T: StoreBackend + Send + Sync> StoreBackend for &T {
         (*self).delete(bucket, resource_id, log_context).await
     }
 
+    #[inline]
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        (*self).delete_many(bucket, resource_ids, log_context).await
+    }
+
+    #[inline]
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        (*self).create_bucket(bucket, log_context).await
+    }
+
     #[inline]
     async fn delete_bucket(
         &self,
@@ -103,6 +330,75 @@ impl<T: StoreBackend + Send + Sync> StoreBackend for &T {
     ) -> Result<(), Self::Error> {
         (*self).delete_bucket(bucket, need_empty, log_context).await
     }
+
+    #[inline]
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        (*self)
+            .copy(
+                src_bucket,
+                src_resource_id,
+                dst_bucket,
+                dst_resource_id,
+                log_context,
+            )
+            .await
+    }
+
+    #[inline]
+    async fn ping(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        (*self).ping(log_context).await
+    }
+
+    #[inline]
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        (*self)
+            .presign_post(bucket, resource_id, conditions, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        (*self).exists(bucket, resource_id, log_context).await
+    }
+
+    #[inline]
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        (*self).size(bucket, resource_id, log_context).await
+    }
+
+    #[inline]
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        (*self).list(bucket, after, limit, log_context).await
+    }
 }
 
 #[async_trait]
@@ -140,6 +436,24 @@ impl<T: StoreBackend + Send + Sync> StoreBackend for Box<T> {
             .await
     }
 
+    #[inline]
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        self.deref()
+            .get_stream(bucket, resource_id, start, end, log_context)
+            .await
+    }
+
     #[inline]
     async fn delete(
         &self,
@@ -150,6 +464,27 @@ impl<T: StoreBackend + Send + Sync> StoreBackend for Box<T> {
         self.deref().delete(bucket, resource_id, log_context).await
     }
 
+    #[inline]
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        self.deref()
+            .delete_many(bucket, resource_ids, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.deref().create_bucket(bucket, log_context).await
+    }
+
     #[inline]
     async fn delete_bucket(
         &self,
@@ -161,6 +496,75 @@ impl<T: StoreBackend + Send + Sync> StoreBackend for Box<T> {
             .delete_bucket(bucket, need_empty, log_context)
             .await
     }
+
+    #[inline]
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.deref()
+            .copy(
+                src_bucket,
+                src_resource_id,
+                dst_bucket,
+                dst_resource_id,
+                log_context,
+            )
+            .await
+    }
+
+    #[inline]
+    async fn ping(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        self.deref().ping(log_context).await
+    }
+
+    #[inline]
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        self.deref()
+            .presign_post(bucket, resource_id, conditions, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        self.deref().exists(bucket, resource_id, log_context).await
+    }
+
+    #[inline]
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        self.deref().size(bucket, resource_id, log_context).await
+    }
+
+    #[inline]
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        self.deref().list(bucket, after, limit, log_context).await
+    }
 }
 
 #[async_trait]
@@ -198,6 +602,24 @@ impl<T: StoreBackend + Send + Sync> StoreBackend for Arc<T> {
             .await
     }
 
+    #[inline]
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        self.deref()
+            .get_stream(bucket, resource_id, start, end, log_context)
+            .await
+    }
+
     #[inline]
     async fn delete(
         &self,
@@ -208,6 +630,27 @@ impl<T: StoreBackend + Send + Sync> StoreBackend for Arc<T> {
         self.deref().delete(bucket, resource_id, log_context).await
     }
 
+    #[inline]
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        self.deref()
+            .delete_many(bucket, resource_ids, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.deref().create_bucket(bucket, log_context).await
+    }
+
     #[inline]
     async fn delete_bucket(
         &self,
@@ -219,4 +662,551 @@ impl<T: StoreBackend + Send + Sync> StoreBackend for Arc<T> {
             .delete_bucket(bucket, need_empty, log_context)
             .await
     }
+
+    #[inline]
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.deref()
+            .copy(
+                src_bucket,
+                src_resource_id,
+                dst_bucket,
+                dst_resource_id,
+                log_context,
+            )
+            .await
+    }
+
+    #[inline]
+    async fn ping(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        self.deref().ping(log_context).await
+    }
+
+    #[inline]
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        self.deref()
+            .presign_post(bucket, resource_id, conditions, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        self.deref().exists(bucket, resource_id, log_context).await
+    }
+
+    #[inline]
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        self.deref().size(bucket, resource_id, log_context).await
+    }
+
+    #[inline]
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        self.deref().list(bucket, after, limit, log_context).await
+    }
+}
+
+/// The backend which is selected at runtime, such as by the config.
+pub type DynBackend<E> = Box<dyn DynStoreBackend<Error = E> + Send + Sync>;
+
+/// The object safe facade of `StoreBackend`, the generic arguments are boxed or converted, so
+/// the backends with the same error type can be held as `DynBackend`. Every `StoreBackend`
+/// implements it by forwarding to the generic methods, and `DynBackend` implements
+/// `StoreBackend` again, so the handler is still generic over it.
+#[async_trait]
+pub trait DynStoreBackend: Debug {
+    type Error: StoreError;
+
+    async fn put_dyn<'r>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource: Pin<Box<dyn AsyncRead + Send + 'r>>,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error>;
+
+    async fn get_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: Option<u64>,
+        end: Option<u64>,
+        log_context: &LogContext,
+    ) -> Result<Bytes, Self::Error>;
+
+    async fn get_stream_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: Option<u64>,
+        end: Option<u64>,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>;
+
+    async fn delete_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error>;
+
+    async fn delete_many_dyn(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error>;
+
+    async fn create_bucket_dyn(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error>;
+
+    async fn delete_bucket_dyn(
+        &self,
+        bucket: &str,
+        need_empty: bool,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error>;
+
+    async fn copy_dyn(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error>;
+
+    async fn ping_dyn(&self, log_context: &LogContext) -> Result<(), Self::Error>;
+
+    async fn presign_post_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error>;
+
+    async fn exists_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error>;
+
+    async fn size_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error>;
+
+    async fn list_dyn(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error>;
+}
+
+#[async_trait]
+impl<T: StoreBackend + Debug + Send + Sync> DynStoreBackend for T {
+    type Error = T::Error;
+
+    #[inline]
+    async fn put_dyn<'r>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource: Pin<Box<dyn AsyncRead + Send + 'r>>,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.put(bucket, resource_id, resource, log_context).await
+    }
+
+    #[inline]
+    async fn get_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: Option<u64>,
+        end: Option<u64>,
+        log_context: &LogContext,
+    ) -> Result<Bytes, Self::Error> {
+        self.get(bucket, resource_id, start, end, log_context).await
+    }
+
+    #[inline]
+    async fn get_stream_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: Option<u64>,
+        end: Option<u64>,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error> {
+        self.get_stream(bucket, resource_id, start, end, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn delete_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.delete(bucket, resource_id, log_context).await
+    }
+
+    #[inline]
+    async fn delete_many_dyn(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        self.delete_many(bucket, resource_ids, log_context).await
+    }
+
+    #[inline]
+    async fn create_bucket_dyn(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.create_bucket(bucket, log_context).await
+    }
+
+    #[inline]
+    async fn delete_bucket_dyn(
+        &self,
+        bucket: &str,
+        need_empty: bool,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.delete_bucket(bucket, need_empty, log_context).await
+    }
+
+    #[inline]
+    async fn copy_dyn(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.copy(
+            src_bucket,
+            src_resource_id,
+            dst_bucket,
+            dst_resource_id,
+            log_context,
+        )
+            .await
+    }
+
+    #[inline]
+    async fn ping_dyn(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        self.ping(log_context).await
+    }
+
+    #[inline]
+    async fn presign_post_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        self.presign_post(bucket, resource_id, conditions, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn exists_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        self.exists(bucket, resource_id, log_context).await
+    }
+
+    #[inline]
+    async fn size_dyn(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        self.size(bucket, resource_id, log_context).await
+    }
+
+    #[inline]
+    async fn list_dyn(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        self.list(bucket, after, limit, log_context).await
+    }
+}
+
+#[async_trait]
+impl<Err: StoreError + Send + 'static> StoreBackend for DynBackend<Err> {
+    type Error = Err;
+
+    #[inline]
+    async fn put<R: AsyncRead + Send>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource: R,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.deref()
+            .put_dyn(bucket, resource_id, Box::pin(resource), log_context)
+            .await
+    }
+
+    #[inline]
+    async fn get<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<Bytes, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        self.deref()
+            .get_dyn(bucket, resource_id, start.into(), end.into(), log_context)
+            .await
+    }
+
+    #[inline]
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        self.deref()
+            .get_stream_dyn(bucket, resource_id, start.into(), end.into(), log_context)
+            .await
+    }
+
+    #[inline]
+    async fn delete(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.deref()
+            .delete_dyn(bucket, resource_id, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        self.deref()
+            .delete_many_dyn(bucket, resource_ids, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.deref().create_bucket_dyn(bucket, log_context).await
+    }
+
+    #[inline]
+    async fn delete_bucket(
+        &self,
+        bucket: &str,
+        need_empty: bool,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.deref()
+            .delete_bucket_dyn(bucket, need_empty, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        self.deref()
+            .copy_dyn(
+                src_bucket,
+                src_resource_id,
+                dst_bucket,
+                dst_resource_id,
+                log_context,
+            )
+            .await
+    }
+
+    #[inline]
+    async fn ping(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        self.deref().ping_dyn(log_context).await
+    }
+
+    #[inline]
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        self.deref()
+            .presign_post_dyn(bucket, resource_id, conditions, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        self.deref()
+            .exists_dyn(bucket, resource_id, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        self.deref()
+            .size_dyn(bucket, resource_id, log_context)
+            .await
+    }
+
+    #[inline]
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        self.deref()
+            .list_dyn(bucket, after, limit, log_context)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::{self, MemoryBackend};
+    use super::replicated::{ReplicaPolicy, ReplicatedBackend};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dyn_backends() {
+        let log_cx = LogContext::builder().request_id("").build();
+
+        let backends: Vec<DynBackend<memory::Error>> = vec![
+            Box::new(MemoryBackend::default()),
+            Box::new(ReplicatedBackend::new(
+                MemoryBackend::default(),
+                MemoryBackend::default(),
+                ReplicaPolicy::Primary,
+            )),
+        ];
+
+        for backend in &backends {
+            backend.create_bucket("bucket", &log_cx).await.unwrap();
+            backend
+                .put("bucket", "id", &b"0123456789"[..], &log_cx)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                backend.get("bucket", "id", 2, 5, &log_cx).await.unwrap(),
+                &b"2345"[..]
+            );
+            assert_eq!(
+                backend
+                    .get("bucket", "id", None, None, &log_cx)
+                    .await
+                    .unwrap(),
+                &b"0123456789"[..]
+            );
+            assert!(backend.exists("bucket", "id", &log_cx).await.unwrap());
+
+            backend.delete("bucket", "id", &log_cx).await.unwrap();
+
+            let err = backend
+                .get("bucket", "id", None, None, &log_cx)
+                .await
+                .unwrap_err();
+
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+        }
+    }
 }
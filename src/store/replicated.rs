@@ -0,0 +1,555 @@
+use std::io;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::future;
+use futures_util::io::{AsyncRead, AsyncReadExt};
+use slog::warn;
+
+use crate::log::{self, LogContext};
+use crate::store::{
+    ErrorKind, PostConditions, PresignedPost, ResourceStream, StoreBackend, StoreError,
+    StoredObject,
+};
+
+/// Whether the failed writes of the replica backend fail the request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReplicaPolicy {
+    /// Only the primary backend must succeed, the replica failures are logged, so the replica
+    /// can be reconciled later.
+    Primary,
+    /// Both backends must succeed.
+    All,
+}
+
+impl ReplicaPolicy {
+    pub const PRIMARY: &'static str = "primary";
+    pub const ALL: &'static str = "all";
+}
+
+impl FromStr for ReplicaPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            Self::PRIMARY => Ok(ReplicaPolicy::Primary),
+            Self::ALL => Ok(ReplicaPolicy::All),
+            s => Err(anyhow::anyhow!(
+                "replica policy {} is invalid, must be {} or {}",
+                s,
+                Self::PRIMARY,
+                Self::ALL
+            )),
+        }
+    }
+}
+
+/// Write every resource to the primary and the replica backends, and read it from the first
+/// backend which has it. The presigned uploads and the listing only go to the primary backend.
+#[derive(Debug)]
+pub struct ReplicatedBackend<P, R> {
+    primary: P,
+    replica: R,
+    policy: ReplicaPolicy,
+}
+
+impl<P, R> ReplicatedBackend<P, R> {
+    pub fn new(primary: P, replica: R, policy: ReplicaPolicy) -> Self {
+        Self {
+            primary,
+            replica,
+            policy,
+        }
+    }
+
+    /// Log the failed replica write, it only fails the request with the `All` policy.
+    fn check_replica<T, E: StoreError>(
+        &self,
+        op: &str,
+        bucket: &str,
+        resource_id: Option<&str>,
+        result: Result<T, E>,
+        log_context: &LogContext,
+    ) -> Result<(), E> {
+        let err = match result {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        };
+
+        warn!(
+            log::get_logger(),
+            "replica backend {} failed: {}", op, err;
+            log_context,
+            "bucket" => bucket,
+            "resource" => resource_id,
+            "policy" => format!("{:?}", self.policy)
+        );
+
+        match self.policy {
+            ReplicaPolicy::Primary => Ok(()),
+            ReplicaPolicy::All => Err(err),
+        }
+    }
+}
+
+fn log_read_replica<E: StoreError>(
+    op: &str,
+    bucket: &str,
+    resource_id: &str,
+    err: &E,
+    log_context: &LogContext,
+) {
+    if err.kind() != ErrorKind::NotFound {
+        warn!(
+            log::get_logger(),
+            "primary backend {} failed, read replica backend: {}", op, err;
+            log_context,
+            "bucket" => bucket,
+            "resource" => resource_id
+        );
+    }
+}
+
+#[async_trait]
+impl<P, R> StoreBackend for ReplicatedBackend<P, R>
+    where
+        P: StoreBackend + Send + Sync,
+        P::Error: From<io::Error> + Send + 'static,
+        R: StoreBackend<Error=P::Error> + Send + Sync,
+{
+    type Error = P::Error;
+
+    async fn put<RD: AsyncRead + Send>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource: RD,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let mut buf = Vec::with_capacity(4096);
+
+        futures_util::pin_mut!(resource);
+
+        resource.read_to_end(&mut buf).await?;
+
+        let (primary_result, replica_result) = future::join(
+            self.primary
+                .put(bucket, resource_id, buf.as_slice(), log_context),
+            self.replica
+                .put(bucket, resource_id, buf.as_slice(), log_context),
+        )
+            .await;
+
+        primary_result?;
+
+        self.check_replica("put", bucket, Some(resource_id), replica_result, log_context)
+    }
+
+    async fn get<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<Bytes, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        let (start, end) = (start.into(), end.into());
+
+        let err = match self
+            .primary
+            .get(bucket, resource_id, start, end, log_context)
+            .await
+        {
+            Ok(data) => return Ok(data),
+            Err(err) => err,
+        };
+
+        log_read_replica("get", bucket, resource_id, &err, log_context);
+
+        // the primary error is more useful when the replica doesn't have it either
+        self.replica
+            .get(bucket, resource_id, start, end, log_context)
+            .await
+            .map_err(|_| err)
+    }
+
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        let (start, end) = (start.into(), end.into());
+
+        let err = match self
+            .primary
+            .get_stream(bucket, resource_id, start, end, log_context)
+            .await
+        {
+            Ok(stream) => return Ok(stream),
+            Err(err) => err,
+        };
+
+        log_read_replica("get_stream", bucket, resource_id, &err, log_context);
+
+        self.replica
+            .get_stream(bucket, resource_id, start, end, log_context)
+            .await
+            .map_err(|_| err)
+    }
+
+    async fn delete(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let (primary_result, replica_result) = future::join(
+            self.primary.delete(bucket, resource_id, log_context),
+            self.replica.delete(bucket, resource_id, log_context),
+        )
+            .await;
+
+        primary_result?;
+
+        self.check_replica("delete", bucket, Some(resource_id), replica_result, log_context)
+    }
+
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        let (primary_result, replica_result) = future::join(
+            self.primary.delete_many(bucket, resource_ids, log_context),
+            self.replica.delete_many(bucket, resource_ids, log_context),
+        )
+            .await;
+
+        let mut failed_ids = primary_result?;
+
+        match replica_result {
+            Ok(replica_failed_ids) if replica_failed_ids.is_empty() => {}
+
+            Ok(replica_failed_ids) => {
+                warn!(
+                    log::get_logger(),
+                    "replica backend delete resources failed";
+                    log_context,
+                    "bucket" => bucket,
+                    "resources" => format!("{:?}", replica_failed_ids)
+                );
+
+                if self.policy == ReplicaPolicy::All {
+                    for resource_id in replica_failed_ids {
+                        if !failed_ids.contains(&resource_id) {
+                            failed_ids.push(resource_id);
+                        }
+                    }
+                }
+            }
+
+            Err(err) => {
+                self.check_replica("delete_many", bucket, None, Err::<(), _>(err), log_context)?
+            }
+        }
+
+        Ok(failed_ids)
+    }
+
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let (primary_result, replica_result) = future::join(
+            self.primary.create_bucket(bucket, log_context),
+            self.replica.create_bucket(bucket, log_context),
+        )
+            .await;
+
+        primary_result?;
+
+        self.check_replica("create_bucket", bucket, None, replica_result, log_context)
+    }
+
+    async fn delete_bucket(
+        &self,
+        bucket: &str,
+        need_empty: bool,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let (primary_result, replica_result) = future::join(
+            self.primary.delete_bucket(bucket, need_empty, log_context),
+            self.replica.delete_bucket(bucket, need_empty, log_context),
+        )
+            .await;
+
+        primary_result?;
+
+        // the replica may never have the bucket, such as it is added after the bucket is created
+        let replica_result = match replica_result {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        };
+
+        self.check_replica("delete_bucket", bucket, None, replica_result, log_context)
+    }
+
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let (primary_result, replica_result) = future::join(
+            self.primary.copy(
+                src_bucket,
+                src_resource_id,
+                dst_bucket,
+                dst_resource_id,
+                log_context,
+            ),
+            self.replica.copy(
+                src_bucket,
+                src_resource_id,
+                dst_bucket,
+                dst_resource_id,
+                log_context,
+            ),
+        )
+            .await;
+
+        primary_result?;
+
+        self.check_replica(
+            "copy",
+            dst_bucket,
+            Some(dst_resource_id),
+            replica_result,
+            log_context,
+        )
+    }
+
+    async fn ping(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        self.primary.ping(log_context).await?;
+
+        match self.replica.ping(log_context).await {
+            Err(err) if self.policy == ReplicaPolicy::All => Err(err),
+
+            Err(err) => {
+                warn!(log::get_logger(), "replica backend ping failed: {}", err; log_context);
+
+                Ok(())
+            }
+
+            Ok(_) => Ok(()),
+        }
+    }
+
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        self.primary
+            .presign_post(bucket, resource_id, conditions, log_context)
+            .await
+    }
+
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        match self.primary.exists(bucket, resource_id, log_context).await {
+            Ok(true) => Ok(true),
+            Ok(false) => self.replica.exists(bucket, resource_id, log_context).await,
+
+            Err(err) => {
+                log_read_replica("exists", bucket, resource_id, &err, log_context);
+
+                self.replica
+                    .exists(bucket, resource_id, log_context)
+                    .await
+                    .map_err(|_| err)
+            }
+        }
+    }
+
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        match self.primary.size(bucket, resource_id, log_context).await {
+            Ok(Some(size)) => Ok(Some(size)),
+            Ok(None) => self.replica.size(bucket, resource_id, log_context).await,
+
+            Err(err) => {
+                log_read_replica("size", bucket, resource_id, &err, log_context);
+
+                self.replica
+                    .size(bucket, resource_id, log_context)
+                    .await
+                    .map_err(|_| err)
+            }
+        }
+    }
+
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        self.primary.list(bucket, after, limit, log_context).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::{Error, MemoryBackend};
+
+    fn log_context() -> LogContext {
+        LogContext::builder().request_id("").build()
+    }
+
+    fn replicated_backend(policy: ReplicaPolicy) -> ReplicatedBackend<MemoryBackend, MemoryBackend> {
+        ReplicatedBackend::new(MemoryBackend::new(), MemoryBackend::new(), policy)
+    }
+
+    #[test]
+    fn test_replica_policy_from_str() {
+        assert_eq!(
+            ReplicaPolicy::from_str("primary").unwrap(),
+            ReplicaPolicy::Primary
+        );
+        assert_eq!(ReplicaPolicy::from_str("all").unwrap(), ReplicaPolicy::All);
+        assert_eq!(
+            ReplicaPolicy::from_str("any").unwrap_err().to_string(),
+            "replica policy any is invalid, must be primary or all"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_both() {
+        let backend = replicated_backend(ReplicaPolicy::All);
+        let log_context = log_context();
+
+        backend
+            .put("bucket", "id", &b"data"[..], &log_context)
+            .await
+            .unwrap();
+
+        assert!(backend.primary.contains("bucket", "id"));
+        assert!(backend.replica.contains("bucket", "id"));
+
+        backend.delete("bucket", "id", &log_context).await.unwrap();
+
+        assert!(!backend.primary.contains("bucket", "id"));
+        assert!(!backend.replica.contains("bucket", "id"));
+    }
+
+    #[tokio::test]
+    async fn test_put_replica_down() {
+        let log_context = log_context();
+
+        let backend = replicated_backend(ReplicaPolicy::Primary);
+        backend.replica.inject_error(Error::Unavailable);
+
+        backend
+            .put("bucket", "id", &b"data"[..], &log_context)
+            .await
+            .unwrap();
+
+        assert!(backend.primary.contains("bucket", "id"));
+        assert!(!backend.replica.contains("bucket", "id"));
+
+        let backend = replicated_backend(ReplicaPolicy::All);
+        backend.replica.inject_error(Error::Unavailable);
+
+        let err = backend
+            .put("bucket", "id", &b"data"[..], &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Unavailable));
+    }
+
+    #[tokio::test]
+    async fn test_put_primary_down() {
+        let backend = replicated_backend(ReplicaPolicy::Primary);
+        let log_context = log_context();
+
+        backend.primary.inject_error(Error::Unavailable);
+
+        let err = backend
+            .put("bucket", "id", &b"data"[..], &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Unavailable));
+    }
+
+    #[tokio::test]
+    async fn test_get_primary_down() {
+        let backend = replicated_backend(ReplicaPolicy::Primary);
+        let log_context = log_context();
+
+        backend
+            .put("bucket", "id", &b"0123456789"[..], &log_context)
+            .await
+            .unwrap();
+
+        backend.primary.inject_error(Error::Unavailable);
+
+        assert_eq!(
+            backend.get("bucket", "id", 2, 4, &log_context).await.unwrap(),
+            &b"234"[..]
+        );
+
+        // only in the replica
+        backend
+            .replica
+            .put("bucket", "replica", &b"replica"[..], &log_context)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            backend.get("bucket", "replica", None, None, &log_context).await.unwrap(),
+            &b"replica"[..]
+        );
+        assert!(backend.exists("bucket", "replica", &log_context).await.unwrap());
+
+        // the primary error is returned when neither has it
+        backend.primary.inject_error(Error::Unavailable);
+
+        let err = backend
+            .get("bucket", "missing", None, None, &log_context)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Unavailable));
+    }
+}
@@ -0,0 +1,251 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::io::AsyncRead;
+
+use crate::log::LogContext;
+use crate::store::{PostConditions, PresignedPost, ResourceStream, StoreBackend, StoredObject};
+use crate::telemetry::{Span, SpanKind};
+
+/// Record a client span for every store request.
+#[derive(Debug)]
+pub struct TracedBackend<S> {
+    backend: S,
+}
+
+impl<S> TracedBackend<S> {
+    pub fn new(backend: S) -> Self {
+        Self { backend }
+    }
+}
+
+fn start_span(name: &str, bucket: &str, log_context: &LogContext) -> Span {
+    let mut span = Span::start(name, SpanKind::Client, log_context);
+    span.set_attribute("bucket", bucket);
+
+    span
+}
+
+fn finish_span<T, E>(mut span: Span, result: Result<T, E>) -> Result<T, E> {
+    if result.is_err() {
+        span.set_error();
+    }
+
+    span.end();
+
+    result
+}
+
+#[async_trait]
+impl<B> StoreBackend for TracedBackend<B>
+    where
+        B: StoreBackend + Send + Sync,
+        B::Error: Send + 'static,
+{
+    type Error = B::Error;
+
+    async fn put<R: AsyncRead + Send>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        resource: R,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let span = start_span("store.put", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend
+                .put(bucket, resource_id, resource, log_context)
+                .await,
+        )
+    }
+
+    async fn get<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<Bytes, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        let span = start_span("store.get", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend
+                .get(bucket, resource_id, start, end, log_context)
+                .await,
+        )
+    }
+
+    async fn get_stream<S, E>(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        start: S,
+        end: E,
+        log_context: &LogContext,
+    ) -> Result<ResourceStream<Self::Error>, Self::Error>
+        where
+            S: Into<Option<u64>> + Send,
+            E: Into<Option<u64>> + Send,
+    {
+        let span = start_span("store.get_stream", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend
+                .get_stream(bucket, resource_id, start, end, log_context)
+                .await,
+        )
+    }
+
+    async fn delete(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let span = start_span("store.delete", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend.delete(bucket, resource_id, log_context).await,
+        )
+    }
+
+    async fn delete_many(
+        &self,
+        bucket: &str,
+        resource_ids: &[String],
+        log_context: &LogContext,
+    ) -> Result<Vec<String>, Self::Error> {
+        let span = start_span("store.delete_many", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend
+                .delete_many(bucket, resource_ids, log_context)
+                .await,
+        )
+    }
+
+    async fn create_bucket(
+        &self,
+        bucket: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let span = start_span("store.create_bucket", bucket, log_context);
+
+        finish_span(span, self.backend.create_bucket(bucket, log_context).await)
+    }
+
+    async fn delete_bucket(
+        &self,
+        bucket: &str,
+        need_empty: bool,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let span = start_span("store.delete_bucket", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend
+                .delete_bucket(bucket, need_empty, log_context)
+                .await,
+        )
+    }
+
+    async fn copy(
+        &self,
+        src_bucket: &str,
+        src_resource_id: &str,
+        dst_bucket: &str,
+        dst_resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<(), Self::Error> {
+        let mut span = start_span("store.copy", src_bucket, log_context);
+        span.set_attribute("dst_bucket", dst_bucket);
+
+        finish_span(
+            span,
+            self.backend
+                .copy(
+                    src_bucket,
+                    src_resource_id,
+                    dst_bucket,
+                    dst_resource_id,
+                    log_context,
+                )
+                .await,
+        )
+    }
+
+    async fn ping(&self, log_context: &LogContext) -> Result<(), Self::Error> {
+        self.backend.ping(log_context).await
+    }
+
+    async fn presign_post(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        conditions: &PostConditions,
+        log_context: &LogContext,
+    ) -> Result<Option<PresignedPost>, Self::Error> {
+        let span = start_span("store.presign_post", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend
+                .presign_post(bucket, resource_id, conditions, log_context)
+                .await,
+        )
+    }
+
+    async fn exists(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<bool, Self::Error> {
+        let span = start_span("store.exists", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend.exists(bucket, resource_id, log_context).await,
+        )
+    }
+
+    async fn size(
+        &self,
+        bucket: &str,
+        resource_id: &str,
+        log_context: &LogContext,
+    ) -> Result<Option<u64>, Self::Error> {
+        let span = start_span("store.size", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend.size(bucket, resource_id, log_context).await,
+        )
+    }
+
+    async fn list(
+        &self,
+        bucket: &str,
+        after: Option<&str>,
+        limit: usize,
+        log_context: &LogContext,
+    ) -> Result<Option<Vec<StoredObject>>, Self::Error> {
+        let span = start_span("store.list", bucket, log_context);
+
+        finish_span(
+            span,
+            self.backend.list(bucket, after, limit, log_context).await,
+        )
+    }
+}
@@ -0,0 +1,261 @@
+//! A small span recorder which follows the W3C trace context of the requests. The spans are
+//! only recorded when a tracer is installed, otherwise every span is a no-op.
+
+// the exporters are only built with the `otlp` feature
+#![cfg_attr(not(feature = "otlp"), allow(dead_code))]
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use once_cell::sync::OnceCell;
+
+use crate::log::LogContext;
+
+#[cfg(feature = "otlp")]
+pub mod otlp;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SpanKind {
+    Internal,
+    Server,
+    Client,
+}
+
+/// A finished span.
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub name: String,
+    pub kind: SpanKind,
+    pub start_time: SystemTime,
+    pub end_time: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+    pub error: bool,
+}
+
+pub trait SpanExporter: Debug + Send + Sync {
+    /// Called when a span ends, it must not block.
+    fn export(&self, span: SpanData);
+}
+
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    exporter: Arc<dyn SpanExporter>,
+}
+
+impl Tracer {
+    pub fn new<E: SpanExporter + 'static>(exporter: E) -> Self {
+        Self {
+            exporter: Arc::new(exporter),
+        }
+    }
+}
+
+static TRACER: OnceCell<Tracer> = OnceCell::new();
+
+/// Install the global tracer, return false if a tracer is installed already.
+pub fn set_tracer(tracer: Tracer) -> bool {
+    TRACER.set(tracer).is_ok()
+}
+
+fn get_tracer() -> Option<&'static Tracer> {
+    TRACER.get()
+}
+
+/// A recording span, it is exported when ended or dropped.
+#[derive(Debug)]
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+impl Span {
+    /// Start a child span of the span in the log context, a new trace is started when the log
+    /// context has no trace.
+    pub fn start(name: &str, kind: SpanKind, log_cx: &LogContext) -> Self {
+        let trace_id = log_cx
+            .trace_id()
+            .map_or_else(new_trace_id, ToOwned::to_owned);
+
+        Self::with_ids(
+            name,
+            kind,
+            &trace_id,
+            &new_span_id(),
+            log_cx.span_id(),
+        )
+    }
+
+    /// Start a span whose ids are decided by the caller, such as the server span of a request.
+    pub fn with_ids(
+        name: &str,
+        kind: SpanKind,
+        trace_id: &str,
+        span_id: &str,
+        parent_span_id: Option<&str>,
+    ) -> Self {
+        if get_tracer().is_none() {
+            return Self { data: None };
+        }
+
+        let now = SystemTime::now();
+
+        Self {
+            data: Some(SpanData {
+                trace_id: trace_id.to_owned(),
+                span_id: span_id.to_owned(),
+                parent_span_id: parent_span_id.map(ToOwned::to_owned),
+                name: name.to_owned(),
+                kind,
+                start_time: now,
+                end_time: now,
+                attributes: vec![],
+                error: false,
+            }),
+        }
+    }
+
+    pub fn set_attribute<V: ToString>(&mut self, key: &'static str, value: V) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.to_string()));
+        }
+    }
+
+    pub fn set_error(&mut self) {
+        if let Some(data) = &mut self.data {
+            data.error = true;
+        }
+    }
+
+    /// The `traceparent` of this span, `None` when the span is not recording.
+    pub fn trace_parent(&self) -> Option<String> {
+        self.data
+            .as_ref()
+            .map(|data| format!("00-{}-{}-01", data.trace_id, data.span_id))
+    }
+
+    /// The log context of the child spans.
+    pub fn log_context(&self, log_cx: &LogContext) -> LogContext {
+        match &self.data {
+            None => log_cx.clone(),
+            Some(data) => LogContext::builder()
+                .request_id(log_cx.request_id())
+                .trace_id(&data.trace_id)
+                .span_id(&data.span_id)
+                .build(),
+        }
+    }
+
+    pub fn end(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end_time = SystemTime::now();
+
+            if let Some(tracer) = get_tracer() {
+                tracer.exporter.export(data);
+            }
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+fn new_trace_id() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+fn new_span_id() -> String {
+    hex::encode(rand::random::<[u8; 8]>())
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Keep the exported spans in memory.
+    #[derive(Debug, Default)]
+    pub struct InMemoryExporter {
+        spans: Mutex<Vec<SpanData>>,
+    }
+
+    impl SpanExporter for Arc<InMemoryExporter> {
+        fn export(&self, span: SpanData) {
+            self.spans.lock().unwrap().push(span);
+        }
+    }
+
+    impl InMemoryExporter {
+        /// The tracer is global, so the tests filter the spans by their own trace id.
+        pub fn spans(&self, trace_id: &str) -> Vec<SpanData> {
+            self.spans
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|span| span.trace_id == trace_id)
+                .cloned()
+                .collect()
+        }
+    }
+
+    /// Install the in-memory exporter as the global tracer once.
+    pub fn exporter() -> Arc<InMemoryExporter> {
+        static EXPORTER: OnceCell<Arc<InMemoryExporter>> = OnceCell::new();
+
+        EXPORTER
+            .get_or_init(|| {
+                let exporter = Arc::new(InMemoryExporter::default());
+
+                assert!(set_tracer(Tracer::new(exporter.clone())), "tracer is set");
+
+                exporter
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_tree() {
+        let exporter = testing::exporter();
+
+        let log_cx = LogContext::builder().request_id("test").build();
+
+        let mut parent = Span::start("parent", SpanKind::Server, &log_cx);
+        let parent_cx = parent.log_context(&log_cx);
+        let trace_id = parent_cx.trace_id().unwrap().to_owned();
+
+        {
+            let mut child = Span::start("child", SpanKind::Internal, &parent_cx);
+            child.set_attribute("key", 1);
+            child.set_error();
+        }
+
+        parent.end();
+
+        let spans = exporter.spans(&trace_id);
+
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].name, "child");
+        assert_eq!(spans[0].parent_span_id.as_deref(), parent_cx.span_id());
+        assert_eq!(spans[0].attributes, vec![("key", "1".to_string())]);
+        assert!(spans[0].error);
+        assert_eq!(spans[1].name, "parent");
+        assert_eq!(spans[1].parent_span_id, None);
+        assert!(spans[1].end_time >= spans[1].start_time);
+        assert_eq!(
+            parent.trace_parent(),
+            None,
+            "the span must not be exported twice"
+        );
+    }
+}
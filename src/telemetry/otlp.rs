@@ -0,0 +1,247 @@
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use serde_json::{json, Value};
+use slog::{error, warn};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::time;
+
+use crate::log::{self, LogContext};
+use crate::telemetry::{SpanData, SpanExporter, SpanKind};
+
+const SERVICE_NAME: &str = "image_bed";
+const TRACES_PATH: &str = "/v1/traces";
+const QUEUE_SIZE: usize = 2048;
+const MAX_BATCH_SIZE: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Export the spans to an OTLP/HTTP collector with the JSON encoding. The spans are queued and
+/// sent in batches by a background task, spans are dropped when the queue is full.
+#[derive(Debug)]
+pub struct OtlpExporter {
+    sender: Sender<SpanData>,
+}
+
+impl OtlpExporter {
+    /// Must be called in the tokio runtime.
+    pub fn new(endpoint: &str) -> anyhow::Result<Self> {
+        let url = format!("{}{}", endpoint.trim_end_matches('/'), TRACES_PATH);
+        let url = Uri::from_str(&url)
+            .map_err(|err| anyhow::anyhow!("otlp endpoint {} is invalid: {}", endpoint, err))?;
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+
+        tokio::spawn(export_loop(url, receiver));
+
+        Ok(Self { sender })
+    }
+}
+
+impl SpanExporter for OtlpExporter {
+    fn export(&self, span: SpanData) {
+        if let Err(TrySendError::Full(span)) = self.sender.clone().try_send(span) {
+            warn!(log::get_logger(), "otlp queue is full, drop span {}", span.name);
+        }
+    }
+}
+
+async fn export_loop(url: Uri, mut receiver: Receiver<SpanData>) {
+    let client: Client<HttpsConnector<HttpConnector>> =
+        Client::builder().build(HttpsConnector::new());
+    let log_cx = LogContext::builder().request_id("otlp").build();
+
+    let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+
+    loop {
+        let closed = match time::timeout(FLUSH_INTERVAL, receiver.recv()).await {
+            Ok(Some(span)) => {
+                batch.push(span);
+
+                if batch.len() < MAX_BATCH_SIZE {
+                    continue;
+                }
+
+                false
+            }
+
+            Ok(None) => true,
+
+            Err(_) => false,
+        };
+
+        if !batch.is_empty() {
+            let spans = batch.drain(..).collect::<Vec<_>>();
+
+            send(&client, &url, &spans, &log_cx).await;
+        }
+
+        if closed {
+            return;
+        }
+    }
+}
+
+async fn send(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &Uri,
+    spans: &[SpanData],
+    log_cx: &LogContext,
+) {
+    let payload = encode(spans).to_string();
+
+    let result = match Request::builder()
+        .method(Method::POST)
+        .uri(url.clone())
+        .header("content-type", "application/json")
+        .body(Body::from(payload))
+    {
+        Err(err) => Err(err.to_string()),
+        Ok(req) => match client.request(req).await {
+            Err(err) => Err(err.to_string()),
+            Ok(resp) if resp.status().is_success() => Ok(()),
+            Ok(resp) => Err(format!("otlp collector responds {}", resp.status())),
+        },
+    };
+
+    if let Err(err) = result {
+        error!(
+            log::get_logger(),
+            "export spans failed: {}", err;
+            log_cx,
+            "spans" => spans.len()
+        );
+    }
+}
+
+/// Encode the spans as an OTLP `ExportTraceServiceRequest`.
+fn encode(spans: &[SpanData]) -> Value {
+    let spans = spans.iter().map(encode_span).collect::<Vec<_>>();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", SERVICE_NAME)]
+            },
+            "scopeSpans": [{
+                "scope": { "name": SERVICE_NAME },
+                "spans": spans
+            }]
+        }]
+    })
+}
+
+fn encode_span(span: &SpanData) -> Value {
+    let kind = match span.kind {
+        SpanKind::Internal => 1,
+        SpanKind::Server => 2,
+        SpanKind::Client => 3,
+    };
+
+    // 1 is ok, 2 is error
+    let status = if span.error { 2 } else { 1 };
+
+    let attributes = span
+        .attributes
+        .iter()
+        .map(|(key, value)| attribute(key, value))
+        .collect::<Vec<_>>();
+
+    json!({
+        "traceId": span.trace_id,
+        "spanId": span.span_id,
+        "parentSpanId": span.parent_span_id.as_deref().unwrap_or(""),
+        "name": span.name,
+        "kind": kind,
+        "startTimeUnixNano": unix_nano(span.start_time),
+        "endTimeUnixNano": unix_nano(span.end_time),
+        "attributes": attributes,
+        "status": { "code": status }
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn unix_nano(time: SystemTime) -> String {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos())
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+
+    use crate::http::webhook::testing;
+
+    use super::*;
+
+    fn span() -> SpanData {
+        SpanData {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+            span_id: "00f067aa0ba902b7".to_string(),
+            parent_span_id: None,
+            name: "HTTP GET".to_string(),
+            kind: SpanKind::Server,
+            start_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+            end_time: SystemTime::UNIX_EPOCH + Duration::from_secs(2),
+            attributes: vec![("http.status_code", "200".to_string())],
+            error: false,
+        }
+    }
+
+    #[test]
+    fn test_encode_span() {
+        let value = encode_span(&span());
+
+        assert_eq!(
+            value,
+            json!({
+                "traceId": "4bf92f3577b34da6a3ce929d0e0e4736",
+                "spanId": "00f067aa0ba902b7",
+                "parentSpanId": "",
+                "name": "HTTP GET",
+                "kind": 2,
+                "startTimeUnixNano": "1000000000",
+                "endTimeUnixNano": "2000000000",
+                "attributes": [{ "key": "http.status_code", "value": { "stringValue": "200" } }],
+                "status": { "code": 1 }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let (addr, bodies) = testing::mock_server(vec![StatusCode::OK]);
+
+        let exporter = OtlpExporter::new(&format!("http://{}/", addr)).unwrap();
+
+        exporter.export(span());
+
+        // closing the queue flushes the batch
+        drop(exporter);
+
+        for _ in 0..50 {
+            if !bodies.lock().unwrap().is_empty() {
+                break;
+            }
+
+            time::delay_for(Duration::from_millis(20)).await;
+        }
+
+        let bodies = bodies.lock().unwrap();
+
+        assert_eq!(bodies.len(), 1);
+
+        let payload: Value = serde_json::from_slice(&bodies[0]).unwrap();
+
+        assert_eq!(
+            payload["resourceSpans"][0]["scopeSpans"][0]["spans"][0],
+            encode_span(&span())
+        );
+    }
+}